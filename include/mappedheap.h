@@ -0,0 +1,80 @@
+/*
+ * C interface to mappedheap. Build the library with
+ *
+ *     cargo rustc --release --features capi --crate-type cdylib
+ *
+ * (or `--crate-type staticlib`).
+ *
+ * Written by hand rather than generated by cbindgen, to keep cbindgen out of the
+ * crate's build. Keep in sync with src/capi.rs (a test there checks that every
+ * function is declared here).
+ *
+ * A panic inside the library (e.g. on a corrupt freelist) aborts the process,
+ * since it may have left a lock in the file held.
+ */
+
+#ifndef MAPPEDHEAP_H
+#define MAPPEDHEAP_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The size of a page in bytes. */
+#define MAPPEDHEAP_PAGESZ 4096
+
+/* The null page guaranteed to always be invalid. */
+#define MAPPEDHEAP_NULL_PAGE 0
+
+typedef uint64_t mappedheap_page_id;
+
+/* Opaque handle to an open heap. */
+typedef struct MappedHeap MappedHeap;
+
+/* Opens (and creates if necessary) the heap at path. Returns NULL on failure. */
+MappedHeap *mappedheap_open(const char *path);
+
+/* Closes a handle. Passing NULL is a no-op. */
+void mappedheap_close(MappedHeap *heap);
+
+/* Allocates a page. Returns MAPPEDHEAP_NULL_PAGE if the file can't grow. */
+mappedheap_page_id mappedheap_alloc(const MappedHeap *heap);
+
+/* Frees a page. Returns 0 on success, -1 if the page id is invalid. */
+int mappedheap_free(const MappedHeap *heap, mappedheap_page_id id);
+
+/* Returns a pointer to MAPPEDHEAP_PAGESZ bytes of the page, or NULL if it doesn't exist. */
+uint8_t *mappedheap_page(const MappedHeap *heap, mappedheap_page_id id);
+
+/* Flushes all changes to disk. Returns 0 on success or the OS error code on failure. */
+int mappedheap_flush(const MappedHeap *heap);
+
+/*
+ * Radix trees (the crate's keyed structure, there is no B-tree), identified by
+ * their meta page. Callers must make sure nobody else uses a tree while they
+ * update it. Zero can't be stored as a value.
+ */
+
+/* Creates an empty tree and returns its meta page, or MAPPEDHEAP_NULL_PAGE if the file can't grow. */
+mappedheap_page_id mappedheap_radix_create(const MappedHeap *heap);
+
+/* Looks up a key. Returns its value, or 0 if there is none (or the tree is invalid). */
+uint64_t mappedheap_radix_get(const MappedHeap *heap, mappedheap_page_id tree, uint64_t key);
+
+/* Inserts or replaces an entry. Returns the previous value, 0 if there was none,
+ * or UINT64_MAX if the tree is invalid or value is zero. */
+uint64_t mappedheap_radix_insert(const MappedHeap *heap, mappedheap_page_id tree, uint64_t key, uint64_t value);
+
+/* Removes an entry. Returns its value, or 0 if there was none (or the tree is invalid). */
+uint64_t mappedheap_radix_remove(const MappedHeap *heap, mappedheap_page_id tree, uint64_t key);
+
+/* Frees all pages of a tree, including its meta page. Returns 0 on success, -1 if the tree is invalid. */
+int mappedheap_radix_destroy(const MappedHeap *heap, mappedheap_page_id tree);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MAPPEDHEAP_H */
@@ -0,0 +1,85 @@
+//! Python bindings for `mappedheap`, meant for analytics and inspection scripts
+//! that share a heap file with Rust services.
+//!
+//! Build with `maturin develop` from this directory.
+
+use std::ptr;
+
+use mappedheap::{PageId, PAGESZ};
+use pyo3::exceptions::{PyIOError, PyIndexError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+/// An extensible memory mapped file that keeps track of used and free pages.
+#[pyclass(name = "MappedHeap", unsendable)]
+struct PyMappedHeap {
+    heap: mappedheap::MappedHeap,
+}
+
+impl PyMappedHeap {
+    fn range(&self, id: PageId, offset: usize, len: usize) -> PyResult<*mut u8> {
+        let page = self
+            .heap
+            .try_page(id)
+            .map_err(|e| PyIOError::new_err(e.to_string()))?
+            .ok_or_else(|| PyIndexError::new_err(format!("page {} does not exist", id)))?;
+        if offset.checked_add(len).map_or(true, |end| end > PAGESZ) {
+            return Err(PyValueError::new_err("range exceeds page bounds"));
+        }
+        Ok(unsafe { (page as *mut u8).add(offset) })
+    }
+}
+
+#[pymethods]
+impl PyMappedHeap {
+    /// Opens (and creates if necessary) the heap at the given path.
+    #[new]
+    fn new(path: &str) -> PyResult<Self> {
+        let heap = mappedheap::MappedHeap::open(path).map_err(|e| PyIOError::new_err(e.to_string()))?;
+        Ok(PyMappedHeap { heap })
+    }
+
+    /// Allocates a new page and returns its id.
+    fn alloc(&self) -> PageId {
+        self.heap.alloc()
+    }
+
+    /// Frees a page.
+    fn free(&self, id: PageId) -> PyResult<()> {
+        self.range(id, 0, 0)?;
+        self.heap.free(id);
+        Ok(())
+    }
+
+    /// Copies `length` bytes starting at `offset` out of a page.
+    #[pyo3(signature = (id, offset=0, length=PAGESZ))]
+    fn read<'py>(&self, py: Python<'py>, id: PageId, offset: usize, length: usize) -> PyResult<Bound<'py, PyBytes>> {
+        let src = self.range(id, offset, length)?;
+        PyBytes::new_bound_with(py, length, |buf| {
+            unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), length) };
+            Ok(())
+        })
+    }
+
+    /// Copies `data` into a page at `offset`.
+    #[pyo3(signature = (id, data, offset=0))]
+    fn write(&self, id: PageId, data: &[u8], offset: usize) -> PyResult<()> {
+        let dst = self.range(id, offset, data.len())?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+
+    /// Flushes all changes to disk.
+    fn flush(&self) -> PyResult<()> {
+        self.heap.flush().map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "mappedheap")]
+fn mappedheap_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("PAGESZ", PAGESZ)?;
+    m.add("NULL_PAGE", mappedheap::NULL_PAGE)?;
+    m.add_class::<PyMappedHeap>()?;
+    Ok(())
+}
@@ -0,0 +1,305 @@
+//! Copying pages: within a heap (e.g. for shadow paging or compaction), or over from another heap
+//! (e.g. to consolidate many small heaps into one).
+
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::{io, ptr};
+
+use {copy_in_file, EventKind, MappedHeap, PageId, PAGESZ};
+
+/// Maps the ids of pages in another heap to the ids of their copies, see `MappedHeap::absorb`.
+pub type RelocationMap = BTreeMap<PageId, PageId>;
+
+impl MappedHeap {
+    /// Allocates a new page with the same contents as `src` and returns its id.
+    ///
+    /// This is the building block of shadow paging: modify the copy, then switch
+    /// whatever refers to the page over to it and free the original. Nobody may
+    /// modify `src` meanwhile.
+    ///
+    /// # Panics
+    ///
+    /// * If `src` doesn't exist.
+    /// * Same as `alloc`.
+    pub fn clone_page(&self, src: PageId) -> PageId {
+        self.page(src).expect("invalid page");
+        let new = self.alloc();
+        self.copy_page(src, new);
+        new
+    }
+
+    /// Moves the contents of page `src` to a newly allocated page, frees `src` and
+    /// returns the id of the new page.
+    ///
+    /// This is the primitive for compacting a heap: move the pages near the end of
+    /// the file (allocated with `AllocPolicy::LowestFirst`, so they land further to
+    /// the front), rewrite whatever refers to them, and the end of the file is left
+    /// free. Nobody may access `src` meanwhile, and it must not be used afterwards.
+    ///
+    /// On Linux, the contents are copied by the kernel (`FICLONERANGE`, which merely
+    /// shares the blocks on file systems with reflinks, or else `copy_file_range`)
+    /// instead of through the mapping, and freeing punches `src` out of the file.
+    ///
+    /// # Panics
+    ///
+    /// * If `src` doesn't exist.
+    /// * Same as `alloc` and `free`.
+    pub fn move_page(&self, src: PageId) -> PageId {
+        let new = self.clone_page(src);
+        self.free(src);
+        new
+    }
+
+    /// Moves allocated pages from the end of the file into the free pages in front of
+    /// them, then truncates the file (see `shrink_to_fit`) and returns by how many
+    /// pages it shrank.
+    ///
+    /// `relocate` is called with the old and new id of every page moved, and must
+    /// rewrite all references to it (root slots are updated automatically). The old
+    /// pages are only freed once all of them have been moved, so `relocate` can still
+    /// read them. The heap's own pages (the event log) and quarantined pages stay put.
+    ///
+    /// Other threads may keep allocating meanwhile, but nobody may access or free
+    /// pages allocated before the call. Pending `free_deferred` frees are applied and
+    /// this handle's `HeapOptions::batch_alloc` cache is given back first, pages cached
+    /// by other handles look allocated and would be moved from under them, so drop
+    /// those handles beforehand.
+    ///
+    /// # Panics
+    ///
+    /// * May panic if the freelist structure is corrupt.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mappedheap::MappedHeap;
+    ///
+    /// # let _ = std::fs::remove_file("/tmp/test-compact.bin");
+    /// let mapping = MappedHeap::open("/tmp/test-compact.bin").unwrap();
+    /// let mut ids: Vec<_> = (0..1000).map(|_| mapping.alloc()).collect();
+    /// for id in ids.drain(..900) {
+    ///     mapping.free(id);
+    /// }
+    /// mapping.compact(|old, new| {
+    ///     let i = ids.iter().position(|&x| x == old).unwrap();
+    ///     ids[i] = new;
+    /// }).unwrap();
+    /// assert_eq!(mapping.len_pages(), 101);
+    /// ```
+    pub fn compact<F: FnMut(PageId, PageId)>(&self, mut relocate: F) -> io::Result<u64> {
+        self.apply_deferred_frees(usize::MAX);
+        self.free_batch(&self.take_cached_pages());
+
+        let header = self.header();
+        let log = header.event_log.load(Ordering::Acquire);
+        let log = log..log + header.event_log_pages.load(Ordering::Relaxed);
+        let freelists = self.lock_freelists();
+        let free = self.collect_free_pages(&freelists);
+        // pair the lowest free pages with the highest allocated ones until they meet
+        let mut moves = Vec::new();
+        let mut src = self.size();
+        for &dst in &free {
+            src -= 1;
+            while src > dst && (free.binary_search(&src).is_ok() || log.contains(&src) || self.is_quarantined(src)) {
+                src -= 1;
+            }
+            if src <= dst {
+                break;
+            }
+            moves.push((src, dst));
+        }
+        let dsts: Vec<_> = moves.iter().map(|x| x.1).collect();
+        let punch = self.unlink_free_pages(&freelists, &dsts);
+        self.unlock_freelists(freelists);
+        self.punch_surplus(punch);
+
+        for &(src, dst) in &moves {
+            self.log_event(EventKind::Alloc, dst);
+            self.track_alloc(dst);
+            self.copy_page(src, dst);
+            for root in &header.roots {
+                let _ = root.compare_exchange(src, dst, Ordering::AcqRel, Ordering::Relaxed);
+            }
+            relocate(src, dst);
+        }
+        let srcs: Vec<_> = moves.iter().map(|x| x.0).collect();
+        for &x in &srcs {
+            self.log_event(EventKind::Free, x);
+            self.track_free(x);
+            self.forget_dirty(x);
+        }
+        self.free_batch(&srcs);
+        self.shrink_to_fit()
+    }
+
+    fn copy_page(&self, src: PageId, dst: PageId) {
+        let len = PAGESZ as u64;
+        if !copy_in_file(self.as_file(), src * len, dst * len, len) {
+            unsafe { ptr::copy_nonoverlapping(self.page(src).unwrap(), self.page(dst).unwrap(), 1) };
+        }
+    }
+
+    /// Clones several pages at once, see `clone_page`, and returns the ids of the
+    /// copies in the same order.
+    pub fn clone_pages(&self, src: &[PageId]) -> Vec<PageId> {
+        src.iter().map(|&id| self.clone_page(id)).collect()
+    }
+
+    /// Copies pages of `other` into freshly allocated pages of this heap and returns
+    /// which page went where.
+    ///
+    /// Pages are copied byte for byte, so any page ids stored inside them still refer
+    /// to `other`. Rewrite them through the returned map (which is why absorbing
+    /// everything a structure references at once is usually what you want). Pages
+    /// listed more than once are only copied once. Nobody may modify the listed pages
+    /// of `other` meanwhile.
+    ///
+    /// # Panics
+    ///
+    /// * If any of the pages doesn't exist in `other`.
+    /// * Same as `alloc`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mappedheap::MappedHeap;
+    ///
+    /// # let _ = std::fs::remove_file("/tmp/test-absorb-tenant.bin");
+    /// let tenant = MappedHeap::open("/tmp/test-absorb-tenant.bin").unwrap();
+    /// let page_id = tenant.alloc();
+    /// unsafe { (*tenant.page(page_id).unwrap())[0] = 42 };
+    ///
+    /// let mapping = MappedHeap::open("/tmp/test-absorb.bin").unwrap();
+    /// let moved = mapping.absorb(&tenant, vec![page_id]);
+    /// assert_eq!(unsafe { (*mapping.page(moved[&page_id]).unwrap())[0] }, 42);
+    /// ```
+    pub fn absorb<I: IntoIterator<Item = PageId>>(&self, other: &MappedHeap, pages: I) -> RelocationMap {
+        let mut ret = RelocationMap::new();
+        for id in pages {
+            if ret.contains_key(&id) {
+                continue;
+            }
+            let src = other.page(id).expect("invalid page");
+            let new = self.alloc();
+            unsafe { ptr::copy_nonoverlapping(src, self.page(new).unwrap(), 1) };
+            ret.insert(id, new);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use {AllocPolicy, ConsistencyLevel, HeapOptions};
+
+    #[test]
+    fn clone_page() {
+        let _ = fs::remove_file("/tmp/clonepage.bin");
+        let mapping = MappedHeap::open("/tmp/clonepage.bin").unwrap();
+        let ids: Vec<_> = (0..3).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            unsafe { (&mut *mapping.page(id).unwrap())[..8].copy_from_slice(&id.to_ne_bytes()) };
+        }
+
+        let copy = mapping.clone_page(ids[0]);
+        unsafe { (*mapping.page(copy).unwrap())[8] = 1 };
+        assert_eq!(unsafe { (*mapping.page(ids[0]).unwrap())[8] }, 0);
+        let copies = mapping.clone_pages(&ids);
+        for (&id, &copy) in ids.iter().zip(&copies) {
+            assert!(copy != id);
+            assert_eq!(unsafe { &(&*mapping.page(copy).unwrap())[..8] }, id.to_ne_bytes());
+        }
+
+        let _ = fs::remove_file("/tmp/clonepage.bin");
+    }
+
+    #[test]
+    fn move_page() {
+        let _ = fs::remove_file("/tmp/movepage.bin");
+        let mapping = HeapOptions::new().alloc_policy(AllocPolicy::LowestFirst).open("/tmp/movepage.bin").unwrap();
+        let ids: Vec<_> = (0..10).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            unsafe { ptr::write_bytes(mapping.page(id).unwrap(), id as u8, 1) };
+        }
+        mapping.free(ids[2]);
+
+        let moved = mapping.move_page(ids[9]);
+        assert_eq!(moved, ids[2]);
+        assert!(unsafe { (*mapping.page(moved).unwrap()).iter().all(|&x| x == ids[9] as u8) });
+        assert_eq!(mapping.alloc(), ids[9]);
+
+        let _ = fs::remove_file("/tmp/movepage.bin");
+    }
+
+    #[test]
+    fn compact() {
+        let _ = fs::remove_file("/tmp/compact.bin");
+        let mapping = HeapOptions::new().event_log(1).open("/tmp/compact.bin").unwrap();
+        let mut ids: Vec<_> = (0..3000).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            unsafe { (&mut *mapping.page(id).unwrap())[..8].copy_from_slice(&id.to_ne_bytes()) };
+        }
+        // keep every tenth page, and the highest one in a root slot
+        let mut kept: BTreeMap<PageId, PageId> = BTreeMap::new();
+        for (i, id) in ids.drain(..).enumerate() {
+            if i % 10 == 0 {
+                kept.insert(id, id);
+            } else {
+                mapping.free(id);
+            }
+        }
+        let top = *kept.keys().last().unwrap();
+        mapping.root_or_create(0, |_| top);
+
+        let size = mapping.size();
+        let mut moved = 0;
+        let shrunk = mapping.compact(|old, new| {
+            assert!(new < old);
+            let original = kept.iter().find(|x| *x.1 == old).map(|x| *x.0).unwrap();
+            kept.insert(original, new);
+            moved += 1;
+        }).unwrap();
+        assert!(moved > 0);
+        // the header, the event log and the kept pages are left
+        assert_eq!(mapping.size(), 2 + kept.len() as u64);
+        assert_eq!(shrunk, size - mapping.size());
+        assert_eq!(mapping.free_pages(), 0);
+        assert_eq!(mapping.root(0), Some(kept[&top]));
+        for (&original, &id) in &kept {
+            assert_eq!(unsafe { &(&*mapping.page(id).unwrap())[..8] }, original.to_ne_bytes());
+        }
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+        assert_eq!(mapping.compact(|_, _| panic!()).unwrap(), 0);
+
+        let _ = fs::remove_file("/tmp/compact.bin");
+    }
+
+    #[test]
+    fn absorb() {
+        let _ = fs::remove_file("/tmp/absorb.bin");
+        let _ = fs::remove_file("/tmp/absorb-tenant.bin");
+        let mapping = MappedHeap::open("/tmp/absorb.bin").unwrap();
+        let tenant = MappedHeap::open("/tmp/absorb-tenant.bin").unwrap();
+        let ids: Vec<_> = (0..100).map(|_| tenant.alloc()).collect();
+        for &id in &ids {
+            unsafe { (&mut *tenant.page(id).unwrap())[..8].copy_from_slice(&id.to_ne_bytes()) };
+        }
+        let own = mapping.alloc();
+
+        let moved = mapping.absorb(&tenant, ids.iter().cloned().chain(ids[..10].iter().cloned()));
+        assert_eq!(moved.len(), 100);
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert!(moved.keys().eq(sorted.iter()));
+        assert!(!moved.values().any(|&x| x == own));
+        for (&old, &new) in &moved {
+            assert_eq!(unsafe { &(&*mapping.page(new).unwrap())[..8] }, old.to_ne_bytes());
+        }
+        assert_eq!(mapping.free_pages(), mapping.size() - 102);
+
+        let _ = fs::remove_file("/tmp/absorb.bin");
+        let _ = fs::remove_file("/tmp/absorb-tenant.bin");
+    }
+}
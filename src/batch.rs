@@ -0,0 +1,204 @@
+//! Write batches: coalescing small updates to the same pages.
+//!
+//! Every page written through a batch gets a private copy that all writes (and reads)
+//! go to. Committing copies only the modified part of each page back, once, in page
+//! order. Sequences of small updates to a few pages thus dirty each cache line of the
+//! mapping at most once, and leave behind compact ranges to write back.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io;
+
+use {write_back, Durability, MappedHeap, PageId, PAGESZ};
+
+struct Shadow {
+    data: Box<[u8; PAGESZ]>,
+    dirty: (usize, usize), // byte range
+}
+
+/// Buffers writes to pages and applies them all at once.
+///
+/// Nothing reaches the heap until `commit`, and dropping the batch discards it.
+/// The heap's pages must not be modified behind the batch's back in the meantime,
+/// as committing overwrites the modified ranges with the batch's copies.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, WriteBatch};
+///
+/// let mapping = MappedHeap::open("/tmp/test-batch.bin").unwrap();
+/// let page_id = mapping.alloc();
+/// let mut batch = WriteBatch::new(&mapping);
+/// batch.write(page_id, 0, b"hello");
+/// batch.write(page_id, 5, b" world");
+/// batch.commit();
+/// assert_eq!(&unsafe { &*mapping.page(page_id).unwrap() }[..11], b"hello world");
+/// mapping.free(page_id);
+/// ```
+pub struct WriteBatch<'a> {
+    heap: &'a MappedHeap,
+    pages: BTreeMap<PageId, Shadow>,
+}
+
+impl<'a> WriteBatch<'a> {
+    /// Creates an empty batch.
+    pub fn new(heap: &'a MappedHeap) -> WriteBatch<'a> {
+        WriteBatch { heap, pages: BTreeMap::new() }
+    }
+
+    fn shadow(&mut self, page: PageId) -> &mut Shadow {
+        let heap = self.heap;
+        self.pages.entry(page).or_insert_with(|| {
+            let data = unsafe { *heap.page(page).expect("invalid page id") };
+            Shadow { data: Box::new(data), dirty: (PAGESZ, 0) }
+        })
+    }
+
+    /// Writes `data` to `page` at byte offset `offset`.
+    ///
+    /// # Panics
+    ///
+    /// * If the page doesn't exist or the write doesn't fit in it.
+    pub fn write(&mut self, page: PageId, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= PAGESZ, "write beyond the end of the page");
+        let shadow = self.shadow(page);
+        shadow.data[offset..offset + data.len()].copy_from_slice(data);
+        shadow.dirty = (cmp::min(shadow.dirty.0, offset), cmp::max(shadow.dirty.1, offset + data.len()));
+    }
+
+    /// Reads from `page` at byte offset `offset`, including the batch's writes.
+    ///
+    /// # Panics
+    ///
+    /// * If the page doesn't exist or the read doesn't fit in it.
+    pub fn read(&self, page: PageId, offset: usize, buf: &mut [u8]) {
+        assert!(offset + buf.len() <= PAGESZ, "read beyond the end of the page");
+        let range = offset..offset + buf.len();
+        match self.pages.get(&page) {
+            Some(shadow) => buf.copy_from_slice(&shadow.data[range]),
+            None => buf.copy_from_slice(unsafe { &(&*self.heap.page(page).expect("invalid page id"))[range] }),
+        }
+    }
+
+    /// The number of pages the batch has written to.
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Whether the batch is empty.
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Applies all writes to the heap.
+    pub fn commit(self) {
+        self.apply();
+    }
+
+    /// Applies all writes to the heap and writes the modified pages back to disk.
+    ///
+    /// Like `MappedHeap::flush_window`, this doesn't sync any metadata.
+    pub fn commit_and_write_back(self) -> io::Result<()> {
+        self.write_back(true)
+    }
+
+    /// Applies all writes to the heap and waits for them to reach the disk as far as
+    /// `durability` says.
+    ///
+    /// `Durability::None` is the same as `commit`, `Durability::Async` only starts
+    /// writing back the modified pages, and `Durability::Sync` waits for them and
+    /// syncs the file's metadata on top, so the writes are durable once this returns.
+    pub fn commit_with(self, durability: Durability) -> io::Result<()> {
+        let heap = self.heap;
+        match durability {
+            Durability::None => {
+                self.apply();
+                Ok(())
+            }
+            Durability::Async => self.write_back(false),
+            Durability::Sync => {
+                self.write_back(true)?;
+                heap.as_file().sync_data()
+            }
+        }
+    }
+
+    fn write_back(self, wait: bool) -> io::Result<()> {
+        let heap = self.heap;
+        // contiguous runs of pages
+        let mut runs: Vec<(PageId, PageId)> = Vec::new();
+        for page in self.apply() {
+            match runs.last_mut() {
+                Some(run) if run.1 == page => run.1 += 1,
+                _ => runs.push((page, page + 1)),
+            }
+        }
+        for (start, end) in runs {
+            write_back(heap.as_file(), start * PAGESZ as u64, (end - start) * PAGESZ as u64, wait)?;
+        }
+        Ok(())
+    }
+
+    // Returns the ids of the modified pages, in ascending order.
+    fn apply(self) -> Vec<PageId> {
+        let mut ret = Vec::with_capacity(self.pages.len());
+        for (id, shadow) in self.pages {
+            let (start, end) = shadow.dirty;
+            if start < end {
+                let page = self.heap.page(id).unwrap();
+                unsafe { (&mut *page)[start..end].copy_from_slice(&shadow.data[start..end]) };
+                ret.push(id);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn write_batch() {
+        let _ = fs::remove_file("/tmp/batch.bin");
+        let mapping = MappedHeap::open("/tmp/batch.bin").unwrap();
+        let ids: Vec<_> = (0..3).map(|_| mapping.alloc()).collect();
+        let page = |id| unsafe { &mut *mapping.page(id).unwrap() };
+        *page(ids[0]) = [1; PAGESZ];
+
+        let mut batch = WriteBatch::new(&mapping);
+        for i in 0..100 {
+            batch.write(ids[0], i * 8, &[2; 4]);
+        }
+        batch.write(ids[2], PAGESZ - 1, &[3]);
+        let mut buf = [0; 8];
+        batch.read(ids[0], 0, &mut buf);
+        assert_eq!(buf, [2, 2, 2, 2, 1, 1, 1, 1]);
+        // nothing applied yet
+        assert_eq!(page(ids[0])[0], 1);
+        assert_eq!(batch.len(), 2);
+
+        // writes to the heap outside of the modified range survive
+        page(ids[0])[PAGESZ - 1] = 4;
+        batch.commit_and_write_back().unwrap();
+        assert_eq!(&page(ids[0])[792..800], &[2, 2, 2, 2, 1, 1, 1, 1]);
+        assert_eq!(page(ids[0])[PAGESZ - 1], 4);
+        assert_eq!(page(ids[2])[PAGESZ - 1], 3);
+
+        let mut batch = WriteBatch::new(&mapping);
+        batch.write(ids[1], 0, &[5]);
+        drop(batch);
+        assert_eq!(page(ids[1])[0], 0);
+
+        for (i, &durability) in [Durability::None, Durability::Async, Durability::Sync].iter().enumerate() {
+            let mut batch = WriteBatch::new(&mapping);
+            batch.write(ids[1], i, &[6]);
+            batch.commit_with(durability).unwrap();
+            assert_eq!(page(ids[1])[i], 6);
+        }
+
+        let _ = fs::remove_file("/tmp/batch.bin");
+    }
+}
@@ -0,0 +1,155 @@
+//! Filling a new heap with large sequential writes instead of through the mapping.
+//!
+//! Populating a fresh heap through `alloc` faults in every page one at a time and
+//! grows the file over and over, which makes the initial load of a multi-GB dataset
+//! far slower than just streaming it to disk. A `BulkLoader` writes the pages to a
+//! temporary file with `pwrite`s of `CHUNK_PAGES` pages each and only maps the file
+//! once it is complete.
+
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tempfile::NamedTempFile;
+
+use {HeapOptions, MappedHeap, PageId, NULL_PAGE, PAGESZ, ROOT_SLOTS};
+
+const CHUNK_PAGES: usize = 256;
+
+/// Writes the initial contents of a new heap file.
+///
+/// Appended pages are numbered consecutively, starting at 2 (page 0 is the header
+/// and page 1 the empty freelist). The file is built under a temporary name and only
+/// appears at its path in `finish`, so nobody ever opens a partially loaded heap.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::BulkLoader;
+///
+/// # let _ = std::fs::remove_file("/tmp/test-bulk.bin");
+/// let mut loader = BulkLoader::create("/tmp/test-bulk.bin").unwrap();
+/// let id = loader.append(b"hello").unwrap();
+/// loader.set_root(0, id);
+/// let mapping = loader.finish().unwrap();
+/// assert_eq!(mapping.root(0), Some(id));
+/// # std::fs::remove_file("/tmp/test-bulk.bin").unwrap();
+/// ```
+pub struct BulkLoader {
+    file: NamedTempFile,
+    path: PathBuf,
+    buf: Vec<u8>, // pages not written yet
+    written: PageId, // the size of the file so far, in pages
+    roots: [PageId; ROOT_SLOTS],
+}
+
+impl BulkLoader {
+    /// Starts loading a new heap file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<BulkLoader> {
+        let path = path.as_ref().to_path_buf();
+        Ok(BulkLoader {
+            file: MappedHeap::create_temp_file(&path)?,
+            path,
+            buf: Vec::with_capacity(CHUNK_PAGES * PAGESZ),
+            // (the header and the freelist page are written by finish)
+            written: 2,
+            roots: [NULL_PAGE; ROOT_SLOTS],
+        })
+    }
+
+    /// The id the next appended page will get.
+    pub fn next_id(&self) -> PageId {
+        self.written + (self.buf.len() / PAGESZ) as u64
+    }
+
+    /// Appends a page, zero-padding `data` to a full page, and returns its id.
+    ///
+    /// # Panics
+    ///
+    /// * If `data` is longer than a page.
+    pub fn append(&mut self, data: &[u8]) -> io::Result<PageId> {
+        assert!(data.len() <= PAGESZ, "data larger than a page");
+        let id = self.next_id();
+        self.buf.extend_from_slice(data);
+        self.buf.resize(self.buf.len() + PAGESZ - data.len(), 0);
+        if self.buf.len() == CHUNK_PAGES * PAGESZ {
+            self.write_buf()?;
+        }
+        Ok(id)
+    }
+
+    /// Stores a page id in a root slot of the new heap, see `MappedHeap::root`.
+    ///
+    /// # Panics
+    ///
+    /// * If `slot` is not less than `ROOT_SLOTS`.
+    /// * If `id` is not an appended page.
+    pub fn set_root(&mut self, slot: usize, id: PageId) {
+        assert!(id >= 2 && id < self.next_id(), "not an appended page");
+        self.roots[slot] = id;
+    }
+
+    fn write_buf(&mut self) -> io::Result<()> {
+        self.file.write_all_at(&self.buf, self.written * PAGESZ as u64)?;
+        self.written += (self.buf.len() / PAGESZ) as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes the header, moves the file to its path and opens it.
+    ///
+    /// Like everything else, the loaded pages are only durable after a `flush`.
+    ///
+    /// Fails with `AlreadyExists` if something was created at the path in the meantime
+    /// (in which case the loaded data is discarded).
+    pub fn finish(mut self) -> io::Result<MappedHeap> {
+        self.write_buf()?;
+        let header = MappedHeap::initial_header(self.written, &self.roots);
+        self.file.write_all_at(&header, 0)?;
+        // page 1 is a hole, i.e. an empty freelist page
+        self.file.set_len(self.written * PAGESZ as u64)?;
+        let file = self.file.persist_noclobber(&self.path).map_err(|x| x.error)?;
+        let mut heap = MappedHeap::open_file_with(file, &HeapOptions::new())?;
+        Arc::get_mut(&mut heap.inner).unwrap().path = Some(self.path);
+        Ok(heap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use ConsistencyLevel;
+
+    #[test]
+    fn bulk_load() {
+        let _ = fs::remove_file("/tmp/bulk.bin");
+        let mut loader = BulkLoader::create("/tmp/bulk.bin").unwrap();
+        for i in 0..1000u64 {
+            assert_eq!(loader.append(&i.to_ne_bytes()).unwrap(), i + 2);
+        }
+        loader.set_root(3, 500);
+        // nothing visible before finish
+        assert!(fs::metadata("/tmp/bulk.bin").is_err());
+        let mapping = loader.finish().unwrap();
+
+        assert_eq!(mapping.size(), 1002);
+        assert_eq!(mapping.root(3), Some(500));
+        assert_eq!(mapping.path(), Some(Path::new("/tmp/bulk.bin")));
+        for i in 0..1000u64 {
+            let page = unsafe { &*mapping.page(i + 2).unwrap() };
+            assert_eq!(page[..8], i.to_ne_bytes());
+            assert!(page[8..].iter().all(|&x| x == 0));
+        }
+        assert_eq!(mapping.free_pages(), 1);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+        let id = mapping.alloc();
+        assert!(id == 1 || id >= 1002);
+
+        let loader = BulkLoader::create("/tmp/bulk.bin").unwrap();
+        assert_eq!(loader.finish().err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+
+        let _ = fs::remove_file("/tmp/bulk.bin");
+    }
+}
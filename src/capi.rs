@@ -0,0 +1,220 @@
+//! C API for non-Rust processes sharing a heap file.
+//!
+//! Every function takes the handle returned by `mappedheap_open`. Since all
+//! coordination happens through the futexes stored in the file itself, C programs
+//! using these functions follow exactly the same locking protocol as Rust code.
+//!
+//! Arguments are validated up front where that is cheap (NULL handles, page ids
+//! outside the file, zero values), and reported through the respective function's
+//! error value. Anything else that makes the heap panic (e.g. a corrupt freelist)
+//! aborts the process: panics never cross the FFI boundary, and unwinding out of
+//! the middle of an operation may leave a lock in the file held, which would hang
+//! every other process using it.
+//!
+//! The crate has no B-tree, its keyed structure is the `RadixTree`, so the
+//! `mappedheap_radix_*` functions stand in for `btree_get`/`insert`/`remove`. A tree
+//! is identified by its meta page id (e.g. kept in a root slot), and just like in
+//! Rust, callers must make sure nobody else uses a tree while they update it.
+//!
+//! The matching header lives in `include/mappedheap.h`. It is written by hand
+//! rather than generated by cbindgen, which would add a build-time dependency
+//! (and a build script) to every build of the crate for a handful of declarations;
+//! a test checks that it declares every function defined here.
+//!
+//! # Safety
+//!
+//! All handle arguments must either be NULL or a live handle obtained from
+//! `mappedheap_open`, all path arguments must be NULL or NUL-terminated, and all
+//! tree arguments must be meta pages created by `mappedheap_radix_create` (or
+//! `RadixTree::create`).
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::{process, ptr};
+
+use {MappedHeap, PageId, RadixTree, NULL_PAGE};
+
+// Runs `f`, aborting the process if it panics (see the module docs).
+fn catch<T, F: FnOnce() -> T>(f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| process::abort())
+}
+
+// The heap behind a handle, and whether `id` is a page within it.
+unsafe fn heap_and_page<'a>(heap: *const MappedHeap, id: PageId) -> Option<&'a MappedHeap> {
+    heap.as_ref().filter(|heap| id != NULL_PAGE && id < heap.size())
+}
+
+/// Opens (and creates if necessary) the heap at the given NUL-terminated path.
+///
+/// Returns NULL on failure. The handle must be released with `mappedheap_close`.
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_open(path: *const c_char) -> *mut MappedHeap {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(x) => x,
+        Err(_) => return ptr::null_mut(),
+    };
+    catch(|| match MappedHeap::open(path) {
+        Ok(heap) => Box::into_raw(Box::new(heap)),
+        Err(_) => ptr::null_mut(),
+    })
+}
+
+/// Closes a handle returned by `mappedheap_open`. Passing NULL is a no-op.
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_close(heap: *mut MappedHeap) {
+    if !heap.is_null() {
+        drop(Box::from_raw(heap));
+    }
+}
+
+/// Allocates a page. Returns `MAPPEDHEAP_NULL_PAGE` (0) if the file can't grow.
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_alloc(heap: *const MappedHeap) -> PageId {
+    match heap.as_ref() {
+        Some(heap) => catch(|| heap.try_alloc().unwrap_or(NULL_PAGE)),
+        None => NULL_PAGE,
+    }
+}
+
+/// Frees a page. Returns 0 on success, -1 if the page id is invalid.
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_free(heap: *const MappedHeap, id: PageId) -> c_int {
+    match heap_and_page(heap, id) {
+        Some(heap) => catch(|| { heap.free(id); 0 }),
+        None => -1,
+    }
+}
+
+/// Returns a pointer to the given page (`MAPPEDHEAP_PAGESZ` bytes), or NULL if it doesn't exist.
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_page(heap: *const MappedHeap, id: PageId) -> *mut u8 {
+    match heap.as_ref() {
+        Some(heap) => catch(|| heap.page(id)).map(|x| x as *mut u8).unwrap_or(ptr::null_mut()),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Flushes all changes to disk. Returns 0 on success or the OS error code on failure.
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_flush(heap: *const MappedHeap) -> c_int {
+    match heap.as_ref() {
+        Some(heap) => catch(|| match heap.flush() {
+            Ok(()) => 0,
+            Err(e) => e.raw_os_error().unwrap_or(-1),
+        }),
+        None => -1,
+    }
+}
+
+/// Creates an empty radix tree and returns its meta page, or `MAPPEDHEAP_NULL_PAGE`
+/// (0) if the file can't grow.
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_radix_create(heap: *const MappedHeap) -> PageId {
+    match heap.as_ref() {
+        Some(heap) => catch(|| match heap.try_alloc() {
+            Ok(id) => RadixTree::init(heap, id).meta_page(),
+            Err(_) => NULL_PAGE,
+        }),
+        None => NULL_PAGE,
+    }
+}
+
+/// Looks up a key. Returns its value, or 0 if there is none (or the tree is invalid).
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_radix_get(heap: *const MappedHeap, tree: PageId, key: u64) -> u64 {
+    match heap_and_page(heap, tree) {
+        Some(heap) => catch(|| RadixTree::open(heap, tree).get(key).unwrap_or(0)),
+        None => 0,
+    }
+}
+
+/// Inserts or replaces an entry. Returns the previous value, 0 if there was none, or
+/// -1 (all bits set) if the tree is invalid or `value` is zero (which can't be stored).
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_radix_insert(heap: *const MappedHeap, tree: PageId, key: u64, value: u64) -> u64 {
+    match heap_and_page(heap, tree) {
+        Some(heap) if value != 0 => catch(|| RadixTree::open(heap, tree).insert(key, value).unwrap_or(0)),
+        _ => u64::MAX,
+    }
+}
+
+/// Removes an entry. Returns its value, or 0 if there was none (or the tree is invalid).
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_radix_remove(heap: *const MappedHeap, tree: PageId, key: u64) -> u64 {
+    match heap_and_page(heap, tree) {
+        Some(heap) => catch(|| RadixTree::open(heap, tree).remove(key).unwrap_or(0)),
+        None => 0,
+    }
+}
+
+/// Frees all pages of a radix tree, including its meta page. Returns 0 on success,
+/// -1 if the tree is invalid.
+#[no_mangle]
+pub unsafe extern "C" fn mappedheap_radix_destroy(heap: *const MappedHeap, tree: PageId) -> c_int {
+    match heap_and_page(heap, tree) {
+        Some(heap) => catch(|| { RadixTree::open(heap, tree).destroy(); 0 }),
+        None => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::fs;
+    use PAGESZ;
+
+    #[test]
+    fn roundtrip() {
+        let _ = fs::remove_file("/tmp/capi.bin");
+        let path = CString::new("/tmp/capi.bin").unwrap();
+        unsafe {
+            let heap = mappedheap_open(path.as_ptr());
+            assert!(!heap.is_null());
+
+            let id = mappedheap_alloc(heap);
+            assert!(id != NULL_PAGE);
+            let page = mappedheap_page(heap, id);
+            assert!(!page.is_null());
+            ptr::write_bytes(page, 0x42, PAGESZ);
+            assert_eq!(mappedheap_flush(heap), 0);
+
+            assert!(mappedheap_page(heap, NULL_PAGE).is_null());
+            assert_eq!(mappedheap_free(heap, NULL_PAGE), -1);
+            assert_eq!(mappedheap_free(heap, 1 << 40), -1);
+            assert_eq!(mappedheap_free(heap, id), 0);
+
+            let tree = mappedheap_radix_create(heap);
+            assert!(tree != NULL_PAGE);
+            assert_eq!(mappedheap_radix_insert(heap, tree, 1 << 20, 7), 0);
+            assert_eq!(mappedheap_radix_insert(heap, tree, 1 << 20, 8), 7);
+            assert_eq!(mappedheap_radix_insert(heap, tree, 1, 0), u64::MAX);
+            assert_eq!(mappedheap_radix_get(heap, tree, 1 << 20), 8);
+            assert_eq!(mappedheap_radix_get(heap, tree, 1), 0);
+            assert_eq!(mappedheap_radix_remove(heap, tree, 1 << 20), 8);
+            assert_eq!(mappedheap_radix_remove(heap, tree, 1 << 20), 0);
+            assert_eq!(mappedheap_radix_destroy(heap, NULL_PAGE), -1);
+            assert_eq!(mappedheap_radix_destroy(heap, tree), 0);
+            mappedheap_close(heap);
+        }
+        let _ = fs::remove_file("/tmp/capi.bin");
+    }
+
+    #[test]
+    fn header() {
+        let header = include_str!("../include/mappedheap.h");
+        let source = include_str!("capi.rs");
+        let fns: Vec<_> = source.split("pub unsafe extern \"C\" fn ").skip(1)
+            .map(|x| &x[..x.find('(').unwrap()])
+            .collect();
+        assert!(fns.len() >= 10);
+        for name in fns {
+            assert!(header.contains(&format!(" {}(", name)) || header.contains(&format!("*{}(", name)), "{} is missing from the header", name);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Reading pages without dying of `SIGBUS`.
+//!
+//! Accessing a mapped page that the file can't back (because it was truncated
+//! behind our back, or because the NFS server or thin-provisioned volume under it
+//! failed) raises `SIGBUS`, which normally kills the process. `checked_read` turns
+//! that into an error instead: a process-wide handler, installed on first use,
+//! replaces the faulting page with a zero page so the access can complete, and the
+//! file is mapped back in once the closure has returned. Faults anywhere else are
+//! passed on to whatever handler was there before.
+
+use libc::{mmap, sigaction, sigemptyset, siginfo_t, c_int, c_void, off_t, MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED,
+           MAP_PRIVATE, MAP_SHARED, PROT_READ, PROT_WRITE, SA_SIGINFO, SIGBUS, SIGSEGV, SIG_DFL, SIG_IGN};
+use std::cell::Cell;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{compiler_fence, AtomicPtr, Ordering};
+use std::sync::Once;
+use std::{io, mem, ptr};
+
+use {MappedHeap, PageId, PAGESZ};
+
+thread_local! {
+    // the page checked_read is reading on this thread (0 if none), and whether it faulted
+    static GUARDED: Cell<usize> = const { Cell::new(0) };
+    static FAULTED: Cell<bool> = const { Cell::new(false) };
+}
+
+static PREVIOUS: [AtomicPtr<sigaction>; 2] = [AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut())];
+static INSTALL: Once = Once::new();
+
+extern "C" fn handler(sig: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let page = GUARDED.with(|x| x.get());
+    if page != 0 && addr >= page && addr < page + PAGESZ {
+        let ret = unsafe {
+            mmap(page as *mut c_void, PAGESZ, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, -1, 0)
+        };
+        if ret != MAP_FAILED {
+            FAULTED.with(|x| x.set(true));
+            return;
+        }
+    }
+
+    let previous = PREVIOUS[(sig == SIGSEGV) as usize].load(Ordering::Acquire);
+    unsafe {
+        if previous.is_null() || (*previous).sa_sigaction == SIG_DFL || (*previous).sa_sigaction == SIG_IGN {
+            // the access faults again once we return, and kills the process as usual
+            let mut action: sigaction = mem::zeroed();
+            action.sa_sigaction = SIG_DFL;
+            sigaction(sig, &action, ptr::null_mut());
+        } else if (*previous).sa_flags & SA_SIGINFO != 0 {
+            let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = mem::transmute((*previous).sa_sigaction);
+            f(sig, info, context);
+        } else {
+            let f: extern "C" fn(c_int) = mem::transmute((*previous).sa_sigaction);
+            f(sig);
+        }
+    }
+}
+
+fn install_handler() {
+    INSTALL.call_once(|| unsafe {
+        for (i, &sig) in [SIGBUS, SIGSEGV].iter().enumerate() {
+            let mut action: sigaction = mem::zeroed();
+            action.sa_sigaction = handler as extern "C" fn(c_int, *mut siginfo_t, *mut c_void) as usize;
+            action.sa_flags = SA_SIGINFO;
+            sigemptyset(&mut action.sa_mask);
+            let previous = Box::into_raw(Box::new(mem::zeroed::<sigaction>()));
+            if sigaction(sig, &action, previous) == 0 {
+                PREVIOUS[i].store(previous, Ordering::Release);
+            }
+        }
+    });
+}
+
+// Restores the thread's state when checked_read is done (or f panics).
+struct Guard {
+    page: usize,
+    faulted: bool,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        GUARDED.with(|x| x.set(self.page));
+        FAULTED.with(|x| x.set(self.faulted));
+    }
+}
+
+impl MappedHeap {
+    /// Calls `f` with a page, returning an error if reading it faults.
+    ///
+    /// Returns `Ok(None)` if the page doesn't exist. If the page is not backed by
+    /// the file (e.g. because the file was truncated, or its storage failed), `f`
+    /// sees zeroes where the page can't be read, its result is discarded and an
+    /// error is returned instead of the process getting killed by `SIGBUS`.
+    ///
+    /// Only reads of the page itself are guarded, and only while `f` runs on the
+    /// calling thread. The first call installs handlers for `SIGBUS` and `SIGSEGV`
+    /// (which chain to any handlers installed before).
+    ///
+    /// No other thread may access the page while `f` runs: after a fault, the page is
+    /// replaced with a zero page for the whole process until `f` returns, so they
+    /// would read zeroes, and their writes would be lost. If the file can't be mapped
+    /// back in afterwards, the page is quarantined (like `HeapOptions::quarantine`
+    /// does on open), so `page` doesn't hand out memory that never reaches the file.
+    pub fn checked_read<R, F: FnOnce(&[u8; PAGESZ]) -> R>(&self, id: PageId, f: F) -> io::Result<Option<R>> {
+        let page = match self.try_page(id)? {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        install_handler();
+
+        let ret;
+        let faulted;
+        {
+            let _guard = Guard {
+                page: GUARDED.with(|x| x.replace(page as usize)),
+                faulted: FAULTED.with(|x| x.replace(false)),
+            };
+            compiler_fence(Ordering::SeqCst);
+            ret = f(unsafe { &*page });
+            compiler_fence(Ordering::SeqCst);
+            faulted = FAULTED.with(|x| x.get());
+        }
+
+        if faulted {
+            // map the file back in, so the page works again once the file does
+            let ret = unsafe {
+                mmap(page as *mut c_void, PAGESZ, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_FIXED,
+                     self.as_raw_fd(), (id * PAGESZ as u64) as off_t)
+            };
+            if ret == MAP_FAILED {
+                let err = io::Error::last_os_error();
+                self.inner.poisoned.write().unwrap().push(id);
+                self.inner.any_poisoned.store(true, Ordering::Release);
+                return Err(err);
+            }
+            return Err(io::Error::other(format!("reading page {} faulted", id)));
+        }
+        Ok(Some(ret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn checked_read() {
+        let _ = fs::remove_file("/tmp/checked.bin");
+        let mapping = MappedHeap::open("/tmp/checked.bin").unwrap();
+        let ids: Vec<_> = (0..100).map(|_| mapping.alloc()).collect();
+        let last = *ids.last().unwrap();
+        unsafe { (*mapping.page(last).unwrap())[0] = 42 };
+        assert_eq!(mapping.checked_read(last, |x| x[0]).unwrap(), Some(42));
+        assert_eq!(mapping.checked_read(mapping.len_pages(), |x| x[0]).unwrap(), None);
+
+        // cut the file short behind the heap's back
+        let len = mapping.len_bytes();
+        mapping.as_file().set_len(last * PAGESZ as u64).unwrap();
+        assert!(mapping.checked_read(last, |x| x[0]).is_err());
+        assert_eq!(mapping.checked_read(ids[0], |x| x.len()).unwrap(), Some(PAGESZ));
+        // nested reads of another page still fault
+        // (the result has to be used, or the read may be optimized out)
+        let ret = mapping.checked_read(ids[0], |_| mapping.checked_read(last, |x| x[0]).map_err(|_| ()));
+        assert_eq!(ret.unwrap(), Some(Err(())));
+
+        mapping.as_file().set_len(len).unwrap();
+        assert_eq!(mapping.checked_read(last, |x| x[0]).unwrap(), Some(0));
+
+        let _ = fs::remove_file("/tmp/checked.bin");
+    }
+}
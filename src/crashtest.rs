@@ -0,0 +1,276 @@
+//! Utilities for torture testing heaps against process crashes (feature `crash-tests`).
+//!
+//! `Torture::run` repeatedly forks worker processes that operate on a heap, kills them
+//! with `SIGKILL` at random points and then checks the heap in a fresh process. Since
+//! workers die in the middle of whatever they're doing, this finds operations that
+//! leave the file inconsistent (or locks held) when interrupted.
+//!
+//! Forking a multi-threaded process is only safe if no other thread holds a lock the
+//! children need (such as the allocator's), so run crash tests in their own test binary
+//! (like `tests/crashtest.rs`) or with `--test-threads=1`.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::{self, c_int, pid_t};
+
+use {MappedHeap, PageId, PAGESZ};
+
+/// A small, deterministic pseudo random number generator (xorshift64*) for workers.
+pub struct Rng(u64);
+
+impl Rng {
+    /// Creates a generator from a seed.
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15 | 1)
+    }
+
+    /// The next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A random number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// Why a torture run failed.
+#[derive(Debug)]
+pub enum Failure {
+    /// Forking, waiting or opening the heap failed.
+    Io(io::Error),
+    /// A worker exited (e.g. panicked) before it was killed.
+    Worker {
+        /// The round in which it happened.
+        round: usize,
+        /// The raw wait status.
+        status: c_int,
+    },
+    /// The check rejected the heap (its message went to stderr) or panicked.
+    Check {
+        /// The round after which the check failed.
+        round: usize,
+    },
+    /// The check didn't finish in time, e.g. because a lock held by a killed
+    /// worker was never taken over.
+    Hang {
+        /// The round after which the check hung.
+        round: usize,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Io(ref e) => write!(f, "i/o error: {}", e),
+            Failure::Worker { round, status } => write!(f, "worker exited in round {} (status {})", round, status),
+            Failure::Check { round } => write!(f, "heap check failed after round {}", round),
+            Failure::Hang { round } => write!(f, "heap check hung after round {}", round),
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure::Io(e)
+    }
+}
+
+/// Configures and runs fork-and-kill torture tests.
+///
+/// # Example
+///
+/// ```no_run
+/// use mappedheap::crashtest::{check_freelists, random_ops, Torture};
+///
+/// Torture::new().rounds(50).run("/tmp/torture.bin".as_ref(), random_ops, check_freelists).unwrap();
+/// ```
+pub struct Torture {
+    workers: usize,
+    rounds: usize,
+    max_lifetime: Duration,
+    check_timeout: Duration,
+    seed: u64,
+}
+
+impl Default for Torture {
+    fn default() -> Torture {
+        Torture::new()
+    }
+}
+
+impl Torture {
+    /// Creates a torture test with default settings: 4 workers, 20 rounds,
+    /// workers live up to 50ms and checks time out after 10s.
+    pub fn new() -> Torture {
+        Torture {
+            workers: 4,
+            rounds: 20,
+            max_lifetime: Duration::from_millis(50),
+            check_timeout: Duration::from_secs(10),
+            seed: 1,
+        }
+    }
+
+    /// The number of concurrent worker processes per round.
+    pub fn workers(&mut self, workers: usize) -> &mut Torture {
+        self.workers = workers;
+        self
+    }
+
+    /// The number of rounds (each forking and killing all workers, then checking the heap).
+    pub fn rounds(&mut self, rounds: usize) -> &mut Torture {
+        self.rounds = rounds;
+        self
+    }
+
+    /// Workers are killed after a random time up to this long.
+    pub fn max_lifetime(&mut self, max_lifetime: Duration) -> &mut Torture {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// How long a check may take before the heap is considered deadlocked.
+    pub fn check_timeout(&mut self, check_timeout: Duration) -> &mut Torture {
+        self.check_timeout = check_timeout;
+        self
+    }
+
+    /// The seed all randomness is derived from.
+    pub fn seed(&mut self, seed: u64) -> &mut Torture {
+        self.seed = seed;
+        self
+    }
+
+    /// Runs the torture test on the heap at `path` (creating it if necessary).
+    ///
+    /// Workers open the heap and call `work` in a loop until they're killed.
+    /// After every round, `check` runs in a separate process on a fresh handle.
+    pub fn run<W, C>(&self, path: &Path, work: W, check: C) -> Result<(), Failure>
+        where W: Fn(&MappedHeap, &mut Rng), C: Fn(&MappedHeap) -> Result<(), String>
+    {
+        drop(MappedHeap::open(path)?);
+        let mut rng = Rng::new(self.seed);
+
+        for round in 0..self.rounds {
+            let mut workers = Vec::with_capacity(self.workers);
+            for _ in 0..self.workers {
+                let seed = rng.next_u64();
+                workers.push(spawn(|| {
+                    let heap = MappedHeap::open(path).expect("failed to open heap");
+                    let mut rng = Rng::new(seed);
+                    loop {
+                        work(&heap, &mut rng);
+                    }
+                })?);
+            }
+
+            let lifetime = rng.below(self.max_lifetime.as_micros() as u64 + 1);
+            thread::sleep(Duration::from_micros(lifetime));
+            for &pid in &workers {
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            }
+            for &pid in &workers {
+                let status = wait(pid, None)?.unwrap();
+                if !(libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGKILL) {
+                    return Err(Failure::Worker { round, status });
+                }
+            }
+
+            let checker = spawn(|| {
+                let heap = MappedHeap::open(path).expect("failed to open heap");
+                if let Err(e) = check(&heap) {
+                    eprintln!("heap check failed after round {}: {}", round, e);
+                    unsafe { libc::_exit(1) };
+                }
+            })?;
+            match wait(checker, Some(self.check_timeout))? {
+                None => {
+                    unsafe { libc::kill(checker, libc::SIGKILL) };
+                    wait(checker, None)?;
+                    return Err(Failure::Hang { round });
+                }
+                Some(status) if !(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0) => {
+                    return Err(Failure::Check { round });
+                }
+                Some(_) => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs `f` in a child process, which exits with status 0 when `f` returns
+// and 101 if it panics.
+fn spawn<F: FnOnce()>(f: F) -> io::Result<pid_t> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            let ok = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(f)).is_ok();
+            // skip destructors and atexit handlers of the parent's state
+            unsafe { libc::_exit(if ok { 0 } else { 101 }) }
+        }
+        pid => Ok(pid),
+    }
+}
+
+// Waits for a child to exit and returns its status, or None on timeout.
+fn wait(pid: pid_t, timeout: Option<Duration>) -> io::Result<Option<c_int>> {
+    let start = Instant::now();
+    loop {
+        let mut status = 0;
+        let flags = if timeout.is_some() { libc::WNOHANG } else { 0 };
+        match unsafe { libc::waitpid(pid, &mut status, flags) } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => (),
+            _ => return Ok(Some(status)),
+        }
+        if timeout.is_some_and(|x| start.elapsed() > x) {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// A worker that allocates a few pages, fills them and frees them again.
+pub fn random_ops(heap: &MappedHeap, rng: &mut Rng) {
+    let ids: Vec<PageId> = (0..rng.below(64) + 1).map(|_| heap.alloc()).collect();
+    for &id in &ids {
+        unsafe { *heap.page(id).unwrap() = [id as u8; PAGESZ] };
+    }
+    for &id in &ids {
+        heap.free(id);
+    }
+}
+
+/// A check that walks all freelists and makes sure they only contain valid
+/// pages, each at most once.
+pub fn check_freelists(heap: &MappedHeap) -> Result<(), String> {
+    let size = heap.size();
+    let mut seen = vec![false; size as usize];
+    let mut ret = Ok(());
+    let freelists = heap.lock_freelists();
+    for (shard, &(_, head)) in freelists.iter().enumerate() {
+        heap.walk_freelist(head.load(::std::sync::atomic::Ordering::Relaxed), |id, entries| {
+            for &x in Some(&id).into_iter().chain(entries) {
+                if x == 0 || x >= size {
+                    ret = Err(format!("invalid page {} in freelist {}", x, shard));
+                } else if seen[x as usize] {
+                    ret = Err(format!("page {} is on the freelists twice", x));
+                } else {
+                    seen[x as usize] = true;
+                }
+            }
+        });
+    }
+    heap.unlock_freelists(freelists);
+    ret
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use {HeapOptions, MappedHeap};
+
+const EXTENSION: &str = "heap";
+
+/// A directory of named heaps, each stored as `<name>.heap`.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{HeapDir, HeapOptions};
+///
+/// let dir = HeapDir::open("/tmp/test-heapdir").unwrap();
+/// let heap = dir.open_heap("tenant-1", &HeapOptions::new()).unwrap();
+/// assert!(dir.list().unwrap().contains(&"tenant-1".to_string()));
+/// ```
+pub struct HeapDir {
+    path: PathBuf,
+}
+
+impl HeapDir {
+    /// Opens a heap directory, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<HeapDir> {
+        fs::create_dir_all(path.as_ref())?;
+        Ok(HeapDir { path: path.as_ref().to_path_buf() })
+    }
+
+    /// The path of the heap file with the given name.
+    ///
+    /// # Panics
+    ///
+    /// * If `name` is empty, starts with a dot or contains a slash or NUL byte.
+    pub fn path(&self, name: &str) -> PathBuf {
+        assert!(!name.is_empty() && !name.starts_with('.') && !name.contains(&['/', '\0'][..]),
+                "invalid heap name: {:?}", name);
+        self.path.join(format!("{}.{}", name, EXTENSION))
+    }
+
+    /// Creates a new heap. Fails with `AlreadyExists` if there already is one with this name.
+    ///
+    /// Creation is atomic: other processes either don't see the heap at all,
+    /// or see it fully initialized.
+    ///
+    /// # Panics
+    ///
+    /// Same as `path`.
+    pub fn create(&self, name: &str, options: &HeapOptions) -> io::Result<MappedHeap> {
+        match MappedHeap::create_file(&self.path(name))? {
+            Some(file) => options.open_file(file),
+            None => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("heap {:?} already exists", name))),
+        }
+    }
+
+    /// Opens a heap, atomically creating it if it doesn't exist.
+    ///
+    /// # Panics
+    ///
+    /// Same as `path`.
+    pub fn open_heap(&self, name: &str, options: &HeapOptions) -> io::Result<MappedHeap> {
+        options.open(self.path(name))
+    }
+
+    /// The names of all heaps in the directory, sorted.
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let mut ret = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            match path.file_stem().and_then(|x| x.to_str()) {
+                // (hidden files are creations in progress)
+                Some(name) if !name.is_empty() && !name.starts_with('.') => ret.push(name.to_string()),
+                _ => (),
+            }
+        }
+        ret.sort();
+        Ok(ret)
+    }
+
+    /// Deletes a heap.
+    ///
+    /// Processes that still have it open can keep using it, but it can't be opened
+    /// by name anymore (opening it again creates a new, empty heap).
+    ///
+    /// # Panics
+    ///
+    /// Same as `path`.
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.path(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heap_dir() {
+        let _ = fs::remove_dir_all("/tmp/heapdir");
+        let dir = HeapDir::open("/tmp/heapdir").unwrap();
+        let options = HeapOptions::new();
+
+        let a = dir.create("a", &options).unwrap();
+        assert_eq!(dir.create("a", &options).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        let id = a.alloc();
+        drop(dir.open_heap("b", &options).unwrap());
+        fs::write("/tmp/heapdir/notes.txt", b"not a heap").unwrap();
+        assert_eq!(dir.list().unwrap(), vec!["a", "b"]);
+
+        // same heap, by name
+        assert_eq!(dir.open_heap("a", &options).unwrap().alloc(), id + 1);
+
+        dir.delete("b").unwrap();
+        assert_eq!(dir.list().unwrap(), vec!["a"]);
+        assert!(dir.delete("b").is_err());
+
+        let _ = fs::remove_dir_all("/tmp/heapdir");
+    }
+}
@@ -0,0 +1,141 @@
+//! Admission control for writes: a hook consulted before a page is first written to.
+//!
+//! Writing to a page that is a hole in the file (fresh from growing the file, or
+//! punched out when it was freed) makes the file system allocate space for it, and
+//! the write can't be undone once it went through the mapping. So writers announce
+//! the first write to each page with `mark_dirty`, which gives an embedder the chance
+//! to refuse (e.g. because a disk quota is used up) while refusing is still possible.
+
+use std::error::Error;
+use std::sync::Arc;
+use std::{fmt, io};
+
+use {MappedHeap, PageId, NULL_PAGE};
+
+pub(crate) type DirtyHook = dyn Fn(&MappedHeap, PageId) -> Result<(), Denied> + Send + Sync;
+
+/// The error for a write refused by the hook registered with `MappedHeap::set_dirty_hook`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Denied;
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "writing the page was denied")
+    }
+}
+
+impl Error for Denied {}
+
+impl From<Denied> for io::Error {
+    fn from(e: Denied) -> io::Error {
+        io::Error::new(io::ErrorKind::PermissionDenied, e)
+    }
+}
+
+impl MappedHeap {
+    /// Registers a callback that `mark_dirty` consults before a page is first
+    /// written to, replacing any previous callback (shared by all clones of this
+    /// handle).
+    ///
+    /// The callback decides whether the write may go ahead, e.g. by charging the
+    /// page against a disk quota. It is called without any of the heap's locks held,
+    /// so it may use the heap (even replace or remove itself).
+    pub fn set_dirty_hook<F: Fn(&MappedHeap, PageId) -> Result<(), Denied> + Send + Sync + 'static>(&self, hook: F) {
+        *self.inner.dirty_hook.write().unwrap() = Some(Arc::new(hook));
+    }
+
+    /// Removes the callback registered with `set_dirty_hook`, and forgets which
+    /// pages were marked dirty.
+    pub fn clear_dirty_hook(&self) {
+        let mut hook = self.inner.dirty_hook.write().unwrap();
+        *hook = None;
+        self.inner.dirtied.lock().unwrap().clear();
+    }
+
+    /// Announces a write to a page, which fails if the callback registered with
+    /// `set_dirty_hook` denies it. Don't write to the page then.
+    ///
+    /// Only the first call for a page since it was allocated consults the callback,
+    /// later ones merely look the page up in an in-memory set (private to this
+    /// process, and kept until the page is freed or the callback removed). Without a
+    /// callback this does nothing at all. The heap can't notice writes that weren't
+    /// announced, so this only works if every writer calls it.
+    ///
+    /// # Panics
+    ///
+    /// * If the page doesn't exist.
+    pub fn mark_dirty(&self, id: PageId) -> Result<(), Denied> {
+        assert!(id != NULL_PAGE && id < self.size(), "invalid page {}", id);
+        let hook = match *self.inner.dirty_hook.read().unwrap() {
+            Some(ref hook) => hook.clone(),
+            None => return Ok(()),
+        };
+        if self.inner.dirtied.lock().unwrap().contains(&id) {
+            return Ok(());
+        }
+        // (two threads writing the same page at once could both end up here, but
+        // they'd be racing anyway)
+        hook(self, id)?;
+        self.inner.dirtied.lock().unwrap().insert(id);
+        Ok(())
+    }
+
+    // Forgets that a page being freed was dirtied, its next user has to ask again.
+    pub(crate) fn forget_dirty(&self, id: PageId) {
+        if self.inner.dirty_hook.read().unwrap().is_some() {
+            self.inner.dirtied.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn dirty_hook() {
+        let _ = fs::remove_file("/tmp/dirty.bin");
+        let mapping = MappedHeap::open("/tmp/dirty.bin").unwrap();
+        let ids: Vec<_> = (0..10).map(|_| mapping.alloc()).collect();
+        assert_eq!(mapping.mark_dirty(ids[0]), Ok(()));
+
+        // a quota of 5 pages
+        let used = Arc::new(AtomicU64::new(0));
+        let quota = used.clone();
+        mapping.set_dirty_hook(move |_, _| match quota.fetch_add(1, Ordering::Relaxed) {
+            x if x < 5 => Ok(()),
+            _ => Err(Denied),
+        });
+        for &id in &ids[..5] {
+            mapping.mark_dirty(id).unwrap();
+            mapping.mark_dirty(id).unwrap();
+        }
+        assert_eq!(used.load(Ordering::Relaxed), 5);
+        assert_eq!(mapping.mark_dirty(ids[5]), Err(Denied));
+        assert_eq!(io::Error::from(Denied).kind(), io::ErrorKind::PermissionDenied);
+
+        // freeing a page gives its successor a new first write
+        mapping.free(ids[0]);
+        used.store(0, Ordering::Relaxed);
+        let id = mapping.alloc();
+        assert_eq!(id, ids[0]);
+        mapping.mark_dirty(id).unwrap();
+        assert_eq!(used.load(Ordering::Relaxed), 1);
+
+        mapping.clear_dirty_hook();
+        assert_eq!(mapping.mark_dirty(ids[9]), Ok(()));
+
+        // a hook may use the heap, here to remove itself after the first page
+        mapping.set_dirty_hook(|heap, id| {
+            heap.clear_dirty_hook();
+            heap.mark_dirty(id)
+        });
+        mapping.mark_dirty(ids[8]).unwrap();
+        mapping.mark_dirty(ids[9]).unwrap();
+        assert!(mapping.inner.dirty_hook.read().unwrap().is_none());
+
+        let _ = fs::remove_file("/tmp/dirty.bin");
+    }
+}
@@ -0,0 +1,153 @@
+//! A persistent ring buffer of the most recent allocator events.
+//!
+//! Once a file has an event log (see `HeapOptions::event_log`), every process using
+//! it records each `alloc`, `free` and growth of the file in it, along with a global
+//! sequence number, a timestamp and its pid. Since the log lives in the file, it
+//! survives crashes and can be read back afterwards to reconstruct what happened to
+//! a corrupted heap right before things went wrong.
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{process, ptr};
+
+use {AllocError, MappedHeap, PageId, PAGESZ};
+
+const EVENTS_PER_PAGE: u64 = (PAGESZ / 32) as u64;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawEvent {
+    seq: u64, // plus one, so zero marks unused slots
+    time_us: u64,
+    page: PageId,
+    pid: u32,
+    kind: u32,
+}
+
+/// What happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// `alloc` returned the page.
+    Alloc,
+    /// The page was freed.
+    Free,
+    /// The file grew (or shrank, see `MappedHeap::shrink_to_fit`) to `page` pages.
+    Grow,
+}
+
+/// An entry of the event log, see `MappedHeap::events`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The position of the event in the file's history.
+    pub seq: u64,
+    /// When it happened.
+    pub time: SystemTime,
+    /// The process that did it.
+    pub pid: u32,
+    /// What it did.
+    pub kind: EventKind,
+    /// The page concerned (or the new size for `Grow`).
+    pub page: PageId,
+}
+
+impl MappedHeap {
+    // Creates the event log unless the file already has one.
+    pub(crate) fn create_event_log(&self, pages: u64) -> Result<(), AllocError> {
+        let header = self.header();
+        // (the same lock order as in root_or_create)
+        self.lock(&header.root_lock);
+        let ret = if header.event_log.load(Ordering::Relaxed) == 0 {
+            self.alloc_grow(None, pages).map(|first| {
+                let first = first.unwrap();
+                for id in first..first + pages {
+                    unsafe { ptr::write_bytes(self.page(id).unwrap(), 0, 1) };
+                }
+                header.event_log_pages.store(pages, Ordering::Relaxed);
+                header.event_log.store(first, Ordering::Release);
+            })
+        } else {
+            Ok(())
+        };
+        self.unlock(&header.root_lock);
+        ret
+    }
+
+    pub(crate) fn log_event(&self, kind: EventKind, page: PageId) {
+        let header = self.header();
+        let first = header.event_log.load(Ordering::Acquire);
+        if first == 0 {
+            return;
+        }
+        let seq = header.event_seq.fetch_add(1, Ordering::Relaxed);
+        let slot = seq % (header.event_log_pages.load(Ordering::Relaxed) * EVENTS_PER_PAGE);
+        let time_us = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_micros() as u64).unwrap_or(0);
+        let event = RawEvent { seq: seq + 1, time_us, page, pid: process::id(), kind: kind as u32 };
+        let page = self.page(first + slot / EVENTS_PER_PAGE).unwrap() as *mut RawEvent;
+        unsafe { ptr::write_volatile(page.add((slot % EVENTS_PER_PAGE) as usize), event) };
+    }
+
+    /// The events in the file's event log, oldest first.
+    ///
+    /// Returns nothing if the file doesn't have an event log. Events being recorded
+    /// concurrently (or when a process crashed) may be missing or garbled.
+    pub fn events(&self) -> Vec<Event> {
+        let header = self.header();
+        let first = header.event_log.load(Ordering::Acquire);
+        if first == 0 {
+            return Vec::new();
+        }
+        let mut ret = Vec::new();
+        for id in first..first + header.event_log_pages.load(Ordering::Relaxed) {
+            let page = self.page(id).expect("corrupt event log") as *const [RawEvent; EVENTS_PER_PAGE as usize];
+            for x in unsafe { ptr::read_volatile(page) }.iter() {
+                let kind = match x.kind {
+                    0 => EventKind::Alloc,
+                    1 => EventKind::Free,
+                    2 => EventKind::Grow,
+                    _ => continue,
+                };
+                if x.seq != 0 {
+                    let time = UNIX_EPOCH + Duration::from_micros(x.time_us);
+                    ret.push(Event { seq: x.seq - 1, time, pid: x.pid, kind, page: x.page });
+                }
+            }
+        }
+        ret.sort_by_key(|x| x.seq);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use HeapOptions;
+
+    #[test]
+    fn event_log() {
+        let _ = fs::remove_file("/tmp/events.bin");
+        let mapping = MappedHeap::open("/tmp/events.bin").unwrap();
+        mapping.free(mapping.alloc());
+        assert!(mapping.events().is_empty());
+
+        let logged = HeapOptions::new().event_log(1).open("/tmp/events.bin").unwrap();
+        let id = logged.alloc();
+        // everyone logs once the file has a log
+        mapping.free(id);
+        let events = logged.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].kind, events[0].page), (EventKind::Alloc, id));
+        assert_eq!((events[1].kind, events[1].page), (EventKind::Free, id));
+        assert_eq!(events[1].pid, process::id());
+
+        // the oldest ones get overwritten
+        let ids: Vec<_> = (0..200).map(|_| mapping.alloc()).collect();
+        let events = mapping.events();
+        assert_eq!(events.len(), EVENTS_PER_PAGE as usize);
+        assert_eq!(events.last().unwrap().page, ids[199]);
+        assert!(events.iter().any(|x| x.kind == EventKind::Grow));
+        assert!(events.windows(2).all(|x| x[0].seq + 1 == x[1].seq));
+
+        let _ = fs::remove_file("/tmp/events.bin");
+    }
+}
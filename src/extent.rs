@@ -0,0 +1,381 @@
+use std::{cmp, ptr};
+use std::sync::atomic::Ordering;
+
+use {find_fragment, AllocError, EventKind, MappedHeap, PageId, PAGESZ};
+
+/// A run of physically consecutive pages holding a value of arbitrary length.
+///
+/// Extents are allocated with `MappedHeap::alloc_extent`. Their pages are consecutive
+/// in the file, so unless the mapping happens to be split between them, they are
+/// also consecutive in memory (see `MappedHeap::extent_slice`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Extent {
+    /// The first page.
+    pub first_page: PageId,
+    /// The length in bytes.
+    pub len: u64,
+}
+
+impl Extent {
+    /// The number of pages this extent spans (at least one).
+    pub fn pages(&self) -> u64 {
+        cmp::max(self.len.div_ceil(PAGESZ as u64), 1)
+    }
+
+    fn last_page(&self) -> PageId {
+        self.first_page + self.pages() - 1
+    }
+}
+
+/// A group of allocations with increasing page ids, see `MappedHeap::alloc_sequential_group`.
+pub struct SequentialGroup<'a> {
+    heap: &'a MappedHeap,
+    next: PageId,
+    end: PageId,
+    n: u64,
+}
+
+impl<'a> SequentialGroup<'a> {
+    /// Allocates the next page of the group, reserving another `n` pages first if
+    /// the current reservation is used up.
+    pub fn alloc(&mut self) -> PageId {
+        if self.next == self.end {
+            self.next = self.heap.alloc_run(self.n, false).unwrap_or_else(|e| panic!("{}", e));
+            self.end = self.next + self.n;
+        }
+        self.next += 1;
+        self.next - 1
+    }
+
+    /// The number of pages reserved but not handed out yet.
+    pub fn remaining(&self) -> u64 {
+        self.end - self.next
+    }
+}
+
+impl<'a> Drop for SequentialGroup<'a> {
+    fn drop(&mut self) {
+        for id in self.next..self.end {
+            self.heap.free(id);
+        }
+    }
+}
+
+impl MappedHeap {
+    /// Allocates an extent of `len` bytes, made up of consecutive pages.
+    ///
+    /// The pages are taken like `alloc_contiguous` does: the lowest run of free pages
+    /// long enough, or else pages at the end of the file. Looking for a run locks all
+    /// freelists and takes time proportional to the number of free pages.
+    ///
+    /// # Panics
+    ///
+    /// Same as `alloc`.
+    pub fn alloc_extent(&self, len: u64) -> Extent {
+        let mut extent = Extent { first_page: 0, len };
+        extent.first_page = self.alloc_run(extent.pages(), true).unwrap_or_else(|e| panic!("{}", e));
+        extent
+    }
+
+    // Allocates `n` consecutive pages and returns the first, see take_run.
+    fn alloc_run(&self, n: u64, anywhere: bool) -> Result<PageId, AllocError> {
+        let first = self.take_run(n, anywhere)?;
+        self.init_run(first, n);
+        Ok(first)
+    }
+
+    fn init_run(&self, first: PageId, n: u64) {
+        // map it right away, the whole range then ends up in a single mmap
+        self.page(first + n - 1).unwrap();
+        for id in first..first + n {
+            self.log_event(EventKind::Alloc, id);
+            self.track_alloc(id);
+        }
+
+        if cfg!(debug_assertions) || self.inner.options.deterministic {
+            for id in first..first + n {
+                unsafe { ptr::write_bytes(self.page(id).unwrap(), 0, 1) };
+            }
+        }
+    }
+
+    /// Allocates `n` consecutive pages and returns the first, for objects larger
+    /// than a page.
+    ///
+    /// This takes the lowest run of `n` free pages in the freelists, or if there is none,
+    /// pages at the end of the file: the free pages the file ends with, followed by new
+    /// pages (growing the file by just as many pages as are missing, regardless of the
+    /// growth policy). Looking for a run locks all freelists and takes time proportional
+    /// to the number of free pages, so this is meant for large objects rather than for
+    /// every allocation.
+    ///
+    /// Returns `None` if there is no such run and the file can't grow (see `try_alloc`).
+    ///
+    /// # Panics
+    ///
+    /// * If `n` is zero.
+    pub fn alloc_contiguous(&self, n: u64) -> Option<PageId> {
+        assert!(n > 0);
+        self.alloc_run(n, true).ok()
+    }
+
+    /// Frees `n` consecutive pages starting at `first`, e.g. allocated with
+    /// `alloc_contiguous`.
+    ///
+    /// The same caveats as for `free` apply.
+    pub fn free_contiguous(&self, first: PageId, n: u64) {
+        for id in first..first + n {
+            self.free(id);
+        }
+    }
+
+    // Takes `n` consecutive pages off the freelists, the lowest run of them if `anywhere`.
+    // Otherwise (or if there is none), takes them from the end of the file: the free
+    // pages it ends with, and as many new pages as are missing. Growing in steps of
+    // the growth policy instead would grow the file by a whole step (e.g. double it)
+    // for every run.
+    fn take_run(&self, n: u64, anywhere: bool) -> Result<PageId, AllocError> {
+        let freelists = self.lock_freelists();
+        let size = self.size();
+        let free = self.collect_free_pages(&freelists);
+        let run = match anywhere {
+            true => free.windows(n as usize).find(|x| x[0] + n - 1 == x[n as usize - 1]).map(|x| x[0]),
+            false => None,
+        };
+        let tail = free.iter().rev().zip((1..size).rev()).take_while(|&(&x, y)| x == y).count();
+        let first = run.unwrap_or(size - tail as u64);
+
+        let ret = match first + n {
+            end if end <= size => Ok(()),
+            end if end > self.max_size() => Err(AllocError::Full),
+            end => self.grow_to(end, true).map_err(AllocError::Io),
+        };
+        if ret.is_ok() {
+            if let Some(ref hook) = *self.inner.grow_hook.read().unwrap() {
+                for id in size..self.size() {
+                    hook(self, id);
+                }
+            }
+            let start = free.partition_point(|&x| x < first);
+            let taken = &free[start..free.partition_point(|&x| x < first + n)];
+            let punch = self.unlink_free_pages(&freelists, taken);
+            self.punch_surplus(punch);
+        }
+        self.unlock_freelists(freelists);
+        ret.map(|_| first)
+    }
+
+    /// Starts a group of allocations whose page ids only ever increase, for appending
+    /// to log-structured data in on-disk order.
+    ///
+    /// Pages are reserved `n` at a time from the end of the file, never from the middle
+    /// of the freelists, so the pages of one reservation are also consecutive. A
+    /// reservation takes the free pages the file ends with, and grows the file by just
+    /// the pages missing (regardless of the growth policy), so the file grows linearly
+    /// with the reservations. Pages reserved but not handed out are freed when the group
+    /// is dropped.
+    ///
+    /// # Panics
+    ///
+    /// * If `n` is zero.
+    /// * Same as `alloc`, here and in `SequentialGroup::alloc`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mappedheap::MappedHeap;
+    ///
+    /// # let _ = std::fs::remove_file("/tmp/test-sequential.bin");
+    /// let mapping = MappedHeap::open("/tmp/test-sequential.bin").unwrap();
+    /// let mut group = mapping.alloc_sequential_group(16);
+    /// let a = group.alloc();
+    /// let b = group.alloc();
+    /// assert_eq!(b, a + 1);
+    /// ```
+    pub fn alloc_sequential_group(&self, n: u64) -> SequentialGroup<'_> {
+        assert!(n > 0);
+        let next = self.alloc_run(n, false).unwrap_or_else(|e| panic!("{}", e));
+        SequentialGroup { heap: self, next, end: next + n, n }
+    }
+
+    /// Frees all pages of an extent.
+    ///
+    /// The same caveats as for `free` apply.
+    pub fn free_extent(&self, extent: Extent) {
+        for id in extent.first_page..extent.last_page() + 1 {
+            self.free(id);
+        }
+    }
+
+    /// Retrieves a pointer to the extent's bytes as one linear buffer, if its pages
+    /// are contiguous in memory (which is usually the case).
+    ///
+    /// Returns `None` if the mapping is split somewhere within the extent or the
+    /// extent doesn't exist within the file. In that case, `extent_read` and
+    /// `extent_write` can still be used.
+    ///
+    /// The same security notes as for `page` apply.
+    pub fn extent_slice(&self, extent: &Extent) -> Option<*mut [u8]> {
+        let first = self.page(extent.first_page)?;
+        self.page(extent.last_page())?;
+
+        let fragments = self.inner.fragments.read();
+        let fragment = &fragments[find_fragment(&fragments, extent.first_page)];
+        if extent.last_page() - fragment.offset < fragment.size.load(Ordering::Relaxed) {
+            Some(ptr::slice_from_raw_parts_mut(first as *mut u8, extent.len as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Copies bytes out of an extent, starting at `offset`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure nobody is writing to the range concurrently.
+    ///
+    /// # Panics
+    ///
+    /// * If the range is out of the extent's bounds or the extent doesn't exist within the file.
+    pub unsafe fn extent_read(&self, extent: &Extent, offset: u64, buf: &mut [u8]) {
+        self.extent_copy(extent, offset, buf.len(), |page, at, n| {
+            ptr::copy_nonoverlapping(page, buf[at..].as_mut_ptr(), n);
+        });
+    }
+
+    /// Copies bytes into an extent, starting at `offset`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure nobody is accessing the range concurrently.
+    ///
+    /// # Panics
+    ///
+    /// * If the range is out of the extent's bounds or the extent doesn't exist within the file.
+    pub unsafe fn extent_write(&self, extent: &Extent, offset: u64, data: &[u8]) {
+        self.extent_copy(extent, offset, data.len(), |page, at, n| {
+            ptr::copy_nonoverlapping(data[at..].as_ptr(), page, n);
+        });
+    }
+
+    // Calls `f` with a pointer into each page and the offset and length of the
+    // corresponding chunk of the buffer.
+    fn extent_copy<F>(&self, extent: &Extent, offset: u64, len: usize, mut f: F)
+        where F: FnMut(*mut u8, usize, usize)
+    {
+        assert!(offset.checked_add(len as u64).is_some_and(|end| end <= extent.len));
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let id = extent.first_page + pos / PAGESZ as u64;
+            let in_page = (pos % PAGESZ as u64) as usize;
+            let n = cmp::min(len - done, PAGESZ - in_page);
+
+            let page = self.page(id).expect("extent doesn't exist") as *mut u8;
+            f(unsafe { page.add(in_page) }, done, n);
+            done += n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use ConsistencyLevel;
+
+    #[test]
+    fn extents() {
+        let _ = fs::remove_file("/tmp/extent.bin");
+        let mapping = MappedHeap::open("/tmp/extent.bin").unwrap();
+        mapping.alloc();
+
+        let extent = mapping.alloc_extent(3 * PAGESZ as u64 + 100);
+        assert_eq!(extent.pages(), 4);
+        let data: Vec<u8> = (0..extent.len).map(|x| x as u8).collect();
+        unsafe { mapping.extent_write(&extent, 0, &data) };
+
+        let slice = unsafe { &*mapping.extent_slice(&extent).unwrap() };
+        assert_eq!(slice, &data[..]);
+
+        let mut buf = vec![0; 5000];
+        unsafe { mapping.extent_read(&extent, 4000, &mut buf) };
+        assert_eq!(buf, &data[4000..9000]);
+
+        // other allocations don't interfere
+        let ids: Vec<_> = (0..10).map(|_| mapping.alloc()).collect();
+        assert!(ids.iter().all(|&x| x < extent.first_page || x >= extent.first_page + 4));
+
+        mapping.free_extent(extent);
+        let _ = fs::remove_file("/tmp/extent.bin");
+    }
+
+    #[test]
+    fn contiguous() {
+        let _ = fs::remove_file("/tmp/contiguous.bin");
+        let mapping = MappedHeap::open("/tmp/contiguous.bin").unwrap();
+        let ids: Vec<_> = (0..3000).map(|_| mapping.alloc()).collect();
+        let size = mapping.size();
+        // a run of 5, and a run of 4 ahead of it
+        let mut sorted = ids.clone();
+        sorted.sort();
+        for &id in sorted[100..104].iter().chain(&sorted[1000..1005]) {
+            mapping.free(id);
+        }
+        let free_pages = mapping.free_pages();
+
+        let first = mapping.alloc_contiguous(5).unwrap();
+        assert_eq!(first, sorted[1000]);
+        assert_eq!(mapping.free_pages(), free_pages - 5);
+        assert_eq!(mapping.recount_free_pages(), free_pages - 5);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+        let slice = unsafe { &mut *mapping.extent_slice(&Extent { first_page: first, len: 5 * PAGESZ as u64 }).unwrap() };
+        slice[5 * PAGESZ - 1] = 1;
+
+        // too long for any free run: taken from the free pages at the end of the file,
+        // growing it by just the pages missing
+        let tail = (1..size).rev().take_while(|x| !ids.contains(x)).count() as u64;
+        let first = mapping.alloc_contiguous(size).unwrap();
+        assert_eq!(first, size - tail);
+        assert_eq!(mapping.size(), first + size);
+        mapping.free_contiguous(first, size);
+        assert_eq!(mapping.free_pages(), mapping.recount_free_pages());
+
+        // freed runs are reused instead of growing the file on every allocation
+        let size = mapping.size();
+        for _ in 0..20 {
+            let extent = mapping.alloc_extent(100 * PAGESZ as u64);
+            mapping.free_extent(extent);
+        }
+        assert_eq!(mapping.size(), size);
+
+        let _ = fs::remove_file("/tmp/contiguous.bin");
+    }
+
+    #[test]
+    fn sequential_group() {
+        let _ = fs::remove_file("/tmp/sequential.bin");
+        let mapping = MappedHeap::open("/tmp/sequential.bin").unwrap();
+
+        let mut group = mapping.alloc_sequential_group(10);
+        let mut ids = Vec::new();
+        for i in 0..25 {
+            ids.push(group.alloc());
+            // interleaved allocations don't interfere
+            if i % 5 == 0 {
+                mapping.alloc();
+            }
+        }
+        assert!(ids.windows(2).all(|x| x[0] < x[1]));
+        assert!(ids[..10].windows(2).all(|x| x[0] + 1 == x[1]));
+        // reservations grow the file by the pages missing, not by the growth policy
+        assert!(mapping.size() < 4096 + 30 + 5);
+        assert_eq!(group.remaining(), 5);
+        let free_pages = mapping.free_pages();
+        drop(group);
+        assert_eq!(mapping.free_pages(), free_pages + 5);
+
+        let _ = fs::remove_file("/tmp/sequential.bin");
+    }
+}
@@ -0,0 +1,193 @@
+//! A persistent bloom filter, for deduplication and admission control.
+//!
+//! This is a blocked bloom filter: a key only ever sets and tests bits within one
+//! 64 byte block, so a lookup touches a single cache line (and page) no matter how
+//! many hash functions are used. The blocks are spread over data pages found through
+//! a radix tree from page index to page id, like the pages of `MappedPriorityQueue`.
+
+use std::{cmp, ptr};
+
+use {MappedHeap, PageId, RadixTree, PAGESZ};
+
+const BLOCK_BITS: u64 = 512;
+const BLOCKS_PER_PAGE: u64 = (PAGESZ / 64) as u64;
+
+#[repr(C)]
+struct Meta {
+    len: u64,
+    pages: u64,
+    hashes: u64,
+    index: PageId,
+}
+
+// FNV-1a, which unlike std's hashers is guaranteed to stay the same, with the
+// splitmix64 finalizer on top (FNV's high bits are poor on their own)
+fn hash(key: &[u8], seed: u64) -> u64 {
+    let h = key.iter().fold(0xcbf2_9ce4_8422_2325 ^ seed, |h, &b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3));
+    let h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// A set of byte strings that may report false positives (but never false
+/// negatives), stored in a `MappedHeap` in a fixed number of pages.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedFilter, MappedHeap};
+///
+/// let mapping = MappedHeap::open("/tmp/test-filter.bin").unwrap();
+/// let mut filter = MappedFilter::with_rate(&mapping, 1000, 0.01);
+/// filter.insert(b"hello");
+/// assert!(filter.contains(b"hello"));
+/// filter.destroy();
+/// ```
+pub struct MappedFilter<'a> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+    index: RadixTree<'a>,
+}
+
+impl<'a> MappedFilter<'a> {
+    /// Creates a new, empty filter of `pages` data pages that sets `hashes` bits
+    /// per key.
+    ///
+    /// # Panics
+    ///
+    /// * If `pages` or `hashes` is zero.
+    pub fn create(heap: &'a MappedHeap, pages: u64, hashes: u32) -> MappedFilter<'a> {
+        assert!(pages > 0 && hashes > 0);
+        let meta_page = heap.alloc();
+        let mut index = RadixTree::create(heap);
+        for i in 0..pages {
+            let page = heap.alloc();
+            unsafe { ptr::write_bytes(heap.page(page).unwrap(), 0, 1) };
+            index.insert(i, page);
+        }
+        let meta = Meta { len: 0, pages, hashes: hashes as u64, index: index.meta_page() };
+        unsafe {
+            ptr::write(heap.page(meta_page).unwrap() as *mut Meta, meta);
+            MappedFilter::open(heap, meta_page)
+        }
+    }
+
+    /// Creates a new, empty filter sized to hold `expected` keys with (about) the
+    /// given false positive rate.
+    ///
+    /// # Panics
+    ///
+    /// * If `false_positive_rate` is not between 0 and 1 (exclusive).
+    pub fn with_rate(heap: &'a MappedHeap, expected: u64, false_positive_rate: f64) -> MappedFilter<'a> {
+        assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0);
+        // the usual optimum for plain bloom filters, blocking costs a little on top
+        let ln2 = std::f64::consts::LN_2;
+        let bits = -(cmp::max(expected, 1) as f64) * false_positive_rate.ln() / (ln2 * ln2);
+        let pages = (bits / (PAGESZ * 8) as f64).ceil() as u64;
+        let hashes = (bits / cmp::max(expected, 1) as f64 * ln2).round() as u32;
+        MappedFilter::create(heap, cmp::max(pages, 1), hashes.clamp(1, 16))
+    }
+
+    /// Opens an existing filter by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create` or `with_rate`, and nobody
+    /// else may modify the filter while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> MappedFilter<'a> {
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        let index = RadixTree::open(heap, (*meta).index);
+        MappedFilter { heap, meta, meta_page, index }
+    }
+
+    /// The id of the meta page, which identifies this filter.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of keys inserted (counting keys inserted more than once repeatedly).
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether no keys have been inserted.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn meta(&self) -> &Meta {
+        unsafe { &*self.meta }
+    }
+
+    // The block a key maps to, and the bits to set within it.
+    fn bits(&self, key: &[u8]) -> (*mut [u64; 8], impl Iterator<Item = u64>) {
+        let blocks = self.meta().pages * BLOCKS_PER_PAGE;
+        let block = ((hash(key, 0) as u128 * blocks as u128) >> 64) as u64;
+        let page = self.index.get(block / BLOCKS_PER_PAGE).expect("corrupt filter");
+        let page = self.heap.page(page).expect("corrupt filter") as *mut [u64; 8];
+        let block = unsafe { page.add((block % BLOCKS_PER_PAGE) as usize) };
+
+        // double hashing
+        let h = hash(key, 1);
+        let (a, b) = (h & 0xffff_ffff, (h >> 32) | 1);
+        (block, (0..self.meta().hashes).map(move |i| a.wrapping_add(i.wrapping_mul(b)) % BLOCK_BITS))
+    }
+
+    /// Adds a key.
+    pub fn insert(&mut self, key: &[u8]) {
+        let (block, bits) = self.bits(key);
+        for bit in bits {
+            unsafe { (*block)[(bit / 64) as usize] |= 1 << (bit % 64) };
+        }
+        unsafe { (*self.meta).len += 1 };
+    }
+
+    /// Whether a key may have been added: false means it definitely wasn't.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        let (block, mut bits) = self.bits(key);
+        bits.all(|bit| unsafe { (*block)[(bit / 64) as usize] & 1 << (bit % 64) != 0 })
+    }
+
+    /// Frees all pages of the filter, including the meta page.
+    pub fn destroy(self) {
+        for i in 0..self.meta().pages {
+            self.heap.free(self.index.get(i).expect("corrupt filter"));
+        }
+        self.index.destroy();
+        self.heap.free(self.meta_page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn filter() {
+        let _ = fs::remove_file("/tmp/filter.bin");
+        let mapping = MappedHeap::open("/tmp/filter.bin").unwrap();
+
+        let mut filter = MappedFilter::with_rate(&mapping, 10000, 0.01);
+        for i in 0u64..10000 {
+            filter.insert(&i.to_le_bytes());
+        }
+        assert_eq!(filter.len(), 10000);
+
+        let meta = filter.meta_page();
+        let filter = unsafe { MappedFilter::open(&mapping, meta) };
+        assert!((0u64..10000).all(|i| filter.contains(&i.to_le_bytes())));
+        let false_positives = (10000u64..110000).filter(|i| filter.contains(&i.to_le_bytes())).count();
+        assert!(false_positives < 2000, "{} false positives", false_positives);
+
+        filter.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/filter.bin");
+    }
+}
@@ -0,0 +1,240 @@
+//! A description of the on-disk format, for tools that read heap files without this
+//! crate (hex dump annotators, readers in other languages, ...).
+//!
+//! All integers are native endian (in practice: little endian) and page ids are `u64`
+//! page numbers. Page 0 holds the `HEADER`, every other page is either allocated (and
+//! contains whatever the user put there) or free. Free pages are found through the
+//! freelists, chains of `FREELIST_PAGE`s starting at the header's `freelist_id` and
+//! `shards[i].freelist_id` fields.
+
+use std::mem::{offset_of, size_of};
+
+use freelist::{self, FreelistPage};
+use {FileHeader, FreelistShard, PAGESZ};
+
+/// A field of an on-disk structure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    /// The field's name.
+    pub name: &'static str,
+    /// The offset from the start of the structure, in bytes.
+    pub offset: usize,
+    /// The size in bytes.
+    pub size: usize,
+    /// What the field contains.
+    pub doc: &'static str,
+}
+
+/// An on-disk structure. Gaps between fields are padding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Struct {
+    /// The structure's name.
+    pub name: &'static str,
+    /// The format version this describes.
+    pub version: u32,
+    /// The size in bytes.
+    pub size: usize,
+    /// The fields, in ascending order of their offset.
+    pub fields: &'static [Field],
+}
+
+const fn field(name: &'static str, offset: usize, size: usize, doc: &'static str) -> Field {
+    Field { name, offset, size, doc }
+}
+
+/// The magic at the start of every heap file.
+pub const MAGIC: &[u8; 16] = ::MAGIC;
+
+/// The newest format version this crate reads and writes.
+///
+/// Files in a newer format (or using feature flags outside of `FEATURES`) fail to
+/// open with `UnsupportedVersion`, instead of being misread.
+pub const VERSION: u32 = ::FORMAT_VERSION;
+
+/// The feature flags this crate understands.
+///
+/// Each flag marks a format change that older versions can't handle. New files have
+/// all of them set, and older files get them set when they are first opened, since
+/// from then on they may contain data in the newer format.
+pub const FEATURES: u64 = ::FORMAT_FEATURES;
+
+/// Freelist pages may be in the checksummed version 2 format (see `FREELIST_PAGE`).
+pub const FEATURE_FREELIST_V2: u64 = ::FEATURE_FREELIST_V2;
+
+/// Free pages may be on the freelists in `shards`, not just on the one in the header.
+pub const FEATURE_FREELIST_SHARDS: u64 = ::FEATURE_FREELIST_SHARDS;
+
+/// `free_pages` is kept up to date by everyone that allocates or frees pages.
+pub const FEATURE_FREE_PAGE_COUNT: u64 = ::FEATURE_FREE_PAGE_COUNT;
+
+/// The lock word of an unlocked lock in the header.
+///
+/// A locked lock word holds the thread id (Linux `gettid`) of its owner, plus
+/// `LOCK_WAITERS` while other threads may be sleeping on it (`FUTEX_WAIT`, with the
+/// futex shared between processes). Whoever unlocks sets the word back to this and
+/// then, if the flag was set, wakes one waiter (`FUTEX_WAKE`), which takes the lock
+/// with the flag set again. Take a lock by changing its word from this to your thread
+/// id with a compare-and-swap.
+///
+/// A waiter that finds the owner's thread gone (`kill` fails with `ESRCH`, or it is a
+/// zombie) swaps the owner's id for its own, and recomputes the checksums of the
+/// freelist pages of the shard if the lock is a freelist lock. So all processes
+/// sharing a file have to be in the same PID namespace.
+pub const LOCK_UNLOCKED: u32 = ::locks::UNLOCKED;
+
+/// The bit of a lock word set while threads may be waiting for the lock, see `LOCK_UNLOCKED`.
+pub const LOCK_WAITERS: u32 = ::locks::WAITERS;
+
+/// The file header in page 0.
+///
+/// The locks are futexes (`u32` lock words, see `LOCK_UNLOCKED`) shared by all processes.
+/// The shards are `SHARD`s, each with a lock and a freelist like shard 0 in the header.
+pub const HEADER: Struct = Struct {
+    name: "header",
+    version: 1,
+    size: size_of::<FileHeader>(),
+    fields: &[
+        field("magic", offset_of!(FileHeader, magic), 16, "MAGIC"),
+        field("version", offset_of!(FileHeader, version), 4, "format version, at most VERSION (0 means 1)"),
+        field("features", offset_of!(FileHeader, features), 8, "incompatible features used, a subset of FEATURES"),
+        field("freelist_capacity", offset_of!(FileHeader, freelist_capacity), 8, "entries per freelist page, 0 means FREELIST_ENTRIES"),
+        field("resize_lock", offset_of!(FileHeader, resize_lock), 4, "futex held while growing the file"),
+        field("size", offset_of!(FileHeader, size), 8, "number of pages in the file"),
+        field("alloc_lock", offset_of!(FileHeader, alloc_lock), 4, "futex of freelist shard 0, also taken for growth"),
+        field("freelist_id", offset_of!(FileHeader, freelist_id), 8, "first page of freelist shard 0, or 0"),
+        field("shards", offset_of!(FileHeader, shards), size_of::<[FreelistShard; ::MAX_FREELIST_SHARDS - 1]>(),
+              "freelist shards 1 to 15, see SHARD"),
+        field("free_pages_tag", offset_of!(FileHeader, free_pages_tag), 8,
+              "FREE_PAGES_TAG if free_pages is maintained (older files have 0)"),
+        field("free_pages", offset_of!(FileHeader, free_pages), 8, "number of free pages, including freelist pages"),
+        field("root_lock", offset_of!(FileHeader, root_lock), 4, "futex held while creating roots"),
+        field("roots", offset_of!(FileHeader, roots), 8 * ::ROOT_SLOTS, "root slots, page ids or 0"),
+        field("event_log", offset_of!(FileHeader, event_log), 8, "first page of the event log, or 0"),
+        field("event_log_pages", offset_of!(FileHeader, event_log_pages), 8, "number of event log pages"),
+        field("event_seq", offset_of!(FileHeader, event_seq), 8, "number of events logged so far"),
+    ],
+};
+
+/// A freelist shard in the header.
+pub const SHARD: Struct = Struct {
+    name: "shard",
+    version: 1,
+    size: size_of::<FreelistShard>(),
+    fields: &[
+        field("lock", offset_of!(FreelistShard, lock), 4, "futex of the shard"),
+        field("freelist_id", offset_of!(FreelistShard, freelist_id), 8, "first page of the shard's freelist, or 0"),
+    ],
+};
+
+/// A freelist page.
+///
+/// Version 1 pages have a zero magic, and use the checksum field as one more entry.
+pub const FREELIST_PAGE: Struct = Struct {
+    name: "freelist page",
+    version: 2,
+    size: PAGESZ,
+    fields: &[
+        field("count", 0, 4, "number of entries"),
+        field("magic", 4, 4, "FREELIST_MAGIC"),
+        field("entries", 8, 8 * freelist::ENTRIES, "ids of free pages"),
+        field("checksum", PAGESZ - 16, 8, "see freelist_checksum"),
+        field("next", PAGESZ - 8, 8, "next freelist page, or 0"),
+    ],
+};
+
+/// The number of entries a freelist page holds, see `HeapOptions::freelist_page_capacity`.
+pub const FREELIST_ENTRIES: usize = freelist::ENTRIES;
+
+/// The magic of version 2 freelist pages.
+pub const FREELIST_MAGIC: u32 = freelist::MAGIC as u32;
+
+/// The value of `free_pages_tag` in files that maintain `free_pages`.
+pub const FREE_PAGES_TAG: u64 = ::FREE_PAGES_TAG;
+
+/// Computes a freelist page's checksum: the wrapping sum of `mix(entry)` over all
+/// entries, `mix(!next)` and the count, where `mix(x)` is
+/// `((x ^ 0x9e3779b97f4a7c15) * 0xbf58476d1ce4e5b9).rotate_left(31)` (multiplying modulo 2^64).
+pub fn freelist_checksum(entries: &[u64], next: u64) -> u64 {
+    let mut buf = [0u64; PAGESZ / 8];
+    buf[0] = (freelist::MAGIC << 32) | entries.len() as u64;
+    buf[1..entries.len() + 1].copy_from_slice(entries);
+    buf[PAGESZ / 8 - 1] = next;
+    unsafe { FreelistPage::checksum(buf.as_mut_ptr() as *mut FreelistPage) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use HeapOptions;
+
+    fn offsets(s: &Struct) -> Vec<(&'static str, usize, usize)> {
+        s.fields.iter().map(|x| (x.name, x.offset, x.size)).collect()
+    }
+
+    // Changing any of these breaks compatibility with existing files.
+    #[test]
+    fn golden_layout() {
+        assert_eq!(HEADER.size, PAGESZ);
+        assert_eq!(offsets(&HEADER), vec![
+            ("magic", 0, 16), ("version", 16, 4), ("features", 24, 8), ("freelist_capacity", 32, 8), ("resize_lock", 64, 4), ("size", 72, 8), ("alloc_lock", 132, 4),
+            ("freelist_id", 136, 8), ("shards", 192, 960), ("free_pages_tag", 1152, 8), ("free_pages", 1160, 8),
+            ("root_lock", 1216, 4), ("roots", 1280, 128), ("event_log", 1408, 8), ("event_log_pages", 1416, 8),
+            ("event_seq", 1424, 8),
+        ]);
+        assert_eq!((LOCK_UNLOCKED, LOCK_WAITERS), (0, 1 << 31));
+        assert_eq!(SHARD.size, 64);
+        assert_eq!(offsets(&SHARD), vec![("lock", 0, 4), ("freelist_id", 8, 8)]);
+        assert_eq!(offsets(&FREELIST_PAGE), vec![
+            ("count", 0, 4), ("magic", 4, 4), ("entries", 8, 4072), ("checksum", 4080, 8), ("next", 4088, 8),
+        ]);
+        for s in &[HEADER, SHARD, FREELIST_PAGE] {
+            assert!(s.fields.windows(2).all(|x| x[0].offset + x[0].size <= x[1].offset));
+            assert!(s.fields.last().is_none_or(|x| x.offset + x.size <= s.size));
+        }
+    }
+
+    fn read_u64(buf: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&buf[offset..offset + 8]);
+        u64::from_ne_bytes(bytes)
+    }
+
+    // Reads a file using nothing but the description above.
+    #[test]
+    fn describes_files() {
+        let _ = fs::remove_file("/tmp/format.bin");
+        let mapping = HeapOptions::new().deterministic(true).open("/tmp/format.bin").unwrap();
+        let ids: Vec<_> = (0..10).map(|_| mapping.alloc()).collect();
+        for &id in &ids[2..] {
+            mapping.free(id);
+        }
+        drop(mapping);
+
+        let file = fs::read("/tmp/format.bin").unwrap();
+        let header = |name| HEADER.fields.iter().find(|x| x.name == name).unwrap().offset;
+        assert_eq!(&file[..16], MAGIC);
+        let size = read_u64(&file, header("size"));
+        assert_eq!(file.len() as u64, size * PAGESZ as u64);
+        for lock in &["resize_lock", "alloc_lock", "root_lock"] {
+            assert_eq!(read_u64(&file, header(lock)) as u32, LOCK_UNLOCKED);
+        }
+
+        let mut free = 0;
+        let mut id = read_u64(&file, header("freelist_id"));
+        while id != 0 {
+            let page = &file[id as usize * PAGESZ..][..PAGESZ];
+            let count = read_u64(page, 0) as u32 as usize;
+            assert_eq!(read_u64(page, 0) >> 32, FREELIST_MAGIC as u64);
+            let entries: Vec<_> = (0..count).map(|i| read_u64(page, 8 + 8 * i)).collect();
+            let next = read_u64(page, PAGESZ - 8);
+            assert_eq!(read_u64(page, PAGESZ - 16), freelist_checksum(&entries, next));
+            free += 1 + count as u64;
+            id = next;
+        }
+        assert_eq!(free, read_u64(&file, header("free_pages")));
+        assert_eq!(read_u64(&file, header("free_pages_tag")), FREE_PAGES_TAG);
+
+        let _ = fs::remove_file("/tmp/format.bin");
+    }
+}
@@ -0,0 +1,220 @@
+//! The format of freelist pages.
+//!
+//! A freelist page holds the ids of free pages plus a link to the next freelist page,
+//! and is free itself. Version 2 pages start with a magic and a `u32` count and keep a
+//! checksum of their contents in the last entry slot, so a corrupt page is detected
+//! instead of handing out garbage page ids. The checksum is a sum over the entries,
+//! so it is updated in constant time as entries come and go.
+//!
+//! Entries are only ever added past the count or removed by lowering it first, so a
+//! process dying in the middle of an update leaves valid entries behind (at worst
+//! losing a free page). Only the checksum may be stale then, see `repair`.
+//!
+//! Version 1 pages started with a `u64` count (which reads as a zero magic) and used
+//! the checksum slot as one more entry. They are still read as such, and upgraded
+//! whenever they are modified.
+
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::{cmp, slice};
+
+use PageId;
+use PAGESZ;
+
+const V1_ENTRIES: usize = PAGESZ / 8 - 2;
+
+/// The number of entries a freelist page holds.
+pub(crate) const ENTRIES: usize = V1_ENTRIES - 1;
+
+pub(crate) const MAGIC: u64 = 0x4c46_5032; // "2PFL"
+
+// Only ever accessed through raw pointers (no references), see `MappedHeap::freelist_page`.
+#[repr(C)]
+pub(crate) struct FreelistPage {
+    header: u64, // v2: MAGIC << 32 | count, v1: count
+    entries: [PageId; V1_ENTRIES], // in v2, the last one holds the checksum
+    next: PageId,
+}
+
+fn mix(x: u64) -> u64 {
+    (x ^ 0x9e37_79b9_7f4a_7c15).wrapping_mul(0xbf58_476d_1ce4_e5b9).rotate_left(31)
+}
+
+impl FreelistPage {
+    unsafe fn is_v2(this: *mut FreelistPage) -> bool {
+        (*this).header >> 32 == MAGIC
+    }
+
+    pub(crate) unsafe fn checksum(this: *mut FreelistPage) -> u64 {
+        let n = FreelistPage::len(this);
+        FreelistPage::entries(this).iter().fold(mix(!(*this).next).wrapping_add(n as u64), |x, &e| x.wrapping_add(mix(e)))
+    }
+
+    unsafe fn set_len(this: *mut FreelistPage, len: usize) {
+        (*this).header = MAGIC << 32 | len as u64;
+    }
+
+    // Turns a v1 page with at most `ENTRIES` entries into a v2 page.
+    unsafe fn upgrade(this: *mut FreelistPage) {
+        FreelistPage::set_len(this, FreelistPage::len(this));
+        (*this).entries[ENTRIES] = FreelistPage::checksum(this);
+    }
+
+    /// Formats a page as an empty freelist page.
+    pub(crate) unsafe fn init(this: *mut FreelistPage, next: PageId) {
+        (*this).next = next;
+        FreelistPage::set_len(this, 0);
+        (*this).entries[ENTRIES] = mix(!next);
+    }
+
+    /// The number of entries.
+    pub(crate) unsafe fn len(this: *mut FreelistPage) -> usize {
+        if FreelistPage::is_v2(this) {
+            cmp::min((*this).header as u32 as usize, ENTRIES)
+        } else {
+            cmp::min((*this).header, V1_ENTRIES as u64) as usize
+        }
+    }
+
+    /// The entries.
+    pub(crate) unsafe fn entries<'a>(this: *mut FreelistPage) -> &'a [PageId] {
+        slice::from_raw_parts((*this).entries.as_ptr(), FreelistPage::len(this))
+    }
+
+    /// The next freelist page.
+    pub(crate) unsafe fn next(this: *mut FreelistPage) -> PageId {
+        (*this).next
+    }
+
+    /// Links to another freelist page.
+    pub(crate) unsafe fn set_next(this: *mut FreelistPage, next: PageId) {
+        if !FreelistPage::is_v2(this) && FreelistPage::len(this) > ENTRIES {
+            // (v1 page without room for the checksum, it'll be upgraded once emptier)
+            (*this).next = next;
+            return;
+        }
+        if !FreelistPage::is_v2(this) {
+            FreelistPage::upgrade(this);
+        }
+        (*this).entries[ENTRIES] = (*this).entries[ENTRIES].wrapping_sub(mix(!(*this).next)).wrapping_add(mix(!next));
+        (*this).next = next;
+    }
+
+    /// Adds an entry, unless the page holds `capacity` entries (or is full) already.
+    pub(crate) unsafe fn push(this: *mut FreelistPage, id: PageId, capacity: usize) -> bool {
+        let len = FreelistPage::len(this);
+        if len >= cmp::min(capacity, ENTRIES) {
+            return false;
+        }
+        if !FreelistPage::is_v2(this) {
+            FreelistPage::upgrade(this);
+        }
+        (*this).entries[len] = id;
+        // (the stores stay in this order, for anyone taking over from a dead process)
+        compiler_fence(Ordering::Release);
+        FreelistPage::set_len(this, len + 1);
+        (*this).entries[ENTRIES] = (*this).entries[ENTRIES].wrapping_add(mix(id)).wrapping_add(1);
+        true
+    }
+
+    /// Removes the last entry.
+    pub(crate) unsafe fn pop(this: *mut FreelistPage) -> Option<PageId> {
+        let len = FreelistPage::len(this);
+        if len == 0 {
+            return None;
+        }
+        let id = (*this).entries[len - 1];
+        if FreelistPage::is_v2(this) {
+            FreelistPage::set_len(this, len - 1);
+            (*this).entries[ENTRIES] = (*this).entries[ENTRIES].wrapping_sub(mix(id)).wrapping_sub(1);
+        } else {
+            (*this).header = len as u64 - 1;
+            FreelistPage::upgrade(this);
+        }
+        Some(id)
+    }
+
+    /// Removes the entry at index `i` (moving the last one in its place).
+    pub(crate) unsafe fn take(this: *mut FreelistPage, i: usize) -> PageId {
+        let len = FreelistPage::len(this);
+        if i == len - 1 || !FreelistPage::is_v2(this) {
+            // the checksum doesn't depend on the order
+            (*this).entries.swap(i, len - 1);
+            return FreelistPage::pop(this).unwrap();
+        }
+        // drop the last entry before it replaces the one taken, so there never are two
+        // copies of it (see the module docs)
+        let (id, last) = ((*this).entries[i], (*this).entries[len - 1]);
+        FreelistPage::set_len(this, len - 1);
+        compiler_fence(Ordering::Release);
+        (*this).entries[i] = last;
+        (*this).entries[ENTRIES] = (*this).entries[ENTRIES].wrapping_sub(mix(id)).wrapping_sub(1);
+        id
+    }
+
+    /// Recomputes the checksum of a v2 page, if it has a valid count but the checksum
+    /// doesn't match. Returns whether it did.
+    pub(crate) unsafe fn repair(this: *mut FreelistPage) -> bool {
+        if !FreelistPage::is_v2(this) || (*this).header as u32 as usize > ENTRIES || FreelistPage::verify(this) {
+            return false;
+        }
+        (*this).entries[ENTRIES] = FreelistPage::checksum(this);
+        true
+    }
+
+    /// Whether the page is a well-formed freelist page.
+    pub(crate) unsafe fn verify(this: *mut FreelistPage) -> bool {
+        if FreelistPage::is_v2(this) {
+            (*this).header as u32 as usize <= ENTRIES && (*this).entries[ENTRIES] == FreelistPage::checksum(this)
+        } else {
+            (*this).header <= V1_ENTRIES as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn freelist_pages() {
+        assert_eq!(mem::size_of::<FreelistPage>(), PAGESZ);
+        let mut buf = [0u64; PAGESZ / 8];
+        let page = buf.as_mut_ptr() as *mut FreelistPage;
+        unsafe {
+            FreelistPage::init(page, 7);
+            for i in 0..ENTRIES as u64 {
+                assert!(FreelistPage::push(page, i + 100, ENTRIES));
+            }
+            assert!(!FreelistPage::push(page, 1, ENTRIES));
+            assert_eq!(FreelistPage::take(page, 0), 100);
+            assert_eq!(FreelistPage::pop(page), Some(ENTRIES as u64 + 98));
+            FreelistPage::set_next(page, 8);
+            assert!(FreelistPage::verify(page));
+            assert_eq!(FreelistPage::len(page), ENTRIES - 2);
+
+            (*page).entries[3] += 1;
+            assert!(!FreelistPage::verify(page));
+            assert!(FreelistPage::repair(page) && FreelistPage::verify(page));
+            assert!(!FreelistPage::repair(page));
+        }
+
+        // a full v1 page
+        let mut buf = [0u64; PAGESZ / 8];
+        buf[0] = V1_ENTRIES as u64;
+        for i in 0..V1_ENTRIES {
+            buf[i + 1] = i as u64 + 100;
+        }
+        buf[PAGESZ / 8 - 1] = 5;
+        let page = buf.as_mut_ptr() as *mut FreelistPage;
+        unsafe {
+            assert!(FreelistPage::verify(page));
+            assert!(!FreelistPage::push(page, 1, ENTRIES));
+            assert_eq!(FreelistPage::entries(page).len(), V1_ENTRIES);
+            assert_eq!(FreelistPage::pop(page), Some(V1_ENTRIES as u64 + 99));
+            assert!(FreelistPage::is_v2(page) && FreelistPage::verify(page));
+            assert_eq!(FreelistPage::next(page), 5);
+            assert_eq!(FreelistPage::entries(page).len(), ENTRIES);
+        }
+    }
+}
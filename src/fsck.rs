@@ -0,0 +1,171 @@
+//! Verifying the freelists, and rebuilding them from scratch when they are beyond saving.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use {ConsistencyLevel, MappedHeap, PageId, FREE_PAGES_TAG, NULL_PAGE};
+
+/// What `MappedHeap::verify` found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapReport {
+    /// The number of pages in the file (including the header).
+    pub size: u64,
+    /// The number of free pages (including the pages holding the freelists).
+    pub free_pages: u64,
+    /// The number of pages holding the freelists.
+    pub freelist_pages: u64,
+    /// The number of pages in use, i.e. neither free nor the header.
+    pub used_pages: u64,
+}
+
+/// A problem with the freelists, see `MappedHeap::verify`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorruptionError {
+    /// A freelist refers to a page that doesn't exist.
+    InvalidPage {
+        /// The page id.
+        page: PageId,
+        /// The freelist shard it was found in.
+        freelist: usize,
+    },
+    /// A freelist page failed its checksum or is malformed.
+    CorruptFreelistPage(PageId),
+    /// A page is on the freelists more than once.
+    DuplicatePage(PageId),
+    /// The freelist shard loops back onto itself.
+    Cycle(usize),
+    /// The free page count in the header doesn't match the freelists.
+    FreeCountMismatch {
+        /// The count in the header.
+        recorded: u64,
+        /// The number of pages on the freelists.
+        actual: u64,
+    },
+}
+
+impl fmt::Display for CorruptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CorruptionError::InvalidPage { page, freelist } => write!(f, "invalid page {} in freelist {}", page, freelist),
+            CorruptionError::CorruptFreelistPage(id) => write!(f, "corrupt freelist page {}", id),
+            CorruptionError::DuplicatePage(id) => write!(f, "page {} is on the freelists twice", id),
+            CorruptionError::Cycle(freelist) => write!(f, "freelist {} has a cycle", freelist),
+            CorruptionError::FreeCountMismatch { recorded, actual } => {
+                write!(f, "free page count is {}, but the freelists hold {}", recorded, actual)
+            }
+        }
+    }
+}
+
+impl Error for CorruptionError {}
+
+impl MappedHeap {
+    /// Walks all freelists, checking that they only contain valid pages, each at
+    /// most once, and that the free page count matches them (like
+    /// `check(ConsistencyLevel::FullFsck)`), and counts the free and used pages.
+    ///
+    /// If this fails, `repair` can rebuild the freelists.
+    pub fn verify(&self) -> Result<HeapReport, CorruptionError> {
+        let (size, state) = self.check_freelists(ConsistencyLevel::FullFsck, &AtomicBool::new(false), |_| ())
+                                .map_err(Option::unwrap)?; // (never cancelled)
+        Ok(HeapReport {
+            size,
+            free_pages: state.free_pages,
+            freelist_pages: state.freelist_pages,
+            used_pages: size - 1 - state.free_pages,
+        })
+    }
+
+    /// Throws away the freelists and rebuilds them from the pages that are still in
+    /// use, returning the number of free pages.
+    ///
+    /// Every page not in `live` becomes free, except the header, the event log and
+    /// quarantined pages. This is conservative in that no page is punched out of the
+    /// file, so a page that was left out of `live` by mistake keeps its contents until
+    /// it is allocated again. The old freelists aren't even read, so this works no
+    /// matter how badly they are corrupted.
+    ///
+    /// Nobody else (no other handle, no other process) may use the heap meanwhile.
+    ///
+    /// # Panics
+    ///
+    /// * If any of the live pages doesn't exist.
+    pub fn repair<I: IntoIterator<Item = PageId>>(&self, live: I) -> u64 {
+        let size = self.size();
+        let mut used = vec![false; size as usize];
+        for id in live {
+            assert!(id != NULL_PAGE && id < size, "invalid page {}", id);
+            used[id as usize] = true;
+        }
+        let header = self.header();
+        let log = header.event_log.load(Ordering::Acquire);
+        let log = log..log + header.event_log_pages.load(Ordering::Relaxed);
+        let free: Vec<_> = (1..size).filter(|&id| !used[id as usize] && !log.contains(&id) && !self.is_quarantined(id)).collect();
+        let count = free.len() as u64;
+
+        // what this handle's batch_alloc cache holds is free now too
+        self.take_cached_pages();
+        if let Some(ref allocated) = self.inner.allocated {
+            let mut allocated = allocated.lock().unwrap();
+            allocated.iter_mut().for_each(|x| *x = 0);
+            allocated.resize(size.div_ceil(64) as usize, 0);
+            for id in (1..size).filter(|&id| used[id as usize]) {
+                allocated[(id / 64) as usize] |= 1 << (id % 64);
+            }
+        }
+
+        let freelists = self.lock_freelists();
+        for &(_, head) in &freelists[1..] {
+            head.store(NULL_PAGE, Ordering::Relaxed);
+        }
+        self.rebuild_freelist(freelists[0].1, Vec::new(), free);
+        header.free_pages.store(count, Ordering::Relaxed);
+        header.free_pages_tag.store(FREE_PAGES_TAG, Ordering::Relaxed);
+        self.unlock_freelists(freelists);
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use freelist::{self, FreelistPage};
+
+    #[test]
+    fn verify_and_repair() {
+        let _ = fs::remove_file("/tmp/verify.bin");
+        let mapping = MappedHeap::open("/tmp/verify.bin").unwrap();
+        let ids: Vec<_> = (0..3000).map(|_| mapping.alloc()).collect();
+        for &id in &ids[1000..] {
+            mapping.free(id);
+        }
+        let report = mapping.verify().unwrap();
+        assert_eq!(report.size, mapping.size());
+        assert_eq!(report.free_pages, mapping.free_pages());
+        assert_eq!(report.used_pages, 1000);
+        assert!(report.freelist_pages > 0);
+
+        // a freelist entry pointing at a live page
+        let head = mapping.header().freelist_id.load(Ordering::Relaxed);
+        unsafe {
+            let freelist = mapping.freelist_page(head);
+            FreelistPage::pop(freelist);
+            FreelistPage::push(freelist, ids[0], freelist::ENTRIES);
+            FreelistPage::push(freelist, ids[0], freelist::ENTRIES);
+        }
+        assert_eq!(mapping.verify(), Err(CorruptionError::DuplicatePage(ids[0])));
+        assert_eq!(mapping.check(ConsistencyLevel::FullFsck).unwrap_err().to_string(),
+                   format!("page {} is on the freelists twice", ids[0]));
+
+        assert_eq!(mapping.repair(ids[..1000].iter().cloned()), mapping.size() - 1001);
+        let repaired = mapping.verify().unwrap();
+        assert_eq!((repaired.free_pages, repaired.used_pages), (report.free_pages, report.used_pages));
+        let mut again: Vec<_> = (0..report.free_pages).map(|_| mapping.alloc()).chain(ids[..1000].iter().cloned()).collect();
+        again.sort();
+        assert!(again.into_iter().eq(1..mapping.size()));
+
+        let _ = fs::remove_file("/tmp/verify.bin");
+    }
+}
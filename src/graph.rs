@@ -0,0 +1,308 @@
+//! Persistent adjacency lists for directed graphs.
+//!
+//! Every node with outgoing edges has a chain of pages holding the ids of its edge
+//! targets, found through a radix tree from node id to the chain's first page. The
+//! first page also records the chain's last page and the node's degree, so appending
+//! takes constant time.
+//!
+//! In sorted mode, every chain is kept sorted and free of duplicates: adding an edge
+//! inserts it into the page it belongs to, splitting that page when it is full.
+
+use std::ptr;
+
+use {MappedHeap, PageId, RadixTree, NULL_PAGE, PAGESZ};
+
+const PER_PAGE: usize = PAGESZ / 8 - 4;
+
+#[repr(C)]
+struct Meta {
+    index: PageId,
+    len: u64,
+    sorted: u64,
+}
+
+#[repr(C)]
+struct Chunk {
+    next: PageId,
+    last: PageId, // only in the first page of a chain
+    degree: u64,  // only in the first page of a chain
+    len: u64,
+    targets: [u64; PER_PAGE],
+}
+
+/// A directed graph stored in a `MappedHeap`, as a list of outgoing edges per node.
+///
+/// Nodes are arbitrary `u64` ids and only take up space once they have edges.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedGraph, MappedHeap};
+///
+/// let mapping = MappedHeap::open("/tmp/test-graph.bin").unwrap();
+/// let mut graph = MappedGraph::create_sorted(&mapping);
+/// graph.add_edge(1, 3);
+/// graph.add_edge(1, 2);
+/// assert!(!graph.add_edge(1, 3));
+/// assert_eq!(graph.edges(1).collect::<Vec<_>>(), vec![2, 3]);
+/// graph.destroy();
+/// ```
+pub struct MappedGraph<'a> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+    index: RadixTree<'a>,
+}
+
+impl<'a> MappedGraph<'a> {
+    /// Creates a new, empty graph that keeps edges in the order they were added
+    /// (including duplicates).
+    pub fn create(heap: &'a MappedHeap) -> MappedGraph<'a> {
+        MappedGraph::create_impl(heap, false)
+    }
+
+    /// Creates a new, empty graph that keeps the edges of every node sorted by
+    /// target and ignores duplicate edges.
+    ///
+    /// Adding an edge then takes time proportional to the number of pages of its
+    /// node's list, rather than constant time.
+    pub fn create_sorted(heap: &'a MappedHeap) -> MappedGraph<'a> {
+        MappedGraph::create_impl(heap, true)
+    }
+
+    fn create_impl(heap: &'a MappedHeap, sorted: bool) -> MappedGraph<'a> {
+        let meta_page = heap.alloc();
+        let index = RadixTree::create(heap).meta_page();
+        unsafe {
+            ptr::write(heap.page(meta_page).unwrap() as *mut Meta, Meta { index, len: 0, sorted: sorted as u64 });
+            MappedGraph::open(heap, meta_page)
+        }
+    }
+
+    /// Opens an existing graph by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create` or `create_sorted`, and nobody
+    /// else may modify the graph while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> MappedGraph<'a> {
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        let index = RadixTree::open(heap, (*meta).index);
+        MappedGraph { heap, meta, meta_page, index }
+    }
+
+    /// The id of the meta page, which identifies this graph.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of edges.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether the graph has no edges.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the graph is in sorted mode, see `create_sorted`.
+    pub fn is_sorted(&self) -> bool {
+        unsafe { (*self.meta).sorted != 0 }
+    }
+
+    fn chunk(&self, id: PageId) -> *mut Chunk {
+        self.heap.page(id).expect("corrupt graph") as *mut Chunk
+    }
+
+    fn new_chunk(&self) -> PageId {
+        let id = self.heap.alloc();
+        unsafe { ptr::write(self.chunk(id), Chunk { next: NULL_PAGE, last: id, degree: 0, len: 0, targets: [0; PER_PAGE] }) };
+        id
+    }
+
+    /// The number of edges leaving `node`.
+    pub fn degree(&self, node: u64) -> u64 {
+        match self.index.get(node) {
+            Some(first) => unsafe { (*self.chunk(first)).degree },
+            None => 0,
+        }
+    }
+
+    /// Adds an edge from `from` to `to`. Returns false (and changes nothing) if the
+    /// graph is sorted and already has that edge.
+    pub fn add_edge(&mut self, from: u64, to: u64) -> bool {
+        let first = match self.index.get(from) {
+            Some(first) => first,
+            None => {
+                let first = self.new_chunk();
+                self.index.insert(from, first);
+                first
+            }
+        };
+        let head = self.chunk(first);
+        let added = if self.is_sorted() { self.insert_sorted(first, to) } else { self.append(head, to) };
+        if added {
+            unsafe {
+                (*head).degree += 1;
+                (*self.meta).len += 1;
+            }
+        }
+        added
+    }
+
+    fn append(&self, head: *mut Chunk, to: u64) -> bool {
+        unsafe {
+            let mut last = self.chunk((*head).last);
+            if (*last).len as usize == PER_PAGE {
+                let id = self.new_chunk();
+                (*last).next = id;
+                (*head).last = id;
+                last = self.chunk(id);
+            }
+            (*last).targets[(*last).len as usize] = to;
+            (*last).len += 1;
+        }
+        true
+    }
+
+    fn insert_sorted(&self, first: PageId, to: u64) -> bool {
+        // the first page whose targets reach `to`, or the last page
+        let mut id = first;
+        let mut chunk = unsafe { &mut *self.chunk(id) };
+        while chunk.next != NULL_PAGE && chunk.targets[..chunk.len as usize].last().is_none_or(|&x| x < to) {
+            id = chunk.next;
+            chunk = unsafe { &mut *self.chunk(id) };
+        }
+        let mut i = match chunk.targets[..chunk.len as usize].binary_search(&to) {
+            Ok(_) => return false,
+            Err(i) => i,
+        };
+
+        if chunk.len as usize == PER_PAGE {
+            // split: the upper half moves to a new page right after this one
+            let new = self.new_chunk();
+            let half = PER_PAGE / 2;
+            let page = unsafe { &mut *self.chunk(new) };
+            page.targets[..PER_PAGE - half].copy_from_slice(&chunk.targets[half..]);
+            page.len = (PER_PAGE - half) as u64;
+            page.next = chunk.next;
+            chunk.len = half as u64;
+            chunk.next = new;
+            if page.next == NULL_PAGE {
+                // (this was the last page)
+                match id == first {
+                    true => chunk.last = new,
+                    false => unsafe { (*self.chunk(first)).last = new },
+                }
+            }
+            if i > half {
+                i -= half;
+                chunk = page;
+            }
+        }
+
+        let len = chunk.len as usize;
+        chunk.targets.copy_within(i..len, i + 1);
+        chunk.targets[i] = to;
+        chunk.len += 1;
+        true
+    }
+
+    /// Iterates over the targets of the edges leaving `node`: in the order they were
+    /// added, or in ascending order if the graph is sorted.
+    pub fn edges(&self, node: u64) -> EdgeIter<'_, 'a> {
+        EdgeIter { graph: self, page: self.index.get(node).unwrap_or(NULL_PAGE), pos: 0 }
+    }
+
+    /// Frees all pages of the graph, including the meta page.
+    pub fn destroy(self) {
+        self.index.for_each(|_, mut id| {
+            while id != NULL_PAGE {
+                let next = unsafe { (*self.chunk(id)).next };
+                self.heap.free(id);
+                id = next;
+            }
+        });
+        self.index.destroy();
+        self.heap.free(self.meta_page);
+    }
+}
+
+/// An iterator over the edge targets of a node in a `MappedGraph`, see `MappedGraph::edges`.
+pub struct EdgeIter<'b, 'a: 'b> {
+    graph: &'b MappedGraph<'a>,
+    page: PageId,
+    pos: usize,
+}
+
+impl<'b, 'a> Iterator for EdgeIter<'b, 'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.page != NULL_PAGE {
+            let chunk = unsafe { &*self.graph.chunk(self.page) };
+            if self.pos < chunk.len as usize {
+                self.pos += 1;
+                return Some(chunk.targets[self.pos - 1]);
+            }
+            self.page = chunk.next;
+            self.pos = 0;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use rand::{self, Rng};
+
+    #[test]
+    fn graph() {
+        let _ = fs::remove_file("/tmp/graph.bin");
+        let mapping = MappedHeap::open("/tmp/graph.bin").unwrap();
+
+        let mut graph = MappedGraph::create(&mapping);
+        for i in 0..2000 {
+            graph.add_edge(1, i % 700);
+        }
+        graph.add_edge(5, 1);
+        assert!(graph.add_edge(5, 1));
+        assert_eq!(graph.len(), 2002);
+        assert_eq!(graph.degree(1), 2000);
+        assert_eq!(graph.degree(2), 0);
+        assert!(graph.edges(1).eq((0..2000).map(|i| i % 700)));
+        assert_eq!(graph.edges(5).collect::<Vec<_>>(), vec![1, 1]);
+        assert_eq!(graph.edges(2).next(), None);
+        graph.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let mut graph = MappedGraph::create_sorted(&mapping);
+        let mut rng = rand::thread_rng();
+        let mut expected: Vec<u64> = Vec::new();
+        for _ in 0..5000 {
+            let to = rng.gen_range(0, 3000);
+            assert_eq!(graph.add_edge(7, to), !expected.contains(&to));
+            if !expected.contains(&to) {
+                expected.push(to);
+            }
+        }
+        expected.sort();
+        let meta = graph.meta_page();
+        let graph = unsafe { MappedGraph::open(&mapping, meta) };
+        assert!(graph.is_sorted());
+        assert_eq!(graph.len(), expected.len() as u64);
+        assert_eq!(graph.degree(7), expected.len() as u64);
+        assert_eq!(graph.edges(7).collect::<Vec<_>>(), expected);
+        graph.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/graph.bin");
+    }
+}
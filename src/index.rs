@@ -0,0 +1,197 @@
+//! A map with a secondary index, kept consistent across crashes.
+//!
+//! Both directions are radix trees: the primary one from key to value, the
+//! secondary one from the secondary key extracted from a value back to its key.
+//! Each tree stays usable when the process is killed in the middle of an update
+//! (see `RadixTree`). There are no transactions across the two, so updates are
+//! ordered such that a crash can only leave behind secondary entries pointing to
+//! keys whose value no longer matches (besides what a crash leaves in a single tree:
+//! a leaked page, or a `len` that is off by one), and lookups through the secondary
+//! index check every entry against the primary tree. Such stale entries are
+//! harmless, and are overwritten or removed as soon as their secondary key is used
+//! again.
+
+use std::ptr;
+
+use {MappedHeap, PageId, RadixTree};
+
+#[repr(C)]
+struct Meta {
+    primary: PageId,
+    secondary: PageId,
+}
+
+/// A map from `u64` keys to `u64` values stored in a `MappedHeap`, that can also be
+/// searched by a secondary key computed from the values.
+///
+/// Secondary keys are unique: inserting a value with the same secondary key as
+/// that of another entry makes the index point to the new entry (the other one
+/// can still be found by its key). Neither keys nor values can be zero.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, SecondaryIndex};
+///
+/// let mapping = MappedHeap::open("/tmp/test-index.bin").unwrap();
+/// // user id -> account number, and back
+/// let mut accounts = SecondaryIndex::create(&mapping, |value| value);
+/// accounts.insert(1, 1000);
+/// accounts.insert(2, 2000);
+/// assert_eq!(accounts.get(1), Some(1000));
+/// assert_eq!(accounts.get_by(2000), Some((2, 2000)));
+/// accounts.destroy();
+/// ```
+pub struct SecondaryIndex<'a, F: Fn(u64) -> u64> {
+    heap: &'a MappedHeap,
+    meta_page: PageId,
+    primary: RadixTree<'a>,
+    secondary: RadixTree<'a>,
+    extract: F,
+}
+
+impl<'a, F: Fn(u64) -> u64> SecondaryIndex<'a, F> {
+    /// Creates a new, empty map whose secondary keys are computed by `extract`.
+    pub fn create(heap: &'a MappedHeap, extract: F) -> SecondaryIndex<'a, F> {
+        let meta_page = heap.alloc();
+        let primary = RadixTree::create(heap).meta_page();
+        let secondary = RadixTree::create(heap).meta_page();
+        unsafe {
+            ptr::write(heap.page(meta_page).unwrap() as *mut Meta, Meta { primary, secondary });
+            SecondaryIndex::open(heap, meta_page, extract)
+        }
+    }
+
+    /// Opens an existing map by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, `extract` must compute the
+    /// same secondary keys as the one it was created with, and nobody else may
+    /// modify the map while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId, extract: F) -> SecondaryIndex<'a, F> {
+        let meta = &*(heap.page(meta_page).expect("invalid meta page") as *const Meta);
+        let primary = RadixTree::open(heap, meta.primary);
+        let secondary = RadixTree::open(heap, meta.secondary);
+        SecondaryIndex { heap, meta_page, primary, secondary, extract }
+    }
+
+    /// The id of the meta page, which identifies this map.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of entries.
+    pub fn len(&self) -> u64 {
+        self.primary.len()
+    }
+
+    /// Whether the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.primary.is_empty()
+    }
+
+    /// Looks up the value of a key.
+    pub fn get(&self, key: u64) -> Option<u64> {
+        self.primary.get(key)
+    }
+
+    /// Looks up the entry whose value has the given secondary key, returning its
+    /// key and value.
+    pub fn get_by(&self, secondary: u64) -> Option<(u64, u64)> {
+        let key = self.secondary.get(secondary)?;
+        // (skips entries left behind by a crash)
+        let value = self.primary.get(key).filter(|&x| (self.extract)(x) == secondary)?;
+        Some((key, value))
+    }
+
+    /// Inserts an entry, updating the secondary index, and returns the key's
+    /// previous value.
+    ///
+    /// # Panics
+    ///
+    /// * If `key` or `value` is zero.
+    pub fn insert(&mut self, key: u64, value: u64) -> Option<u64> {
+        assert!(key != 0 && value != 0, "keys and values can't be zero");
+        let secondary = (self.extract)(value);
+        self.secondary.insert(secondary, key);
+        let old = self.primary.insert(key, value);
+        if let Some(old) = old {
+            self.remove_secondary(key, old, Some(secondary));
+        }
+        old
+    }
+
+    /// Removes an entry and its secondary index entry, returning its value.
+    pub fn remove(&mut self, key: u64) -> Option<u64> {
+        let old = self.primary.remove(key)?;
+        self.remove_secondary(key, old, None);
+        Some(old)
+    }
+
+    // Removes the secondary entry of `key`'s old value, unless it is `keep` or
+    // already points elsewhere.
+    fn remove_secondary(&mut self, key: u64, old: u64, keep: Option<u64>) {
+        let secondary = (self.extract)(old);
+        if Some(secondary) != keep && self.secondary.get(secondary) == Some(key) {
+            self.secondary.remove(secondary);
+        }
+    }
+
+    /// Frees all pages of the map, including the meta page.
+    pub fn destroy(self) {
+        self.primary.destroy();
+        self.secondary.destroy();
+        self.heap.free(self.meta_page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn secondary_index() {
+        let _ = fs::remove_file("/tmp/index.bin");
+        let mapping = MappedHeap::open("/tmp/index.bin").unwrap();
+
+        // value = secondary key * 1000 + payload
+        let mut index = SecondaryIndex::create(&mapping, |x| x / 1000);
+        for key in 1..=100 {
+            index.insert(key, key * 2000 + 7);
+        }
+        assert_eq!(index.len(), 100);
+        assert_eq!(index.get_by(20), Some((10, 20007)));
+        assert_eq!(index.get_by(21), None);
+
+        // moving to another secondary key drops the old one
+        assert_eq!(index.insert(10, 21007), Some(20007));
+        assert_eq!(index.get_by(20), None);
+        assert_eq!(index.get_by(21), Some((10, 21007)));
+        // a new payload keeps it
+        assert_eq!(index.insert(10, 21008), Some(21007));
+        assert_eq!(index.get_by(21), Some((10, 21008)));
+
+        assert_eq!(index.remove(10), Some(21008));
+        assert_eq!(index.get_by(21), None);
+        assert_eq!(index.remove(10), None);
+
+        // what a crash between the two trees leaves behind is ignored
+        let meta = index.meta_page();
+        let mut index = unsafe { SecondaryIndex::open(&mapping, meta, |x| x / 1000) };
+        index.secondary.insert(500, 3);
+        assert_eq!(index.get_by(500), None);
+        index.insert(50, 500001);
+        assert_eq!(index.get_by(500), Some((50, 500001)));
+
+        index.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/index.bin");
+    }
+}
@@ -0,0 +1,145 @@
+//! A live inspection endpoint: a Unix socket serving heap statistics and recent
+//! allocator events to whoever connects, e.g. an operator's viewer attached to a
+//! running service.
+//!
+//! The protocol is line based. Every request is a single line, and every response a
+//! single line holding a JSON object:
+//!
+//! * `stats`: `{"size": .., "free_pages": .., "deferred_frees": .., "cached_pages": ..}`,
+//!   where `cached_pages` counts the pages in the `batch_alloc` caches of all handles
+//!   of the heap in this process
+//! * `report`: the `FragmentationReport`, with the same field names
+//! * `events <n>`: `{"events": [..]}`, the last `n` entries of the event log (see
+//!   `MappedHeap::events`), oldest first, each like
+//!   `{"seq": .., "time": <seconds since the epoch>, "pid": .., "kind": "Alloc", "page": ..}`
+//!
+//! Anything else gets `{"error": ".."}`.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use MappedHeap;
+
+/// A running inspection endpoint, see `MappedHeap::serve_inspector`.
+///
+/// Dropping it removes the socket and stops accepting connections. A client that is
+/// connected at the time is still served until it hangs up.
+pub struct Inspector {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Inspector {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // wake up the accept
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl MappedHeap {
+    /// Serves statistics and recent events of this heap on a Unix socket at `path`,
+    /// see the `inspector` module for the protocol.
+    ///
+    /// Connections are served one at a time by a background thread, through a clone
+    /// of this handle. Anyone who can connect to the socket can read the statistics
+    /// and the event log, so put it somewhere only operators can reach.
+    pub fn serve_inspector<P: AsRef<Path>>(&self, path: P) -> io::Result<Inspector> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let heap = self.clone();
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    // (a client hanging up is none of our business)
+                    let _ = heap.serve_client(stream);
+                }
+            }
+        });
+        Ok(Inspector { path, stop })
+    }
+
+    fn serve_client(&self, stream: UnixStream) -> io::Result<()> {
+        let mut out = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            let response = match (words.next(), words.next().map(|x| x.parse::<usize>())) {
+                (Some("stats"), None) => format!(
+                    "{{\"size\": {}, \"free_pages\": {}, \"deferred_frees\": {}, \"cached_pages\": {}}}",
+                    self.size(), self.free_pages(), self.deferred_frees(), self.inner.cached_pages.load(Ordering::Relaxed)),
+                (Some("report"), None) => {
+                    let report = self.fragmentation_report();
+                    let regions: Vec<_> = report.free_pages_by_region.iter().map(|x| x.to_string()).collect();
+                    format!("{{\"size\": {}, \"free_pages\": {}, \"free_runs\": {}, \"largest_free_run\": {}, \
+                             \"tail_free_pages\": {}, \"free_pages_by_region\": [{}]}}",
+                            report.size, report.free_pages, report.free_runs, report.largest_free_run,
+                            report.tail_free_pages, regions.join(", "))
+                }
+                (Some("events"), Some(Ok(n))) => {
+                    let events = self.events();
+                    let events: Vec<_> = events[events.len().saturating_sub(n)..].iter().map(|x| {
+                        let time = x.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+                        format!("{{\"seq\": {}, \"time\": {}, \"pid\": {}, \"kind\": \"{:?}\", \"page\": {}}}",
+                                x.seq, time, x.pid, x.kind, x.page)
+                    }).collect();
+                    format!("{{\"events\": [{}]}}", events.join(", "))
+                }
+                _ => "{\"error\": \"unknown request\"}".to_string(),
+            };
+            writeln!(out, "{}", response)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use HeapOptions;
+
+    #[test]
+    fn inspector() {
+        let _ = fs::remove_file("/tmp/inspector.bin");
+        let _ = fs::remove_file("/tmp/inspector.sock");
+        let mapping = HeapOptions::new().event_log(1).batch_alloc(true).open("/tmp/inspector.bin").unwrap();
+        let id = (0..1000).map(|_| mapping.alloc()).last().unwrap();
+        mapping.free(id);
+        // the inspector's handle has a cache of its own, the count covers all of them
+        let cached = mapping.pages.borrow().len();
+        assert!(cached > 0);
+
+        let inspector = mapping.serve_inspector("/tmp/inspector.sock").unwrap();
+        let stream = UnixStream::connect("/tmp/inspector.sock").unwrap();
+        let mut out = stream.try_clone().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut request = |x: &str| {
+            writeln!(out, "{}", x).unwrap();
+            lines.next().unwrap().unwrap()
+        };
+
+        assert_eq!(request("stats"), format!("{{\"size\": {}, \"free_pages\": {}, \"deferred_frees\": 0, \"cached_pages\": {}}}",
+                                             mapping.size(), mapping.free_pages(), cached));
+        assert!(request("report").contains("\"free_runs\": "));
+        let events = request("events 1");
+        assert!(events.starts_with("{\"events\": [{\"seq\": "));
+        assert!(events.ends_with(&format!("\"kind\": \"Free\", \"page\": {}}}]}}", id)));
+        assert_eq!(request("events x"), "{\"error\": \"unknown request\"}");
+
+        drop(inspector);
+        assert!(!Path::new("/tmp/inspector.sock").exists());
+        let _ = fs::remove_file("/tmp/inspector.bin");
+    }
+}
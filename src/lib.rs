@@ -6,34 +6,175 @@
 
 extern crate libc;
 extern crate futex;
+extern crate parking_lot;
 extern crate tempfile;
+#[cfg(feature = "bytemuck")]
+extern crate bytemuck;
 #[cfg(test)]
 extern crate rand;
 
-use libc::{mmap, munmap, PROT_READ, PROT_WRITE, MAP_SHARED, c_int, off_t, c_void, MAP_FAILED};
-use std::fs::{File, OpenOptions};
+use libc::{mmap, munmap, msync, PROT_NONE, PROT_READ, PROT_WRITE, MAP_SHARED, MAP_PRIVATE, MAP_ANONYMOUS,
+           MAP_NORESERVE, MAP_FIXED, MS_SYNC, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN, c_int, off_t, c_void, MAP_FAILED};
+use std::fs::{File, OpenOptions, Permissions};
 use std::io::Write;
-use std::os::unix::io::AsRawFd;
-use std::{mem, ptr, cmp, io};
-use std::cell::Cell;
-use std::usize;
-use std::path::Path;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::{mem, ptr, cmp, fmt, io};
+use std::error::Error;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-use futex::raw::Mutex;
 use futex::RwLock;
-use tempfile::NamedTempFileOptions;
+use parking_lot::RawMutex;
+use parking_lot::lock_api::RawMutex as _;
+use tempfile::{NamedTempFile, NamedTempFileOptions};
+
+use dirty::DirtyHook;
+use freelist::FreelistPage;
+use locks::HeaderLock;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "crash-tests")]
+pub mod crashtest;
+mod absorb;
+mod batch;
+mod bulk;
+mod checked;
+mod diff;
+mod dir;
+mod dirty;
+mod events;
+mod extent;
+mod filter;
+pub mod format;
+mod freelist;
+mod fsck;
+mod graph;
+mod index;
+#[cfg(feature = "inspector")]
+pub mod inspector;
+mod locks;
+mod options;
+mod pool;
+mod posting;
+mod pqueue;
+mod radix;
+mod region;
+mod report;
+mod scratch;
+mod slotted;
+mod spatial;
+mod stats;
+mod strings;
+mod tier;
+mod trie;
+mod txn;
+mod unionfind;
+#[cfg(feature = "bytemuck")]
+mod typed;
+#[cfg(feature = "bytemuck")]
+mod vec;
+
+pub use absorb::RelocationMap;
+pub use batch::WriteBatch;
+pub use bulk::BulkLoader;
+pub use dir::HeapDir;
+pub use dirty::Denied;
+pub use events::{Event, EventKind};
+pub use extent::{Extent, SequentialGroup};
+pub use filter::MappedFilter;
+pub use fsck::{CorruptionError, HeapReport};
+pub use graph::{EdgeIter, MappedGraph};
+pub use index::SecondaryIndex;
+#[cfg(feature = "inspector")]
+pub use inspector::Inspector;
+pub use options::{AllocPolicy, ConsistencyLevel, Durability, GrowthPolicy, HeapOptions, ValidationLevel};
+pub use pool::HeapPool;
+pub use posting::{PostingIter, PostingList};
+pub use pqueue::MappedPriorityQueue;
+pub use radix::RadixTree;
+pub use region::Region;
+pub use report::{FragmentationReport, REPORT_REGIONS};
+pub use scratch::ScratchPage;
+pub use slotted::SlottedPage;
+pub use spatial::SpatialIndex;
+pub use stats::StructureStats;
+pub use strings::{StringTable, MAX_STRING_LEN};
+pub use tier::TieredHeap;
+pub use trie::{MappedTrie, TrieIter};
+pub use txn::ReadTxn;
+pub use unionfind::MappedUnionFind;
+#[cfg(feature = "bytemuck")]
+pub use vec::MappedVec;
+
+fn flock(file: &File, operation: c_int) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// How often (every millisecond) to retry taking a `flock` that is held by someone else.
+const FLOCK_RETRIES: u32 = 100;
+
+// Takes a `flock` without blocking for long.
+//
+// The lock is only ever held for good by heaps in the other mode (see
+// `HeapOptions::single_process`), but also briefly while the file is initialized
+// (see `open_or_init`), so this retries for a moment before failing with `WouldBlock`.
+fn try_flock(file: &File, operation: c_int) -> io::Result<()> {
+    let mut retries = FLOCK_RETRIES;
+    loop {
+        match flock(file, operation | LOCK_NB) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && retries > 0 => {
+                retries -= 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            ret => return ret,
+        }
+    }
+}
+
+// Maps `length` bytes of the file at `offset`, preferably at `hint_addr`.
+//
+// If `reserve` exceeds `length`, that many bytes of inaccessible address space are
+// reserved first and the file is placed at the start of the reservation.
+fn do_mmap(fd: c_int, offset: off_t, length: usize, hint_addr: Option<usize>, reserve: usize) -> io::Result<usize> {
+    let mut addr = hint_addr.map(|x| x as *mut c_void).unwrap_or(ptr::null_mut());
+    let mut flags = MAP_SHARED;
+    let reserved = reserve > length;
+    if reserved {
+        addr = unsafe {
+            mmap(addr, reserve, PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0)
+        };
+        if addr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        flags |= MAP_FIXED;
+    }
 
-fn do_mmap(fd: c_int, offset: off_t, length: usize, fixed_addr: Option<usize>) -> io::Result<usize> {
     let ret = unsafe {
-        mmap(fixed_addr.map(|x| x as *mut c_void).unwrap_or(ptr::null_mut()),
+        mmap(addr,
              length,
              PROT_READ | PROT_WRITE,
-             MAP_SHARED,
+             flags,
              fd, offset)
     };
 
     if ret == MAP_FAILED {
-        Err(io::Error::last_os_error())
+        let err = io::Error::last_os_error();
+        if reserved {
+            unsafe { munmap(addr, reserve) };
+        }
+        Err(err)
     } else {
         Ok(ret as usize)
     }
@@ -41,13 +182,43 @@ fn do_mmap(fd: c_int, offset: off_t, length: usize, fixed_addr: Option<usize>) -
 
 /// The size of a page in bytes.
 pub const PAGESZ: usize = 4096;
+
+/// Panics unless a `T` at the start of a page is properly aligned.
+///
+/// Pages are aligned to `PAGESZ` and nothing more, so this only fails for types
+/// requiring larger alignment. See `assert_aligned_at` for types placed within pages.
+pub fn assert_aligned<T>() {
+    assert_aligned_at::<T>(0);
+}
+
+/// Panics unless a `T` placed `offset` bytes into a page is properly aligned.
+///
+/// Casting a misaligned pointer into a page to `&T` is undefined behavior, and since
+/// many types only need small alignment on common platforms, such bugs tend to go
+/// unnoticed until someone uses a stricter type. Check slot layouts with this, e.g.
+/// `assert_aligned_at::<Slot>(HEADER_LEN + i * mem::size_of::<Slot>())`.
+pub fn assert_aligned_at<T>(offset: usize) {
+    let align = mem::align_of::<T>();
+    assert!(align <= PAGESZ && offset.is_multiple_of(align),
+            "{} needs {}-byte alignment, but would be placed at offset {} of a page",
+            ::std::any::type_name::<T>(), align, offset);
+}
 const MAGIC: &[u8; 16] = b"\x89MAPHEAP\r\n\x1a\n\n\n\n\n";
+// the newest format version we can open (files predating the field have 0, i.e. 1)
+const FORMAT_VERSION: u32 = 1;
+// incompatible format changes, see the format module
+const FEATURE_FREELIST_V2: u64 = 1 << 0;
+const FEATURE_FREELIST_SHARDS: u64 = 1 << 1;
+const FEATURE_FREE_PAGE_COUNT: u64 = 1 << 2;
+// the feature flags we understand, files using any other can't be opened
+const FORMAT_FEATURES: u64 = FEATURE_FREELIST_V2 | FEATURE_FREELIST_SHARDS | FEATURE_FREE_PAGE_COUNT;
 
 /// An extensible memory mapped file that keeps track of used and free pages
 /// with a simple freelist allocator.
 ///
-/// The file will grow whenever necessary. It will always doube in size to
-/// make sure resizes are rare.
+/// The file grows whenever the free pages run out, by one step of the growth
+/// policy (`HeapOptions::growth_policy`, doubling the size by default) so that
+/// resizes are rare, but never beyond `HeapOptions::max_size_bytes`.
 ///
 /// # Example
 ///
@@ -60,118 +231,515 @@ const MAGIC: &[u8; 16] = b"\x89MAPHEAP\r\n\x1a\n\n\n\n\n";
 /// // do someting with page_ptr ...
 /// mapping.free(page_id);
 /// ```
+///
+/// Handles are cheap to clone: all clones share the same mapping. Give each thread
+/// its own clone rather than sharing one handle, every handle keeps a private copy of
+/// the fragment list so page lookups don't contend on the shared one.
 pub struct MappedHeap {
+    inner: Arc<HeapInner>,
+    cache: RefCell<Vec<CachedFragment>>,
+    pages: RefCell<Vec<PageId>>, // see HeapOptions::batch_alloc
+}
+
+struct HeapInner {
     file: File,
+    path: Option<PathBuf>,
     header_ptr: *mut FileHeader,
     fragments: RwLock<Vec<Fragment>>,
+    options: HeapOptions,
+    deferred: std::sync::Mutex<Vec<PageId>>, // see free_deferred
+    cached_pages: AtomicU64, // in the batch_alloc caches of all handles
+    grow_hook: std::sync::RwLock<Option<Box<GrowHook>>>,
+    dirty_hook: std::sync::RwLock<Option<Arc<DirtyHook>>>,
+    dirtied: std::sync::Mutex<HashSet<PageId>>, // see mark_dirty
+    local_locks: Vec<RawMutex>, // see HeapOptions::single_process
+    quarantined: Vec<PageId>, // sorted, see HeapOptions::quarantine
+    poisoned: std::sync::RwLock<Vec<PageId>>, // quarantined later on, see checked_read
+    any_poisoned: AtomicBool,
+    allocated: Option<std::sync::Mutex<Vec<u64>>>, // a bit per page, see HeapOptions::track_allocations
+}
+
+type GrowHook = dyn Fn(&MappedHeap, PageId) + Send + Sync;
+
+// The header only ever changes through atomics and futexes, and fragments only
+// under the write lock.
+unsafe impl Send for HeapInner {}
+unsafe impl Sync for HeapInner {}
+
+impl Clone for MappedHeap {
+    fn clone(&self) -> MappedHeap {
+        MappedHeap { inner: self.inner.clone(), cache: self.cache.clone(), pages: RefCell::new(Vec::new()) }
+    }
+}
+
+// Gives the pages taken by batch_alloc back to the freelists.
+impl Drop for MappedHeap {
+    fn drop(&mut self) {
+        let pages = self.take_cached_pages();
+        if !pages.is_empty() {
+            self.free_batch(&pages);
+        }
+    }
+}
+
+// A copy of a fragment's location. Fragments never move or shrink once the heap is
+// open, so stale copies are merely incomplete.
+#[derive(Clone, Copy)]
+struct CachedFragment {
+    addr: usize,
+    offset: u64,
+    size: u64,
 }
 
+// With max_fragments, new fragments reserve address space for this many times the file size.
+const FRAGMENT_RESERVE: u64 = 8;
+
 struct Fragment {
     addr: usize,
     offset: u64,
-    size: Cell<u64>,
+    size: AtomicU64,
+    capacity: AtomicU64, // pages of address space owned, the part beyond size is inaccessible
+    guard: bool,
 }
 
 impl Fragment {
-    fn grow(&self, file: &File, additional: u64) -> Option<Fragment> {
-        let size = self.size.get();
-        let addr_desired = self.addr + size as usize * PAGESZ;
-
-        let addr = do_mmap(file.as_raw_fd(),
-                           ((self.offset + size) as usize * PAGESZ) as i64,
-                           additional as usize * PAGESZ,
-                           Some(addr_desired)).expect("Error while trying to grow mapping");
-        if addr == addr_desired {
-            self.size.set(size + additional);
-            None
+    // Maps `additional` pages after this fragment, either by extending it
+    // or (if that isn't possible) as a new fragment with room for `capacity` pages.
+    fn grow(&self, file: &File, additional: u64, capacity: u64) -> io::Result<Option<Fragment>> {
+        let size = self.size.load(Ordering::Relaxed);
+        let reserved = self.capacity.load(Ordering::Relaxed);
+        let offset = self.offset + size;
+        let file_offset = (offset as usize * PAGESZ) as off_t;
+        let length = additional as usize * PAGESZ;
+        let end = self.addr + size as usize * PAGESZ;
+
+        if size + additional <= reserved {
+            // we own the address space already, so this can't collide with anything
+            let ret = unsafe {
+                mmap(end as *mut c_void, length, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_FIXED,
+                     file.as_raw_fd(), file_offset)
+            };
+            if ret == MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            self.size.store(size + additional, Ordering::Relaxed);
+            return Ok(None);
+        }
+
+        // Try to map what doesn't fit into our address space right after it, and
+        // the rest into it, so this stays a single fragment (but never grow into our
+        // own guard page).
+        if !self.guard {
+            let inside = (reserved - size) as usize * PAGESZ;
+            let reserved_end = end + inside;
+            let addr = do_mmap(file.as_raw_fd(), file_offset + inside as off_t, length - inside, Some(reserved_end), 0)?;
+            if addr == reserved_end {
+                if inside > 0 {
+                    let ret = unsafe {
+                        mmap(end as *mut c_void, inside, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_FIXED,
+                             file.as_raw_fd(), file_offset)
+                    };
+                    if ret == MAP_FAILED {
+                        let err = io::Error::last_os_error();
+                        unsafe { munmap(addr as *mut _, length - inside) };
+                        return Err(err);
+                    }
+                }
+                self.size.store(size + additional, Ordering::Relaxed);
+                self.capacity.store(size + additional, Ordering::Relaxed);
+                return Ok(None);
+            }
+            if inside == 0 && capacity <= additional {
+                return Ok(Some(Fragment::new(addr, offset, additional, additional, false)));
+            }
+            unsafe { munmap(addr as *mut _, length - inside) };
+        }
+
+        // map the new range separately instead
+        let capacity = cmp::max(capacity, additional);
+        let guard = if self.guard { PAGESZ } else { 0 };
+        let addr = do_mmap(file.as_raw_fd(), file_offset, length, None, capacity as usize * PAGESZ + guard)?;
+        Ok(Some(Fragment::new(addr, offset, additional, capacity, self.guard)))
+    }
+
+    fn new(addr: usize, offset: u64, size: u64, capacity: u64, guard: bool) -> Fragment {
+        Fragment { addr, offset, size: AtomicU64::new(size), capacity: AtomicU64::new(capacity), guard }
+    }
+
+    // Synchronously writes back the part of the fragment within a range of pages.
+    fn flush(&self, pages: &Range<PageId>) -> io::Result<()> {
+        let start = cmp::max(pages.start, self.offset);
+        let end = cmp::min(pages.end, self.offset + self.size.load(Ordering::Relaxed));
+        if start >= end {
+            return Ok(());
+        }
+        let addr = self.addr + (start - self.offset) as usize * PAGESZ;
+        let ret = unsafe { msync(addr as *mut c_void, (end - start) as usize * PAGESZ, MS_SYNC) };
+        if ret == 0 {
+            Ok(())
         } else {
-            Some(Fragment {
-                addr: addr,
-                offset: self.offset + size,
-                size: Cell::new(additional),
-            })
+            Err(io::Error::last_os_error())
         }
     }
 }
 
+// The index of the fragment that contains (or, if it isn't mapped yet, will contain) a page.
+fn find_fragment(fragments: &[Fragment], id: PageId) -> usize {
+    match fragments.binary_search_by_key(&id, |x| x.offset) {
+        Ok(i) => i,
+        Err(i) => i - 1,
+    }
+}
+
 impl Drop for Fragment {
     fn drop(&mut self) {
+        let guard = if self.guard { PAGESZ } else { 0 };
         unsafe {
-            munmap(self.addr as *mut _, self.size.get() as usize * PAGESZ);
+            munmap(self.addr as *mut _, self.capacity.load(Ordering::Relaxed) as usize * PAGESZ + guard);
         }
     }
 }
 
 impl MappedHeap {
-    fn header(&self) -> &mut FileHeader {
-        unsafe { &mut *self.header_ptr }
+    // Everything in the header that changes is atomic (or a futex), so shared
+    // references are all we ever need - and all that other processes allow for.
+    fn header(&self) -> &FileHeader {
+        unsafe { &*self.inner.header_ptr }
     }
 
     fn initialize<W: Write>(file: &mut W) {
-        let header = FileHeader {
-            magic: *MAGIC,
-            size: 2,
-            _pad0: [0; 48],
-            resize_lock: Mutex::new(),
-            _pad1: [0; 52],
-            alloc_lock: Mutex::new(),
-            freelist_id: 1,
-            _pad2: [0; 48],
-            _pad_end: [0; HEADER_PAD_END],
-        };
-        let header: [u8; PAGESZ] = unsafe { mem::transmute(header) };
-        file.write_all(&header).unwrap();
+        file.write_all(&MappedHeap::initial_header(2, &[NULL_PAGE; ROOT_SLOTS])).unwrap();
+        // page 1 is an empty (v1) freelist page
         file.write_all(&[0u8; PAGESZ]).unwrap();
     }
 
+    // The header of a file of `size` pages whose only free page is page 1.
+    fn initial_header(size: PageId, roots: &[PageId; ROOT_SLOTS]) -> [u8; PAGESZ] {
+        // see format::HEADER, all other fields are zero: unlocked locks (see
+        // format::LOCK_UNLOCKED), empty freelists (which is also what files predating
+        // the shards contain)
+        let mut header = [0u8; PAGESZ];
+        let mut put = |offset: usize, bytes: &[u8]| header[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(mem::offset_of!(FileHeader, magic), MAGIC);
+        put(mem::offset_of!(FileHeader, version), &FORMAT_VERSION.to_ne_bytes());
+        put(mem::offset_of!(FileHeader, features), &FORMAT_FEATURES.to_ne_bytes());
+        put(mem::offset_of!(FileHeader, size), &size.to_ne_bytes());
+        put(mem::offset_of!(FileHeader, freelist_id), &1u64.to_ne_bytes());
+        put(mem::offset_of!(FileHeader, free_pages_tag), &FREE_PAGES_TAG.to_ne_bytes());
+        put(mem::offset_of!(FileHeader, free_pages), &1u64.to_ne_bytes());
+        for (i, root) in roots.iter().enumerate() {
+            put(mem::offset_of!(FileHeader, roots) + 8 * i, &root.to_ne_bytes());
+        }
+        header
+    }
+
     /// Opens a file as a MappedHeap.
     ///
-    /// This will panic if the file is not a valid MappedHeap.
+    /// Fails with an `InvalidData` error if the file is not a heap, or wrapping
+    /// `UnsupportedVersion` if it is in a format this version doesn't support. Fails
+    /// with `WouldBlock` if the heap is open in single-process mode (see
+    /// `HeapOptions::single_process`).
     pub fn open_file(file: File) -> io::Result<MappedHeap> {
-        let len = file.metadata()?.len();
-        assert!(len <= usize::MAX as u64);
+        MappedHeap::open_file_with(file, &HeapOptions::new())
+    }
+
+    /// Opens a file as a MappedHeap.
+    ///
+    /// This will atomically create and initialize the file if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MappedHeap> {
+        MappedHeap::open_with(path.as_ref(), &HeapOptions::new())
+    }
+
+    /// Opens a file as a MappedHeap, initializing it in place if it is empty.
+    ///
+    /// This is meant for files created by someone else (e.g. handed over as an
+    /// unnamed `O_TMPFILE`) instead of by path. Initialization happens under an
+    /// exclusive `flock`, so any number of processes may call this concurrently on
+    /// the same file and they will all end up with the same heap.
+    ///
+    /// Fails like `open_file` if the file is neither empty nor a heap.
+    pub fn open_or_init(file: File) -> io::Result<MappedHeap> {
+        MappedHeap::open_or_init_with(file, &HeapOptions::new())
+    }
+
+    /// Creates a heap that lives in memory only, starting out with at least
+    /// `initial_pages` pages (counting the header, like `len_pages`).
+    ///
+    /// The heap is backed by an anonymous file (`memfd_create` on Linux, an unnamed
+    /// temporary file elsewhere) rather than one in the file system, so it works like
+    /// any other heap but disappears once the last handle is dropped. Useful for tests
+    /// and volatile caches.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mappedheap::MappedHeap;
+    ///
+    /// let mapping = MappedHeap::anonymous(100).unwrap();
+    /// assert!(mapping.free_pages() >= 99);
+    /// let page_id = mapping.alloc();
+    /// mapping.free(page_id);
+    /// ```
+    pub fn anonymous(initial_pages: u64) -> io::Result<MappedHeap> {
+        MappedHeap::anonymous_with(initial_pages, &HeapOptions::new())
+    }
+
+    fn anonymous_with(initial_pages: u64, options: &HeapOptions) -> io::Result<MappedHeap> {
+        let heap = MappedHeap::open_or_init_with(anonymous_file()?, options)?;
+        if initial_pages > heap.size() {
+            let n = initial_pages - heap.size();
+            let first = heap.alloc_grow(None, n)?.unwrap();
+            heap.free_batch(&(first..first + n).collect::<Vec<_>>());
+        }
+        Ok(heap)
+    }
+
+    /// Opens a private, in-memory copy of the heap in a file.
+    ///
+    /// The file is copied into an anonymous heap (see `anonymous`), so nothing done
+    /// to the copy ever reaches the file. Meant for tools that experiment on production
+    /// files: dry runs of repairs, previews of `compact`, and the like. The copy takes
+    /// as much memory as the file is large (holes included).
+    ///
+    /// The copy is a snapshot of whatever the file contains at the time, which is
+    /// only consistent if nobody modifies the heap meanwhile. Locks held by other
+    /// processes at the time are released in the copy.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mappedheap::MappedHeap;
+    ///
+    /// # let _ = std::fs::remove_file("/tmp/test-private.bin");
+    /// let mapping = MappedHeap::open("/tmp/test-private.bin").unwrap();
+    /// let page_id = mapping.alloc();
+    ///
+    /// let copy = MappedHeap::open_private_copy("/tmp/test-private.bin").unwrap();
+    /// copy.free(page_id);
+    /// assert_eq!(copy.free_pages(), mapping.free_pages() + 1);
+    /// ```
+    pub fn open_private_copy<P: AsRef<Path>>(path: P) -> io::Result<MappedHeap> {
+        MappedHeap::open_private_copy_with(path.as_ref(), &HeapOptions::new())
+    }
 
+    fn open_private_copy_with(path: &Path, options: &HeapOptions) -> io::Result<MappedHeap> {
+        let mut copy = anonymous_file()?;
+        io::copy(&mut File::open(path)?, &mut copy)?;
+        // (nobody would ever unlock them)
+        let unlocked = locks::UNLOCKED.to_ne_bytes();
+        let shards = (0..MAX_FREELIST_SHARDS - 1).map(|i| {
+            mem::offset_of!(FileHeader, shards) + i * mem::size_of::<FreelistShard>() + mem::offset_of!(FreelistShard, lock)
+        });
+        let locks = [mem::offset_of!(FileHeader, resize_lock), mem::offset_of!(FileHeader, alloc_lock),
+                     mem::offset_of!(FileHeader, root_lock)];
+        for offset in locks.iter().cloned().chain(shards) {
+            copy.write_all_at(&unlocked, offset as u64)?;
+        }
+        MappedHeap::open_file_with(copy, options)
+    }
+
+    fn open_or_init_with(file: File, options: &HeapOptions) -> io::Result<MappedHeap> {
+        let ret = (|| {
+            // Only one process may initialize the file, under an exclusive lock. Once
+            // the file isn't empty anymore, the lock is held by the heaps using it.
+            let mut retries = FLOCK_RETRIES;
+            while file.metadata()?.len() == 0 {
+                match flock(&file, LOCK_EX | LOCK_NB) {
+                    Ok(()) => {
+                        if file.metadata()?.len() == 0 {
+                            let mut buf = Vec::with_capacity(2 * PAGESZ);
+                            MappedHeap::initialize(&mut buf);
+                            file.write_all_at(&buf, 0)?;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && retries > 0 => {
+                        // someone else is initializing it
+                        retries -= 1;
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            // (this turns our exclusive lock, if any, into the one the heap holds)
+            MappedHeap::open_file_with(file.try_clone()?, options)
+        })();
+        if ret.is_err() {
+            flock(&file, LOCK_UN)?;
+        }
+        ret
+    }
+
+    fn open_file_with(file: File, options: &HeapOptions) -> io::Result<MappedHeap> {
+        let len = file.metadata()?.len();
+        if len < PAGESZ as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the file is too short to be a heap"));
+        }
+        if len > usize::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the file is too large to be mapped"));
+        }
         let size = len / (PAGESZ as u64); // round down to full pages
-        assert!(size > 0);
 
-        let addr = do_mmap(file.as_raw_fd(), 0, size as usize * PAGESZ, None)?;
+        // Every handle holds a lock for as long as the heap is open, an exclusive one
+        // in single-process mode and a shared one otherwise, so the modes exclude
+        // each other.
+        let (operation, in_use) = match options.single_process {
+            true => (LOCK_EX, "the heap is already in use"),
+            false => (LOCK_SH, "the heap is in use in single-process mode"),
+        };
+        try_flock(&file, operation).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(e.kind(), in_use),
+            _ => e,
+        })?;
+
+        let guard = options.guard_pages;
+        let length = size as usize * PAGESZ;
+        let addr = do_mmap(file.as_raw_fd(), 0, length, None, if guard { length + PAGESZ } else { 0 })?;
 
-        Ok(MappedHeap {
+        let inner = HeapInner {
             file,
+            path: None,
             header_ptr: addr as *mut _,
-            fragments: RwLock::new(vec![Fragment { addr, offset: 0, size: Cell::new(size) }]),
-        }.sanity_check())
+            fragments: RwLock::new(vec![Fragment::new(addr, 0, size, size, guard)]),
+            options: options.clone(),
+            deferred: std::sync::Mutex::new(Vec::new()),
+            cached_pages: AtomicU64::new(0),
+            grow_hook: std::sync::RwLock::new(None),
+            dirty_hook: std::sync::RwLock::new(None),
+            dirtied: std::sync::Mutex::new(HashSet::new()),
+            local_locks: match options.single_process {
+                true => (0..PAGESZ / 64).map(|_| RawMutex::INIT).collect(),
+                false => Vec::new(),
+            },
+            quarantined: Vec::new(),
+            poisoned: std::sync::RwLock::new(Vec::new()),
+            any_poisoned: AtomicBool::new(false),
+            allocated: None,
+        };
+        let mut heap = MappedHeap { inner: Arc::new(inner), cache: RefCell::new(Vec::new()), pages: RefCell::new(Vec::new()) };
+        heap.check_format()?;
+        if let Some(capacity) = options.freelist_page_capacity {
+            heap.header().freelist_capacity.store(capacity as u64, Ordering::Relaxed);
+        }
+
+        if options.deterministic {
+            heap.fit_file_to_header()?;
+        }
+        // (before anything that would panic on a corrupt freelist)
+        if options.quarantine {
+            let quarantined = heap.quarantine_freelists();
+            Arc::get_mut(&mut heap.inner).unwrap().quarantined = quarantined;
+            heap.recount_free_pages();
+        } else {
+            heap.check(options.consistency)?;
+        }
+        if heap.header().free_pages_tag.load(Ordering::Relaxed) != FREE_PAGES_TAG {
+            heap.recount_free_pages();
+        }
+        if options.track_allocations {
+            // everything that isn't free is taken
+            let size = heap.size();
+            let free = heap.sorted_free_pages();
+            let mut allocated = vec![0u64; size.div_ceil(64) as usize];
+            for id in (1..size).filter(|x| free.binary_search(x).is_err()) {
+                allocated[(id / 64) as usize] |= 1 << (id % 64);
+            }
+            Arc::get_mut(&mut heap.inner).unwrap().allocated = Some(std::sync::Mutex::new(allocated));
+        }
+        if let Some(pages) = options.event_log {
+            heap.create_event_log(pages)?;
+        }
+        Ok(heap)
     }
 
-    /// Opens a file as a MappedHeap.
-    ///
-    /// This will atomically create and initialize the file if it doesn't exist.
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MappedHeap> {
-        loop {
-            match OpenOptions::new().read(true).write(true).open(path.as_ref()) {
-                Ok(file) => return MappedHeap::open_file(file),
+    fn open_with(path: &Path, options: &HeapOptions) -> io::Result<MappedHeap> {
+        let file = loop {
+            match OpenOptions::new().read(true).write(true).open(path) {
+                Ok(file) => break file,
                 Err(ref x) if x.kind() == io::ErrorKind::NotFound => {
-                    let dir = path.as_ref().parent().unwrap();
-                    let stem = path.as_ref().file_stem().and_then(|x| x.to_str()).unwrap();
-                    let ext = path.as_ref().extension().and_then(|x| x.to_str()).unwrap();
-                    let mut tmp = NamedTempFileOptions::new().prefix(stem)
-                        .suffix(&format!(".{}", ext)).create_in(dir)?;
-                    MappedHeap::initialize(&mut tmp);
-                    // ignore the result of this
-                    // either we just created it
-                    // or it already existed
-                    // either way, go loop and try to open
-                    let _ = tmp.persist_noclobber(path.as_ref());
+                    if let Some(file) = MappedHeap::create_file(path)? {
+                        break file;
+                    }
+                    // someone else was faster, go loop and try to open
                 }
                 Err(e) => return Err(e),
             }
+        };
+        let mut heap = MappedHeap::open_file_with(file, options)?;
+        Arc::get_mut(&mut heap.inner).unwrap().path = Some(path.to_path_buf());
+        Ok(heap)
+    }
+
+    // Atomically creates an initialized heap file at the given path: the file is
+    // initialized under a temporary (hidden) name and then renamed into place,
+    // so nobody ever sees a partially initialized heap.
+    //
+    // Returns None if the path already exists.
+    fn create_file(path: &Path) -> io::Result<Option<File>> {
+        let mut tmp = MappedHeap::create_temp_file(path)?;
+        MappedHeap::initialize(&mut tmp);
+        match tmp.persist_noclobber(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(ref x) if x.error.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e.error),
+        }
+    }
+
+    // A hidden temporary file next to `path`, to be renamed into place once it is ready.
+    fn create_temp_file(path: &Path) -> io::Result<NamedTempFile> {
+        let dir = match path.parent() {
+            Some(x) if x != Path::new("") => x,
+            _ => Path::new("."),
+        };
+        let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("heap");
+        NamedTempFileOptions::new().prefix(&format!(".{}", name)).suffix(".tmp").create_in(dir)
+    }
+
+    // Makes the file length match the header exactly, discarding partial pages and
+    // trailing garbage (or re-extending a file whose resize was interrupted).
+    fn fit_file_to_header(&self) -> io::Result<()> {
+        let size = self.size();
+        if self.inner.file.metadata()?.len() != size * PAGESZ as u64 {
+            self.inner.file.set_len(size * PAGESZ as u64)?;
+        }
+
+        let fragments = self.inner.fragments.write();
+        let fragment = &fragments[0];
+        if fragment.size.load(Ordering::Relaxed) > size {
+            let mut end = fragment.addr + size as usize * PAGESZ;
+            if fragment.guard {
+                // move the guard page down to the new end, then drop the rest
+                let ret = unsafe {
+                    mmap(end as *mut c_void, PAGESZ, PROT_NONE,
+                         MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE | MAP_FIXED, -1, 0)
+                };
+                if ret == MAP_FAILED {
+                    return Err(io::Error::last_os_error());
+                }
+                end += PAGESZ;
+            }
+            unsafe { munmap(end as *mut _, (fragment.capacity.load(Ordering::Relaxed) - size) as usize * PAGESZ) };
+            fragment.size.store(size, Ordering::Relaxed);
+            fragment.capacity.store(size, Ordering::Relaxed);
+            self.cache.borrow_mut().clear();
         }
+        Ok(())
     }
 
-    // FIXME: remove this - instead check on open and error if necessary
-    fn sanity_check(self) -> MappedHeap {
-        assert_eq!(&self.header().magic, MAGIC);
-        self
+    // Makes sure the file is a heap in a format we can handle.
+    fn check_format(&self) -> io::Result<()> {
+        let header = self.header();
+        if &header.magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a heap file"));
+        }
+        let features = header.features.load(Ordering::Acquire);
+        if header.version > FORMAT_VERSION || features & !FORMAT_FEATURES != 0 {
+            return Err(UnsupportedVersion { version: header.version, features }.into());
+        }
+        // we write everything in the newest format, so older files use it from now on
+        if features != FORMAT_FEATURES {
+            header.features.fetch_or(FORMAT_FEATURES, Ordering::AcqRel);
+        }
+        Ok(())
     }
 
     /// Retrieves a pointer to a given page by Id, if exists within the file.
@@ -196,39 +764,100 @@ impl MappedHeap {
     ///
     /// * If the mapping needs to be extended but the syscall fails.
     ///   Resource exhaustion (memory limits) is the only documented case where this can happen.
+    ///   Use `try_page` to handle this gracefully.
     pub fn page(&self, id: PageId) -> Option<*mut [u8; PAGESZ]> {
-        if id == NULL_PAGE || id >= self.header().size {
+        if self.is_quarantined(id) {
             return None;
         }
+        let ret = self.try_page(id).expect("Error while trying to grow mapping");
+        debug_assert!(ret.is_none_or(|x| (x as usize).is_multiple_of(PAGESZ)), "misaligned page {}", id);
+        ret
+    }
 
-        let mut fragments = self.fragments.read();
-        let mut index = match fragments.binary_search_by_key(&id, |x| x.offset) {
-            Ok(i) => i,
-            Err(i) => i - 1,
-        };
+    /// Like `page`, but returns an error instead of panicking if the mapping
+    /// needs to be extended and the syscall fails.
+    ///
+    /// This usually means that a limit such as `vm.max_map_count` or `RLIMIT_AS`
+    /// was hit, which a server may want to handle by shedding load instead of crashing.
+    /// The heap remains fully usable (for pages that are already mapped) after an error.
+    ///
+    /// Pages quarantined on open (see `HeapOptions::quarantine`) fail with an
+    /// `InvalidData` error wrapping `Quarantined`.
+    pub fn try_page(&self, id: PageId) -> io::Result<Option<*mut [u8; PAGESZ]>> {
+        if id == NULL_PAGE || id >= self.size() {
+            return Ok(None);
+        }
+        if self.is_quarantined(id) {
+            return Err(Quarantined(id).into());
+        }
+        if let Some(page) = self.cached_page(id) {
+            return Ok(Some(page));
+        }
+
+        let mut fragments = self.inner.fragments.read();
+        let mut index = find_fragment(&fragments, id);
 
-        if id - fragments[index].offset >= fragments[index].size.get() {
+        if id - fragments[index].offset >= fragments[index].size.load(Ordering::Relaxed) {
             // need more mapping
             drop(fragments);
 
-            let mut m_fragments = self.fragments.write();
-            if id - m_fragments[index].offset >= m_fragments[index].size.get() {
-                let mapsize: u64 = m_fragments.iter().map(|x| x.size.get()).sum();
-                let required = self.header().size - mapsize;
+            let mut m_fragments = self.inner.fragments.write();
+            // someone else might have extended the mapping in the meantime
+            index = find_fragment(&m_fragments, id);
+            if id - m_fragments[index].offset >= m_fragments[index].size.load(Ordering::Relaxed) {
+                let mapsize: u64 = m_fragments.iter().map(|x| x.size.load(Ordering::Relaxed)).sum();
+                let required = self.size() - mapsize;
                 assert!(required > 0);
-                if let Some(x) = m_fragments.last().unwrap().grow(&self.file, required) {
+                // past the limit, new fragments get room for the file to grow a lot more
+                let capacity = match self.inner.options.max_fragments {
+                    Some(max) if m_fragments.len() >= max => self.size() * FRAGMENT_RESERVE - mapsize,
+                    _ => required,
+                };
+                if let Some(x) = m_fragments.last().unwrap().grow(&self.inner.file, required, capacity)? {
                     m_fragments.push(x);
                     index += 1;
                 }
             }
             drop(m_fragments);
 
-            fragments = self.fragments.read();
+            fragments = self.inner.fragments.read();
         }
 
         let fragment = &fragments[index];
-        assert!(id - fragment.offset < fragment.size.get());
-        Some(((fragment.addr + (id - fragment.offset) as usize * PAGESZ) as *mut [u8; PAGESZ]))
+        assert!(id - fragment.offset < fragment.size.load(Ordering::Relaxed));
+        *self.cache.borrow_mut() = fragments.iter().map(|x| CachedFragment {
+            addr: x.addr,
+            offset: x.offset,
+            size: x.size.load(Ordering::Relaxed),
+        }).collect();
+        Ok(Some((fragment.addr + (id - fragment.offset) as usize * PAGESZ) as *mut [u8; PAGESZ]))
+    }
+
+    fn is_quarantined(&self, id: PageId) -> bool {
+        let quarantined = &self.inner.quarantined;
+        (!quarantined.is_empty() && quarantined.binary_search(&id).is_ok())
+            || (self.inner.any_poisoned.load(Ordering::Acquire) && self.inner.poisoned.read().unwrap().contains(&id))
+    }
+
+    /// The pages found corrupt and quarantined on open, in ascending order, see
+    /// `HeapOptions::quarantine`.
+    pub fn quarantined_pages(&self) -> &[PageId] {
+        &self.inner.quarantined
+    }
+
+    // Looks a page up in this handle's copy of the fragment list.
+    fn cached_page(&self, id: PageId) -> Option<*mut [u8; PAGESZ]> {
+        let cache = self.cache.borrow();
+        let fragment = match cache.binary_search_by_key(&id, |x| x.offset) {
+            Ok(i) => cache[i],
+            Err(0) => return None,
+            Err(i) => cache[i - 1],
+        };
+        if id - fragment.offset < fragment.size {
+            Some((fragment.addr + (id - fragment.offset) as usize * PAGESZ) as *mut [u8; PAGESZ])
+        } else {
+            None
+        }
     }
 
     /// Retrieves a reference to a given page by Id, if it exists within the file.
@@ -244,6 +873,8 @@ impl MappedHeap {
     /// * The page is in use concurrently - data races will occur.
     /// * The page was arbitrarily modified by another application.
     ///
+    /// # Safety
+    ///
     /// In fact, even if you implement locking (you should!) you are still forced to
     /// just blindly assume that no other application (that doesn't respect your locks)
     /// is concurrently modifying the file. Whenever this assumption is violated, your
@@ -259,241 +890,2618 @@ impl MappedHeap {
     ///   Resource exhaustion (memory limits) is the only documented case where this can happen.
     pub unsafe fn page_ref<T>(&self, id: PageId) -> Option<&T> {
         assert_eq!(PAGESZ, mem::size_of::<T>());
+        assert_aligned::<T>();
         self.page(id).map(|x| &*(x as *const T))
     }
 
-    // internal convenience function - &mut T is UB in like 100% of all cases
-    unsafe fn page_mut<T>(&self, id: PageId) -> Option<&mut T> {
-        assert_eq!(PAGESZ, mem::size_of::<T>());
-        self.page(id).map(|x| &mut *(x as *mut T))
-    }
-
-    fn double_file(&self) {
-        let header = self.header();
-        header.resize_lock.acquire();
-        header.size *= 2;
-        self.file.set_len(header.size * (PAGESZ as u64)).expect("Failed to double file size");
-        header.resize_lock.release();
+    /// Copies bytes out of a page with volatile reads, without ever forming a reference
+    /// to the page.
+    ///
+    /// Unlike `page_ref`, this is well-defined even while other threads or processes
+    /// write the page: what you get may be a mix of old and new bytes (a torn read),
+    /// but never undefined behavior. Returns false if the page doesn't exist.
+    ///
+    /// # Panics
+    ///
+    /// * If `offset + buf.len()` exceeds the page size.
+    pub fn read_volatile_at(&self, id: PageId, offset: usize, buf: &mut [u8]) -> bool {
+        assert!(offset.checked_add(buf.len()).is_some_and(|x| x <= PAGESZ), "out of page bounds");
+        let page = match self.page(id) {
+            Some(page) => page as *const u8,
+            None => return false,
+        };
+        for (i, x) in buf.iter_mut().enumerate() {
+            *x = unsafe { ptr::read_volatile(page.add(offset + i)) };
+        }
+        true
     }
 
-    /// Allocates a new page and returns its Id.
+    /// Copies bytes into a page with volatile writes, without ever forming a reference
+    /// to the page. Returns false if the page doesn't exist.
     ///
-    /// This may double the file's size (if necessary).
-    ///
-    /// *Security note*: Outside interference as well as bugs in your code (see `free` for details)
-    /// may corrupt the freelist structure. In that case, while this function will not violate
-    /// memory safety, its behavior is undefined otherwise.
+    /// Other threads and processes may access the page concurrently (see
+    /// `read_volatile_at`), but nothing makes the write atomic, so readers may see it
+    /// half done.
     ///
     /// # Panics
     ///
-    /// * If the mapping needs to be extended but the syscall fails.
-    ///   Resource exhaustion (memory limits) is the only documented case where this can happen.
-    /// * If the file has to be extended but the syscall fails.
-    /// * May panic if the freelist structure is corrupt.
-    pub fn alloc(&self) -> PageId {
-        self.header().alloc_lock.acquire();
+    /// * If `offset + data.len()` exceeds the page size.
+    pub fn write_volatile_at(&self, id: PageId, offset: usize, data: &[u8]) -> bool {
+        assert!(offset.checked_add(data.len()).is_some_and(|x| x <= PAGESZ), "out of page bounds");
+        let page = match self.page(id) {
+            Some(page) => page as *mut u8,
+            None => return false,
+        };
+        for (i, &x) in data.iter().enumerate() {
+            unsafe { ptr::write_volatile(page.add(offset + i), x) };
+        }
+        true
+    }
 
-        let ret;
-        if self.header().freelist_id == NULL_PAGE {
-            // slow path :(
-            ret = self.header().size;
-            self.double_file();
+    // The freelist page with the given id. Only ever accessed through raw pointers
+    // (no references) while holding the respective shard's lock.
+    fn freelist_page(&self, id: PageId) -> *mut FreelistPage {
+        self.page(id).expect("corrupt freelist") as *mut FreelistPage
+    }
 
-            let header = self.header();
-            // inclusive start, exclusive end
-            let mut first_free: PageId = ret + 1; // we allocated the first page, everything after is free game
-            let mut last_free: PageId = self.header().size;
-            while first_free != last_free {
-                last_free -= 1;
-                let pid = last_free;
-
-                let page: &mut FreelistPage = unsafe { self.page_mut(pid).unwrap() };
-                page.n_entries = cmp::min(last_free - first_free, FREELIST_E_PER_PAGE as u64);
-                for (i, e) in page.entries.iter_mut().enumerate().take(page.n_entries as usize) {
-                    *e = i as u64 + first_free;
-                }
-                page.next = header.freelist_id;
-                header.freelist_id = pid;
-                first_free += page.n_entries;
+    // The number of entries freelist pages are filled with, see
+    // HeapOptions::freelist_page_capacity.
+    fn freelist_capacity(&self) -> usize {
+        match self.header().freelist_capacity.load(Ordering::Relaxed) {
+            0 => freelist::ENTRIES,
+            x => cmp::min(x, freelist::ENTRIES as u64) as usize,
+        }
+    }
+
+    // Panics unless the page is a well-formed freelist page.
+    fn verify_freelist_page(&self, id: PageId) {
+        assert!(unsafe { FreelistPage::verify(self.freelist_page(id)) }, "corrupt freelist page {}", id);
+    }
+
+    // The number of pages in the file.
+    //
+    // Acquire pairs with the release in grow_file: once we see a size,
+    // the file is guaranteed to be at least that large.
+    fn size(&self) -> PageId {
+        self.header().size.load(Ordering::Acquire)
+    }
+
+    // The most pages the file may have.
+    fn max_size(&self) -> PageId {
+        self.inner.options.max_size_bytes.map_or(u64::MAX, |x| x / PAGESZ as u64)
+    }
+
+    // Grows the file by one step of the growth policy, or to `to` pages (but never
+    // beyond max_size).
+    fn grow_file(&self, to: Option<PageId>) -> io::Result<()> {
+        let header = self.header();
+        self.lock(&header.resize_lock);
+        let size = header.size.load(Ordering::Relaxed);
+        let next = to.unwrap_or_else(|| self.inner.options.growth_policy.next_size(size));
+        let size = cmp::max(size, cmp::min(next, self.max_size()));
+        // extend the file before publishing the new size,
+        // anyone touching pages beyond the end of the file would get SIGBUS
+        if let Err(e) = self.inner.file.set_len(size * (PAGESZ as u64)) {
+            self.unlock(&header.resize_lock);
+            return Err(e);
+        }
+        header.size.store(size, Ordering::Release);
+        self.unlock(&header.resize_lock);
+        self.log_event(EventKind::Grow, size);
+        Ok(())
+    }
+
+    // Grows the file until it has at least `size` pages (in steps of the growth policy,
+    // unless `exact`) and maps all of it. Undoes the growth if that fails, which is fine
+    // since the caller holds the growth lock: nobody else can have used the new pages yet.
+    fn grow_to(&self, size: PageId, exact: bool) -> io::Result<()> {
+        let old = self.size();
+        let ret = (|| {
+            while self.size() < size {
+                self.grow_file(if exact { Some(size) } else { None })?;
             }
-        } else {
+            self.try_page(self.size() - 1).map(|_| ())
+        })();
+        if ret.is_err() && self.size() != old {
             let header = self.header();
-            let freelist: &mut FreelistPage = unsafe { self.page_mut(header.freelist_id).unwrap() };
-            if freelist.n_entries == 0 {
-                // consume self page
-                ret = header.freelist_id;
-                header.freelist_id = freelist.next;
-            } else {
-                freelist.n_entries -= 1;
-                ret = freelist.entries[freelist.n_entries as usize];
-            }
+            self.lock(&header.resize_lock);
+            header.size.store(old, Ordering::Release);
+            let _ = self.inner.file.set_len(old * (PAGESZ as u64));
+            self.unlock(&header.resize_lock);
+            self.log_event(EventKind::Grow, old);
         }
-        self.header().alloc_lock.release();
+        ret
+    }
 
-        // In debug builds, zero out pages before we return them.
-        #[cfg(debug)]
-        unsafe { ptr::write_bytes(self.page(ret).unwrap(), 0, 1) };
+    /// Registers a callback that is called with every page created by growing the
+    /// file, before the page is handed out or put on the freelists. Replaces any
+    /// previous callback (shared by all clones of this handle).
+    ///
+    /// Nobody can allocate these pages yet while the callback runs, so it can prepare
+    /// them (e.g. stamp a page type) without racing allocators. It runs under the
+    /// allocator lock, so it must not allocate or free pages itself. Only growth
+    /// through this process' handles calls it.
+    ///
+    /// The pages that end up holding the freelist are overwritten by it, and `alloc`
+    /// zeroes pages in debug builds and deterministic mode, so don't rely on page
+    /// contents written here surviving until the page is allocated.
+    pub fn set_grow_hook<F: Fn(&MappedHeap, PageId) + Send + Sync + 'static>(&self, hook: F) {
+        *self.inner.grow_hook.write().unwrap() = Some(Box::new(hook));
+    }
 
-        ret
+    /// Removes the callback registered with `set_grow_hook`.
+    pub fn clear_grow_hook(&self) {
+        *self.inner.grow_hook.write().unwrap() = None;
     }
 
-    /// Frees a page.
+    /// Allocates a new page and returns its Id.
     ///
-    /// Even though neither the mapping nor the file size will ever shrink,
-    /// the disk space associated with this page may be reclaimed on supported
-    /// operating and file systems (right now, only Linux is supported, have a
-    /// look at fallocate(2) for a list of file systems that support hole punching).
+    /// This may grow the file (if necessary), see `HeapOptions::growth_policy`.
     ///
-    /// *Security note*: This only checks that the given page exists - nothing else.
+    /// # Panics
     ///
-    /// Invoking this method on pages that were not previously returned by `alloc`
-    /// ("double-free") will corrupt the freelist structure.
-    /// Concurrent modification by other applications not using this API may have
-    /// the same effect. In both cases, while this function will not violate
+    /// Same as `try_alloc`, and if it fails.
+    pub fn alloc(&self) -> PageId {
+        self.try_alloc().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Allocates a new page and returns its Id, failing instead of panicking if the file
+    /// can't grow: beyond `HeapOptions::max_size_bytes`, or because extending the file
+    /// or the mapping fails (e.g. for lack of disk space or memory).
+    ///
+    /// This may grow the file (if necessary), see `HeapOptions::growth_policy`.
+    ///
+    /// *Security note*: Outside interference as well as bugs in your code (see `free` for details)
+    /// may corrupt the freelist structure. In that case, while this function will not violate
     /// memory safety, its behavior is undefined otherwise.
     ///
     /// # Panics
     ///
-    /// * If the given page id is not valid.
     /// * May panic if the freelist structure is corrupt.
-    pub fn free(&self, id: PageId) {
-        assert!(id != NULL_PAGE);
-        assert!(id < self.header().size);
-
-        let header = self.header();
-        header.alloc_lock.acquire();
+    pub fn try_alloc(&self) -> Result<PageId, AllocError> {
+        let cached = self.pages.borrow_mut().pop();
+        if cached.is_some() {
+            self.inner.cached_pages.fetch_sub(1, Ordering::Relaxed);
+        }
+        let ret = loop {
+            if let Some(id) = cached {
+                break id;
+            }
+            let size = self.size();
+            let free = match self.inner.options.alloc_policy {
+                AllocPolicy::Any => self.alloc_from_freelists(),
+                AllocPolicy::LowestFirst => self.alloc_lowest(),
+            };
+            if let Some(id) = free {
+                break id;
+            }
+            // slow path :(
+            if let Some(id) = self.alloc_grow(Some(size), 1)? {
+                break id;
+            }
+        };
 
-        if header.freelist_id != NULL_PAGE {
-            // try appending to existing freelist page
-            let freelist: &mut FreelistPage = unsafe { self.page_mut(header.freelist_id) }.unwrap();
-            if freelist.n_entries < freelist.entries.len() as u64 {
-                freelist.entries[freelist.n_entries as usize] = id;
-                freelist.n_entries += 1;
-                // added to freelist, so we can free it in the file
-                clear_page(self.page(id).unwrap() as usize);
-                header.alloc_lock.release();
-                return;
+        self.log_event(EventKind::Alloc, ret);
+        self.track_alloc(ret);
+        if self.inner.options.validation >= ValidationLevel::Cheap {
+            assert!(ret != NULL_PAGE && ret < self.size(), "heap invariant violated: allocated invalid page {}", ret);
+            if self.inner.options.validation == ValidationLevel::Paranoid {
+                assert!(self.sorted_free_pages().binary_search(&ret).is_err(),
+                        "heap invariant violated: allocated page {} is still on the freelists", ret);
             }
+            self.validate();
         }
 
-        // link in at front
-        let freelist: &mut FreelistPage = unsafe { self.page_mut(id) }.unwrap();
-        freelist.n_entries = 0;
-        freelist.next = header.freelist_id;
-        header.freelist_id = id;
-        header.alloc_lock.release();
+        // In debug builds (and for deterministic layouts), zero out pages before we return them.
+        if cfg!(debug_assertions) || self.inner.options.deterministic {
+            unsafe { ptr::write_bytes(self.page(ret).unwrap(), 0, 1) };
+        }
+
+        Ok(ret)
     }
-}
 
-const FREELIST_E_PER_PAGE: usize = (PAGESZ / 8) - 2;
+    fn shards(&self) -> usize {
+        // the shard of a thread depends on the order threads first touched a heap,
+        // so deterministic layouts use a single one
+        if self.inner.options.deterministic {
+            return 1;
+        }
+        cmp::max(self.inner.options.freelist_shards, 1)
+    }
 
-#[repr(C)]
-struct FreelistPage {
-    n_entries: u64,
-    entries: [PageId; FREELIST_E_PER_PAGE],
-    next: PageId,
-}
+    // The shard this thread allocates from and frees to.
+    fn home_shard(&self) -> usize {
+        static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+        thread_local!(static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed));
 
-/// References a page.
-pub type PageId = u64;
+        let shards = self.shards();
+        if shards == 1 {
+            0
+        } else {
+            STRIPE.with(|x| *x % shards)
+        }
+    }
 
-/// The null page guaranteed to always be invalid.
-///
-/// Internally, the first page (id 0) is reserved for the file header,
-/// so it is never valid in any public calls (never returned by `alloc`,
+    // A freelist shard's lock and head.
+    //
+    // The head is only modified while holding the lock, which also orders all accesses
+    // to the freelist pages, so relaxed loads suffice. New heads are stored with release
+    // ordering anyway, so a process dying under the lock leaves the head pointing at a
+    // fully written page for whoever takes the lock over (see locks.rs).
+    fn freelist(&self, shard: usize) -> (&HeaderLock, &AtomicU64) {
+        let header = self.header();
+        if shard == 0 {
+            (&header.alloc_lock, &header.freelist_id)
+        } else {
+            let shard = &header.shards[shard - 1];
+            (&shard.lock, &shard.freelist_id)
+        }
+    }
+
+    // Tries our own shard first, then steals from all others (including those
+    // beyond our own shard count, which other handles may be using).
+    fn alloc_from_freelists(&self) -> Option<PageId> {
+        let home = self.home_shard();
+        (0..MAX_FREELIST_SHARDS).map(|i| (home + i) % MAX_FREELIST_SHARDS).filter_map(|shard| {
+            let (lock, head) = self.freelist(shard);
+            // cheap unlocked peek so we don't lock every empty shard
+            if head.load(Ordering::Relaxed) == NULL_PAGE {
+                return None;
+            }
+
+            self.lock(lock);
+            let ret = match self.inner.options.batch_alloc {
+                true => self.take_freelist_page(head),
+                false => None,
+            };
+            let ret = ret.or_else(|| self.pop_freelist(head));
+            self.unlock(lock);
+            ret
+        }).next()
+    }
+
+    // Empties this handle's batch_alloc cache, returning the pages.
+    fn take_cached_pages(&self) -> Vec<PageId> {
+        let pages = self.pages.replace(Vec::new());
+        self.inner.cached_pages.fetch_sub(pages.len() as u64, Ordering::Relaxed);
+        pages
+    }
+
+    // Takes the head freelist page, if it is full, with all its entries: the entries go
+    // into our cache and the page itself is returned.
+    fn take_freelist_page(&self, head: &AtomicU64) -> Option<PageId> {
+        let id = head.load(Ordering::Relaxed);
+        if id == NULL_PAGE {
+            return None;
+        }
+        let freelist = self.freelist_page(id);
+        let len = unsafe { FreelistPage::len(freelist) };
+        if len < self.freelist_capacity() {
+            return None;
+        }
+
+        self.header().free_pages.fetch_sub(len as u64 + 1, Ordering::Relaxed);
+        self.inner.cached_pages.fetch_add(len as u64, Ordering::Relaxed);
+        unsafe {
+            self.pages.borrow_mut().extend_from_slice(FreelistPage::entries(freelist));
+            let next = FreelistPage::next(freelist);
+            if next != NULL_PAGE {
+                self.verify_freelist_page(next);
+            }
+            head.store(next, Ordering::Release);
+        }
+        Some(id)
+    }
+
+    // Takes the lowest-numbered page off the freelists.
+    fn alloc_lowest(&self) -> Option<PageId> {
+        // (shard, previous freelist page, freelist page, index of the entry or None for the page itself)
+        let mut lowest: Option<(usize, PageId, PageId, Option<usize>)> = None;
+        let mut best = PageId::MAX;
+        let freelists = self.lock_freelists();
+        for (shard, &(_, head)) in freelists.iter().enumerate() {
+            let mut prev = NULL_PAGE;
+            self.walk_freelist(head.load(Ordering::Relaxed), |id, entries| {
+                if id < best {
+                    best = id;
+                    lowest = Some((shard, prev, id, None));
+                }
+                for (i, &x) in entries.iter().enumerate() {
+                    if x < best {
+                        best = x;
+                        lowest = Some((shard, prev, id, Some(i)));
+                    }
+                }
+                prev = id;
+            });
+        }
+
+        if let Some((shard, prev, id, entry)) = lowest {
+            self.header().free_pages.fetch_sub(1, Ordering::Relaxed);
+            let freelist = self.freelist_page(id);
+            unsafe {
+                // what the link to this freelist page has to point to afterwards
+                let replacement = match entry {
+                    Some(i) => {
+                        FreelistPage::take(freelist, i);
+                        id
+                    }
+                    None => match FreelistPage::pop(freelist) {
+                        None => FreelistPage::next(freelist),
+                        Some(new) => {
+                            // hand the remaining entries over to the last of them (and to
+                            // more of them, if the page held more than the capacity)
+                            let capacity = self.freelist_capacity();
+                            let mut entries = FreelistPage::entries(freelist).to_vec();
+                            let mut next = FreelistPage::next(freelist);
+                            let mut new = Some(new);
+                            while let Some(id) = new {
+                                let page = self.freelist_page(id);
+                                FreelistPage::init(page, next);
+                                let start = entries.len().saturating_sub(capacity);
+                                for x in entries.drain(start..) {
+                                    FreelistPage::push(page, x, capacity);
+                                }
+                                next = id;
+                                new = entries.pop();
+                            }
+                            next
+                        }
+                    },
+                };
+                match prev {
+                    NULL_PAGE => freelists[shard].1.store(replacement, Ordering::Relaxed),
+                    prev => FreelistPage::set_next(self.freelist_page(prev), replacement),
+                }
+            }
+        }
+        self.unlock_freelists(freelists);
+        lowest.map(|_| best)
+    }
+
+    fn pop_freelist(&self, head: &AtomicU64) -> Option<PageId> {
+        let id = head.load(Ordering::Relaxed);
+        if id == NULL_PAGE {
+            return None;
+        }
+
+        self.header().free_pages.fetch_sub(1, Ordering::Relaxed);
+        let freelist = self.freelist_page(id);
+        unsafe {
+            match FreelistPage::pop(freelist) {
+                Some(x) => Some(x),
+                None => {
+                    // consume self page, making the next one the head (and checking it)
+                    let next = FreelistPage::next(freelist);
+                    if next != NULL_PAGE {
+                        self.verify_freelist_page(next);
+                    }
+                    head.store(next, Ordering::Release);
+                    Some(id)
+                }
+            }
+        }
+    }
+
+    // Grows the file and returns the first of `n` consecutive pages at its old end.
+    // Unless someone else already grew it beyond `seen_size` (in which case we return
+    // None and the caller retries the freelists).
+    //
+    // The remaining new pages are spread over our shards, a whole freelist page at a time.
+    fn alloc_grow(&self, seen_size: Option<PageId>, n: u64) -> Result<Option<PageId>, AllocError> {
+        // shard 0's lock doubles as the growth lock, it is always taken first
+        let header = self.header();
+        self.lock(&header.alloc_lock);
+        if seen_size.is_some_and(|x| x != self.size()) {
+            self.unlock(&header.alloc_lock);
+            return Ok(None);
+        }
+
+        let ret = self.size();
+        // (checked up front, so we never grow the file without using the new pages)
+        if ret.saturating_add(n) > self.max_size() {
+            self.unlock(&header.alloc_lock);
+            return Err(AllocError::Full);
+        }
+        if let Err(e) = self.grow_to(ret + n, false) {
+            self.unlock(&header.alloc_lock);
+            return Err(AllocError::Io(e));
+        }
+        header.free_pages.fetch_add(self.size() - ret - n, Ordering::Relaxed);
+        if let Some(ref hook) = *self.inner.grow_hook.read().unwrap() {
+            for id in ret..self.size() {
+                hook(self, id);
+            }
+        }
+
+        let shards = self.shards();
+        let capacity = self.freelist_capacity();
+        // inclusive start, exclusive end
+        let mut first_free: PageId = ret + n; // we allocated the first pages, everything after is free game
+        let mut last_free: PageId = self.size();
+        let mut shard = 0;
+        while first_free != last_free {
+            last_free -= 1;
+            let pid = last_free;
+
+            let page = self.freelist_page(pid);
+            let n_entries = cmp::min(last_free - first_free, capacity as u64);
+            unsafe {
+                FreelistPage::init(page, NULL_PAGE);
+                for i in 0..n_entries {
+                    FreelistPage::push(page, i + first_free, capacity);
+                }
+            }
+            first_free += n_entries;
+
+            let (lock, head) = self.freelist(shard);
+            if shard != 0 {
+                self.lock(lock);
+            }
+            unsafe { FreelistPage::set_next(page, head.load(Ordering::Relaxed)) };
+            head.store(pid, Ordering::Release);
+            if shard != 0 {
+                self.unlock(lock);
+            }
+            shard = (shard + 1) % shards;
+        }
+        self.unlock(&header.alloc_lock);
+
+        Ok(Some(ret))
+    }
+
+    /// The underlying file.
+    ///
+    /// Useful for backup tooling, `fstat` and the like. Never shrink the file (see `shrink_to_fit`)
+    /// or otherwise modify it behind the heap's back - accessing mapped pages beyond
+    /// its end kills the process with `SIGBUS`.
+    pub fn as_file(&self) -> &File {
+        &self.inner.file
+    }
+
+    /// The path the heap was opened from, if it was opened by path.
+    ///
+    /// Note that the file may have been renamed or deleted since.
+    pub fn path(&self) -> Option<&Path> {
+        self.inner.path.as_deref()
+    }
+
+    /// The number of pages in the file (including the header and all free pages).
+    pub fn len_pages(&self) -> u64 {
+        self.size()
+    }
+
+    /// The size of the heap in bytes.
+    pub fn len_bytes(&self) -> u64 {
+        self.size() * PAGESZ as u64
+    }
+
+    /// Changes the permissions of the underlying file.
+    pub fn set_permissions(&self, permissions: Permissions) -> io::Result<()> {
+        self.inner.file.set_permissions(permissions)
+    }
+
+    /// The number of free pages, i.e. how many pages can be allocated before
+    /// the file has to grow again.
+    ///
+    /// The count is maintained in the file header, so it is shared by all processes.
+    pub fn free_pages(&self) -> u64 {
+        self.header().free_pages.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes that can be allocated before the file has to grow again.
+    ///
+    /// Services can compare this with their disk quota to warn before the next growth
+    /// (see `HeapOptions::growth_policy`) would exceed it.
+    pub fn capacity_remaining_bytes(&self) -> u64 {
+        self.free_pages() * PAGESZ as u64
+    }
+
+    /// The number of fragments, i.e. separate memory mappings, this handle uses.
+    ///
+    /// Each of them counts towards the kernel's `vm.max_map_count` limit
+    /// (see `HeapOptions::max_fragments`).
+    pub fn fragments(&self) -> usize {
+        self.inner.fragments.read().len()
+    }
+
+    /// Where this handle's fragments are mapped, in file order.
+    ///
+    /// Each entry corresponds to a file-backed line in `/proc/self/maps`. Address
+    /// space reserved for future growth (see `HeapOptions::max_fragments`) and guard
+    /// pages are not included, they show up as anonymous mappings right after the
+    /// fragment.
+    pub fn mappings(&self) -> Vec<MappingInfo> {
+        self.inner.fragments.read().iter().map(|x| MappingInfo {
+            addr: x.addr,
+            len: x.size.load(Ordering::Relaxed) as usize * PAGESZ,
+            file_offset: x.offset * PAGESZ as u64,
+        }).collect()
+    }
+
+    /// Recomputes the free page count by walking all freelists and stores it
+    /// in the header. Returns the new count.
+    ///
+    /// This happens automatically when opening files that predate the count.
+    ///
+    /// # Panics
+    ///
+    /// * May panic if the freelist structure is corrupt.
+    pub fn recount_free_pages(&self) -> u64 {
+        // lock everything so the count is exact
+        let freelists = self.lock_freelists();
+        let mut count = 0;
+        for &(_, head) in &freelists {
+            self.walk_freelist(head.load(Ordering::Relaxed), |_, entries| count += 1 + entries.len() as u64);
+        }
+
+        let header = self.header();
+        header.free_pages.store(count, Ordering::Relaxed);
+        header.free_pages_tag.store(FREE_PAGES_TAG, Ordering::Relaxed);
+
+        self.unlock_freelists(freelists);
+        count
+    }
+
+    /// Checks the heap's consistency to the given level (see `ConsistencyLevel`),
+    /// returning an `InvalidData` error describing the first problem found.
+    ///
+    /// Unlike most other methods, this doesn't panic on a corrupt freelist structure.
+    pub fn check(&self, level: ConsistencyLevel) -> io::Result<()> {
+        self.check_with(level, &AtomicBool::new(false), |_| ())
+    }
+
+    /// Same as `check`, but reports its progress after every freelist page, and
+    /// stops with an `Interrupted` error as soon as `cancel` is set.
+    ///
+    /// Meant for large heaps, where a check can take a long time: the callback can
+    /// show progress or decide to give up (by setting `cancel`), and other threads
+    /// can cancel the check too. All freelists stay locked until the check returns,
+    /// so keep the callback short. A cancelled check can't be resumed (the freelists
+    /// may have changed in the meantime), just run it again later.
+    pub fn check_with<F: FnMut(CheckProgress)>(&self, level: ConsistencyLevel, cancel: &AtomicBool, progress: F) -> io::Result<()> {
+        self.check_freelists(level, cancel, progress).map(|_| ()).map_err(|e| match e {
+            Some(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+            None => io::Error::new(io::ErrorKind::Interrupted, "check cancelled"),
+        })
+    }
+
+    // The body of check_with, returning the size of the heap and the final progress
+    // (or None as the error of a cancelled check).
+    fn check_freelists<F: FnMut(CheckProgress)>(&self, level: ConsistencyLevel, cancel: &AtomicBool, mut progress: F)
+                                               -> Result<(PageId, CheckProgress), Option<CorruptionError>> {
+        let mut state = CheckProgress {
+            freelist_pages: 0,
+            free_pages: 0,
+            expected_free_pages: self.free_pages(),
+        };
+        if level == ConsistencyLevel::None {
+            return Ok((self.size(), state));
+        }
+        let freelists = self.lock_freelists();
+        let size = self.size();
+        let mut seen = vec![false; if level == ConsistencyLevel::FullFsck { size as usize } else { 0 }];
+        let mut count = 0;
+        let ret = (|| {
+            for (shard, &(_, head)) in freelists.iter().enumerate() {
+                let mut id = head.load(Ordering::Relaxed);
+                // a cycle would make us walk forever, there can't be more freelist pages than pages
+                for _ in 0..size {
+                    if id == NULL_PAGE {
+                        break;
+                    }
+                    if cancel.load(Ordering::Relaxed) {
+                        return Err(None);
+                    }
+                    if id >= size {
+                        return Err(Some(CorruptionError::InvalidPage { page: id, freelist: shard }));
+                    }
+                    let freelist = self.freelist_page(id);
+                    if !unsafe { FreelistPage::verify(freelist) } {
+                        return Err(Some(CorruptionError::CorruptFreelistPage(id)));
+                    }
+                    if !seen.is_empty() {
+                        for &x in Some(&id).into_iter().chain(unsafe { FreelistPage::entries(freelist) }) {
+                            if x == NULL_PAGE || x >= size {
+                                return Err(Some(CorruptionError::InvalidPage { page: x, freelist: shard }));
+                            } else if seen[x as usize] {
+                                return Err(Some(CorruptionError::DuplicatePage(x)));
+                            }
+                            seen[x as usize] = true;
+                            count += 1;
+                        }
+                    }
+                    state.freelist_pages += 1;
+                    state.free_pages += 1 + unsafe { FreelistPage::len(freelist) } as u64;
+                    progress(state);
+                    id = unsafe { FreelistPage::next(freelist) };
+                }
+                if id != NULL_PAGE {
+                    return Err(Some(CorruptionError::Cycle(shard)));
+                }
+            }
+            let header = self.header();
+            let free_pages = header.free_pages.load(Ordering::Relaxed);
+            let counted = header.free_pages_tag.load(Ordering::Relaxed) == FREE_PAGES_TAG;
+            if !seen.is_empty() && counted && free_pages != count {
+                return Err(Some(CorruptionError::FreeCountMismatch { recorded: free_pages, actual: count }));
+            }
+            Ok((size, state))
+        })();
+        self.unlock_freelists(freelists);
+        ret
+    }
+
+    // Locks all freelist shards (in shard order, like alloc does).
+    fn lock_freelists(&self) -> Vec<(&HeaderLock, &AtomicU64)> {
+        let freelists: Vec<_> = (0..MAX_FREELIST_SHARDS).map(|x| self.freelist(x)).collect();
+        for &(lock, _) in &freelists {
+            self.lock(lock);
+        }
+        freelists
+    }
+
+    fn unlock_freelists(&self, freelists: Vec<(&HeaderLock, &AtomicU64)>) {
+        for &(lock, _) in freelists.iter().rev() {
+            self.unlock(lock);
+        }
+    }
+
+    // Calls `f` with every page of the freelist starting at `id` and the entries stored in it.
+    // The caller must hold the freelist's lock.
+    fn walk_freelist<F: FnMut(PageId, &[PageId])>(&self, mut id: PageId, mut f: F) {
+        // a cycle would make us walk forever, there can't be more freelist pages than pages
+        for _ in 0..self.size() {
+            if id == NULL_PAGE {
+                break;
+            }
+            self.verify_freelist_page(id);
+            let freelist = self.freelist_page(id);
+            unsafe {
+                f(id, FreelistPage::entries(freelist));
+                id = FreelistPage::next(freelist);
+            }
+        }
+    }
+
+    // All free pages (including the freelist pages) in ascending order. The caller
+    // must hold all freelist locks, see lock_freelists.
+    fn collect_free_pages(&self, freelists: &[(&HeaderLock, &AtomicU64)]) -> Vec<PageId> {
+        let mut free: Vec<PageId> = Vec::with_capacity(self.free_pages() as usize);
+        for &(_, head) in freelists {
+            self.walk_freelist(head.load(Ordering::Relaxed), |id, entries| {
+                free.push(id);
+                free.extend_from_slice(entries);
+            });
+        }
+        free.sort_unstable();
+        free.dedup();
+        // garbage entries would throw off the numbers, and would be a bug elsewhere anyway
+        let size = self.size();
+        free.retain(|&id| id != 0 && id < size);
+        free
+    }
+
+    // Takes free pages (sorted) off the freelists, rebuilding the chains that held
+    // them, and returns the freelist pages that became plain entries (see
+    // punch_surplus). The caller must hold all freelist locks.
+    fn unlink_free_pages(&self, freelists: &[(&HeaderLock, &AtomicU64)], pages: &[PageId]) -> Vec<PageId> {
+        let mut surplus = Vec::new();
+        for &(_, head) in freelists {
+            let mut chain = Vec::new();
+            let mut entries = Vec::new();
+            self.walk_freelist(head.load(Ordering::Relaxed), |id, x| {
+                chain.push(id);
+                entries.extend_from_slice(x);
+            });
+            let len = chain.len() + entries.len();
+            chain.retain(|x| pages.binary_search(x).is_err());
+            entries.retain(|x| pages.binary_search(x).is_err());
+            if chain.len() + entries.len() != len {
+                surplus.extend(self.rebuild_freelist(head, chain, entries));
+            }
+        }
+        self.header().free_pages.fetch_sub(pages.len() as u64, Ordering::Relaxed);
+        surplus
+    }
+
+    // Cuts every freelist short right before the first page that fails the checks of
+    // ConsistencyLevel::FullFsck (or closes a cycle), and returns the corrupt freelist
+    // pages in ascending order. The free pages beyond the cuts are leaked.
+    fn quarantine_freelists(&self) -> Vec<PageId> {
+        let size = self.size();
+        let mut seen = vec![false; size as usize];
+        let mut bad = Vec::new();
+
+        let freelists = self.lock_freelists();
+        for &(_, head) in &freelists {
+            let mut prev = NULL_PAGE;
+            let mut id = head.load(Ordering::Relaxed);
+            while id != NULL_PAGE {
+                let mut valid = id < size && !seen[id as usize];
+                if valid {
+                    let freelist = self.freelist_page(id);
+                    valid = unsafe { FreelistPage::verify(freelist) };
+                    let mut marked = vec![id];
+                    seen[id as usize] = true;
+                    for &x in unsafe { FreelistPage::entries(freelist) } {
+                        if !valid || x == NULL_PAGE || x >= size || seen[x as usize] {
+                            valid = false;
+                            break;
+                        }
+                        seen[x as usize] = true;
+                        marked.push(x);
+                    }
+                    if !valid {
+                        // (the page itself stays marked, in case some other freelist points to it)
+                        for &x in &marked[1..] {
+                            seen[x as usize] = false;
+                        }
+                        bad.push(id);
+                    }
+                }
+                if !valid {
+                    match prev {
+                        NULL_PAGE => head.store(NULL_PAGE, Ordering::Relaxed),
+                        prev => unsafe { FreelistPage::set_next(self.freelist_page(prev), NULL_PAGE) },
+                    }
+                    break;
+                }
+                prev = id;
+                id = unsafe { FreelistPage::next(self.freelist_page(id)) };
+            }
+        }
+        self.unlock_freelists(freelists);
+        bad.sort();
+        bad
+    }
+
+    /// Retrieves the page stored in a root slot, if it has been created.
+    ///
+    /// # Panics
+    ///
+    /// * If `slot` is not less than `ROOT_SLOTS`.
+    pub fn root(&self, slot: usize) -> Option<PageId> {
+        match self.header().roots[slot].load(Ordering::Acquire) {
+            NULL_PAGE => None,
+            id => Some(id),
+        }
+    }
+
+    /// Retrieves the page stored in a root slot, creating it if necessary.
+    ///
+    /// Root slots live in the file header and let data structures find their entry
+    /// point. When several processes race to create the same structure, `create` runs
+    /// in exactly one of them (under a lock in the file) and all of them end up with
+    /// its result. The slot is only filled in after `create` returns, so a process
+    /// crashing halfway through leaves the slot empty (and the next caller tries again)
+    /// rather than exposing a half-initialized structure.
+    ///
+    /// `create` must not call `root_or_create` itself.
+    ///
+    /// # Panics
+    ///
+    /// * If `slot` is not less than `ROOT_SLOTS`.
+    /// * If `create` returns `NULL_PAGE`.
+    pub fn root_or_create<F: FnOnce(&MappedHeap) -> PageId>(&self, slot: usize, create: F) -> PageId {
+        if let Some(id) = self.root(slot) {
+            return id;
+        }
+
+        let header = self.header();
+        self.lock(&header.root_lock);
+        // someone may have beaten us to it
+        let id = match self.root(slot) {
+            Some(id) => id,
+            None => {
+                let id = create(self);
+                assert!(id != NULL_PAGE);
+                header.roots[slot].store(id, Ordering::Release);
+                id
+            }
+        };
+        self.unlock(&header.root_lock);
+        id
+    }
+
+    /// Flushes all changes made through the mapping to disk, with the durability set
+    /// by `HeapOptions::durability` (see `flush_with`).
+    pub fn flush(&self) -> io::Result<()> {
+        self.flush_with(self.inner.options.durability)
+    }
+
+    /// Flushes all changes made through the mapping to disk.
+    ///
+    /// With `Durability::Sync`, this synchronously writes back every mapped fragment
+    /// and then syncs the file (including the header), so all modifications made
+    /// before the call are durable once it returns. With `HeapOptions::max_dirty_bytes`,
+    /// the data is written back gradually using `flush_window` first.
+    ///
+    /// With `Durability::Async`, this only starts writing back the whole file (only
+    /// implemented on Linux, see `flush_window`), and with `Durability::None` it does
+    /// nothing at all.
+    pub fn flush_with(&self, durability: Durability) -> io::Result<()> {
+        match durability {
+            Durability::None => return Ok(()),
+            Durability::Async => return write_back(&self.inner.file, 0, self.size() * PAGESZ as u64, false),
+            Durability::Sync => (),
+        }
+        if let Some(max_dirty_bytes) = self.inner.options.max_dirty_bytes {
+            self.flush_window(0..self.size(), cmp::max(max_dirty_bytes / 2, PAGESZ as u64))?;
+        }
+        for fragment in self.inner.fragments.read().iter() {
+            fragment.flush(&(0..PageId::MAX))?;
+        }
+        self.inner.file.sync_all()
+    }
+
+    /// Synchronously writes back a range of pages, for commit points that only
+    /// touched a few pages of a large heap.
+    ///
+    /// This only writes back the parts of the mapping holding these pages
+    /// (`msync`), and doesn't sync the file's metadata like `flush` does. So after
+    /// the file has grown, use `flush` once to make its new size durable.
+    pub fn flush_pages(&self, pages: Range<PageId>) -> io::Result<()> {
+        let end = cmp::min(pages.end, self.size());
+        if pages.start >= end {
+            return Ok(());
+        }
+        // (maps the whole range)
+        self.page(end - 1);
+        for fragment in self.inner.fragments.read().iter() {
+            fragment.flush(&pages)?;
+        }
+        Ok(())
+    }
+
+    /// Writes back a range of pages in windows of `window` bytes, keeping at most
+    /// two windows under writeback at any time.
+    ///
+    /// Writing back lots of dirty pages at once (as `flush` does) can saturate the
+    /// device for seconds, stalling everyone else using it. This spreads the writes out
+    /// instead. It only writes back data and doesn't sync any metadata, so use `flush`
+    /// afterwards for durability (which then has little left to do).
+    ///
+    /// Only implemented on Linux (using `sync_file_range`), elsewhere this does nothing.
+    ///
+    /// # Panics
+    ///
+    /// * If `window` is zero.
+    pub fn flush_window(&self, pages: Range<PageId>, window: u64) -> io::Result<()> {
+        assert!(window > 0);
+        let end = cmp::min(pages.end, self.size()) * PAGESZ as u64;
+        let mut offset = pages.start * PAGESZ as u64;
+        let mut prev = None;
+        while offset < end {
+            let len = cmp::min(window, end - offset);
+            write_back(&self.inner.file, offset, len, false)?;
+            // wait for the previous window while this one is being written
+            if let Some((offset, len)) = prev {
+                write_back(&self.inner.file, offset, len, true)?;
+            }
+            prev = Some((offset, len));
+            offset += len;
+        }
+        if let Some((offset, len)) = prev {
+            write_back(&self.inner.file, offset, len, true)?;
+        }
+        Ok(())
+    }
+
+    /// Frees a page.
+    ///
+    /// Even though neither the mapping nor the file size will ever shrink,
+    /// the disk space associated with this page may be reclaimed on supported
+    /// operating and file systems: Linux (have a look at fallocate(2) for a list of
+    /// file systems that support hole punching), macOS (APFS) and FreeBSD 14 or later.
+    ///
+    /// *Security note*: This only checks that the given page exists - nothing else.
+    ///
+    /// Invoking this method on pages that were not previously returned by `alloc`
+    /// ("double-free") will corrupt the freelist structure.
+    /// Concurrent modification by other applications not using this API may have
+    /// the same effect. In both cases, while this function will not violate
+    /// memory safety, its behavior is undefined otherwise.
+    ///
+    /// # Panics
+    ///
+    /// * If the given page id is not valid.
+    /// * May panic if the freelist structure is corrupt.
+    pub fn free(&self, id: PageId) {
+        self.free_impl(id, self.inner.options.trim_on_free);
+    }
+
+    /// Frees a page and makes sure it doesn't keep occupying memory.
+    ///
+    /// `free` only punches a hole for pages that end up as freelist entries. Pages that
+    /// become freelist pages themselves stay dirty in the page cache. This additionally
+    /// writes those back and drops them from both the mapping and the page cache, so
+    /// freeing large amounts of memory actually shrinks the resident set.
+    ///
+    /// The same caveats as for `free` apply.
+    pub fn free_and_trim(&self, id: PageId) {
+        self.free_impl(id, true);
+    }
+
+    /// Frees pages later, in bulk: the pages are only recorded for now, and actually
+    /// freed by `apply_deferred_frees`.
+    ///
+    /// Dropping a large structure page by page takes a freelist lock (and punches a
+    /// hole) for every page. Instead, call this and have a maintenance thread of your
+    /// own call `apply_deferred_frees` in the background, which frees pages a whole
+    /// freelist page at a time and punches their holes before taking any lock.
+    ///
+    /// The pending frees are only kept in memory (shared by all clones of this handle),
+    /// pages still pending when the last handle is dropped or the process dies are leaked.
+    ///
+    /// The same caveats as for `free` apply.
+    ///
+    /// # Panics
+    ///
+    /// * If any of the page ids is not valid.
+    pub fn free_deferred(&self, ids: &[PageId]) {
+        let size = self.size();
+        assert!(ids.iter().all(|&id| id != NULL_PAGE && id < size));
+        for &id in ids {
+            self.track_free(id);
+            self.forget_dirty(id);
+        }
+        self.inner.deferred.lock().unwrap().extend_from_slice(ids);
+    }
+
+    /// The number of pages waiting to be freed, see `free_deferred`.
+    pub fn deferred_frees(&self) -> usize {
+        self.inner.deferred.lock().unwrap().len()
+    }
+
+    /// Frees up to `max` of the pages passed to `free_deferred` and returns how many it freed.
+    pub fn apply_deferred_frees(&self, max: usize) -> usize {
+        let ids = {
+            let mut deferred = self.inner.deferred.lock().unwrap();
+            let n = cmp::min(max, deferred.len());
+            let at = deferred.len() - n;
+            deferred.split_off(at)
+        };
+        for &x in &ids {
+            self.log_event(EventKind::Free, x);
+        }
+        self.free_batch(&ids);
+        ids.len()
+    }
+
+    // Frees pages a whole freelist page at a time, see apply_deferred_frees.
+    fn free_batch(&self, ids: &[PageId]) {
+        let poison = self.inner.options.poison_on_free;
+        let capacity = self.freelist_capacity();
+        for chunk in ids.chunks(capacity + 1) {
+            let (&id, entries) = chunk.split_last().unwrap();
+            // the pages are still ours, so the freelist page can be filled in and the
+            // entries punched before locking (once linked in, anyone may allocate and
+            // write them)
+            let freelist = self.freelist_page(id);
+            unsafe {
+                FreelistPage::init(freelist, NULL_PAGE);
+                for &x in entries {
+                    FreelistPage::push(freelist, x, capacity);
+                }
+            }
+            for &x in entries {
+                match poison {
+                    true => self.poison_page(x),
+                    false => self.punch_page(x),
+                }
+            }
+
+            let (lock, head) = self.freelist(self.home_shard());
+            self.lock(lock);
+            unsafe { FreelistPage::set_next(freelist, head.load(Ordering::Relaxed)) };
+            head.store(id, Ordering::Release);
+            self.header().free_pages.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            self.unlock(lock);
+
+            // (trimming keeps the contents, so it doesn't matter if someone allocated
+            // the page in the meantime)
+            if !poison && self.inner.options.trim_on_free {
+                self.trim_page(id);
+            }
+        }
+    }
+
+    /// Packs every freelist as densely as possible and returns by how many pages
+    /// this shortened the freelist chains.
+    ///
+    /// Allocating with `AllocPolicy::LowestFirst` and freeing from many shards leaves
+    /// behind partially filled freelist pages, which `alloc` and `free` never merge.
+    /// This rewrites each shard's chain into full freelist pages (reusing existing
+    /// freelist pages, and only leaving the last one partially filled), so there are
+    /// fewer pages to walk and more full ones for `HeapOptions::batch_alloc` to take. The pages that become plain entries are
+    /// punched out of the file.
+    ///
+    /// This locks all freelists meanwhile and takes time proportional to the number
+    /// of free pages.
+    pub fn compact_freelist(&self) -> u64 {
+        let mut removed = 0;
+        let mut punch = Vec::new();
+        let capacity = self.freelist_capacity();
+        let freelists = self.lock_freelists();
+        for &(_, head) in &freelists {
+            let mut pages = Vec::new();
+            let mut entries = Vec::new();
+            let mut packed = true; // whether all but the last page are full
+            self.walk_freelist(head.load(Ordering::Relaxed), |id, x| {
+                packed &= entries.len() == pages.len() * capacity && x.len() <= capacity;
+                pages.push(id);
+                entries.extend_from_slice(x);
+            });
+            if packed {
+                continue;
+            }
+            let surplus = self.rebuild_freelist(head, pages, entries);
+            removed += surplus.len() as u64;
+            punch.extend(surplus);
+        }
+        self.punch_surplus(punch);
+        self.unlock_freelists(freelists);
+        removed
+    }
+
+    /// Gives the free pages at the end of the file back to the filesystem by
+    /// truncating it, and returns by how many pages this shrank the file.
+    ///
+    /// The file only ever grows on its own, so a long-running service that frees
+    /// most of its pages keeps its peak size. This takes the trailing free pages
+    /// (see `FragmentationReport::tail_free_pages`) off the freelists and cuts them
+    /// off; allocate with `AllocPolicy::LowestFirst` (or move pages to the front, see
+    /// `move_page`) so as much free space as possible ends up there. The address space
+    /// stays mapped, and is reused should the file grow again.
+    ///
+    /// This locks all freelists (and thus blocks allocation) meanwhile, and takes time
+    /// proportional to the number of free pages. Nobody may access the pages cut off,
+    /// which are free anyway.
+    ///
+    /// # Panics
+    ///
+    /// * May panic if the freelist structure is corrupt.
+    pub fn shrink_to_fit(&self) -> io::Result<u64> {
+        let freelists = self.lock_freelists();
+        let size = self.size();
+        let free = self.collect_free_pages(&freelists);
+        let tail = free.iter().rev().zip((1..size).rev()).take_while(|&(&x, y)| x == y).count();
+        if tail == 0 {
+            self.unlock_freelists(freelists);
+            return Ok(0);
+        }
+
+        // unlink the tail first, so the freelists never point beyond the end
+        let new_size = size - tail as u64;
+        let punch = self.unlink_free_pages(&freelists, &free[free.len() - tail..]);
+        let header = self.header();
+        self.lock(&header.resize_lock);
+        header.size.store(new_size, Ordering::Release);
+        let ret = self.inner.file.set_len(new_size * PAGESZ as u64);
+        self.unlock(&header.resize_lock);
+        self.punch_surplus(punch);
+        self.unlock_freelists(freelists);
+        self.log_event(EventKind::Grow, new_size);
+        ret.map(|_| size - new_size)
+    }
+
+    // Rewrites a freelist chain into as few full freelist pages as possible, made of
+    // `pages` (as far as they go, then some of the `entries`), and returns the surplus
+    // `pages` that became entries. The caller must hold the freelist's lock.
+    fn rebuild_freelist(&self, head: &AtomicU64, mut pages: Vec<PageId>, mut entries: Vec<PageId>) -> Vec<PageId> {
+        let capacity = self.freelist_capacity();
+        let needed = (pages.len() + entries.len()).div_ceil(capacity + 1);
+        while pages.len() < needed {
+            pages.push(entries.pop().unwrap());
+        }
+
+        // the surplus freelist pages become entries, and the chain is rebuilt back to front
+        let surplus = pages.split_off(needed);
+        entries.extend_from_slice(&surplus);
+        let mut next = NULL_PAGE;
+        for (i, &id) in pages.iter().enumerate().rev() {
+            // (the last page may come up empty)
+            let start = cmp::min(i * capacity, entries.len());
+            let chunk = &entries[start..cmp::min(start + capacity, entries.len())];
+            let freelist = self.freelist_page(id);
+            unsafe {
+                FreelistPage::init(freelist, next);
+                for &x in chunk {
+                    FreelistPage::push(freelist, x, capacity);
+                }
+            }
+            next = id;
+        }
+        head.store(next, Ordering::Release);
+        surplus
+    }
+
+    // Punches out freelist pages that became plain entries, unless freed pages are
+    // poisoned. The caller must still hold the freelist locks: once they are released,
+    // anyone may allocate the pages and write to them.
+    fn punch_surplus(&self, pages: Vec<PageId>) {
+        if !self.inner.options.poison_on_free {
+            for id in pages {
+                self.punch_page(id);
+            }
+        }
+    }
+
+    fn free_impl(&self, id: PageId, trim: bool) {
+        assert!(id != NULL_PAGE);
+        assert!(id < self.size());
+        self.track_free(id);
+        self.forget_dirty(id);
+        match self.inner.options.validation {
+            ValidationLevel::Off => return self.free_to_freelist(id, trim),
+            ValidationLevel::Cheap => {
+                // (only the page the next free goes to, so only catches repeated frees)
+                let (lock, head) = self.freelist(self.home_shard());
+                self.lock(lock);
+                let head = head.load(Ordering::Relaxed);
+                let twice = head == id || (head != NULL_PAGE && unsafe { FreelistPage::entries(self.freelist_page(head)) }.contains(&id));
+                self.unlock(lock);
+                assert!(!twice, "double free of page {}", id);
+            }
+            ValidationLevel::Paranoid => {
+                assert!(self.sorted_free_pages().binary_search(&id).is_err(), "double free of page {}", id);
+            }
+        }
+        self.free_to_freelist(id, trim);
+        self.validate();
+    }
+
+    // Checks the heap as a whole after an operation, see ValidationLevel.
+    fn validate(&self) {
+        match self.inner.options.validation {
+            ValidationLevel::Off => (),
+            ValidationLevel::Cheap => {
+                let (free_pages, size) = (self.free_pages(), self.size());
+                assert!(free_pages < size, "heap invariant violated: {} free pages out of {}", free_pages, size);
+            }
+            ValidationLevel::Paranoid => {
+                if let Err(e) = self.check(ConsistencyLevel::FullFsck) {
+                    panic!("heap invariant violated: {}", e);
+                }
+            }
+        }
+    }
+
+    fn free_to_freelist(&self, id: PageId, trim: bool) {
+        self.log_event(EventKind::Free, id);
+
+        // the page's contents have to stay in place to be of any use
+        let poison = self.inner.options.poison_on_free;
+        if poison {
+            self.poison_page(id);
+        }
+
+        let (lock, head) = self.freelist(self.home_shard());
+        self.lock(lock);
+        self.header().free_pages.fetch_add(1, Ordering::Relaxed);
+
+        let head_id = head.load(Ordering::Relaxed);
+        if head_id != NULL_PAGE {
+            // try appending to existing freelist page
+            let freelist = self.freelist_page(head_id);
+            if unsafe { FreelistPage::push(freelist, id, self.freelist_capacity()) } {
+                // added to freelist, so we can free it in the file
+                if !poison {
+                    self.punch_page(id);
+                }
+                self.unlock(lock);
+                return;
+            }
+        }
+
+        // link in at front
+        unsafe { FreelistPage::init(self.freelist_page(id), head_id) };
+        head.store(id, Ordering::Release);
+        self.unlock(lock);
+
+        if trim && !poison {
+            // (harmless even if someone allocated it in the meantime)
+            self.trim_page(id);
+        }
+    }
+
+    // Records that a page was handed out, see HeapOptions::track_allocations.
+    fn track_alloc(&self, id: PageId) {
+        if let Some(ref allocated) = self.inner.allocated {
+            let mut allocated = allocated.lock().unwrap();
+            let word = (id / 64) as usize;
+            if allocated.len() <= word {
+                allocated.resize(word + 1, 0); // (the file grew)
+            }
+            let taken = allocated[word] & 1 << (id % 64) != 0;
+            allocated[word] |= 1 << (id % 64);
+            drop(allocated);
+            assert!(!taken, "heap invariant violated: allocated page {} twice", id);
+        }
+    }
+
+    // Checks that a page being freed is allocated, and records that it no longer is.
+    fn track_free(&self, id: PageId) {
+        if let Some(ref allocated) = self.inner.allocated {
+            let mut allocated = allocated.lock().unwrap();
+            let word = (id / 64) as usize;
+            let taken = allocated.get(word).is_some_and(|x| x & 1 << (id % 64) != 0);
+            if taken {
+                allocated[word] &= !(1 << (id % 64));
+            }
+            drop(allocated);
+            assert!(taken, "double free of page {} (or it was never allocated)", id);
+        }
+    }
+
+    // Fills a page with POISON, stamping every other word with the page id.
+    fn poison_page(&self, id: PageId) {
+        let page = self.page(id).unwrap() as *mut [u64; PAGESZ / 8];
+        for i in 0..PAGESZ / 8 {
+            unsafe { (*page)[i] = if i % 2 == 0 { POISON } else { id } };
+        }
+    }
+
+    /// Drops a page from this process' mapping and asks the kernel to evict it
+    /// from the page cache, without changing its contents.
+    ///
+    /// This is purely advisory: the next access simply reads the page back from disk.
+    /// Dirty pages are written back first, so they may stay cached for a while.
+    pub fn evict_page(&self, id: PageId) {
+        if let Some(page) = self.page(id) {
+            drop_page_cache(&self.inner.file, page as usize, id * PAGESZ as u64);
+        }
+    }
+
+    // Releases the memory and disk space backing a free page (unless shrink_to_fit
+    // has cut it off in the meantime, same for trim_page).
+    fn punch_page(&self, id: PageId) {
+        if let Some(page) = self.page(id) {
+            clear_page(&self.inner.file, page as usize, id * PAGESZ as u64);
+        }
+    }
+
+    // Writes back a page and drops it from memory.
+    fn trim_page(&self, id: PageId) {
+        if let Some(page) = self.page(id) {
+            let addr = page as usize;
+            unsafe { msync(addr as *mut c_void, PAGESZ, MS_SYNC) };
+            drop_page_cache(&self.inner.file, addr, id * PAGESZ as u64);
+        }
+    }
+}
+
+/// The pattern freed pages are filled with when `HeapOptions::poison_on_free` is set.
+///
+/// Every other (8-byte, native endian) word of a poisoned page is this, the others
+/// hold the page's id.
+pub const POISON: u64 = 0xdede_dede_dede_dede;
+
+impl AsRawFd for MappedHeap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.file.as_raw_fd()
+    }
+}
+
+/// How far a check has come, see `MappedHeap::check_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckProgress {
+    /// The number of freelist pages checked so far.
+    pub freelist_pages: u64,
+    /// The number of free pages (freelist pages and their entries) checked so far.
+    pub free_pages: u64,
+    /// The free page count when the check started, which is how many free pages
+    /// there are to check unless the heap is inconsistent.
+    pub expected_free_pages: u64,
+}
+
+/// A memory mapping of part of the file, see `MappedHeap::mappings`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappingInfo {
+    /// The start address.
+    pub addr: usize,
+    /// The length in bytes.
+    pub len: usize,
+    /// The offset in the file (in bytes) that is mapped at `addr`.
+    pub file_offset: u64,
+}
+
+/// The error returned when an allocation fails, see `MappedHeap::try_alloc`.
+#[derive(Debug)]
+pub enum AllocError {
+    /// The file would have to grow beyond `HeapOptions::max_size_bytes`.
+    Full,
+    /// Extending the file or the mapping failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AllocError::Full => write!(f, "heap is full"),
+            AllocError::Io(ref e) => write!(f, "failed to grow the heap: {}", e),
+        }
+    }
+}
+
+impl Error for AllocError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            AllocError::Full => None,
+            AllocError::Io(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<AllocError> for io::Error {
+    fn from(e: AllocError) -> io::Error {
+        match e {
+            AllocError::Full => io::Error::new(io::ErrorKind::StorageFull, e),
+            AllocError::Io(e) => e,
+        }
+    }
+}
+
+/// The error for accessing a page that was quarantined on open, see
+/// `HeapOptions::quarantine`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quarantined(pub PageId);
+
+impl fmt::Display for Quarantined {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "page {} is quarantined", self.0)
+    }
+}
+
+impl Error for Quarantined {}
+
+impl From<Quarantined> for io::Error {
+    fn from(e: Quarantined) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The error for opening a file in a newer format than this version of the crate
+/// supports, see `format::VERSION` and `format::FEATURES`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedVersion {
+    /// The file's format version.
+    pub version: u32,
+    /// The file's feature flags.
+    pub features: u64,
+}
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unsupported heap format version {} with features {:#x} (supported: up to version {} with features {:#x})",
+               self.version, self.features, FORMAT_VERSION, FORMAT_FEATURES)
+    }
+}
+
+impl Error for UnsupportedVersion {}
+
+impl From<UnsupportedVersion> for io::Error {
+    fn from(e: UnsupportedVersion) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// References a page.
+pub type PageId = u64;
+
+/// The null page guaranteed to always be invalid.
+///
+/// Internally, the first page (id 0) is reserved for the file header,
+/// so it is never valid in any public calls (never returned by `alloc`,
 /// never accessible through `page` etc.).
 pub const NULL_PAGE: PageId = 0;
 
-const HEADER_PAD_END: usize = PAGESZ - 64 * 3;
+/// The maximum number of freelist shards a file can hold.
+///
+/// See `HeapOptions::freelist_shards`.
+pub const MAX_FREELIST_SHARDS: usize = 16;
+
+/// The number of root slots in the file header.
+///
+/// See `MappedHeap::root_or_create`.
+pub const ROOT_SLOTS: usize = 16;
+
+const HEADER_PAD_END: usize = PAGESZ - 64 * 6 - 64 * (MAX_FREELIST_SHARDS - 1) - 8 * ROOT_SLOTS;
+
+// marks `FileHeader::free_pages` as maintained (older files don't have it)
+const FREE_PAGES_TAG: u64 = 0x5345_4741_5045_4552; // "REEPAGES"
+
+#[repr(C)]
+struct FreelistShard {
+    lock: HeaderLock,
+    _pad_lock: [u8; 4],
+    freelist_id: AtomicU64,
+    _pad: [u8; 48],
+}
+
+#[repr(C)]
+struct FileHeader {
+    magic: [u8; 16],
+    version: u32,
+    _pad_version: [u8; 4],
+    features: AtomicU64,
+    freelist_capacity: AtomicU64, // see HeapOptions::freelist_page_capacity
+    _pad0: [u8; 24],
+    resize_lock: HeaderLock,
+    _pad_lock: [u8; 4], // all padding is explicit, see format::HEADER for the offsets
+    size: AtomicU64, // number of pages
+    _pad1: [u8; 52],
+    alloc_lock: HeaderLock,
+    freelist_id: AtomicU64,
+    _pad2: [u8; 48],
+    // shard 0 is alloc_lock + freelist_id above
+    shards: [FreelistShard; MAX_FREELIST_SHARDS - 1],
+    free_pages_tag: AtomicU64,
+    free_pages: AtomicU64, // number of pages on all freelists (including the freelist pages)
+    _pad3: [u8; 48],
+    root_lock: HeaderLock,
+    _pad4: [u8; 60],
+    roots: [AtomicU64; ROOT_SLOTS],
+    // all zeroes in files without an event log
+    event_log: AtomicU64, // first page
+    event_log_pages: AtomicU64,
+    event_seq: AtomicU64,
+    _pad5: [u8; 40],
+    _pad_end: [u8; HEADER_PAD_END],
+}
+
+
+#[cfg(target_os = "linux")]
+fn clear_page(file: &File, addr: usize, offset: u64) {
+    use libc::{fallocate, madvise, MADV_REMOVE, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+    unsafe {
+        if madvise(addr as *mut c_void, PAGESZ, MADV_REMOVE) != 0 {
+            // some file systems only support this through fallocate
+            fallocate(file.as_raw_fd(), FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+                      offset as off_t, PAGESZ as off_t);
+        }
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn clear_page(file: &File, _: usize, offset: u64) {
+    use libc::{fcntl, fpunchhole_t, F_PUNCHHOLE};
+    let hole = fpunchhole_t { fp_flags: 0, reserved: 0, fp_offset: offset as off_t, fp_length: PAGESZ as off_t };
+    // (fails harmlessly on file systems without holes, such as HFS+)
+    unsafe { fcntl(file.as_raw_fd(), F_PUNCHHOLE, &hole) };
+}
+
+#[cfg(target_os = "freebsd")]
+fn clear_page(file: &File, _: usize, offset: u64) {
+    use libc::{fspacectl, spacectl_range, SPACECTL_DEALLOC};
+    let range = spacectl_range { r_offset: offset as off_t, r_len: PAGESZ as off_t };
+    unsafe { fspacectl(file.as_raw_fd(), SPACECTL_DEALLOC, &range, 0, ptr::null_mut()) };
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios", target_os = "freebsd")))]
+fn clear_page(_: &File, _: usize, _: u64) {
+    // unimplemented, do nothing
+    // sorry, your space is wasted
+}
+
+#[cfg(target_os = "linux")]
+fn anonymous_file() -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+    let fd = unsafe { libc::memfd_create(b"mappedheap\0".as_ptr() as *const _, libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn anonymous_file() -> io::Result<File> {
+    tempfile::tempfile()
+}
+
+// Copies a range within the file without going through the mapping, sharing the
+// blocks if the file system supports it (reflinks). Returns false if the kernel
+// can't, leaving the destination in an unspecified state.
+#[cfg(target_os = "linux")]
+fn copy_in_file(file: &File, src: u64, dst: u64, len: u64) -> bool {
+    use libc::{copy_file_range, file_clone_range, ioctl, FICLONERANGE};
+    let fd = file.as_raw_fd();
+    let range = file_clone_range { src_fd: fd as i64, src_offset: src, src_length: len, dest_offset: dst };
+    if unsafe { ioctl(fd, FICLONERANGE, &range) } == 0 {
+        return true;
+    }
+    let (mut src, mut dst, mut left) = (src as i64, dst as i64, len as usize);
+    while left > 0 {
+        let n = unsafe { copy_file_range(fd, &mut src, fd, &mut dst, left, 0) };
+        if n <= 0 {
+            return false;
+        }
+        left -= n as usize;
+    }
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_in_file(_: &File, _: u64, _: u64, _: u64) -> bool {
+    false
+}
+
+// Starts writeback of a range of the file, or waits until it has completed.
+#[cfg(target_os = "linux")]
+fn write_back(file: &File, offset: u64, len: u64, wait: bool) -> io::Result<()> {
+    use libc::{sync_file_range, SYNC_FILE_RANGE_WAIT_AFTER, SYNC_FILE_RANGE_WAIT_BEFORE, SYNC_FILE_RANGE_WRITE};
+    let mut flags = SYNC_FILE_RANGE_WAIT_BEFORE | SYNC_FILE_RANGE_WRITE;
+    if wait {
+        flags |= SYNC_FILE_RANGE_WAIT_AFTER;
+    }
+    if unsafe { sync_file_range(file.as_raw_fd(), offset as _, len as _, flags) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_back(_: &File, _: u64, _: u64, _: bool) -> io::Result<()> {
+    // unimplemented, flush does all the work
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn drop_page_cache(file: &File, addr: usize, offset: u64) {
+    use libc::{madvise, posix_fadvise, MADV_DONTNEED, POSIX_FADV_DONTNEED};
+    unsafe {
+        madvise(addr as *mut c_void, PAGESZ, MADV_DONTNEED);
+        posix_fadvise(file.as_raw_fd(), offset as off_t, PAGESZ as off_t, POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_page_cache(_: &File, addr: usize, _: u64) {
+    use libc::{madvise, MADV_DONTNEED};
+    unsafe {
+        madvise(addr as *mut c_void, PAGESZ, MADV_DONTNEED);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn size() {
+        assert_eq!(mem::size_of::<FileHeader>(), PAGESZ);
+    }
+
+    #[test]
+    fn it_works() {
+        let _ = fs::remove_file("/tmp/map.bin");
+        let mapping = MappedHeap::open("/tmp/map.bin").unwrap();
+
+        assert_eq!(mapping.size(), 2);
+        assert_eq!(mapping.alloc(), 1);
+        assert_eq!(mapping.size(), 2);
+        assert_eq!(mapping.alloc(), 2);
+        assert_eq!(mapping.size(), 4);
+        assert_eq!(mapping.alloc(), 3);
+        assert_eq!(mapping.size(), 4);
+        mapping.free(1);
+        assert_eq!(mapping.alloc(), 1);
+        mapping.free(1);
+        mapping.free(2);
+        mapping.free(3);
+        mapping.alloc();
+        mapping.alloc();
+        mapping.alloc();
+        assert_eq!(mapping.size(), 4);
+        assert_eq!(mapping.alloc(), 4);
+        assert_eq!(mapping.size(), 8);
+
+        let _ = fs::remove_file("/tmp/map.bin");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn try_page() {
+        let _ = fs::remove_file("/tmp/try_page.bin");
+        let mapping = MappedHeap::open("/tmp/try_page.bin").unwrap();
+        let mapping2 = MappedHeap::open("/tmp/try_page.bin").unwrap();
+        assert!(mapping.try_page(NULL_PAGE).unwrap().is_none());
+        assert!(mapping.try_page(1).unwrap().is_some());
+
+        // make any further mmap calls on the first handle fail
+        let devnull = File::open("/dev/null").unwrap();
+        assert!(unsafe { libc::dup2(devnull.as_raw_fd(), mapping.inner.file.as_raw_fd()) } >= 0);
+
+        let id = (0..10).map(|_| mapping2.alloc()).last().unwrap();
+        assert!(mapping.try_page(id).is_err());
+        // already mapped pages are unaffected
+        assert!(mapping.try_page(1).unwrap().is_some());
+
+        let _ = fs::remove_file("/tmp/try_page.bin");
+    }
+
+    #[test]
+    fn it_doesnt_bug() {
+        let _ = fs::remove_file("/tmp/map2.bin");
+        let mapping = MappedHeap::open("/tmp/map2.bin").unwrap();
+
+        let mut allocs = Vec::new();
+        for _ in 0..128 {
+            let alloc = mapping.alloc();
+            assert!(!allocs.contains(&alloc));
+            allocs.push(alloc);
+        }
+
+        for alloc in allocs.drain(..) {
+            mapping.free(alloc);
+        }
+
+        for _ in 0..129 {
+            let alloc = mapping.alloc();
+            assert!(!allocs.contains(&alloc));
+            allocs.push(alloc);
+        }
+
+        let _ = fs::remove_file("/tmp/map2.bin");
+    }
+
+    #[test]
+    fn deterministic_layout() {
+        let run = |path: &str, junk: u64| {
+            let _ = fs::remove_file(path);
+            drop(MappedHeap::open(path).unwrap());
+            // ambient trailing garbage must not leak into the layout
+            fs::OpenOptions::new().write(true).open(path).unwrap()
+                .set_len(2 * PAGESZ as u64 + junk).unwrap();
+
+            let mapping = HeapOptions::new().deterministic(true).freelist_shards(4).open(path).unwrap();
+            // (each run on a new thread, which would get a different shard than the last)
+            let clone = mapping.clone();
+            thread::spawn(move || {
+                let ids: Vec<_> = (0..10).map(|_| clone.alloc()).collect();
+                for &id in &ids {
+                    unsafe { ptr::write_bytes(clone.page(id).unwrap(), id as u8, 1) };
+                }
+                clone.free(ids[3]);
+                clone.free(ids[7]);
+                clone.alloc();
+            }).join().unwrap();
+            drop(mapping);
+            let data = fs::read(path).unwrap();
+            let _ = fs::remove_file(path);
+            data
+        };
+
+        assert_eq!(run("/tmp/det1.bin", 0), run("/tmp/det2.bin", 3 * PAGESZ as u64 + 17));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn guard_pages() {
+        let _ = fs::remove_file("/tmp/guard.bin");
+        drop(MappedHeap::open("/tmp/guard.bin").unwrap());
+        fs::OpenOptions::new().write(true).open("/tmp/guard.bin").unwrap()
+            .set_len(5 * PAGESZ as u64).unwrap();
+
+        // deterministic so the initial fragment has to be cut back to two pages
+        let mapping = HeapOptions::new().guard_pages(true).deterministic(true).open("/tmp/guard.bin").unwrap();
+        for _ in 0..100 {
+            let id = mapping.alloc();
+            unsafe { ptr::write_bytes(mapping.page(id).unwrap(), 0xff, 1) };
+        }
+
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        for fragment in mapping.inner.fragments.read().iter() {
+            assert!(fragment.guard);
+            let end = fragment.addr + fragment.size.load(Ordering::Relaxed) as usize * PAGESZ;
+            let prefix = format!("{:x}-{:x} ---p", end, end + PAGESZ);
+            assert!(maps.lines().any(|x| x.starts_with(&prefix)));
+        }
+
+        let _ = fs::remove_file("/tmp/guard.bin");
+    }
+
+    #[test]
+    fn max_fragments() {
+        let _ = fs::remove_file("/tmp/fragments.bin");
+        // guard pages would otherwise force a new fragment for every growth
+        let mapping = HeapOptions::new().guard_pages(true).max_fragments(2).open("/tmp/fragments.bin").unwrap();
+        for _ in 0..5000 {
+            let id = mapping.alloc();
+            unsafe { ptr::write_bytes(mapping.page(id).unwrap(), 0xff, 1) };
+        }
+        assert!(mapping.size() >= 4096);
+        assert!(mapping.inner.fragments.read().len() <= 5);
+
+        let _ = fs::remove_file("/tmp/fragments.bin");
+    }
+
+    #[test]
+    fn grow_past_reservation() {
+        let file = anonymous_file().unwrap();
+        file.set_len(16 * PAGESZ as u64).unwrap();
+        for i in 0..16u8 {
+            file.write_all_at(&[i], i as u64 * PAGESZ as u64).unwrap();
+        }
+        // free address space for 16 pages, of which the fragment reserves 4
+        let hole = unsafe {
+            let hole = mmap(ptr::null_mut(), 16 * PAGESZ, PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            assert!(hole != MAP_FAILED);
+            munmap(hole, 16 * PAGESZ);
+            hole as usize
+        };
+        let addr = do_mmap(file.as_raw_fd(), 0, 2 * PAGESZ, Some(hole), 4 * PAGESZ).unwrap();
+        let fragment = Fragment::new(addr, 0, 2, 4, false);
+
+        // (unless another thread took the hole in the meantime)
+        if addr == hole {
+            assert!(fragment.grow(&file, 6, 6).unwrap().is_none());
+            assert_eq!((fragment.size.load(Ordering::Relaxed), fragment.capacity.load(Ordering::Relaxed)), (8, 8));
+            for i in 0..8 {
+                assert_eq!(unsafe { *((addr + i * PAGESZ) as *const u8) }, i as u8);
+            }
+        }
+    }
 
-#[repr(C)]
-struct FileHeader {
-    magic: [u8; 16],
-    _pad0: [u8; 48],
-    resize_lock: Mutex,
-    size: PageId, // number of pages
-    _pad1: [u8; 52],
-    alloc_lock: Mutex,
-    freelist_id: PageId,
-    _pad2: [u8; 48],
-    _pad_end: [u8; HEADER_PAD_END],
-}
+    #[test]
+    fn open_or_init() {
+        let _ = fs::remove_file("/tmp/init.bin");
+        let file = || fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open("/tmp/init.bin").unwrap();
 
+        let mapping = MappedHeap::open_or_init(file()).unwrap();
+        assert_eq!(mapping.size(), 2);
+        assert_eq!(mapping.alloc(), 1);
 
-#[cfg(target_os = "linux")]
-fn clear_page(addr: usize) {
-    use libc::{madvise, MADV_REMOVE};
-    unsafe {
-        madvise(addr as *mut c_void, PAGESZ, MADV_REMOVE);
+        // already initialized, must be left alone
+        let mapping2 = MappedHeap::open_or_init(file()).unwrap();
+        assert_eq!(mapping2.alloc(), 2);
+
+        let _ = fs::remove_file("/tmp/init.bin");
     }
-}
 
-#[cfg(not(target_os = "linux"))]
-fn clear_page(_: usize) {
-    // unimplemented, do nothing
-    // sorry, your space is wasted
-}
+    #[test]
+    fn anonymous() {
+        let mapping = MappedHeap::anonymous(1000).unwrap();
+        assert_eq!(mapping.path(), None);
+        assert_eq!(mapping.size(), 1024);
+        assert_eq!(mapping.free_pages(), 1023);
+        assert_eq!(mapping.recount_free_pages(), 1023);
+        let ids: Vec<_> = (0..2000).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            unsafe { (*mapping.page(id).unwrap())[0] = id as u8 };
+        }
+        let other = mapping.clone();
+        assert!(ids.iter().all(|&id| unsafe { (*other.page(id).unwrap())[0] } == id as u8));
 
+        let mapping = MappedHeap::anonymous(0).unwrap();
+        assert_eq!(mapping.size(), 2);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
+    #[test]
+    fn open_private_copy() {
+        let _ = fs::remove_file("/tmp/privatecopy.bin");
+        let mapping = MappedHeap::open("/tmp/privatecopy.bin").unwrap();
+        let ids: Vec<_> = (0..100).map(|_| mapping.alloc()).collect();
+        unsafe { (*mapping.page(ids[0]).unwrap())[0] = 1 };
+        let size = mapping.size();
+        let free = mapping.free_pages();
+
+        // even with a lock held
+        mapping.lock(&mapping.header().alloc_lock);
+        let copy = MappedHeap::open_private_copy("/tmp/privatecopy.bin").unwrap();
+        mapping.unlock(&mapping.header().alloc_lock);
+        assert_eq!(copy.path(), None);
+        assert_eq!(unsafe { (*copy.page(ids[0]).unwrap())[0] }, 1);
+        unsafe { (*copy.page(ids[0]).unwrap())[0] = 2 };
+        for &id in &ids {
+            copy.free(id);
+        }
+        for _ in 0..2000 {
+            copy.alloc();
+        }
+        copy.check(ConsistencyLevel::FullFsck).unwrap();
+
+        assert_eq!(unsafe { (*mapping.page(ids[0]).unwrap())[0] }, 1);
+        assert_eq!(mapping.size(), size);
+        assert_eq!(mapping.free_pages(), free);
+        assert_eq!(mapping.as_file().metadata().unwrap().len(), size * PAGESZ as u64);
+
+        let _ = fs::remove_file("/tmp/privatecopy.bin");
+    }
 
     #[test]
-    fn size() {
-        assert_eq!(mem::size_of::<FileHeader>(), PAGESZ);
+    fn sharded_freelists() {
+        use std::thread;
+
+        let _ = fs::remove_file("/tmp/shards.bin");
+        let mapping = HeapOptions::new().freelist_shards(4).open("/tmp/shards.bin").unwrap();
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let mapping = mapping.clone();
+            thread::spawn(move || {
+                let mut allocs = Vec::new();
+                for i in 0..2000 {
+                    let id = mapping.alloc();
+                    unsafe { *(mapping.page(id).unwrap() as *mut u64) = id };
+                    allocs.push(id);
+                    if i % 3 == 0 {
+                        mapping.free(allocs.swap_remove(i % allocs.len()));
+                    }
+                }
+                for &id in &allocs {
+                    assert_eq!(unsafe { *(mapping.page(id).unwrap() as *const u64) }, id);
+                }
+                allocs
+            })
+        }).collect();
+
+        let mut all: Vec<_> = threads.into_iter().flat_map(|x| x.join().unwrap()).collect();
+        let len = all.len();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), len);
+
+        let _ = fs::remove_file("/tmp/shards.bin");
     }
 
     #[test]
-    fn it_works() {
-        let _ = fs::remove_file("/tmp/map.bin");
-        let mapping = MappedHeap::open("/tmp/map.bin").unwrap();
+    fn single_process() {
+        use std::thread;
 
-        assert_eq!(mapping.header().size, 2);
-        assert_eq!(mapping.alloc(), 1);
-        assert_eq!(mapping.header().size, 2);
-        assert_eq!(mapping.alloc(), 2);
-        assert_eq!(mapping.header().size, 4);
-        assert_eq!(mapping.alloc(), 3);
-        assert_eq!(mapping.header().size, 4);
-        mapping.free(1);
+        let _ = fs::remove_file("/tmp/singleprocess.bin");
+        let mut options = HeapOptions::new();
+        options.single_process(true);
+        let mapping = options.open("/tmp/singleprocess.bin").unwrap();
+        let err = options.open("/tmp/singleprocess.bin").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // neither ordinary opens nor initializing opens may share the file
+        let err = MappedHeap::open("/tmp/singleprocess.bin").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let file = OpenOptions::new().read(true).write(true).open("/tmp/singleprocess.bin").unwrap();
+        let err = MappedHeap::open_or_init(file).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let mapping = mapping.clone();
+            thread::spawn(move || (0..2000).map(|_| mapping.alloc()).collect::<Vec<_>>())
+        }).collect();
+        let mut all: Vec<_> = threads.into_iter().flat_map(|x| x.join().unwrap()).collect();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 8000);
+
+        drop(mapping);
+        let ordinary = MappedHeap::open("/tmp/singleprocess.bin").unwrap();
+        let err = options.open("/tmp/singleprocess.bin").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(ordinary);
+        let mapping = options.open("/tmp/singleprocess.bin").unwrap();
+        assert_eq!(mapping.free_pages(), mapping.size() - 8001);
+
+        let _ = fs::remove_file("/tmp/singleprocess.bin");
+    }
+
+    #[test]
+    fn freelist_page_capacity() {
+        let _ = fs::remove_file("/tmp/capacity.bin");
+        let mapping = MappedHeap::open("/tmp/capacity.bin").unwrap();
+        let ids: Vec<_> = (0..1000).map(|_| mapping.alloc()).collect();
+        for &id in &ids[..600] {
+            mapping.free(id);
+        }
+        drop(mapping);
+
+        let mapping = HeapOptions::new().freelist_page_capacity(10).open("/tmp/capacity.bin").unwrap();
+        for &id in &ids[600..] {
+            mapping.free(id);
+        }
+        mapping.compact_freelist();
+        let mut pages = 0;
+        mapping.walk_freelist(mapping.header().freelist_id.load(Ordering::Relaxed), |_, entries| {
+            assert!(entries.len() <= 10);
+            pages += 1;
+        });
+        assert_eq!(pages, (mapping.size() - 1).div_ceil(11));
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        // the capacity sticks with the file
+        drop(mapping);
+        let mapping = MappedHeap::open("/tmp/capacity.bin").unwrap();
+        assert_eq!(mapping.freelist_capacity(), 10);
+        let ids: Vec<_> = (0..mapping.size() + 100).map(|_| mapping.alloc()).collect();
+        mapping.free_batch(&ids);
+        mapping.walk_freelist(mapping.header().freelist_id.load(Ordering::Relaxed), |_, entries| {
+            assert!(entries.len() <= 10);
+        });
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        let _ = fs::remove_file("/tmp/capacity.bin");
+    }
+
+    #[test]
+    fn free_pages() {
+        let _ = fs::remove_file("/tmp/freecount.bin");
+        let mapping = MappedHeap::open("/tmp/freecount.bin").unwrap();
+        assert_eq!(mapping.free_pages(), 1);
+
+        let ids: Vec<_> = (0..1000).map(|_| mapping.alloc()).collect();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1001);
+        for &id in &ids[..600] {
+            mapping.free(id);
+        }
+        let expected = mapping.size() - 401;
+        assert_eq!(mapping.free_pages(), expected);
+        assert_eq!(mapping.capacity_remaining_bytes(), expected * PAGESZ as u64);
+
+        // files predating the count get it rebuilt on open
+        mapping.header().free_pages_tag.store(0, Ordering::Relaxed);
+        mapping.header().free_pages.store(0, Ordering::Relaxed);
+        drop(mapping);
+        let mapping = MappedHeap::open("/tmp/freecount.bin").unwrap();
+        assert_eq!(mapping.free_pages(), expected);
+
+        let _ = fs::remove_file("/tmp/freecount.bin");
+    }
+
+    #[test]
+    fn lowest_first() {
+        let _ = fs::remove_file("/tmp/lowest.bin");
+        let mapping = HeapOptions::new().alloc_policy(AllocPolicy::LowestFirst).freelist_shards(3)
+            .open("/tmp/lowest.bin").unwrap();
+
+        let ids: Vec<_> = (0..2000).map(|_| mapping.alloc()).collect();
+        assert!(ids.iter().cloned().eq(1..2001));
+        // free in an order that makes some of the low pages freelist pages
+        for &id in ids.iter().rev().step_by(2).chain(ids.iter().step_by(2)) {
+            mapping.free(id);
+        }
+        let free = mapping.free_pages();
+        let again: Vec<_> = (0..1500).map(|_| mapping.alloc()).collect();
+        assert!(again.iter().cloned().eq(1..1501));
+        assert_eq!(mapping.free_pages(), free - 1500);
+
+        // freelist pages fuller than a lowered capacity hand their entries over to
+        // several pages, instead of dropping those that don't fit
+        let batch: Vec<_> = again[1..300].iter().chain(&again[..1]).cloned().collect();
+        mapping.free_batch(&batch); // (page 1 becomes a freelist page)
+        drop(mapping);
+        let mapping = HeapOptions::new().alloc_policy(AllocPolicy::LowestFirst).freelist_page_capacity(10)
+            .open("/tmp/lowest.bin").unwrap();
+        let free = mapping.free_pages();
         assert_eq!(mapping.alloc(), 1);
-        mapping.free(1);
-        mapping.free(2);
-        mapping.free(3);
-        mapping.alloc();
-        mapping.alloc();
+        assert_eq!(mapping.free_pages(), free - 1);
+        assert_eq!(mapping.recount_free_pages(), free - 1);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        let _ = fs::remove_file("/tmp/lowest.bin");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mappings() {
+        let _ = fs::remove_file("/tmp/mappings.bin");
+        let mapping = HeapOptions::new().guard_pages(true).open("/tmp/mappings.bin").unwrap();
+        for _ in 0..100 {
+            mapping.alloc();
+        }
+        let mappings = mapping.mappings();
+        assert_eq!(mappings.len(), mapping.fragments());
+        assert_eq!(mappings.iter().map(|x| x.len as u64).sum::<u64>(), mapping.len_bytes());
+
+        // every fragment shows up in /proc/self/maps with the same offset
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        for info in &mappings {
+            let line = maps.lines().find(|x| x.starts_with(&format!("{:x}-", info.addr))).unwrap();
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let end = usize::from_str_radix(fields[0].split('-').nth(1).unwrap(), 16).unwrap();
+            assert_eq!(end, info.addr + info.len);
+            assert_eq!(u64::from_str_radix(fields[2], 16).unwrap(), info.file_offset);
+            assert_eq!(fields[1], "rw-s");
+        }
+
+        let _ = fs::remove_file("/tmp/mappings.bin");
+    }
+
+    #[test]
+    fn alignment() {
+        #[repr(align(64))]
+        struct Line(#[allow(dead_code)] [u8; 64]);
+        #[repr(align(8192))]
+        struct Huge(#[allow(dead_code)] u8);
+
+        assert_aligned::<u64>();
+        assert_aligned_at::<Line>(128);
+        assert!(::std::panic::catch_unwind(|| assert_aligned_at::<Line>(8)).is_err());
+        assert!(::std::panic::catch_unwind(assert_aligned::<Huge>).is_err());
+    }
+
+    #[test]
+    fn consistency_levels() {
+        let _ = fs::remove_file("/tmp/fsck.bin");
+        let mapping = MappedHeap::open("/tmp/fsck.bin").unwrap();
+        let ids: Vec<_> = (0..1000).map(|_| mapping.alloc()).collect();
+        for &id in &ids[..600] {
+            mapping.free(id);
+        }
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        let open = |level| HeapOptions::new().consistency(level).open("/tmp/fsck.bin");
+        // an allocated page ends up on a freelist twice, checksums can't tell
+        let head = mapping.header().freelist_id.load(Ordering::Relaxed);
+        unsafe {
+            let page = mapping.freelist_page(head);
+            let entry = FreelistPage::entries(page)[0];
+            FreelistPage::push(page, entry, freelist::ENTRIES);
+        }
+        open(ConsistencyLevel::ChecksumVerify).unwrap();
+        assert_eq!(open(ConsistencyLevel::FullFsck).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // scribbling over a freelist page breaks its checksum
+        unsafe { (*mapping.page(head).unwrap())[100] ^= 1 };
+        open(ConsistencyLevel::None).unwrap();
+        assert!(open(ConsistencyLevel::ChecksumVerify).is_err());
+
+        let _ = fs::remove_file("/tmp/fsck.bin");
+    }
+
+    #[test]
+    fn check_progress() {
+        let _ = fs::remove_file("/tmp/checkprogress.bin");
+        let mapping = MappedHeap::open("/tmp/checkprogress.bin").unwrap();
+        let ids: Vec<_> = (0..5000).map(|_| mapping.alloc()).collect();
+        for &id in ids.iter().step_by(2) {
+            mapping.free(id);
+        }
+
+        let mut last = None;
+        mapping.check_with(ConsistencyLevel::FullFsck, &AtomicBool::new(false), |x| last = Some(x)).unwrap();
+        let last = last.unwrap();
+        assert!(last.freelist_pages > 1);
+        assert_eq!(last.free_pages, mapping.free_pages());
+        assert_eq!(last.expected_free_pages, mapping.free_pages());
+
+        let cancel = AtomicBool::new(false);
+        let mut calls = 0;
+        let err = mapping.check_with(ConsistencyLevel::FullFsck, &cancel, |_| {
+            calls += 1;
+            cancel.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(calls, 1);
+        // (the freelists were unlocked)
+        mapping.free(mapping.alloc());
+
+        let _ = fs::remove_file("/tmp/checkprogress.bin");
+    }
+
+    #[test]
+    fn validation_levels() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let _ = fs::remove_file("/tmp/validation.bin");
+        let mapping = HeapOptions::new().validation(ValidationLevel::Cheap).open("/tmp/validation.bin").unwrap();
+        let ids: Vec<_> = (0..100).map(|_| mapping.alloc()).collect();
+        mapping.free(ids[0]);
+        let err = panic::catch_unwind(AssertUnwindSafe(|| mapping.free(ids[0]))).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), &format!("double free of page {}", ids[0]));
+
+        // a double free further back only shows with more effort
+        let mapping = HeapOptions::new().validation(ValidationLevel::Paranoid).open("/tmp/validation.bin").unwrap();
+        for &id in &ids[1..] {
+            mapping.free(id);
+        }
+        for _ in 0..10 {
+            mapping.free(mapping.alloc());
+        }
+        let err = panic::catch_unwind(AssertUnwindSafe(|| mapping.free(ids[1]))).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), &format!("double free of page {}", ids[1]));
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        let _ = fs::remove_file("/tmp/validation.bin");
+    }
+
+    #[test]
+    fn track_allocations() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let _ = fs::remove_file("/tmp/tracking.bin");
+        let mapping = MappedHeap::open("/tmp/tracking.bin").unwrap();
+        let before: Vec<_> = (0..10).map(|_| mapping.alloc()).collect();
+        drop(mapping);
+
+        let mapping = HeapOptions::new().track_allocations(true).open("/tmp/tracking.bin").unwrap();
+        let free = |id| panic::catch_unwind(AssertUnwindSafe(|| mapping.free(id))).map_err(|e| e.downcast::<String>().unwrap());
+        // pages allocated before opening count
+        assert!(free(before[0]).is_ok());
+        let message = format!("double free of page {} (or it was never allocated)", before[0]);
+        assert_eq!(*free(before[0]).unwrap_err(), message);
+
+        // far back in the freelists, which ValidationLevel::Cheap wouldn't notice
+        let ids: Vec<_> = (0..2000).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            mapping.free(id);
+        }
+        assert!(free(ids[0]).is_err());
+        assert!(free(mapping.size() - 1).is_err());
+        let deferred = panic::catch_unwind(AssertUnwindSafe(|| mapping.free_deferred(&[before[1], before[1]])));
+        assert!(deferred.is_err());
+
+        // and so do runs
+        let first = mapping.alloc_contiguous(3).unwrap();
+        mapping.free_contiguous(first, 3);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        let _ = fs::remove_file("/tmp/tracking.bin");
+    }
+
+    #[test]
+    fn format_version() {
+        let _ = fs::remove_file("/tmp/version.bin");
+        let mapping = MappedHeap::open("/tmp/version.bin").unwrap();
+        assert_eq!(mapping.header().version, FORMAT_VERSION);
+        assert_eq!(mapping.header().features.load(Ordering::Relaxed), FORMAT_FEATURES);
+        drop(mapping);
+        let file = fs::OpenOptions::new().write(true).open("/tmp/version.bin").unwrap();
+        let version = mem::offset_of!(FileHeader, version) as u64;
+        let features = mem::offset_of!(FileHeader, features) as u64;
+
+        // files from before the version field, which are upgraded on open
+        file.write_all_at(&0u32.to_ne_bytes(), version).unwrap();
+        file.write_all_at(&0u64.to_ne_bytes(), features).unwrap();
+        let mapping = MappedHeap::open("/tmp/version.bin").unwrap();
+        assert_eq!(mapping.header().features.load(Ordering::Relaxed), FORMAT_FEATURES);
+        drop(mapping);
+
+        let unsupported = |v: u32, f: u64| {
+            file.write_all_at(&v.to_ne_bytes(), version).unwrap();
+            file.write_all_at(&f.to_ne_bytes(), features).unwrap();
+            let err = MappedHeap::open("/tmp/version.bin").err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            *err.get_ref().unwrap().downcast_ref::<UnsupportedVersion>().unwrap()
+        };
+        assert_eq!(unsupported(FORMAT_VERSION + 1, 0), UnsupportedVersion { version: FORMAT_VERSION + 1, features: 0 });
+        assert_eq!(unsupported(FORMAT_VERSION, 1 << 63).features, 1 << 63);
+
+        file.write_all_at(b"not a heap", 0).unwrap();
+        let err = MappedHeap::open("/tmp/version.bin").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // too short to even hold a header
+        for &len in &[100, 0] {
+            file.set_len(len).unwrap();
+            let err = MappedHeap::open("/tmp/version.bin").err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let err = HeapOptions::new().open_file(file.try_clone().unwrap()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let _ = fs::remove_file("/tmp/version.bin");
+    }
+
+    #[test]
+    fn quarantine() {
+        let _ = fs::remove_file("/tmp/quarantine.bin");
+        let mapping = MappedHeap::open("/tmp/quarantine.bin").unwrap();
+        let ids: Vec<_> = (0..1000).map(|_| mapping.alloc()).collect();
+        for &id in &ids[..600] {
+            mapping.free(id);
+        }
+        let kept = ids[999];
+        unsafe { (*mapping.page(kept).unwrap())[0] = 42 };
+        // the second page of the chain
+        let head = mapping.header().freelist_id.load(Ordering::Relaxed);
+        let bad = unsafe { FreelistPage::next(mapping.freelist_page(head)) };
+        unsafe { (*mapping.page(bad).unwrap())[100] ^= 1 };
+        drop(mapping);
+        assert!(MappedHeap::open("/tmp/quarantine.bin").is_ok());
+        assert!(HeapOptions::new().consistency(ConsistencyLevel::ChecksumVerify).open("/tmp/quarantine.bin").is_err());
+
+        let mapping = HeapOptions::new().quarantine(true).open("/tmp/quarantine.bin").unwrap();
+        assert_eq!(mapping.quarantined_pages(), &[bad]);
+        assert_eq!(mapping.page(bad), None);
+        let err = mapping.try_page(bad).err().unwrap();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<Quarantined>(), Some(&Quarantined(bad)));
+        assert_eq!(unsafe { (*mapping.page(kept).unwrap())[0] }, 42);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        // the rest of the chain went with it, everything else is still there
+        let free = mapping.free_pages();
+        assert!(free > 1);
+        let again: Vec<_> = (0..free).map(|_| mapping.alloc()).collect();
+        assert!(again.contains(&head) && !again.contains(&bad) && !again.contains(&kept));
+
+        let _ = fs::remove_file("/tmp/quarantine.bin");
+    }
+
+    #[test]
+    fn grow_hook() {
+        let _ = fs::remove_file("/tmp/growhook.bin");
+        let mapping = MappedHeap::open("/tmp/growhook.bin").unwrap();
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = seen.clone();
+        mapping.set_grow_hook(move |heap, id| {
+            assert!(heap.page(id).is_some());
+            log.lock().unwrap().push(id);
+        });
+
+        let size = mapping.size();
+        while mapping.size() == size {
+            mapping.alloc();
+        }
+        assert!(seen.lock().unwrap().iter().cloned().eq(size..mapping.size()));
+
+        mapping.clear_grow_hook();
+        let size = mapping.size();
+        while mapping.size() == size {
+            mapping.alloc();
+        }
+        assert_eq!(seen.lock().unwrap().len() as u64, size / 2);
+
+        let _ = fs::remove_file("/tmp/growhook.bin");
+    }
+
+    #[test]
+    fn volatile_access() {
+        let _ = fs::remove_file("/tmp/volatile.bin");
+        let mapping = MappedHeap::open("/tmp/volatile.bin").unwrap();
+        let id = mapping.alloc();
+        assert!(mapping.write_volatile_at(id, 100, b"hello"));
+        let mut buf = [0; 7];
+        assert!(mapping.read_volatile_at(id, 99, &mut buf));
+        assert_eq!(&buf, b"\0hello\0");
+        assert_eq!(unsafe { &(&*mapping.page(id).unwrap())[100..105] }, b"hello");
+        assert!(mapping.read_volatile_at(id, PAGESZ - 7, &mut buf));
+        assert!(!mapping.read_volatile_at(mapping.size(), 0, &mut buf));
+        assert!(!mapping.write_volatile_at(NULL_PAGE, 0, b"x"));
+
+        let _ = fs::remove_file("/tmp/volatile.bin");
+    }
+
+    #[test]
+    #[should_panic(expected = "out of page bounds")]
+    fn volatile_access_bounds() {
+        let _ = fs::remove_file("/tmp/volatilebounds.bin");
+        let mapping = MappedHeap::open("/tmp/volatilebounds.bin").unwrap();
+        let _ = fs::remove_file("/tmp/volatilebounds.bin");
+        mapping.write_volatile_at(mapping.alloc(), PAGESZ - 1, b"xy");
+    }
+
+    #[test]
+    fn max_size() {
+        let _ = fs::remove_file("/tmp/maxsize.bin");
+        let mapping = HeapOptions::new().max_size_bytes(10 * PAGESZ as u64 + 123).open("/tmp/maxsize.bin").unwrap();
+        // 2, 4, 8, then clamped to 10 pages (of which page 0 is the header)
+        let ids: Vec<_> = (0..9).map(|_| mapping.alloc()).collect();
+        assert_eq!(mapping.len_pages(), 10);
+        assert!(matches!(mapping.try_alloc(), Err(AllocError::Full)));
+        assert_eq!(mapping.len_pages(), 10);
+        mapping.free(ids[3]);
+        assert_eq!(mapping.try_alloc().unwrap(), ids[3]);
+
+        let err = HeapOptions::new().max_size_bytes(10 * PAGESZ as u64).event_log(1).open("/tmp/maxsize.bin");
+        assert_eq!(err.err().unwrap().kind(), io::ErrorKind::StorageFull);
+
+        let _ = fs::remove_file("/tmp/maxsize.bin");
+    }
+
+    #[test]
+    fn alloc_error() {
+        let _ = fs::remove_file("/tmp/allocerror.bin");
+        let mapping = MappedHeap::open("/tmp/allocerror.bin").unwrap();
+        // (the file size limit applies to the whole process, so hit it in a child)
+        let status = match unsafe { libc::fork() } {
+            0 => {
+                let limit = libc::rlimit { rlim_cur: 64 * PAGESZ as u64, rlim_max: libc::RLIM_INFINITY };
+                unsafe {
+                    libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+                    libc::setrlimit(libc::RLIMIT_FSIZE, &limit);
+                }
+                let err = loop {
+                    if let Err(e) = mapping.try_alloc() {
+                        break e;
+                    }
+                };
+                let ok = matches!(err, AllocError::Io(_)) && mapping.size() == 64 && mapping.free_pages() == 0;
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            pid => {
+                let mut status = 0;
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+                status
+            }
+        };
+        assert_eq!(status, 0);
+        assert_eq!(mapping.size(), 64);
         mapping.alloc();
-        assert_eq!(mapping.header().size, 4);
-        assert_eq!(mapping.alloc(), 4);
-        assert_eq!(mapping.header().size, 8);
+        assert_eq!(mapping.size(), 128);
 
-        let _ = fs::remove_file("/tmp/map.bin");
+        let _ = fs::remove_file("/tmp/allocerror.bin");
     }
 
     #[test]
-    fn it_doesnt_bug() {
-        let _ = fs::remove_file("/tmp/map2.bin");
-        let mapping = MappedHeap::open("/tmp/map2.bin").unwrap();
+    fn growth_policy() {
+        let _ = fs::remove_file("/tmp/growth.bin");
+        let sizes = |policy| {
+            let _ = fs::remove_file("/tmp/growth.bin");
+            let mapping = HeapOptions::new().growth_policy(policy).open("/tmp/growth.bin").unwrap();
+            let mut sizes = vec![mapping.size()];
+            for _ in 0..100 {
+                mapping.alloc();
+                if mapping.size() != *sizes.last().unwrap() {
+                    sizes.push(mapping.size());
+                }
+            }
+            sizes
+        };
+        assert_eq!(sizes(GrowthPolicy::Double), vec![2, 4, 8, 16, 32, 64, 128]);
+        assert_eq!(sizes(GrowthPolicy::Factor(1.5)), vec![2, 3, 5, 8, 12, 18, 27, 41, 62, 93, 140]);
+        assert_eq!(sizes(GrowthPolicy::Pages(40)), vec![2, 42, 82, 122]);
+        assert_eq!(sizes(GrowthPolicy::Custom(Arc::new(|x| x * 10))), vec![2, 20, 200]);
+        // (at least one page)
+        assert_eq!(sizes(GrowthPolicy::Custom(Arc::new(|_| 0))).len(), 100);
 
-        let mut allocs = Vec::new();
-        for _ in 0..128 {
-            let alloc = mapping.alloc();
-            assert!(!allocs.contains(&alloc));
-            allocs.push(alloc);
+        let _ = fs::remove_file("/tmp/growth.bin");
+    }
+
+    #[test]
+    fn free_deferred() {
+        let _ = fs::remove_file("/tmp/deferred.bin");
+        let mapping = MappedHeap::open("/tmp/deferred.bin").unwrap();
+        let ids: Vec<_> = (0..5000).map(|_| mapping.alloc()).collect();
+        let free = mapping.free_pages();
+
+        mapping.free_deferred(&ids);
+        assert_eq!(mapping.deferred_frees(), 5000);
+        assert_eq!(mapping.free_pages(), free);
+        assert_eq!(mapping.apply_deferred_frees(1000), 1000);
+        assert_eq!(mapping.free_pages(), free + 1000);
+        assert_eq!(mapping.apply_deferred_frees(usize::MAX), 4000);
+        assert_eq!(mapping.deferred_frees(), 0);
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+        assert_eq!(mapping.recount_free_pages(), mapping.size() - 1);
+
+        // every page can be allocated again, exactly once
+        let mut again: Vec<_> = (0..mapping.size() - 1).map(|_| mapping.alloc()).collect();
+        again.sort();
+        assert!(again.into_iter().eq(1..mapping.size()));
+
+        let _ = fs::remove_file("/tmp/deferred.bin");
+    }
+
+    // Has another thread allocate pages, fill them, check them and free them with `free`
+    // while `maintain` runs over and over. Returns how many pages lost their contents.
+    fn lost_writes<F: Fn(&MappedHeap)>(mapping: &MappedHeap, free: fn(&MappedHeap, &[PageId]), maintain: F) -> usize {
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (mapping, done) = (mapping.clone(), done.clone());
+            thread::spawn(move || {
+                let mut lost = 0;
+                for _ in 0..300 {
+                    let ids: Vec<_> = (0..32).map(|_| mapping.alloc()).collect();
+                    for &id in &ids {
+                        unsafe { *mapping.page(id).unwrap() = [0xaa; PAGESZ] };
+                    }
+                    thread::yield_now();
+                    lost += ids.iter().filter(|&&id| unsafe { (*mapping.page(id).unwrap()).iter().any(|&x| x != 0xaa) }).count();
+                    free(&mapping, &ids);
+                }
+                done.store(true, Ordering::Relaxed);
+                lost
+            })
+        };
+        while !done.load(Ordering::Relaxed) {
+            maintain(mapping);
         }
+        writer.join().unwrap()
+    }
 
-        for alloc in allocs.drain(..) {
-            mapping.free(alloc);
+    #[test]
+    fn free_deferred_concurrently() {
+        let _ = fs::remove_file("/tmp/deferredrace.bin");
+        let mapping = MappedHeap::open("/tmp/deferredrace.bin").unwrap();
+        let _ = fs::remove_file("/tmp/deferredrace.bin");
+        // holes are only punched into pages that were freed, never into ones in use
+        let lost = lost_writes(&mapping, |heap, ids| heap.free_deferred(ids), |heap| {
+            heap.apply_deferred_frees(usize::MAX);
+        });
+        assert_eq!(lost, 0);
+    }
+
+    #[test]
+    fn batch_alloc() {
+        let _ = fs::remove_file("/tmp/batchalloc.bin");
+        let mut options = HeapOptions::new();
+        options.batch_alloc(true);
+        let mapping = options.open("/tmp/batchalloc.bin").unwrap();
+        let ids: Vec<_> = (0..5000).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            mapping.free(id);
         }
+        // (fills up the freelist page at the head)
+        mapping.compact_freelist();
+        let free = mapping.free_pages();
 
-        for _ in 0..129 {
-            let alloc = mapping.alloc();
-            assert!(!allocs.contains(&alloc));
-            allocs.push(alloc);
+        // the first allocation takes a whole freelist page, the next ones come from the cache
+        let other = options.open("/tmp/batchalloc.bin").unwrap();
+        let first = other.alloc();
+        assert_eq!(mapping.free_pages(), free - freelist::ENTRIES as u64 - 1);
+        let mut taken: Vec<_> = (0..freelist::ENTRIES).map(|_| other.alloc()).collect();
+        assert_eq!(mapping.free_pages(), free - freelist::ENTRIES as u64 - 1);
+        for id in taken.drain(10..) {
+            other.free(id);
         }
+        taken.push(first);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
 
-        let _ = fs::remove_file("/tmp/map2.bin");
+        // dropping the handle gives back what is left in its cache
+        other.alloc();
+        drop(other);
+        assert_eq!(mapping.free_pages(), free - 12);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        let _ = fs::remove_file("/tmp/batchalloc.bin");
+    }
+
+    #[test]
+    fn compact_freelist() {
+        let _ = fs::remove_file("/tmp/compactfreelist.bin");
+        let mapping = HeapOptions::new().alloc_policy(AllocPolicy::LowestFirst).open("/tmp/compactfreelist.bin").unwrap();
+        let ids: Vec<_> = (0..5000).map(|_| mapping.alloc()).collect();
+        for &id in ids.iter().rev() {
+            mapping.free(id);
+        }
+        // taking the lowest pages leaves holes all over the freelist pages
+        let kept: Vec<_> = (0..2000).map(|_| mapping.alloc()).collect();
+        let free = mapping.free_pages();
+
+        assert!(mapping.compact_freelist() > 0);
+        assert_eq!(mapping.compact_freelist(), 0);
+        assert_eq!(mapping.free_pages(), free);
+        assert_eq!(mapping.recount_free_pages(), free);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        // every free page can be allocated, exactly once
+        let mut again: Vec<_> = (0..free).map(|_| mapping.alloc()).chain(kept).collect();
+        again.sort();
+        assert!(again.into_iter().eq(1..mapping.size()));
+
+        let _ = fs::remove_file("/tmp/compactfreelist.bin");
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let _ = fs::remove_file("/tmp/shrink.bin");
+        let mapping = HeapOptions::new().freelist_shards(4).open("/tmp/shrink.bin").unwrap();
+        let ids: Vec<_> = (0..5000).map(|_| mapping.alloc()).collect();
+        let size = mapping.size();
+        for &id in &ids[100..] {
+            mapping.free(id);
+        }
+        mapping.free(ids[50]);
+        let highest = *ids[..100].iter().max().unwrap();
+
+        assert_eq!(mapping.shrink_to_fit().unwrap(), size - highest - 1);
+        assert_eq!(mapping.size(), highest + 1);
+        assert_eq!(mapping.as_file().metadata().unwrap().len(), mapping.size() * PAGESZ as u64);
+        assert_eq!(mapping.page(highest + 1), None);
+        assert_eq!(mapping.shrink_to_fit().unwrap(), 0);
+        assert_eq!(mapping.recount_free_pages(), mapping.size() - 100);
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        // the file grows back as usual
+        let again: Vec<_> = (0..5000).map(|_| mapping.alloc()).collect();
+        assert!(again.contains(&ids[50]));
+        mapping.check(ConsistencyLevel::FullFsck).unwrap();
+
+        let _ = fs::remove_file("/tmp/shrink.bin");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn free_and_trim() {
+        use std::os::unix::fs::MetadataExt;
+
+        let _ = fs::remove_file("/tmp/trim.bin");
+        // guard pages force a new fragment for every growth
+        let mapping = HeapOptions::new().guard_pages(true).open("/tmp/trim.bin").unwrap();
+        let ids: Vec<_> = (0..2000).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            unsafe { ptr::write_bytes(mapping.page(id).unwrap(), 0xaa, 1) };
+        }
+        mapping.flush().unwrap();
+        assert!(mapping.inner.fragments.read().len() > 5);
+        let blocks = fs::metadata("/tmp/trim.bin").unwrap().blocks();
+
+        for &id in &ids {
+            mapping.free_and_trim(id);
+        }
+        // everything but the few freelist pages is gone from disk
+        assert!(fs::metadata("/tmp/trim.bin").unwrap().blocks() < blocks / 10);
+        let zeroed = ids.iter().filter(|&&id| unsafe { (*mapping.page(id).unwrap()).iter().all(|&x| x == 0) });
+        assert!(zeroed.count() > 1900);
+
+        let _ = fs::remove_file("/tmp/trim.bin");
+    }
+
+    #[test]
+    fn poison_on_free() {
+        let _ = fs::remove_file("/tmp/poison.bin");
+        let mapping = HeapOptions::new().poison_on_free(true).open("/tmp/poison.bin").unwrap();
+
+        let ids: Vec<_> = (0..10).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            mapping.free(id);
+        }
+        for &id in &ids {
+            // freelist pages keep their header in the first and last word
+            let page = unsafe { &*(mapping.page(id).unwrap() as *const [u64; PAGESZ / 8]) };
+            for (i, &word) in page.iter().enumerate().skip(1).take(PAGESZ / 8 - 2) {
+                assert_eq!(word, if i % 2 == 0 { POISON } else { id });
+            }
+        }
+
+        let _ = fs::remove_file("/tmp/poison.bin");
+    }
+
+    #[test]
+    fn flush_window() {
+        let _ = fs::remove_file("/tmp/flush.bin");
+        let mapping = HeapOptions::new().max_dirty_bytes(8 * PAGESZ as u64).open("/tmp/flush.bin").unwrap();
+        let ids: Vec<_> = (0..100).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            unsafe { ptr::write_bytes(mapping.page(id).unwrap(), id as u8, 1) };
+        }
+        mapping.flush_window(0..1000, 3 * PAGESZ as u64).unwrap();
+        mapping.flush().unwrap();
+        mapping.flush_with(Durability::Async).unwrap();
+        mapping.flush_with(Durability::None).unwrap();
+        mapping.flush_pages(10..20).unwrap();
+        mapping.flush_pages(990..2000).unwrap();
+
+        let file = File::open("/tmp/flush.bin").unwrap();
+        let mut buf = [0; PAGESZ];
+        for &id in &ids {
+            file.read_exact_at(&mut buf, id * PAGESZ as u64).unwrap();
+            assert!(buf.iter().all(|&x| x == id as u8));
+        }
+
+        let _ = fs::remove_file("/tmp/flush.bin");
+    }
+
+    #[test]
+    fn file_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _ = fs::remove_file("/tmp/metadata.bin");
+        let mapping = MappedHeap::open("/tmp/metadata.bin").unwrap();
+        assert_eq!(mapping.path(), Some(Path::new("/tmp/metadata.bin")));
+        assert_eq!(mapping.len_pages(), 2);
+        assert_eq!(mapping.len_bytes(), mapping.as_file().metadata().unwrap().len());
+
+        mapping.set_permissions(Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(fs::metadata("/tmp/metadata.bin").unwrap().permissions().mode() & 0o777, 0o600);
+        let file = OpenOptions::new().read(true).write(true).open("/tmp/metadata.bin").unwrap();
+        assert_eq!(MappedHeap::open_file(file).unwrap().path(), None);
+
+        let _ = fs::remove_file("/tmp/metadata.bin");
+    }
+
+    #[test]
+    fn roots() {
+        let _ = fs::remove_file("/tmp/roots.bin");
+        let mapping = MappedHeap::open("/tmp/roots.bin").unwrap();
+        let mapping2 = MappedHeap::open("/tmp/roots.bin").unwrap();
+
+        assert_eq!(mapping.root(3), None);
+        let id = mapping.root_or_create(3, |heap| heap.alloc());
+        assert_eq!(mapping2.root(3), Some(id));
+        assert_eq!(mapping2.root_or_create(3, |_| panic!("created twice")), id);
+        assert_eq!(mapping.root(4), None);
+
+        let _ = fs::remove_file("/tmp/roots.bin");
     }
 }
@@ -0,0 +1,446 @@
+//! The locks in the file header, and debug checks for them.
+//!
+//! The header locks are futexes with a protocol of their own (see `HeaderLock`),
+//! which debug and release builds, and every process sharing the file, follow alike.
+//!
+//! A process can die holding a header lock (say, killed in the middle of `alloc`).
+//! Since the lock word names its owner, waiters check every so often whether the
+//! owner still exists, and take over the lock if it doesn't. Whoever takes over a
+//! freelist lock repairs what the owner may have left half done (see `recover`).
+//! Owners are identified by thread id, so all processes sharing a file have to be
+//! in the same PID namespace, and a thread id reused (by the time the lock is looked
+//! at) after its owner died leaves the lock held for good.
+//!
+//! A bug that makes a thread acquire a header lock it already holds (say, `alloc`
+//! from a callback that runs under the lock) or that leaks a lock would otherwise
+//! just hang the process inside `alloc` or `free`, with nothing to go on. So in
+//! debug builds, every thread keeps track of the locks it holds (and where it took
+//! them), and acquiring one of them again panics. Waiting for a lock longer than
+//! `HeapOptions::lock_timeout` panics too, naming the owner. Release builds just
+//! take the locks.
+//!
+//! In single-process mode (see `HeapOptions::single_process`), every header lock is
+//! replaced by an in-process lock of the heap, picked by the lock's offset.
+
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::lock_api::{RawMutex as _, RawMutexTimed};
+use parking_lot::RawMutex;
+
+use format::HEADER;
+use freelist::FreelistPage;
+use {MappedHeap, FreelistShard, MAX_FREELIST_SHARDS, NULL_PAGE};
+
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often waiters check whether the owner of a lock still exists.
+const OWNER_POLL: Duration = Duration::from_millis(10);
+
+/// The lock word of an unlocked header lock.
+pub(crate) const UNLOCKED: u32 = 0;
+
+/// Set in the lock word of a header lock while other threads may be waiting for it.
+pub(crate) const WAITERS: u32 = 1 << 31;
+
+// The rest of a locked lock word is the owner's thread id.
+const OWNER: u32 = WAITERS - 1;
+
+/// A lock in the file header, shared by all processes using the file.
+///
+/// The lock word is `UNLOCKED` (zero), or holds the id of the thread holding the lock
+/// (its process id where there are no thread ids), plus `WAITERS` if anyone may be
+/// sleeping on the futex. Unlocking resets it to zero, and wakes a waiter if the flag
+/// was set. A woken waiter can't tell whether others are left, so it takes the lock
+/// with the flag set. A waiter that finds the owner gone takes the lock over the same
+/// way, by swapping the dead owner's id for its own.
+#[repr(transparent)]
+pub(crate) struct HeaderLock(AtomicU32);
+
+// How `HeaderLock::acquire` went.
+#[derive(PartialEq)]
+enum Acquired {
+    Yes,
+    // from an owner that died holding it
+    Recovered,
+    TimedOut,
+}
+
+impl HeaderLock {
+    // Takes the lock, waiting until the deadline at most.
+    fn acquire(&self, deadline: Option<Instant>) -> Acquired {
+        let me = owner_id();
+        let mut state = match self.0.compare_exchange(UNLOCKED, me, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => return Acquired::Yes,
+            Err(state) => state,
+        };
+        let mut waited = false;
+        loop {
+            if state == UNLOCKED {
+                match self.0.compare_exchange(UNLOCKED, me | WAITERS, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => return Acquired::Yes,
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+            if waited && is_dead(state & OWNER) {
+                match self.0.compare_exchange(state, me | WAITERS, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => return Acquired::Recovered,
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+            if state & WAITERS == 0 {
+                if let Err(x) = self.0.compare_exchange(state, state | WAITERS, Ordering::Relaxed, Ordering::Relaxed) {
+                    state = x;
+                    continue;
+                }
+                state |= WAITERS;
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(x) if !x.is_zero() => x.min(OWNER_POLL),
+                    _ => return Acquired::TimedOut,
+                },
+                None => OWNER_POLL,
+            };
+            futex_wait(&self.0, state, Some(timeout));
+            waited = true;
+            state = self.0.load(Ordering::Relaxed);
+        }
+    }
+
+    fn release(&self) {
+        if self.0.swap(UNLOCKED, Ordering::Release) & WAITERS != 0 {
+            futex_wake(&self.0);
+        }
+    }
+
+    // The id of the thread holding the lock, if anyone does.
+    fn owner(&self) -> Option<u32> {
+        match self.0.load(Ordering::Relaxed) & OWNER {
+            0 => None,
+            owner => Some(owner),
+        }
+    }
+}
+
+thread_local!(static HELD: RefCell<Vec<(usize, Backtrace)>> = const { RefCell::new(Vec::new()) });
+
+// The in-process locks (see HeapOptions::single_process) held by threads of this
+// process (address and thread id), without locks of our own, so they stay usable in
+// forked children. Header locks know their owner themselves.
+struct Owner {
+    lock: AtomicUsize,
+    thread: AtomicU64,
+}
+
+static OWNERS: [Owner; 64] = [const { Owner { lock: AtomicUsize::new(0), thread: AtomicU64::new(0) } }; 64];
+
+// Sleeps until the futex word changes from `value` (or might have), for at most `timeout`.
+#[cfg(target_os = "linux")]
+fn futex_wait(word: &AtomicU32, value: u32, timeout: Option<Duration>) {
+    let timeout = timeout.map(|x| ::libc::timespec { tv_sec: x.as_secs() as _, tv_nsec: x.subsec_nanos() as _ });
+    let timeout = timeout.as_ref().map_or(ptr::null(), |x| x as *const _);
+    unsafe { ::libc::syscall(::libc::SYS_futex, word.as_ptr(), ::libc::FUTEX_WAIT, value, timeout) };
+}
+
+// Wakes one thread sleeping in futex_wait, in any process.
+#[cfg(target_os = "linux")]
+fn futex_wake(word: &AtomicU32) {
+    unsafe { ::libc::syscall(::libc::SYS_futex, word.as_ptr(), ::libc::FUTEX_WAKE, 1) };
+}
+
+// (no futexes, so waiters poll)
+#[cfg(not(target_os = "linux"))]
+fn futex_wait(_: &AtomicU32, _: u32, timeout: Option<Duration>) {
+    let poll = Duration::from_millis(1);
+    ::std::thread::sleep(timeout.map_or(poll, |x| x.min(poll)));
+}
+
+#[cfg(not(target_os = "linux"))]
+fn futex_wake(_: &AtomicU32) {}
+
+// What goes into the lock word for the current thread. (Linux thread ids stay below
+// 2^22, far from the WAITERS bit.)
+#[cfg(target_os = "linux")]
+fn owner_id() -> u32 {
+    unsafe { ::libc::gettid() as u32 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn owner_id() -> u32 {
+    unsafe { ::libc::getpid() as u32 }
+}
+
+#[cfg(target_os = "linux")]
+fn thread_id() -> u64 {
+    unsafe { ::libc::gettid() as u64 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_id() -> u64 {
+    unsafe { ::libc::pthread_self() as u64 }
+}
+
+// Whether a thread (as in a lock word) is one of this process.
+#[cfg(target_os = "linux")]
+fn is_own_thread(owner: u32) -> bool {
+    ::std::path::Path::new(&format!("/proc/self/task/{}", owner)).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_own_thread(owner: u32) -> bool {
+    owner == owner_id()
+}
+
+// Whether a thread (as in a lock word) has exited, with its whole process.
+fn is_dead(owner: u32) -> bool {
+    if unsafe { ::libc::kill(owner as ::libc::pid_t, 0) } != 0 {
+        return ::std::io::Error::last_os_error().raw_os_error() == Some(::libc::ESRCH);
+    }
+    is_zombie(owner)
+}
+
+// (killed processes linger as zombies until reaped, and still answer to kill)
+#[cfg(target_os = "linux")]
+fn is_zombie(owner: u32) -> bool {
+    ::std::fs::read_to_string(format!("/proc/{}/stat", owner))
+        .is_ok_and(|x| x.rsplit(") ").next().is_some_and(|x| x.starts_with(['Z', 'X'])))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_zombie(_: u32) -> bool {
+    false
+}
+
+impl MappedHeap {
+    // Names a header lock for messages.
+    fn lock_name(&self, lock: &HeaderLock) -> String {
+        let offset = lock as *const HeaderLock as usize - self.inner.header_ptr as usize;
+        match HEADER.fields.iter().find(|x| x.offset <= offset && offset < x.offset + x.size) {
+            Some(x) if x.name == "shards" => {
+                format!("freelist shard {}", 1 + (offset - x.offset) / std::mem::size_of::<FreelistShard>())
+            }
+            Some(x) => x.name.to_string(),
+            None => format!("header offset {}", offset),
+        }
+    }
+
+    // The in-process lock standing in for a header lock in single-process mode.
+    // (header locks are in separate 64 byte blocks, see format::HEADER)
+    fn local_lock(&self, lock: &HeaderLock) -> Option<&RawMutex> {
+        let offset = lock as *const HeaderLock as usize - self.inner.header_ptr as usize;
+        self.inner.local_locks.get(offset / 64)
+    }
+
+    // Repairs what the dead owner of a lock may have left half done. Freelist pages
+    // are modified in an order that keeps their entries valid at every point, but the
+    // checksum may not have caught up. (Neither may the free page count, which is only
+    // fixed by `recount_free_pages`.)
+    fn recover(&self, lock: &HeaderLock) {
+        let shard = match (0..MAX_FREELIST_SHARDS).find(|&x| ptr::eq(self.freelist(x).0, lock)) {
+            Some(shard) => shard,
+            None => return,
+        };
+        let mut id = self.freelist(shard).1.load(Ordering::Relaxed);
+        // (a cycle is left for fsck to find)
+        for _ in 0..self.size() {
+            let page = match self.page(id) {
+                Some(page) if id != NULL_PAGE => page as *mut FreelistPage,
+                _ => break,
+            };
+            unsafe {
+                FreelistPage::repair(page);
+                id = FreelistPage::next(page);
+            }
+        }
+    }
+
+    pub(crate) fn lock(&self, lock: &HeaderLock) {
+        let local = self.local_lock(lock);
+        if !cfg!(debug_assertions) {
+            match local {
+                Some(local) => local.lock(),
+                None => {
+                    if lock.acquire(None) == Acquired::Recovered {
+                        self.recover(lock);
+                    }
+                }
+            }
+            return;
+        }
+        let addr = lock as *const HeaderLock as usize;
+        HELD.with(|held| {
+            if let Some((_, backtrace)) = held.borrow().iter().find(|x| x.0 == addr) {
+                panic!("re-entrant acquisition of the {} lock, which this thread took at:\n{}",
+                       self.lock_name(lock), backtrace);
+            }
+        });
+
+        let timeout = self.inner.options.lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT);
+        let locked = match local {
+            Some(local) => local.try_lock_for(timeout),
+            None => match lock.acquire(Some(Instant::now() + timeout)) {
+                Acquired::Yes => true,
+                Acquired::Recovered => {
+                    self.recover(lock);
+                    true
+                }
+                Acquired::TimedOut => false,
+            },
+        };
+        if !locked {
+            let owner = match local {
+                Some(_) => OWNERS.iter().find(|x| x.lock.load(Ordering::Relaxed) == addr)
+                    .map(|x| format!("thread {} of this process", x.thread.load(Ordering::Relaxed))),
+                None => lock.owner().map(|x| match is_own_thread(x) {
+                    true => format!("thread {} of this process", x),
+                    false => format!("thread {} of another process", x),
+                }),
+            };
+            panic!("suspected deadlock: waited {:?} for the {} lock, held by {}",
+                   timeout, self.lock_name(lock), owner.as_deref().unwrap_or("an unknown thread"));
+        }
+
+        HELD.with(|held| held.borrow_mut().push((addr, Backtrace::capture())));
+        if local.is_some() {
+            let thread = thread_id();
+            for owner in OWNERS.iter() {
+                if owner.lock.compare_exchange(0, addr, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                    owner.thread.store(thread, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn unlock(&self, lock: &HeaderLock) {
+        let local = self.local_lock(lock);
+        if cfg!(debug_assertions) {
+            let addr = lock as *const HeaderLock as usize;
+            HELD.with(|held| {
+                let mut held = held.borrow_mut();
+                if let Some(i) = held.iter().rposition(|x| x.0 == addr) {
+                    drop(held.remove(i));
+                }
+            });
+            let thread = thread_id();
+            if let Some(owner) = OWNERS.iter().find(|x| {
+                x.lock.load(Ordering::Relaxed) == addr && x.thread.load(Ordering::Relaxed) == thread
+            }) {
+                owner.lock.store(0, Ordering::Relaxed);
+            }
+        }
+        match local {
+            Some(local) => unsafe { local.unlock() },
+            None => lock.release(),
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::mpsc;
+    use std::thread;
+    use HeapOptions;
+
+    #[test]
+    #[should_panic(expected = "re-entrant acquisition of the root_lock lock")]
+    fn reentrant_lock() {
+        let _ = fs::remove_file("/tmp/reentrant.bin");
+        let mapping = MappedHeap::open("/tmp/reentrant.bin").unwrap();
+        let _ = fs::remove_file("/tmp/reentrant.bin");
+        mapping.root_or_create(0, |heap| heap.root_or_create(1, |heap| heap.alloc()));
+    }
+
+    #[test]
+    #[should_panic(expected = "waited 100ms for the root_lock lock, held by thread")]
+    fn lock_timeout() {
+        let _ = fs::remove_file("/tmp/locktimeout.bin");
+        let mapping = HeapOptions::new().lock_timeout(Duration::from_millis(100)).open("/tmp/locktimeout.bin").unwrap();
+        let _ = fs::remove_file("/tmp/locktimeout.bin");
+        hold_root_lock(mapping);
+    }
+
+    #[test]
+    #[should_panic(expected = "waited 100ms for the root_lock lock, held by thread")]
+    fn single_process_lock_timeout() {
+        let _ = fs::remove_file("/tmp/singletimeout.bin");
+        let mapping = HeapOptions::new().single_process(true).lock_timeout(Duration::from_millis(100))
+            .open("/tmp/singletimeout.bin").unwrap();
+        let _ = fs::remove_file("/tmp/singletimeout.bin");
+        hold_root_lock(mapping);
+    }
+
+    #[test]
+    fn lock_word() {
+        let _ = fs::remove_file("/tmp/lockword.bin");
+        let mapping = MappedHeap::open("/tmp/lockword.bin").unwrap();
+        let _ = fs::remove_file("/tmp/lockword.bin");
+        let lock = &mapping.header().root_lock;
+        assert_eq!(lock.0.load(Ordering::Relaxed), UNLOCKED);
+        mapping.lock(lock);
+        assert_eq!(lock.0.load(Ordering::Relaxed), owner_id());
+        assert_eq!(lock.owner(), Some(owner_id()));
+
+        // a waiter sets the flag, and is woken by the unlock
+        let other = mapping.clone();
+        let waiter = thread::spawn(move || {
+            let lock = &other.header().root_lock;
+            other.lock(lock);
+            let word = lock.0.load(Ordering::Relaxed);
+            other.unlock(lock);
+            word
+        });
+        while lock.0.load(Ordering::Relaxed) & WAITERS == 0 {
+            thread::yield_now();
+        }
+        mapping.unlock(lock);
+        assert_eq!(waiter.join().unwrap() & WAITERS, WAITERS);
+        assert_eq!(lock.0.load(Ordering::Relaxed), UNLOCKED);
+    }
+
+    #[test]
+    fn owner_death() {
+        let _ = fs::remove_file("/tmp/ownerdeath.bin");
+        let mapping = HeapOptions::new().lock_timeout(Duration::from_secs(5)).open("/tmp/ownerdeath.bin").unwrap();
+        let _ = fs::remove_file("/tmp/ownerdeath.bin");
+        let ids: Vec<_> = (0..10).map(|_| mapping.alloc()).collect();
+        for &id in &ids {
+            mapping.free(id);
+        }
+        let dead = thread::spawn(owner_id).join().unwrap();
+
+        // an owner that died in the middle of popping an entry, before updating the checksum
+        let header = mapping.header();
+        let head = mapping.freelist_page(header.freelist_id.load(Ordering::Relaxed));
+        unsafe { *(head as *mut u64) -= 1 };
+        assert!(mapping.check(::ConsistencyLevel::FullFsck).is_err());
+        header.alloc_lock.0.store(dead | WAITERS, Ordering::Relaxed);
+        mapping.alloc();
+        assert_eq!(header.alloc_lock.0.load(Ordering::Relaxed), UNLOCKED);
+        // (the popped entry is lost)
+        mapping.recount_free_pages();
+        mapping.check(::ConsistencyLevel::FullFsck).unwrap();
+    }
+
+    // Holds the root lock in another thread while trying to take it.
+    fn hold_root_lock(mapping: MappedHeap) {
+        let (tx, rx) = mpsc::channel();
+        let other = mapping.clone();
+        thread::spawn(move || other.root_or_create(0, |heap| {
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_secs(1));
+            heap.alloc()
+        }));
+        rx.recv().unwrap();
+        mapping.root_or_create(1, |heap| heap.alloc());
+    }
+}
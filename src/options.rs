@@ -0,0 +1,454 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{cmp, fmt, io};
+
+use freelist;
+use {MappedHeap, PageId, MAX_FREELIST_SHARDS, PAGESZ};
+
+/// How `alloc` picks among the free pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// Whatever free page is cheapest to get at (the default).
+    #[default]
+    Any,
+    /// Always the lowest-numbered free page.
+    ///
+    /// This keeps live data dense at the front of the file, so that the free pages
+    /// accumulate at its end where compaction can actually give them back.
+    ///
+    /// This is expensive: every allocation locks all freelist shards (stalling every
+    /// other `alloc` and `free` meanwhile) and walks every freelist page, so it takes
+    /// time proportional to the number of free pages, and allocations don't scale with
+    /// `HeapOptions::freelist_shards`.
+    LowestFirst,
+}
+
+/// How thoroughly a heap is checked when it is opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConsistencyLevel {
+    /// Only the header's magic and format version (the default), for trusted local files.
+    #[default]
+    None,
+    /// Also verifies the checksum of every freelist page.
+    ChecksumVerify,
+    /// Also checks that the freelists only contain valid pages, each at most once,
+    /// and that the free page count matches them.
+    FullFsck,
+}
+
+/// How much checking of its own invariants the heap does as it goes, see
+/// `HeapOptions::validation`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValidationLevel {
+    /// No checks beyond the ones that are always done (the default).
+    #[default]
+    Off,
+    /// Constant-time checks on every `alloc` and `free`: the page id is in bounds,
+    /// the page isn't freed right after it was already freed, and the free page
+    /// count doesn't exceed the size.
+    Cheap,
+    /// Also checks that a freed page isn't on the freelists already, and checks the
+    /// whole heap like `ConsistencyLevel::FullFsck` after every `alloc` and `free`.
+    /// That takes time proportional to the number of free pages, every time.
+    Paranoid,
+}
+
+/// How much the file grows whenever the free pages run out, see
+/// `HeapOptions::growth_policy`.
+#[derive(Clone, Default)]
+pub enum GrowthPolicy {
+    /// Doubles the size (the default).
+    #[default]
+    Double,
+    /// Multiplies the size by this factor (rounding up), which must be greater than one.
+    Factor(f64),
+    /// Adds this many pages, which must be at least one.
+    Pages(u64),
+    /// Calls the function with the current size in pages, and grows the file to the
+    /// size it returns (or by one page, if that is more).
+    Custom(Arc<dyn Fn(PageId) -> PageId + Send + Sync>),
+}
+
+impl GrowthPolicy {
+    // The size to grow a file of `size` pages to (before applying max_size_bytes).
+    pub(crate) fn next_size(&self, size: PageId) -> PageId {
+        let next = match *self {
+            GrowthPolicy::Double => size.saturating_mul(2),
+            GrowthPolicy::Factor(factor) => (size as f64 * factor).ceil() as PageId,
+            GrowthPolicy::Pages(pages) => size.saturating_add(pages),
+            GrowthPolicy::Custom(ref f) => f(size),
+        };
+        cmp::max(next, size + 1)
+    }
+}
+
+impl fmt::Debug for GrowthPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GrowthPolicy::Double => write!(f, "Double"),
+            GrowthPolicy::Factor(factor) => write!(f, "Factor({})", factor),
+            GrowthPolicy::Pages(pages) => write!(f, "Pages({})", pages),
+            GrowthPolicy::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// How long an operation waits for its writes to reach the disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Doesn't write anything back, the kernel does so whenever it sees fit.
+    None,
+    /// Starts writing back, but doesn't wait for it to complete.
+    Async,
+    /// Waits until the writes (and the metadata needed to read them back) are on
+    /// disk (the default).
+    #[default]
+    Sync,
+}
+
+/// Options and flags which can be used to configure how a `MappedHeap` is opened.
+///
+/// Options are per-handle and never stored in the file, so different processes may
+/// open the same heap with different options.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::HeapOptions;
+///
+/// let mapping = HeapOptions::new().deterministic(true).open("/tmp/test-options.bin").unwrap();
+/// let page_id = mapping.alloc();
+/// mapping.free(page_id);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HeapOptions {
+    pub(crate) deterministic: bool,
+    pub(crate) guard_pages: bool,
+    pub(crate) freelist_shards: usize,
+    pub(crate) freelist_page_capacity: Option<usize>,
+    pub(crate) trim_on_free: bool,
+    pub(crate) max_fragments: Option<usize>,
+    pub(crate) poison_on_free: bool,
+    pub(crate) max_dirty_bytes: Option<u64>,
+    pub(crate) alloc_policy: AllocPolicy,
+    pub(crate) event_log: Option<u64>,
+    pub(crate) consistency: ConsistencyLevel,
+    pub(crate) max_size_bytes: Option<u64>,
+    pub(crate) lock_timeout: Option<Duration>,
+    pub(crate) batch_alloc: bool,
+    pub(crate) single_process: bool,
+    pub(crate) quarantine: bool,
+    pub(crate) durability: Durability,
+    pub(crate) validation: ValidationLevel,
+    pub(crate) track_allocations: bool,
+    pub(crate) growth_policy: GrowthPolicy,
+}
+
+impl HeapOptions {
+    /// Creates a blank set of options.
+    pub fn new() -> HeapOptions {
+        HeapOptions::default()
+    }
+
+    /// Makes the file layout a pure function of the sequence of operations performed.
+    ///
+    /// On open, the file length is made to match the size recorded in the header
+    /// exactly (instead of rounding down whatever length the file happens to have),
+    /// pages are always zeroed before `alloc` returns them, and all threads share a
+    /// single freelist shard (ignoring `freelist_shards`, whose choice of shard depends
+    /// on thread scheduling). Identical operation sequences thus produce byte-identical
+    /// files, which makes golden-file tests of the on-disk format feasible.
+    pub fn deterministic(&mut self, deterministic: bool) -> &mut HeapOptions {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Places an inaccessible (`PROT_NONE`) guard page after every mapped fragment.
+    ///
+    /// Pointer arithmetic running off the end of a fragment then faults immediately
+    /// instead of silently touching whatever happens to be mapped next. As a fragment
+    /// can't grow into its own guard, every growth of the mapping creates a new fragment.
+    pub fn guard_pages(&mut self, guard_pages: bool) -> &mut HeapOptions {
+        self.guard_pages = guard_pages;
+        self
+    }
+
+    /// Spreads the freelist over this many shards, each with its own lock (default: 1).
+    ///
+    /// Every thread allocates from and frees to its own shard (stealing from the
+    /// others when it runs dry), so concurrent allocations from many threads don't
+    /// all serialize on a single lock and cache line. New pages created by growing
+    /// the file are distributed over the shards.
+    ///
+    /// Since all shards are always searched before growing, processes using different
+    /// shard counts on the same file cooperate just fine.
+    ///
+    /// Ignored in deterministic mode, which always uses a single shard.
+    ///
+    /// # Panics
+    ///
+    /// * If `shards` is zero or larger than `MAX_FREELIST_SHARDS`.
+    pub fn freelist_shards(&mut self, shards: usize) -> &mut HeapOptions {
+        assert!(shards > 0 && shards <= MAX_FREELIST_SHARDS);
+        self.freelist_shards = shards;
+        self
+    }
+
+    /// Fills freelist pages with at most this many entries (default: as many as fit,
+    /// `format::FREELIST_ENTRIES`).
+    ///
+    /// Smaller freelist pages waste more pages on the freelists, but a corrupt one
+    /// loses fewer free pages, and `batch_alloc` takes fewer pages at a time. The
+    /// capacity is stored in the file header on open, so it applies to every process
+    /// using the heap from then on. Pages filled before it was lowered keep their
+    /// entries until `MappedHeap::compact_freelist` repacks them.
+    ///
+    /// # Panics
+    ///
+    /// * If `capacity` is zero or larger than `format::FREELIST_ENTRIES`.
+    pub fn freelist_page_capacity(&mut self, capacity: usize) -> &mut HeapOptions {
+        assert!(capacity > 0 && capacity <= freelist::ENTRIES);
+        self.freelist_page_capacity = Some(capacity);
+        self
+    }
+
+    /// Makes every `free` behave like `MappedHeap::free_and_trim`.
+    pub fn trim_on_free(&mut self, trim_on_free: bool) -> &mut HeapOptions {
+        self.trim_on_free = trim_on_free;
+        self
+    }
+
+    /// Fills every freed page with a recognizable pattern (see `POISON`).
+    ///
+    /// Use-after-free through stale pointers then reads obviously bogus data that
+    /// stands out in dumps and asserts, instead of whatever the page contained before.
+    /// Freed pages are neither punched out of the file nor trimmed while this is set,
+    /// so this is meant for debugging and testing.
+    pub fn poison_on_free(&mut self, poison_on_free: bool) -> &mut HeapOptions {
+        self.poison_on_free = poison_on_free;
+        self
+    }
+
+    /// Makes `flush` write back data gradually, with at most this many bytes
+    /// under writeback at any time (see `MappedHeap::flush_window`).
+    ///
+    /// This keeps large flushes from stalling the device for everyone else,
+    /// at the cost of making them take longer.
+    pub fn max_dirty_bytes(&mut self, max_dirty_bytes: u64) -> &mut HeapOptions {
+        self.max_dirty_bytes = Some(max_dirty_bytes);
+        self
+    }
+
+    /// Sets how much the file grows whenever the free pages run out (default:
+    /// `GrowthPolicy::Double`).
+    ///
+    /// Doubling keeps the number of growths (and fragments) logarithmic, but a huge
+    /// heap then grows by a huge amount at once. Growing by a smaller factor or a
+    /// fixed number of pages bounds the overshoot instead, at the cost of growing
+    /// (and, without `max_fragments`, adding fragments) more often. Since options
+    /// aren't stored in the file, processes using it may use different policies.
+    ///
+    /// # Panics
+    ///
+    /// * If the policy is `Factor` of at most one or `Pages` of zero.
+    pub fn growth_policy(&mut self, growth_policy: GrowthPolicy) -> &mut HeapOptions {
+        match growth_policy {
+            GrowthPolicy::Factor(factor) => assert!(factor > 1.0, "growth factor must be greater than one"),
+            GrowthPolicy::Pages(pages) => assert!(pages > 0, "growth must be at least one page"),
+            _ => (),
+        }
+        self.growth_policy = growth_policy;
+        self
+    }
+
+    /// Checks the heap's invariants as it is used (default: `ValidationLevel::Off`),
+    /// panicking as soon as one is violated.
+    ///
+    /// Unlike debug assertions this needs no recompilation, so e.g. staging can run
+    /// with `ValidationLevel::Paranoid` the same build that runs with
+    /// `ValidationLevel::Off` in production. Violations are most likely caused by
+    /// bugs like double frees, or by outside modification of the file.
+    pub fn validation(&mut self, validation: ValidationLevel) -> &mut HeapOptions {
+        self.validation = validation;
+        self
+    }
+
+    /// Keeps a bitmap of the allocated pages in memory, so `free` panics with a clear
+    /// message on a double free, or on freeing a page that was never allocated,
+    /// instead of corrupting the freelists.
+    ///
+    /// This catches what `ValidationLevel` only catches with a full walk of the
+    /// freelists, at the cost of one bit of memory per page and a lock per `alloc`
+    /// and `free`. The bitmap is private to the process: pages allocated or freed by
+    /// other processes throw it off, so only use this while no other process
+    /// modifies the heap.
+    pub fn track_allocations(&mut self, track_allocations: bool) -> &mut HeapOptions {
+        self.track_allocations = track_allocations;
+        self
+    }
+
+    /// Sets the durability of `MappedHeap::flush` (default: `Durability::Sync`).
+    ///
+    /// Use `MappedHeap::flush_with` to pick the durability of a single flush instead.
+    pub fn durability(&mut self, durability: Durability) -> &mut HeapOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the allocation policy (default: `AllocPolicy::Any`).
+    pub fn alloc_policy(&mut self, alloc_policy: AllocPolicy) -> &mut HeapOptions {
+        self.alloc_policy = alloc_policy;
+        self
+    }
+
+    /// Gives the file an event log of this many pages (128 events each) on open,
+    /// unless it already has one (see `MappedHeap::events`).
+    ///
+    /// The log can't be removed or resized, and once a file has one, all processes
+    /// record their allocator events in it regardless of their options.
+    ///
+    /// # Panics
+    ///
+    /// * If `pages` is zero.
+    pub fn event_log(&mut self, pages: u64) -> &mut HeapOptions {
+        assert!(pages > 0);
+        self.event_log = Some(pages);
+        self
+    }
+
+    /// Checks the heap on open, failing with `InvalidData` if it is inconsistent
+    /// (default: `ConsistencyLevel::None`, see `MappedHeap::check`).
+    ///
+    /// Use a thorough level for files received from elsewhere. Checking takes time
+    /// proportional to the number of free pages and locks all freelists meanwhile.
+    pub fn consistency(&mut self, consistency: ConsistencyLevel) -> &mut HeapOptions {
+        self.consistency = consistency;
+        self
+    }
+
+    /// Never lets the file grow beyond this many bytes (rounded down to full pages).
+    ///
+    /// Growth follows the `growth_policy` as usual, except that the last step stops
+    /// exactly at the limit. Allocations that would need more space fail with `AllocError::Full` (see
+    /// `MappedHeap::try_alloc`) instead. Since options aren't stored in the file, all
+    /// processes using it should set the same limit.
+    ///
+    /// # Panics
+    ///
+    /// * If `max_size_bytes` is less than two pages.
+    pub fn max_size_bytes(&mut self, max_size_bytes: u64) -> &mut HeapOptions {
+        assert!(max_size_bytes >= 2 * PAGESZ as u64);
+        self.max_size_bytes = Some(max_size_bytes);
+        self
+    }
+
+    /// In debug builds, panics when waiting longer than this for one of the locks in
+    /// the file (default: 30 seconds), instead of hanging forever because of a lock
+    /// leaked by a bug or a crashed process.
+    ///
+    /// Debug builds also panic right away when a thread tries to take a lock it
+    /// already holds. Release builds wait as long as it takes.
+    pub fn lock_timeout(&mut self, lock_timeout: Duration) -> &mut HeapOptions {
+        self.lock_timeout = Some(lock_timeout);
+        self
+    }
+
+    /// Lets `alloc` take a full freelist page off the freelists in one go: the page
+    /// itself is returned, and its entries go into a cache of this handle that the
+    /// following allocations are served from without locking.
+    ///
+    /// This shortens the freelist chains and makes long runs of allocations cheap.
+    /// Cached pages don't count as free (see `MappedHeap::free_pages`) and are given
+    /// back when the handle is dropped, so they leak if the process dies. Clones start
+    /// with an empty cache. Only applies to `AllocPolicy::Any`.
+    pub fn batch_alloc(&mut self, batch_alloc: bool) -> &mut HeapOptions {
+        self.batch_alloc = batch_alloc;
+        self
+    }
+
+    /// Asserts that only this process uses the heap, which lets all handles opened
+    /// from this one use in-process locks instead of the futexes in the file header.
+    ///
+    /// Opening takes an exclusive `flock` on the file for as long as the heap stays
+    /// open (ordinary opens take a shared one), so opening fails with `WouldBlock`
+    /// while anyone else has the file open, and so do other opens while this heap is.
+    pub fn single_process(&mut self, single_process: bool) -> &mut HeapOptions {
+        self.single_process = single_process;
+        self
+    }
+
+    /// Opens heaps with a corrupt freelist instead of failing or panicking, so the
+    /// data can be salvaged.
+    ///
+    /// On open, the freelists are checked like `ConsistencyLevel::FullFsck` does
+    /// (which replaces `consistency`). Every freelist is cut short right before its
+    /// first corrupt page, which is quarantined: `page` returns `None` for it and
+    /// `try_page` fails with `Quarantined` (see `MappedHeap::quarantined_pages`).
+    /// The free pages beyond the cut are leaked. The cuts are written to the file,
+    /// the quarantine only lasts as long as the heap stays open.
+    pub fn quarantine(&mut self, quarantine: bool) -> &mut HeapOptions {
+        self.quarantine = quarantine;
+        self
+    }
+
+    /// Keeps the number of fragments (separate mappings of the file) low, to
+    /// avoid exhausting the kernel's `vm.max_map_count` (default: unlimited).
+    ///
+    /// Whenever the mapping can't be extended in place, a new fragment is created.
+    /// Once a handle has `max_fragments` of them, new fragments reserve inaccessible
+    /// address space for the file to grow to eight times its current size, so later
+    /// growth extends them in place. Growth beyond that still extends the fragment
+    /// if the address space right after the reservation happens to be free.
+    /// Fragments are never moved to merge them, since that would invalidate pointers
+    /// previously returned by `page`.
+    ///
+    /// This is a soft limit: the number of fragments still grows, but only
+    /// logarithmically to the base of eight rather than two (whatever the
+    /// `GrowthPolicy`, as the reservation depends on the size alone).
+    ///
+    /// # Panics
+    ///
+    /// * If `max_fragments` is zero.
+    pub fn max_fragments(&mut self, max_fragments: usize) -> &mut HeapOptions {
+        assert!(max_fragments > 0);
+        self.max_fragments = Some(max_fragments);
+        self
+    }
+
+    /// Opens a file as a MappedHeap with these options.
+    ///
+    /// This will atomically create and initialize the file if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> io::Result<MappedHeap> {
+        MappedHeap::open_with(path.as_ref(), self)
+    }
+
+    /// Opens an existing file as a MappedHeap with these options.
+    ///
+    /// Fails like `MappedHeap::open_file`.
+    pub fn open_file(&self, file: File) -> io::Result<MappedHeap> {
+        MappedHeap::open_file_with(file, self)
+    }
+
+    /// Creates a heap in memory only with these options.
+    ///
+    /// See `MappedHeap::anonymous` for details.
+    pub fn anonymous(&self, initial_pages: u64) -> io::Result<MappedHeap> {
+        MappedHeap::anonymous_with(initial_pages, self)
+    }
+
+    /// Opens a private, in-memory copy of a heap file with these options.
+    ///
+    /// See `MappedHeap::open_private_copy` for details.
+    pub fn open_private_copy<P: AsRef<Path>>(&self, path: P) -> io::Result<MappedHeap> {
+        MappedHeap::open_private_copy_with(path.as_ref(), self)
+    }
+
+    /// Opens a file as a MappedHeap with these options, initializing it if it is empty.
+    ///
+    /// See `MappedHeap::open_or_init` for details.
+    pub fn open_or_init(&self, file: File) -> io::Result<MappedHeap> {
+        MappedHeap::open_or_init_with(file, self)
+    }
+}
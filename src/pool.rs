@@ -0,0 +1,141 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use {HeapOptions, MappedHeap};
+
+/// A pool of open heaps, for systems that keep many small heap files (e.g. one per tenant).
+///
+/// Every open heap costs a file descriptor and at least one memory mapping. The pool keeps
+/// at most `max_open` heaps open and closes the least recently used ones beyond that, so
+/// the process stays within its fd and `vm.max_map_count` limits no matter how many heap
+/// files there are.
+///
+/// Heaps that are still referenced outside of the pool are never closed (and don't count
+/// as candidates for eviction), so all limits are soft.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{HeapOptions, HeapPool};
+///
+/// let mut pool = HeapPool::new(HeapOptions::new(), 100);
+/// let heap = pool.get("/tmp/test-pool.bin").unwrap();
+/// let page_id = heap.alloc();
+/// heap.free(page_id);
+/// ```
+pub struct HeapPool {
+    options: HeapOptions,
+    max_open: usize,
+    max_fragments: usize,
+    heaps: Vec<(PathBuf, Rc<MappedHeap>)>, // least recently used first
+}
+
+impl HeapPool {
+    /// Creates an empty pool that opens all heaps with the given options.
+    ///
+    /// # Panics
+    ///
+    /// * If `max_open` is zero.
+    pub fn new(options: HeapOptions, max_open: usize) -> HeapPool {
+        assert!(max_open > 0);
+        HeapPool { options, max_open, max_fragments: usize::MAX, heaps: Vec::new() }
+    }
+
+    /// Limits the total number of fragments (i.e. memory mappings) of all heaps in the pool.
+    ///
+    /// Least recently used heaps are closed whenever the budget is exceeded.
+    /// Combine this with `HeapOptions::max_fragments` to keep individual heaps from
+    /// needing many mappings in the first place.
+    pub fn map_budget(&mut self, max_fragments: usize) -> &mut HeapPool {
+        self.max_fragments = max_fragments;
+        self.evict();
+        self
+    }
+
+    /// Returns the heap at the given path, opening (and creating) it if necessary.
+    pub fn get<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Rc<MappedHeap>> {
+        let path = path.as_ref();
+        let heap = match self.heaps.iter().position(|x| x.0 == path) {
+            Some(i) => self.heaps.remove(i).1,
+            None => Rc::new(self.options.open(path)?),
+        };
+        self.heaps.push((path.to_path_buf(), heap.clone()));
+        self.evict();
+        Ok(heap)
+    }
+
+    /// Removes the heap at the given path from the pool, closing it unless it
+    /// is still referenced elsewhere. Returns false if it wasn't in the pool.
+    pub fn close<P: AsRef<Path>>(&mut self, path: P) -> bool {
+        let path = path.as_ref();
+        match self.heaps.iter().position(|x| x.0 == path) {
+            Some(i) => {
+                self.heaps.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The number of heaps in the pool.
+    pub fn len(&self) -> usize {
+        self.heaps.len()
+    }
+
+    /// Whether the pool is empty.
+    pub fn is_empty(&self) -> bool {
+        self.heaps.is_empty()
+    }
+
+    /// The total number of fragments of all heaps in the pool.
+    pub fn fragments(&self) -> usize {
+        self.heaps.iter().map(|x| x.1.fragments()).sum()
+    }
+
+    fn evict(&mut self) {
+        let mut fragments = self.fragments();
+        let mut i = 0;
+        while (self.heaps.len() > self.max_open || fragments > self.max_fragments) && i < self.heaps.len() {
+            if Rc::strong_count(&self.heaps[i].1) == 1 {
+                fragments -= self.heaps.remove(i).1.fragments();
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn lru() {
+        let paths: Vec<_> = (0..3).map(|i| format!("/tmp/pool{}.bin", i)).collect();
+        for path in &paths {
+            let _ = fs::remove_file(path);
+        }
+
+        let mut pool = HeapPool::new(HeapOptions::new(), 2);
+        let first = pool.get(&paths[0]).unwrap();
+        drop(pool.get(&paths[1]).unwrap());
+        drop(pool.get(&paths[2]).unwrap());
+        // the first heap is still in use, so the second one had to go
+        assert_eq!(pool.len(), 2);
+        assert!(Rc::ptr_eq(&first, &pool.get(&paths[0]).unwrap()));
+        drop(first);
+        assert!(pool.close(&paths[0]));
+        assert!(!pool.close(&paths[1]));
+
+        pool.get(&paths[1]).unwrap();
+        pool.map_budget(1);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.fragments(), 1);
+
+        for path in &paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
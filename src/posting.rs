@@ -0,0 +1,377 @@
+//! Posting lists: sorted sets of `u64` document ids, as used by inverted indexes.
+//!
+//! Ids are stored as varint-encoded deltas in a chain of pages. Every page header
+//! records the first and last id in the page, which serve as skip pointers: seeking
+//! (and thus intersecting) skips entire pages without decoding them.
+
+use std::ptr;
+
+use stats::Stats;
+use {MappedHeap, PageId, StructureStats, NULL_PAGE, PAGESZ};
+
+#[repr(C)]
+struct Meta {
+    first: PageId,
+    last: PageId,
+    len: u64,
+}
+
+#[repr(C)]
+struct Chunk {
+    next: PageId,
+    first: u64,
+    last: u64,
+    count: u32,
+    used: u32, // bytes of data
+    data: [u8; PAGESZ - 32],
+}
+
+// LEB128
+fn encode(mut value: u64, buf: &mut [u8; 10]) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            return len + 1;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+fn decode(data: &[u8], pos: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    while *pos < data.len() && shift < 64 {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+/// A sorted list of document ids stored in a `MappedHeap`, supporting appends
+/// and fast intersection and union.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, PostingList};
+///
+/// let mapping = MappedHeap::open("/tmp/test-posting.bin").unwrap();
+/// let mut a = PostingList::create(&mapping);
+/// let mut b = PostingList::create(&mapping);
+/// for doc in &[1, 3, 5, 7] { a.append(*doc); }
+/// for doc in &[3, 4, 7] { b.append(*doc); }
+/// assert_eq!(a.intersect(&b), vec![3, 7]);
+/// a.destroy();
+/// b.destroy();
+/// ```
+pub struct PostingList<'a> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+    stats: Stats,
+}
+
+impl<'a> PostingList<'a> {
+    /// Creates a new, empty posting list.
+    pub fn create(heap: &'a MappedHeap) -> PostingList<'a> {
+        let meta_page = heap.alloc();
+        unsafe {
+            let list = PostingList::open(heap, meta_page);
+            ptr::write(list.meta, Meta { first: NULL_PAGE, last: NULL_PAGE, len: 0 });
+            list
+        }
+    }
+
+    /// Opens an existing posting list by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, and nobody may modify the
+    /// list while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> PostingList<'a> {
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        PostingList { heap, meta, meta_page, stats: Stats::default() }
+    }
+
+    /// The id of the meta page, which identifies this list.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of ids in the list.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The I/O counters of this handle.
+    pub fn stats(&self) -> StructureStats {
+        self.stats.get()
+    }
+
+    fn chunk(&self, id: PageId) -> *mut Chunk {
+        let chunk = self.heap.page(id).expect("corrupt posting list");
+        self.stats.access(chunk as usize);
+        chunk as *mut Chunk
+    }
+
+    fn new_chunk(&self) -> PageId {
+        let id = self.stats.alloc(self.heap);
+        unsafe { ptr::write_bytes(self.chunk(id), 0, 1) };
+        id
+    }
+
+    /// Appends an id.
+    ///
+    /// # Panics
+    ///
+    /// * If `doc` is not greater than the last id in the list.
+    pub fn append(&mut self, doc: u64) {
+        let mut buf = [0; 10];
+        let meta = unsafe { &mut *self.meta };
+
+        let mut len = 0;
+        if meta.last != NULL_PAGE {
+            let chunk = unsafe { &*self.chunk(meta.last) };
+            assert!(doc > chunk.last, "posting list ids must be ascending");
+            len = encode(doc - chunk.last, &mut buf);
+            if chunk.used as usize + len > chunk.data.len() {
+                len = 0;
+            }
+        }
+        if len == 0 {
+            // each page starts over at zero, so it can be decoded on its own
+            let id = self.new_chunk();
+            match meta.last {
+                NULL_PAGE => meta.first = id,
+                last => {
+                    unsafe { (*self.chunk(last)).next = id };
+                    self.stats.written(1);
+                }
+            }
+            meta.last = id;
+            unsafe { (*self.chunk(id)).first = doc };
+            len = encode(doc, &mut buf);
+        }
+
+        let chunk = unsafe { &mut *self.chunk(meta.last) };
+        let used = chunk.used as usize;
+        chunk.data[used..used + len].copy_from_slice(&buf[..len]);
+        chunk.used += len as u32;
+        chunk.count += 1;
+        chunk.last = doc;
+        meta.len += 1;
+        // the chunk and the meta page
+        self.stats.written(2);
+    }
+
+    /// Iterates over all ids in ascending order.
+    pub fn iter(&self) -> PostingIter<'_, 'a> {
+        let mut ret = PostingIter { list: self, page: NULL_PAGE, pos: 0, left: 0, prev: 0, evict: false };
+        ret.enter(self.meta().first);
+        ret
+    }
+
+    fn meta(&self) -> &Meta {
+        unsafe { &*self.meta }
+    }
+
+    /// The ids contained in both lists, in ascending order.
+    pub fn intersect(&self, other: &PostingList) -> Vec<u64> {
+        let mut ret = Vec::new();
+        let (mut a, mut b) = (self.iter(), other.iter());
+        let (mut x, mut y) = (a.next(), b.next());
+        while let (Some(i), Some(j)) = (x, y) {
+            if i == j {
+                ret.push(i);
+                x = a.next();
+                y = b.next();
+            } else if i < j {
+                x = a.seek(j);
+            } else {
+                y = b.seek(i);
+            }
+        }
+        ret
+    }
+
+    /// The ids contained in either list, in ascending order.
+    pub fn union(&self, other: &PostingList) -> Vec<u64> {
+        let mut ret = Vec::with_capacity((self.len() + other.len()) as usize);
+        let (mut a, mut b) = (self.iter(), other.iter());
+        let (mut x, mut y) = (a.next(), b.next());
+        loop {
+            match (x, y) {
+                (Some(i), Some(j)) if i == j => {
+                    ret.push(i);
+                    x = a.next();
+                    y = b.next();
+                }
+                (Some(i), Some(j)) if i < j => {
+                    ret.push(i);
+                    x = a.next();
+                }
+                (Some(i), None) => {
+                    ret.push(i);
+                    x = a.next();
+                }
+                (_, Some(j)) => {
+                    ret.push(j);
+                    y = b.next();
+                }
+                (None, None) => return ret,
+            }
+        }
+    }
+
+    /// Frees all pages of the list, including the meta page.
+    pub fn destroy(self) {
+        let mut id = self.meta().first;
+        // bounded, so a corrupt chain can't make us loop forever
+        for _ in 0..self.len() {
+            if id == NULL_PAGE {
+                break;
+            }
+            let next = unsafe { (*self.chunk(id)).next };
+            self.stats.free(self.heap, id);
+            id = next;
+        }
+        self.stats.free(self.heap, self.meta_page);
+    }
+}
+
+/// An iterator over the ids in a `PostingList`, see `PostingList::iter`.
+pub struct PostingIter<'b, 'a: 'b> {
+    list: &'b PostingList<'a>,
+    page: PageId,
+    pos: usize,
+    left: u32,
+    prev: u64,
+    evict: bool,
+}
+
+impl<'b, 'a> PostingIter<'b, 'a> {
+    /// Evicts the pages the iterator is done with from memory (see `MappedHeap::evict_page`).
+    ///
+    /// This keeps scans over cold lists from pushing the hot working set out of the
+    /// page cache. Pages are evicted as soon as the iterator moves past them, and the
+    /// current page when the iterator is dropped.
+    pub fn evict_on_drop(mut self, evict: bool) -> Self {
+        self.evict = evict;
+        self
+    }
+
+    fn enter(&mut self, page: PageId) {
+        if self.evict && self.page != NULL_PAGE {
+            self.list.heap.evict_page(self.page);
+        }
+        self.page = page;
+        self.pos = 0;
+        self.prev = 0;
+        self.left = if page == NULL_PAGE { 0 } else { unsafe { (*self.list.chunk(page)).count } };
+    }
+
+    /// Advances to the first id not less than `target` and returns it.
+    ///
+    /// Pages that end before `target` are skipped without being decoded.
+    pub fn seek(&mut self, target: u64) -> Option<u64> {
+        while self.page != NULL_PAGE {
+            let chunk = unsafe { &*self.list.chunk(self.page) };
+            if chunk.last >= target {
+                break;
+            }
+            self.enter(chunk.next);
+        }
+        loop {
+            match self.next() {
+                Some(x) if x < target => (),
+                x => return x,
+            }
+        }
+    }
+}
+
+impl<'b, 'a> Drop for PostingIter<'b, 'a> {
+    fn drop(&mut self) {
+        self.enter(NULL_PAGE);
+    }
+}
+
+impl<'b, 'a> Iterator for PostingIter<'b, 'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.left == 0 {
+            if self.page == NULL_PAGE {
+                return None;
+            }
+            let next = unsafe { (*self.list.chunk(self.page)).next };
+            self.enter(next);
+        }
+
+        let chunk = unsafe { &*self.list.chunk(self.page) };
+        let data = &chunk.data[..(chunk.used as usize).min(chunk.data.len())];
+        self.prev += decode(data, &mut self.pos);
+        self.left -= 1;
+        Some(self.prev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn posting_lists() {
+        let _ = fs::remove_file("/tmp/posting.bin");
+        let mapping = MappedHeap::open("/tmp/posting.bin").unwrap();
+
+        let mut threes = PostingList::create(&mapping);
+        let mut fives = PostingList::create(&mapping);
+        for doc in (0..100000).step_by(3) {
+            threes.append(doc);
+        }
+        for doc in (0..100000).step_by(5) {
+            fives.append(doc);
+        }
+        fives.append(u64::MAX);
+
+        let meta = threes.meta_page();
+        let threes = unsafe { PostingList::open(&mapping, meta) };
+        assert_eq!(threes.len(), 33334);
+        assert!(threes.iter().eq((0..100000).step_by(3)));
+        assert!(threes.iter().evict_on_drop(true).eq((0..100000).step_by(3)));
+        assert_eq!(threes.iter().seek(50000), Some(50001));
+        assert_eq!(fives.iter().seek(100000), Some(u64::MAX));
+
+        let both = threes.intersect(&fives);
+        assert!(both.iter().cloned().eq((0..100000).step_by(15)));
+        let either = threes.union(&fives);
+        assert_eq!(either.len(), 33334 + 20001 - both.len());
+        assert!(either.windows(2).all(|x| x[0] < x[1]));
+
+        threes.destroy();
+        fives.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/posting.bin");
+    }
+}
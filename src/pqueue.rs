@@ -0,0 +1,213 @@
+//! A persistent min-priority queue of `(key, value)` pairs.
+//!
+//! This is a plain binary heap over an array of entries. The array is stored in data
+//! pages of 256 entries each, found through a radix tree from page index to page id
+//! (like `MappedVec`, without needing `bytemuck`), so the queue only occupies as many
+//! pages as it has entries.
+
+use std::ptr;
+
+use {MappedHeap, PageId, RadixTree, PAGESZ};
+
+const PER_PAGE: u64 = (PAGESZ / 16) as u64;
+
+type Entry = [u64; 2]; // key, value
+
+#[repr(C)]
+struct Meta {
+    len: u64,
+    index: PageId,
+}
+
+/// A min-priority queue stored in a `MappedHeap`, e.g. for schedulers and timers that
+/// need to survive restarts.
+///
+/// Entries with equal keys come out in no particular order.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, MappedPriorityQueue};
+///
+/// let mapping = MappedHeap::open("/tmp/test-pqueue.bin").unwrap();
+/// let mut queue = MappedPriorityQueue::create(&mapping);
+/// queue.push(30, 3);
+/// queue.push(10, 1);
+/// assert_eq!(queue.pop_min(), Some((10, 1)));
+/// queue.destroy();
+/// ```
+pub struct MappedPriorityQueue<'a> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+    index: RadixTree<'a>,
+}
+
+impl<'a> MappedPriorityQueue<'a> {
+    /// Creates a new, empty queue.
+    pub fn create(heap: &'a MappedHeap) -> MappedPriorityQueue<'a> {
+        let meta_page = heap.alloc();
+        let index = RadixTree::create(heap).meta_page();
+        unsafe {
+            ptr::write(heap.page(meta_page).unwrap() as *mut Meta, Meta { len: 0, index });
+            MappedPriorityQueue::open(heap, meta_page)
+        }
+    }
+
+    /// Opens an existing queue by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, and nobody else may modify
+    /// the queue while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> MappedPriorityQueue<'a> {
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        let index = RadixTree::open(heap, (*meta).index);
+        MappedPriorityQueue { heap, meta, meta_page, index }
+    }
+
+    /// The id of the meta page, which identifies this queue.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of entries.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn slot(&self, i: u64) -> *mut Entry {
+        let page = self.index.get(i / PER_PAGE).expect("corrupt priority queue");
+        let page = self.heap.page(page).expect("corrupt priority queue") as *mut Entry;
+        unsafe { page.add((i % PER_PAGE) as usize) }
+    }
+
+    fn get(&self, i: u64) -> Entry {
+        unsafe { *self.slot(i) }
+    }
+
+    fn set(&mut self, i: u64, entry: Entry) {
+        unsafe { *self.slot(i) = entry };
+    }
+
+    /// Adds an entry.
+    pub fn push(&mut self, key: u64, value: u64) {
+        let len = self.len();
+        if len.is_multiple_of(PER_PAGE) {
+            let page = self.heap.alloc();
+            self.index.insert(len / PER_PAGE, page);
+        }
+        unsafe { (*self.meta).len += 1 };
+
+        // sift up: move parents down until the new entry fits
+        let mut i = len;
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            let entry = self.get(parent);
+            if entry[0] <= key {
+                break;
+            }
+            self.set(i, entry);
+            i = parent;
+        }
+        self.set(i, [key, value]);
+    }
+
+    /// The entry with the smallest key.
+    pub fn peek(&self) -> Option<(u64, u64)> {
+        if self.is_empty() {
+            return None;
+        }
+        let [key, value] = self.get(0);
+        Some((key, value))
+    }
+
+    /// Removes the entry with the smallest key and returns it.
+    pub fn pop_min(&mut self) -> Option<(u64, u64)> {
+        let ret = self.peek()?;
+        let len = self.len() - 1;
+        let last = self.get(len);
+        unsafe { (*self.meta).len = len };
+        if len.is_multiple_of(PER_PAGE) {
+            self.heap.free(self.index.remove(len / PER_PAGE).expect("corrupt priority queue"));
+        }
+
+        // sift down: move the last entry into the hole at the top and push it down
+        let mut i = 0;
+        if len > 0 {
+            loop {
+                let mut child = 2 * i + 1;
+                if child >= len {
+                    break;
+                }
+                if child + 1 < len && self.get(child + 1)[0] < self.get(child)[0] {
+                    child += 1;
+                }
+                let entry = self.get(child);
+                if last[0] <= entry[0] {
+                    break;
+                }
+                self.set(i, entry);
+                i = child;
+            }
+            self.set(i, last);
+        }
+        Some(ret)
+    }
+
+    /// Frees all pages of the queue, including the meta page.
+    pub fn destroy(self) {
+        for chunk in 0..self.len().div_ceil(PER_PAGE) {
+            self.heap.free(self.index.get(chunk).expect("corrupt priority queue"));
+        }
+        self.index.destroy();
+        self.heap.free(self.meta_page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use rand::{self, Rng};
+
+    #[test]
+    fn priority_queue() {
+        let _ = fs::remove_file("/tmp/pqueue.bin");
+        let mapping = MappedHeap::open("/tmp/pqueue.bin").unwrap();
+
+        let mut queue = MappedPriorityQueue::create(&mapping);
+        assert_eq!(queue.pop_min(), None);
+        let mut rng = rand::thread_rng();
+        let mut keys: Vec<u64> = (0..10000).map(|_| rng.gen_range(0, 1000)).collect();
+        for (i, &key) in keys.iter().enumerate() {
+            queue.push(key, i as u64);
+        }
+        assert_eq!(queue.len(), 10000);
+        keys.sort();
+
+        let meta = queue.meta_page();
+        let mut queue = unsafe { MappedPriorityQueue::open(&mapping, meta) };
+        assert_eq!(queue.peek().unwrap().0, keys[0]);
+        for &key in &keys[..5000] {
+            assert_eq!(queue.pop_min().unwrap().0, key);
+        }
+        assert_eq!(queue.len(), 5000);
+        queue.push(0, 42);
+        assert_eq!(queue.pop_min(), Some((0, 42)));
+
+        queue.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/pqueue.bin");
+    }
+}
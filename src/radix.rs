@@ -0,0 +1,406 @@
+//! A persistent radix tree mapping `u64` keys to `u64` values.
+//!
+//! Every node is a single page of 512 slots, each level consuming 9 bits of the key.
+//! The tree is only as high as the largest key requires (one level for keys below 512,
+//! two for keys below 512², ...), so dense integer keys such as page ids need very
+//! few levels and no comparisons at all.
+//!
+//! This is a plain radix tree, not an adaptive one (ART): every node has all 512 slots
+//! no matter how few of them are used, so sparse keys cost a page per node along
+//! each path. It suits dense keys, and keeps nodes trivially simple to update.
+//!
+//! The tree is described by a small meta page (height, root node and number of entries),
+//! whose id is all that needs to be stored elsewhere (e.g. in a root slot).
+//!
+//! Updates survive the process getting killed at any point, in that the tree stays
+//! usable: every change to the structure is a single write linking in a fully
+//! initialized node (or unlinking one), and nodes are only freed once unlinked. These
+//! writes are atomic stores with release ordering, so the node's contents land in the
+//! shared mapping before the link does. All a crash can leave behind is a leaked node,
+//! or a `len` that is off by one. This only covers the process dying, not the machine:
+//! the kernel writes pages back in any order, so surviving power loss needs a `flush`
+//! after every update (and even then only the last flushed state survives).
+
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use stats::Stats;
+use {MappedHeap, PageId, StructureStats, NULL_PAGE, PAGESZ};
+
+const FANOUT: usize = PAGESZ / 8;
+const BITS: u32 = FANOUT.trailing_zeros(); // key bits per level
+const MAX_HEIGHT: u32 = u64::BITS.div_ceil(BITS);
+
+type Node = [u64; FANOUT];
+
+const HEIGHT_SHIFT: u32 = 56;
+
+#[repr(C)]
+struct Meta {
+    height: u64, // only used if `root` doesn't hold the height, see set_root
+    root: PageId, // the height in the top byte
+    len: u64,
+}
+
+/// A radix tree stored in a `MappedHeap`.
+///
+/// Zero is reserved to mark empty slots, so it can't be stored as a value.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, RadixTree};
+///
+/// let mapping = MappedHeap::open("/tmp/test-radix.bin").unwrap();
+/// let mut tree = RadixTree::create(&mapping);
+/// tree.insert(42, 7);
+/// assert_eq!(tree.get(42), Some(7));
+/// tree.destroy();
+/// ```
+pub struct RadixTree<'a> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+    stats: Stats,
+}
+
+impl<'a> RadixTree<'a> {
+    /// Creates a new, empty tree.
+    pub fn create(heap: &'a MappedHeap) -> RadixTree<'a> {
+        let meta_page = heap.alloc();
+        unsafe { RadixTree::init(heap, meta_page) }
+    }
+
+    /// Formats an allocated page as the meta page of an empty tree.
+    ///
+    /// # Safety
+    ///
+    /// Same as `open`.
+    pub unsafe fn init(heap: &'a MappedHeap, meta_page: PageId) -> RadixTree<'a> {
+        let tree = RadixTree::open(heap, meta_page);
+        ptr::write(tree.meta, Meta { height: 0, root: NULL_PAGE, len: 0 });
+        tree
+    }
+
+    /// Opens an existing tree by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create` (or `init`), and nobody else may
+    /// access the tree while the returned handle (or any other handle to it) is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> RadixTree<'a> {
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        RadixTree { heap, meta, meta_page, stats: Stats::default() }
+    }
+
+    /// The id of the meta page, which identifies this tree.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of entries.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether the tree is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The I/O counters of this handle.
+    pub fn stats(&self) -> StructureStats {
+        self.stats.get()
+    }
+
+    fn height(&self) -> u32 {
+        unsafe {
+            match (*self.meta).root >> HEIGHT_SHIFT {
+                0 => (*self.meta).height as u32,
+                height => height as u32,
+            }
+        }
+    }
+
+    fn root(&self) -> PageId {
+        unsafe { (*self.meta).root & ((1 << HEIGHT_SHIFT) - 1) }
+    }
+
+    // Replaces the root node, along with the height in the same write, so a crash
+    // can't pair a root with the wrong height. (Trees written before the height was
+    // kept in the root only have the height field, which is kept up to date for them.)
+    fn set_root(&mut self, root: PageId, height: u32) {
+        unsafe {
+            if root == NULL_PAGE {
+                (*self.meta).height = 0;
+                RadixTree::publish(&mut (*self.meta).root, NULL_PAGE);
+            } else {
+                RadixTree::publish(&mut (*self.meta).root, root | (height as u64) << HEIGHT_SHIFT);
+                (*self.meta).height = height as u64;
+            }
+        }
+    }
+
+    // Links a node or value into a slot, after everything written before.
+    fn publish(slot: *mut u64, value: u64) {
+        unsafe { (*(slot as *const AtomicU64)).store(value, Ordering::Release) };
+    }
+
+    fn node(&self, id: PageId) -> *mut Node {
+        let node = self.heap.page(id).expect("corrupt radix tree");
+        self.stats.access(node as usize);
+        node as *mut Node
+    }
+
+    fn alloc_node(&self) -> PageId {
+        let id = self.stats.alloc(self.heap);
+        unsafe { ptr::write_bytes(self.node(id), 0, 1) };
+        self.stats.written(1);
+        id
+    }
+
+    // Whether a tree of the given height can hold the key.
+    fn fits(key: u64, height: u32) -> bool {
+        height >= MAX_HEIGHT || key >> (height * BITS) == 0
+    }
+
+    fn index(key: u64, level: u32) -> usize {
+        ((key >> (level * BITS)) as usize) & (FANOUT - 1)
+    }
+
+    /// Looks up a key.
+    pub fn get(&self, key: u64) -> Option<u64> {
+        let height = self.height();
+        if height == 0 || !RadixTree::fits(key, height) {
+            return None;
+        }
+
+        let mut id = self.root();
+        for level in (0..height).rev() {
+            id = unsafe { (*self.node(id))[RadixTree::index(key, level)] };
+            if id == 0 {
+                return None;
+            }
+        }
+        Some(id)
+    }
+
+    /// Inserts or replaces an entry, returning the previous value.
+    ///
+    /// # Panics
+    ///
+    /// * If `value` is zero.
+    pub fn insert(&mut self, key: u64, value: u64) -> Option<u64> {
+        assert!(value != 0);
+
+        // grow upwards until the key fits, the old root becomes the first child
+        let grown = self.height() == 0 || !RadixTree::fits(key, self.height());
+        while self.height() == 0 || !RadixTree::fits(key, self.height()) {
+            let root = self.alloc_node();
+            unsafe { (*self.node(root))[0] = self.root() };
+            let height = self.height() + 1;
+            self.set_root(root, height);
+        }
+
+        // (new nodes count as written already)
+        let mut written = 0;
+        let mut fresh = grown;
+        let mut node = self.node(self.root());
+        for level in (1..self.height()).rev() {
+            let slot = unsafe { &mut (*node)[RadixTree::index(key, level)] };
+            if *slot == NULL_PAGE {
+                RadixTree::publish(slot, self.alloc_node());
+                written += !fresh as u64;
+                fresh = true;
+            } else {
+                fresh = false;
+            }
+            node = self.node(*slot);
+        }
+
+        let old = unsafe { &mut (*node)[RadixTree::index(key, 0)] };
+        let ret = *old;
+        RadixTree::publish(old, value);
+        written += !fresh as u64;
+        let ret = if ret == 0 {
+            unsafe { (*self.meta).len += 1 };
+            None
+        } else {
+            Some(ret)
+        };
+        // plus the meta page
+        self.stats.written(written + (grown || ret.is_none()) as u64);
+        ret
+    }
+
+    /// Removes an entry, returning its value.
+    ///
+    /// Nodes that become empty are freed, and the tree shrinks to the height
+    /// required by its largest remaining key.
+    pub fn remove(&mut self, key: u64) -> Option<u64> {
+        let height = self.height();
+        if height == 0 || !RadixTree::fits(key, height) {
+            return None;
+        }
+
+        // remember the path so empty nodes can be freed bottom-up
+        let mut path = Vec::with_capacity(height as usize);
+        let mut id = self.root();
+        for level in (1..height).rev() {
+            path.push(id);
+            id = unsafe { (*self.node(id))[RadixTree::index(key, level)] };
+            if id == NULL_PAGE {
+                return None;
+            }
+        }
+        path.push(id);
+
+        let ret = unsafe { (*self.node(id))[RadixTree::index(key, 0)] };
+        if ret == 0 {
+            return None;
+        }
+        unsafe { (*self.meta).len -= 1 };
+        self.stats.written(1);
+
+        // empty nodes are freed once the next level up no longer links to them
+        let mut empty = Vec::new();
+        for (level, &id) in path.iter().rev().enumerate() {
+            let node = self.node(id);
+            unsafe { RadixTree::publish(&mut (*node)[RadixTree::index(key, level as u32)], 0) };
+            self.stats.written(1);
+            if unsafe { (*node).iter().any(|&x| x != 0) } {
+                break;
+            }
+            empty.push(id);
+            if level as u32 == height - 1 {
+                self.set_root(NULL_PAGE, 0);
+            }
+        }
+        for id in empty {
+            self.stats.free(self.heap, id);
+        }
+
+        // drop roots that only have a first child
+        while self.height() > 1 {
+            let root = self.root();
+            if unsafe { (&(*self.node(root)))[1..].iter().any(|&x| x != 0) } {
+                break;
+            }
+            let child = unsafe { (*self.node(root))[0] };
+            let height = self.height() - 1;
+            self.set_root(child, height);
+            self.stats.free(self.heap, root);
+        }
+        Some(ret)
+    }
+
+    /// Calls `f` with every entry, in ascending key order.
+    pub fn for_each<F: FnMut(u64, u64)>(&self, f: F) {
+        self.for_each_pruned(|_, _| true, f)
+    }
+
+    // Like for_each, but skips the subtrees for whose range of keys (first and last)
+    // `visit` returns false.
+    pub(crate) fn for_each_pruned<V: FnMut(u64, u64) -> bool, F: FnMut(u64, u64)>(&self, mut visit: V, mut f: F) {
+        if self.height() != 0 {
+            self.walk(self.root(), self.height() - 1, 0, &mut visit, &mut f);
+        }
+    }
+
+    fn walk<V: FnMut(u64, u64) -> bool, F: FnMut(u64, u64)>(&self, id: PageId, level: u32, prefix: u64, visit: &mut V, f: &mut F) {
+        let node = self.node(id);
+        for i in 0..FANOUT {
+            let x = unsafe { (*node)[i] };
+            if x == 0 {
+                continue;
+            }
+            let key = prefix | (i as u64) << (level * BITS);
+            if level == 0 {
+                f(key, x);
+            } else if visit(key, key | ((1 << (level * BITS)) - 1)) {
+                self.walk(x, level - 1, key, visit, f);
+            }
+        }
+    }
+
+    /// Frees all pages of the tree, including the meta page.
+    pub fn destroy(self) {
+        if self.height() != 0 {
+            self.free_subtree(self.root(), self.height() - 1);
+        }
+        self.stats.free(self.heap, self.meta_page);
+    }
+
+    fn free_subtree(&self, id: PageId, level: u32) {
+        if level > 0 {
+            for i in 0..FANOUT {
+                let child = unsafe { (*self.node(id))[i] };
+                if child != NULL_PAGE {
+                    self.free_subtree(child, level - 1);
+                }
+            }
+        }
+        self.stats.free(self.heap, id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn radix_tree() {
+        let _ = fs::remove_file("/tmp/radix.bin");
+        let mapping = MappedHeap::open("/tmp/radix.bin").unwrap();
+
+        let mut tree = RadixTree::create(&mapping);
+        let keys = [0, 1, 511, 512, 1 << 20, 1 << 40, u64::MAX];
+        for (i, &key) in keys.iter().enumerate() {
+            assert_eq!(tree.insert(key, i as u64 + 1), None);
+        }
+        assert_eq!(tree.insert(512, 100), Some(4));
+        assert_eq!(tree.len(), keys.len() as u64);
+        assert_eq!(tree.get(513), None);
+        assert_eq!(tree.get(1 << 40), Some(6));
+
+        let mut seen = Vec::new();
+        tree.for_each(|k, _| seen.push(k));
+        assert_eq!(seen, keys);
+
+        let meta = tree.meta_page();
+        let mut tree = unsafe { RadixTree::open(&mapping, meta) };
+        for &key in keys.iter().rev().take(3) {
+            assert!(tree.remove(key).is_some());
+        }
+        assert_eq!(tree.remove(u64::MAX), None);
+        // back down to two levels
+        assert_eq!(tree.height(), 2);
+        for &key in &keys[..4] {
+            assert!(tree.remove(key).is_some());
+        }
+        assert!(tree.is_empty());
+        assert_eq!(tree.height(), 0);
+
+        // trees that only have the height field
+        tree.insert(1 << 20, 1);
+        unsafe { (*tree.meta).root &= (1 << HEIGHT_SHIFT) - 1 };
+        assert_eq!((tree.height(), tree.get(1 << 20)), (3, Some(1)));
+        tree.insert(1 << 30, 2);
+        assert_eq!(tree.height(), 4);
+        assert_eq!(tree.remove(1 << 30), Some(2));
+        assert_eq!((tree.height(), tree.get(1 << 20)), (3, Some(1)));
+        tree.remove(1 << 20);
+
+        tree.insert(5, 5);
+        tree.destroy();
+        // everything but the header is free again
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/radix.bin");
+    }
+}
@@ -0,0 +1,193 @@
+//! Regions: sub-allocators that can free everything allocated through them at once.
+//!
+//! A region records the id of every page it allocates in a chain of list pages,
+//! described by a meta page. Since that record lives in the heap, a region whose meta
+//! page id is kept somewhere (e.g. in a root slot) can still be torn down after a
+//! restart, so a crashed temporary computation doesn't leak its pages for good.
+
+use std::ptr;
+
+use {MappedHeap, PageId, NULL_PAGE, PAGESZ};
+
+const ENTRIES: usize = PAGESZ / 8 - 2;
+
+#[repr(C)]
+struct Meta {
+    list: PageId, // the list page ids are currently added to, linking to the older ones
+    len: u64,
+}
+
+#[repr(C)]
+struct List {
+    next: PageId,
+    n_entries: u64,
+    entries: [PageId; ENTRIES],
+}
+
+/// A group of pages that are freed together.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, Region};
+///
+/// let mapping = MappedHeap::open("/tmp/test-region.bin").unwrap();
+/// let region = Region::create(&mapping);
+/// for _ in 0..100 {
+///     let page_id = region.alloc();
+///     // ...
+/// }
+/// region.drop_all();
+/// ```
+pub struct Region<'a> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+}
+
+impl<'a> Region<'a> {
+    /// Creates a new, empty region.
+    pub fn create(heap: &'a MappedHeap) -> Region<'a> {
+        let meta_page = heap.alloc();
+        unsafe {
+            let region = Region::open(heap, meta_page);
+            ptr::write(region.meta, Meta { list: NULL_PAGE, len: 0 });
+            region
+        }
+    }
+
+    /// Opens an existing region by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, and nobody else may use
+    /// the region while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> Region<'a> {
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        Region { heap, meta, meta_page }
+    }
+
+    /// The id of the meta page, which identifies this region.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of pages allocated through the region.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether no pages have been allocated through the region.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn list(&self, id: PageId) -> *mut List {
+        self.heap.page(id).expect("corrupt region") as *mut List
+    }
+
+    /// Allocates a page that belongs to the region.
+    ///
+    /// The page is recorded after it has been allocated, so a crash in between
+    /// leaks it rather than leaving the region with a page it doesn't own.
+    pub fn alloc(&self) -> PageId {
+        let meta = unsafe { &mut *self.meta };
+        let full = meta.list == NULL_PAGE || unsafe { (*self.list(meta.list)).n_entries } as usize >= ENTRIES;
+        if full {
+            let id = self.heap.alloc();
+            unsafe {
+                (*self.list(id)).next = meta.list;
+                (*self.list(id)).n_entries = 0;
+            }
+            meta.list = id;
+        }
+
+        let id = self.heap.alloc();
+        let list = unsafe { &mut *self.list(meta.list) };
+        list.entries[list.n_entries as usize] = id;
+        list.n_entries += 1;
+        meta.len += 1;
+        id
+    }
+
+    // Calls `f` with every list page and the entries in it, newest first.
+    // `f` may free the list page.
+    fn walk<F: FnMut(PageId, &[PageId])>(&self, mut f: F) {
+        let mut id = unsafe { (*self.meta).list };
+        // bounded, so a corrupt chain can't make us loop forever
+        for _ in 0..self.heap.size() {
+            if id == NULL_PAGE {
+                break;
+            }
+            let list = unsafe { &*self.list(id) };
+            let n_entries = (list.n_entries as usize).min(ENTRIES);
+            let next = list.next;
+            f(id, &list.entries[..n_entries]);
+            id = next;
+        }
+    }
+
+    /// Frees every page allocated through the region, and the region itself.
+    pub fn drop_all(self) {
+        let heap = self.heap;
+        self.walk(|id, entries| {
+            for &x in entries {
+                heap.free(x);
+            }
+            // (last, the entries live in it)
+            heap.free(id);
+        });
+        heap.free(self.meta_page);
+    }
+
+    /// Frees the region itself, but keeps all pages allocated through it.
+    ///
+    /// Use this once whatever was built in the region is complete and should stay,
+    /// e.g. after swapping a rebuilt index in.
+    pub fn release(self) {
+        let heap = self.heap;
+        self.walk(|id, _| heap.free(id));
+        heap.free(self.meta_page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn regions() {
+        let _ = fs::remove_file("/tmp/region.bin");
+        let mapping = MappedHeap::open("/tmp/region.bin").unwrap();
+
+        let region = Region::create(&mapping);
+        let kept = Region::create(&mapping);
+        let ids: Vec<_> = (0..2000).map(|_| kept.alloc()).collect();
+        for _ in 0..2000 {
+            region.alloc();
+        }
+        assert_eq!(region.len(), 2000);
+
+        // as if after a restart
+        let meta = region.meta_page();
+        let region = unsafe { Region::open(&mapping, meta) };
+        let free = mapping.free_pages();
+        region.drop_all();
+        // the pages, 4 list pages and the meta page
+        assert_eq!(mapping.free_pages(), free + 2005);
+
+        kept.release();
+        assert_eq!(mapping.free_pages(), free + 2010);
+        for id in ids {
+            mapping.free(id);
+        }
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/region.bin");
+    }
+}
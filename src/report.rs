@@ -0,0 +1,136 @@
+use {MappedHeap, PageId};
+
+/// The number of equally sized regions `FragmentationReport::free_pages_by_region`
+/// splits the file into.
+pub const REPORT_REGIONS: usize = 16;
+
+/// A snapshot of how the free pages are distributed over the file.
+///
+/// Obtained with `MappedHeap::fragmentation_report`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FragmentationReport {
+    /// The number of pages in the file.
+    pub size: u64,
+    /// The number of free pages (including the pages holding the freelists).
+    pub free_pages: u64,
+    /// The number of maximal runs of consecutive free pages.
+    pub free_runs: u64,
+    /// The length of the longest run of consecutive free pages.
+    pub largest_free_run: u64,
+    /// The length of the run of free pages at the very end of the file (if any).
+    ///
+    /// This is the part of the file that could be given back to the filesystem
+    /// by truncating it.
+    pub tail_free_pages: u64,
+    /// The number of free pages in each of `REPORT_REGIONS` equally sized regions of the file.
+    pub free_pages_by_region: [u64; REPORT_REGIONS],
+}
+
+impl FragmentationReport {
+    /// The fraction of the file that is free.
+    pub fn free_ratio(&self) -> f64 {
+        self.free_pages as f64 / self.size as f64
+    }
+
+    /// The fraction of the free pages that are *not* part of the longest free run.
+    ///
+    /// 0 means all free space is contiguous, values approaching 1 mean that free
+    /// pages are scattered all over the file.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free_pages == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free_run as f64 / self.free_pages as f64
+        }
+    }
+
+    /// Whether compacting the heap would pay off, i.e. whether the fraction of the
+    /// file made up of scattered free pages (free pages outside the longest run)
+    /// is at least `threshold`.
+    ///
+    /// Maintenance jobs can poll this to decide when to compact.
+    /// A threshold around 0.25 is a sensible starting point.
+    pub fn should_compact(&self, threshold: f64) -> bool {
+        (self.free_pages - self.largest_free_run) as f64 / self.size as f64 >= threshold
+    }
+}
+
+impl MappedHeap {
+    /// Analyzes the freelists and reports how fragmented the free space is.
+    ///
+    /// This walks all freelists while holding all their locks, so all allocations and
+    /// frees (in all processes) stall for the duration of the walk. The cost is linear
+    /// in the number of free pages, plus sorting them.
+    ///
+    /// # Panics
+    ///
+    /// * May panic if the freelist structure is corrupt.
+    pub fn fragmentation_report(&self) -> FragmentationReport {
+        let free = self.sorted_free_pages();
+        let size = self.size();
+        let mut report = FragmentationReport {
+            size,
+            free_pages: free.len() as u64,
+            free_runs: 0,
+            largest_free_run: 0,
+            tail_free_pages: 0,
+            free_pages_by_region: [0; REPORT_REGIONS],
+        };
+        let mut run = 0;
+        for (i, &id) in free.iter().enumerate() {
+            if i > 0 && free[i - 1] + 1 == id {
+                run += 1;
+            } else {
+                report.free_runs += 1;
+                run = 1;
+            }
+            report.largest_free_run = report.largest_free_run.max(run);
+            report.free_pages_by_region[(id * REPORT_REGIONS as u64 / size) as usize] += 1;
+        }
+        if free.last() == Some(&(size - 1)) {
+            report.tail_free_pages = run;
+        }
+        report
+    }
+
+    // All free pages (including the freelist pages) in ascending order, see
+    // fragmentation_report for the cost.
+    pub(crate) fn sorted_free_pages(&self) -> Vec<PageId> {
+        let freelists = self.lock_freelists();
+        let free = self.collect_free_pages(&freelists);
+        self.unlock_freelists(freelists);
+        free
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn fragmentation_report() {
+        let _ = fs::remove_file("/tmp/report.bin");
+        let mapping = MappedHeap::open("/tmp/report.bin").unwrap();
+
+        let mut ids: Vec<_> = (0..1000).map(|_| mapping.alloc()).collect();
+        ids.sort();
+        let report = mapping.fragmentation_report();
+        assert_eq!(report.size, 1024);
+        assert_eq!(report.free_pages, mapping.free_pages());
+        assert!(report.fragmentation() < 0.1);
+        assert!(!report.should_compact(0.25));
+
+        for &id in ids.iter().step_by(2) {
+            mapping.free(id);
+        }
+        let report = mapping.fragmentation_report();
+        assert_eq!(report.free_pages, mapping.free_pages());
+        assert!(report.free_runs > 500);
+        assert!(report.fragmentation() > 0.9);
+        assert!(report.should_compact(0.25));
+        assert_eq!(report.free_pages_by_region.iter().sum::<u64>(), report.free_pages);
+
+        let _ = fs::remove_file("/tmp/report.bin");
+    }
+}
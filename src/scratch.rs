@@ -0,0 +1,98 @@
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use libc::{c_void, mmap, munmap, off_t, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+use {MappedHeap, PageId, PAGESZ};
+
+/// A private copy-on-write view of a page, see `MappedHeap::page_scratch`.
+///
+/// Modifications only ever affect the view itself, never the file or other mappings.
+/// The view shows the page as it was when it was first written to through the view;
+/// until then, changes made to the page through the heap may or may not show.
+pub struct ScratchPage {
+    addr: *mut [u8; PAGESZ],
+}
+
+impl Deref for ScratchPage {
+    type Target = [u8; PAGESZ];
+
+    fn deref(&self) -> &[u8; PAGESZ] {
+        unsafe { &*self.addr }
+    }
+}
+
+impl DerefMut for ScratchPage {
+    fn deref_mut(&mut self) -> &mut [u8; PAGESZ] {
+        unsafe { &mut *self.addr }
+    }
+}
+
+impl Drop for ScratchPage {
+    fn drop(&mut self) {
+        unsafe { munmap(self.addr as *mut c_void, PAGESZ) };
+    }
+}
+
+impl MappedHeap {
+    /// Maps a page privately (`MAP_PRIVATE`), for modifications that must never reach the file.
+    ///
+    /// Useful for speculative computations, or for testing recovery code against a
+    /// production file without any risk of changing it. Returns `None` if the page
+    /// doesn't exist within the file.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mappedheap::MappedHeap;
+    ///
+    /// let mapping = MappedHeap::open("/tmp/test-scratch.bin").unwrap();
+    /// let page_id = mapping.alloc();
+    /// let mut scratch = mapping.page_scratch(page_id).unwrap().unwrap();
+    /// scratch[0] = 42;
+    /// assert_eq!(unsafe { (*mapping.page(page_id).unwrap())[0] }, 0);
+    /// mapping.free(page_id);
+    /// ```
+    pub fn page_scratch(&self, id: PageId) -> io::Result<Option<ScratchPage>> {
+        if id >= self.size() {
+            return Ok(None);
+        }
+        let addr = unsafe {
+            mmap(ptr::null_mut(), PAGESZ, PROT_READ | PROT_WRITE, MAP_PRIVATE,
+                 self.as_raw_fd(), (id * PAGESZ as u64) as off_t)
+        };
+        if addr == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(ScratchPage { addr: addr as *mut _ }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn scratch_pages() {
+        let _ = fs::remove_file("/tmp/scratch.bin");
+        let mapping = MappedHeap::open("/tmp/scratch.bin").unwrap();
+        let id = mapping.alloc();
+        unsafe { (&mut *mapping.page(id).unwrap())[..3].copy_from_slice(b"abc") };
+
+        let mut scratch = mapping.page_scratch(id).unwrap().unwrap();
+        assert_eq!(&scratch[..3], b"abc");
+        scratch[..3].copy_from_slice(b"xyz");
+        assert_eq!(unsafe { &(&*mapping.page(id).unwrap())[..3] }, b"abc");
+        drop(scratch);
+
+        drop(mapping);
+        let mapping = MappedHeap::open("/tmp/scratch.bin").unwrap();
+        assert_eq!(unsafe { &(&*mapping.page(id).unwrap())[..3] }, b"abc");
+        assert!(mapping.page_scratch(mapping.size()).unwrap().is_none());
+
+        let _ = fs::remove_file("/tmp/scratch.bin");
+    }
+}
@@ -0,0 +1,326 @@
+//! A slotted page layout for variable-length records within a single page.
+//!
+//! The page starts with a small header, followed by an optional area reserved for the
+//! caller and the slot directory, which grows upwards. Record data (cells) is placed at
+//! the end of the page and grows downwards:
+//!
+//! ```text
+//! | header | reserved | slot 0 | slot 1 | ... -> free space <- ... | cell 1 | cell 0 |
+//! ```
+//!
+//! Slot numbers are stable: removing a record leaves its slot empty (to be reused by
+//! later inserts) and compaction moves cells without renumbering them.
+//!
+//! All offsets are read from the page itself, which may be shared with other processes.
+//! Inconsistent values never cause out-of-bounds accesses, records with invalid
+//! bounds simply read as absent.
+
+use std::cmp;
+
+use PAGESZ;
+
+const HEADER_LEN: usize = 8;
+const SLOT_LEN: usize = 4;
+
+// header fields (u16 each)
+const N_SLOTS: usize = 0;
+const CELL_START: usize = 2;
+const GARBAGE: usize = 4;
+const RESERVED: usize = 6;
+
+/// A view of a page as a slotted page.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{SlottedPage, PAGESZ};
+///
+/// let mut page = [0u8; PAGESZ];
+/// let mut slotted = SlottedPage::init(&mut page, 0);
+/// let slot = slotted.insert(b"hello").unwrap();
+/// assert_eq!(slotted.get(slot), Some(&b"hello"[..]));
+/// ```
+pub struct SlottedPage<'a> {
+    page: &'a mut [u8; PAGESZ],
+}
+
+impl<'a> SlottedPage<'a> {
+    /// Formats `page` as an empty slotted page, reserving `reserved` bytes after the
+    /// header for the caller's own use (see `reserved`).
+    ///
+    /// # Panics
+    ///
+    /// * If `reserved` doesn't leave room for the header.
+    pub fn init(page: &'a mut [u8; PAGESZ], reserved: usize) -> SlottedPage<'a> {
+        assert!(reserved <= PAGESZ - HEADER_LEN);
+        let mut ret = SlottedPage { page };
+        ret.set(N_SLOTS, 0);
+        ret.set(CELL_START, PAGESZ);
+        ret.set(GARBAGE, 0);
+        ret.set(RESERVED, reserved);
+        ret
+    }
+
+    /// Interprets an existing page (previously formatted by `init`) as a slotted page.
+    pub fn new(page: &'a mut [u8; PAGESZ]) -> SlottedPage<'a> {
+        SlottedPage { page }
+    }
+
+    fn field(&self, field: usize) -> usize {
+        u16::from_le_bytes([self.page[field], self.page[field + 1]]) as usize
+    }
+
+    fn set(&mut self, field: usize, value: usize) {
+        debug_assert!(value <= u16::MAX as usize);
+        self.page[field..field + 2].copy_from_slice(&(value as u16).to_le_bytes());
+    }
+
+    fn add_garbage(&mut self, len: usize) {
+        let garbage = cmp::min(self.field(GARBAGE) + len, PAGESZ);
+        self.set(GARBAGE, garbage);
+    }
+
+    fn dir_start(&self) -> usize {
+        HEADER_LEN + cmp::min(self.field(RESERVED), PAGESZ - HEADER_LEN)
+    }
+
+    fn dir_end(&self) -> usize {
+        cmp::min(self.dir_start() + self.field(N_SLOTS) * SLOT_LEN, PAGESZ)
+    }
+
+    fn contiguous_space(&self) -> usize {
+        cmp::min(self.field(CELL_START), PAGESZ).saturating_sub(self.dir_end())
+    }
+
+    // (offset, len) of the cell in the given slot, if it is in use and sane
+    fn cell(&self, slot: u16) -> Option<(usize, usize)> {
+        if slot as usize >= self.slots() as usize {
+            return None;
+        }
+        let at = self.dir_start() + slot as usize * SLOT_LEN;
+        if at + SLOT_LEN > PAGESZ {
+            return None;
+        }
+        let offset = self.field(at);
+        let len = self.field(at + 2);
+        if offset == 0 || offset < self.dir_end() || offset + len > PAGESZ {
+            None
+        } else {
+            Some((offset, len))
+        }
+    }
+
+    fn set_cell(&mut self, slot: u16, offset: usize, len: usize) {
+        let at = self.dir_start() + slot as usize * SLOT_LEN;
+        self.set(at, offset);
+        self.set(at + 2, len);
+    }
+
+    /// The caller's reserved area.
+    pub fn reserved(&self) -> &[u8] {
+        &self.page[HEADER_LEN..self.dir_start()]
+    }
+
+    /// The caller's reserved area.
+    pub fn reserved_mut(&mut self) -> &mut [u8] {
+        let end = self.dir_start();
+        &mut self.page[HEADER_LEN..end]
+    }
+
+    /// The number of slots (including empty ones).
+    pub fn slots(&self) -> u16 {
+        ((self.dir_end() - self.dir_start()) / SLOT_LEN) as u16
+    }
+
+    /// The record in the given slot, if any.
+    pub fn get(&self, slot: u16) -> Option<&[u8]> {
+        self.cell(slot).map(|(offset, len)| &self.page[offset..offset + len])
+    }
+
+    /// The record in the given slot, if any.
+    pub fn get_mut(&mut self, slot: u16) -> Option<&mut [u8]> {
+        match self.cell(slot) {
+            Some((offset, len)) => Some(&mut self.page[offset..offset + len]),
+            None => None,
+        }
+    }
+
+    /// The number of bytes available for new records (after compaction, if necessary).
+    ///
+    /// Each new record may additionally need `4` bytes for its slot.
+    pub fn free_space(&self) -> usize {
+        self.contiguous_space() + self.field(GARBAGE)
+    }
+
+    // Reserves `len` bytes of cell space, compacting if needed.
+    // `extra` is the number of bytes the slot directory is about to grow by.
+    fn alloc_cell(&mut self, len: usize, extra: usize) -> Option<usize> {
+        if self.contiguous_space() < len + extra {
+            if self.free_space() < len + extra {
+                return None;
+            }
+            self.compact();
+            if self.contiguous_space() < len + extra {
+                return None;
+            }
+        }
+        let offset = cmp::min(self.field(CELL_START), PAGESZ) - len;
+        self.set(CELL_START, offset);
+        Some(offset)
+    }
+
+    /// Inserts a record and returns its slot, or `None` if the page is full.
+    pub fn insert(&mut self, data: &[u8]) -> Option<u16> {
+        let free_slot = (0..self.slots()).find(|&x| self.cell(x).is_none());
+        let (slot, extra) = match free_slot {
+            Some(slot) => (slot, 0),
+            None => (self.slots(), SLOT_LEN),
+        };
+
+        let offset = self.alloc_cell(data.len(), extra)?;
+        if extra != 0 {
+            let n_slots = self.slots() as usize + 1;
+            self.set(N_SLOTS, n_slots);
+        }
+        self.page[offset..offset + data.len()].copy_from_slice(data);
+        self.set_cell(slot, offset, data.len());
+        Some(slot)
+    }
+
+    /// Replaces the record in the given slot (keeping the slot number).
+    ///
+    /// Returns false (leaving the page unchanged) if the slot is empty or the
+    /// new record doesn't fit.
+    pub fn update(&mut self, slot: u16, data: &[u8]) -> bool {
+        let (offset, len) = match self.cell(slot) {
+            Some(x) => x,
+            None => return false,
+        };
+
+        if data.len() <= len {
+            self.page[offset..offset + data.len()].copy_from_slice(data);
+            self.set_cell(slot, offset, data.len());
+            self.add_garbage(len - data.len());
+            return true;
+        }
+
+        // give up the old cell first so compaction may reclaim it
+        let old = self.page[offset..offset + len].to_vec();
+        self.set_cell(slot, 0, 0);
+        self.add_garbage(len);
+        let (ret, offset, data) = match self.alloc_cell(data.len(), 0) {
+            Some(offset) => (true, offset, data),
+            // the old record was freed, so it definitely fits back in
+            None => (false, self.alloc_cell(len, 0).expect("page is corrupt"), &old[..]),
+        };
+        self.page[offset..offset + data.len()].copy_from_slice(data);
+        self.set_cell(slot, offset, data.len());
+        ret
+    }
+
+    /// Removes the record in the given slot. Returns false if the slot was empty.
+    pub fn remove(&mut self, slot: u16) -> bool {
+        let len = match self.cell(slot) {
+            Some((_, len)) => len,
+            None => return false,
+        };
+        self.set_cell(slot, 0, 0);
+        self.add_garbage(len);
+
+        // trailing empty slots can go away entirely
+        let mut n_slots = self.slots();
+        while n_slots > 0 && self.cell(n_slots - 1).is_none() {
+            n_slots -= 1;
+        }
+        self.set(N_SLOTS, n_slots as usize);
+        true
+    }
+
+    /// Moves all records to the end of the page, turning all space freed by
+    /// removals and updates into contiguous free space.
+    pub fn compact(&mut self) {
+        let mut copy = *self.page;
+        let old = SlottedPage::new(&mut copy);
+
+        let dir_end = self.dir_end();
+        let mut cell_start = PAGESZ;
+        for slot in 0..old.slots() {
+            match old.cell(slot) {
+                // (overlapping cells in a corrupt page might not all fit)
+                Some((offset, len)) if len <= cell_start - dir_end => {
+                    cell_start -= len;
+                    self.page[cell_start..cell_start + len].copy_from_slice(&old.page[offset..offset + len]);
+                    self.set_cell(slot, cell_start, len);
+                }
+                _ => self.set_cell(slot, 0, 0),
+            }
+        }
+        self.set(CELL_START, cell_start);
+        self.set(GARBAGE, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut page = [0u8; PAGESZ];
+        let mut slotted = SlottedPage::init(&mut page, 16);
+        assert_eq!(slotted.reserved().len(), 16);
+
+        let a = slotted.insert(b"alpha").unwrap();
+        let b = slotted.insert(b"").unwrap();
+        let c = slotted.insert(b"gamma").unwrap();
+        assert_eq!((a, b, c), (0, 1, 2));
+        assert_eq!(slotted.get(b), Some(&b""[..]));
+
+        assert!(slotted.remove(a));
+        assert!(!slotted.remove(a));
+        assert_eq!(slotted.get(a), None);
+        // slot numbers are reused
+        assert_eq!(slotted.insert(b"delta").unwrap(), a);
+
+        assert!(slotted.remove(c));
+        assert_eq!(slotted.slots(), 2);
+        assert_eq!(slotted.get(a), Some(&b"delta"[..]));
+    }
+
+    #[test]
+    fn fill_update_and_compact() {
+        let mut page = [0u8; PAGESZ];
+        let mut slotted = SlottedPage::init(&mut page, 0);
+
+        let record = [7u8; 100];
+        let mut slots = Vec::new();
+        while let Some(slot) = slotted.insert(&record) {
+            slots.push(slot);
+        }
+        assert_eq!(slots.len(), (PAGESZ - HEADER_LEN) / (100 + SLOT_LEN));
+
+        // free every other record, then grow the rest, which requires compaction
+        for &slot in slots.iter().step_by(2) {
+            assert!(slotted.remove(slot));
+        }
+        for &slot in slots.iter().skip(1).step_by(2) {
+            assert!(slotted.update(slot, &[slot as u8; 150]));
+        }
+        for &slot in slots.iter().skip(1).step_by(2) {
+            assert_eq!(slotted.get(slot), Some(&[slot as u8; 150][..]));
+        }
+        assert!(!slotted.update(slots[1], &[0; PAGESZ]));
+        assert_eq!(slotted.get(slots[1]), Some(&[slots[1] as u8; 150][..]));
+    }
+
+    #[test]
+    fn corrupt_header() {
+        let mut page = [0xffu8; PAGESZ];
+        let mut slotted = SlottedPage::new(&mut page);
+        for slot in 0..slotted.slots() {
+            let _ = slotted.get(slot);
+        }
+        let _ = slotted.insert(b"x");
+        slotted.compact();
+    }
+}
@@ -0,0 +1,217 @@
+//! A persistent index of points in the plane, for rectangle queries.
+//!
+//! Points are stored in a `RadixTree` under their z-order (Morton) code, which
+//! interleaves the bits of the two coordinates. Every subtree of the radix tree then
+//! covers an aligned block of z-codes, and every such block is a rectangle in the
+//! plane, so a query only descends into the subtrees whose rectangle overlaps the one
+//! queried.
+
+use {MappedHeap, PageId, RadixTree, StructureStats};
+
+// Spreads the bits of `x` out to the even bits.
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | x << 16) & 0x0000_ffff_0000_ffff;
+    x = (x | x << 8) & 0x00ff_00ff_00ff_00ff;
+    x = (x | x << 4) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | x << 2) & 0x3333_3333_3333_3333;
+    (x | x << 1) & 0x5555_5555_5555_5555
+}
+
+// The inverse of spread, ignoring the odd bits.
+fn gather(z: u64) -> u32 {
+    let mut x = z & 0x5555_5555_5555_5555;
+    x = (x | x >> 1) & 0x3333_3333_3333_3333;
+    x = (x | x >> 2) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | x >> 4) & 0x00ff_00ff_00ff_00ff;
+    x = (x | x >> 8) & 0x0000_ffff_0000_ffff;
+    (x | x >> 16) as u32
+}
+
+fn encode(x: u32, y: u32) -> u64 {
+    spread(x) | spread(y) << 1
+}
+
+fn decode(z: u64) -> (u32, u32) {
+    (gather(z), gather(z >> 1))
+}
+
+/// A map from points with `u32` coordinates to `u64` values, stored in a `MappedHeap`,
+/// that can list all points within a rectangle.
+///
+/// Each point holds at most one value, zero can't be stored as a value. To index
+/// areas (e.g. bounding boxes), insert a representative point of each, such as its
+/// center, and widen queries by the largest extent.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, SpatialIndex};
+///
+/// let mapping = MappedHeap::open("/tmp/test-spatial.bin").unwrap();
+/// let mut index = SpatialIndex::create(&mapping);
+/// index.insert(10, 10, 1);
+/// index.insert(12, 40, 2);
+/// index.insert(500, 20, 3);
+/// let mut found = Vec::new();
+/// index.for_each_in((0, 0), (100, 100), |x, y, value| found.push((x, y, value)));
+/// assert_eq!(found, vec![(10, 10, 1), (12, 40, 2)]);
+/// index.destroy();
+/// ```
+pub struct SpatialIndex<'a> {
+    tree: RadixTree<'a>,
+}
+
+impl<'a> SpatialIndex<'a> {
+    /// Creates a new, empty index.
+    pub fn create(heap: &'a MappedHeap) -> SpatialIndex<'a> {
+        SpatialIndex { tree: RadixTree::create(heap) }
+    }
+
+    /// Opens an existing index by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, and nobody else may modify
+    /// the index while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> SpatialIndex<'a> {
+        SpatialIndex { tree: RadixTree::open(heap, meta_page) }
+    }
+
+    /// The id of the meta page, which identifies this index.
+    pub fn meta_page(&self) -> PageId {
+        self.tree.meta_page()
+    }
+
+    /// The number of points.
+    pub fn len(&self) -> u64 {
+        self.tree.len()
+    }
+
+    /// Whether the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Counters of the pages this handle allocated, freed and wrote, see `StructureStats`.
+    pub fn stats(&self) -> StructureStats {
+        self.tree.stats()
+    }
+
+    /// Looks up the value of a point.
+    pub fn get(&self, x: u32, y: u32) -> Option<u64> {
+        self.tree.get(encode(x, y))
+    }
+
+    /// Inserts or replaces a point, returning its previous value.
+    ///
+    /// # Panics
+    ///
+    /// * If `value` is zero.
+    pub fn insert(&mut self, x: u32, y: u32, value: u64) -> Option<u64> {
+        self.tree.insert(encode(x, y), value)
+    }
+
+    /// Removes a point, returning its value.
+    pub fn remove(&mut self, x: u32, y: u32) -> Option<u64> {
+        self.tree.remove(encode(x, y))
+    }
+
+    /// Calls `f` with every point (and its value) within the rectangle from `min` to
+    /// `max` (both inclusive), in z-order.
+    pub fn for_each_in<F: FnMut(u32, u32, u64)>(&self, min: (u32, u32), max: (u32, u32), mut f: F) {
+        let overlaps = |first: (u32, u32), last: (u32, u32)| {
+            first.0 <= max.0 && last.0 >= min.0 && first.1 <= max.1 && last.1 >= min.1
+        };
+        self.tree.for_each_pruned(|first, last| overlaps(decode(first), decode(last)), |z, value| {
+            let (x, y) = decode(z);
+            if overlaps((x, y), (x, y)) {
+                f(x, y, value);
+            }
+        });
+    }
+
+    /// Calls `f` with every point and its value, in z-order.
+    pub fn for_each<F: FnMut(u32, u32, u64)>(&self, mut f: F) {
+        self.tree.for_each(|z, value| {
+            let (x, y) = decode(z);
+            f(x, y, value);
+        });
+    }
+
+    /// Frees all pages of the index, including the meta page.
+    pub fn destroy(self) {
+        self.tree.destroy();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use rand::{self, Rng};
+
+    #[test]
+    fn z_order() {
+        for &(x, y) in &[(0, 0), (1, 0), (0, 1), (u32::MAX, 0), (0x1234_5678, 0x9abc_def0), (u32::MAX, u32::MAX)] {
+            assert_eq!(decode(encode(x, y)), (x, y));
+        }
+        assert_eq!(encode(3, 0), 0b101);
+        assert_eq!(encode(0, 3), 0b1010);
+    }
+
+    #[test]
+    fn spatial_index() {
+        let _ = fs::remove_file("/tmp/spatial.bin");
+        let mapping = MappedHeap::open("/tmp/spatial.bin").unwrap();
+
+        let mut index = SpatialIndex::create(&mapping);
+        let mut expected = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 1..3000u64 {
+            // clustered, and spread over the whole plane
+            let (x, y) = match i % 2 {
+                0 => (rng.gen_range(1000, 2000), rng.gen_range(5000, 6000)),
+                _ => (rng.gen::<u32>(), rng.gen::<u32>()),
+            };
+            assert_eq!(index.insert(x, y, i), expected.insert((x, y), i));
+        }
+        assert_eq!(index.len(), expected.len() as u64);
+        let meta = index.meta_page();
+        let mut index = unsafe { SpatialIndex::open(&mapping, meta) };
+
+        for _ in 0..100 {
+            let (a, b) = (rng.gen_range(0, 3000), rng.gen_range(4000, 7000));
+            let (min, max) = ((a, b), (a + rng.gen_range(0, 1000), b + rng.gen_range(0, 1000)));
+            let mut found = Vec::new();
+            index.for_each_in(min, max, |x, y, value| found.push((x, y, value)));
+            found.sort();
+            let inside: Vec<_> = expected.iter().filter(|&(&(x, y), _)| x >= min.0 && x <= max.0 && y >= min.1 && y <= max.1)
+                                                .map(|(&(x, y), &value)| (x, y, value)).collect();
+            assert_eq!(found, inside);
+        }
+        let mut all = 0;
+        index.for_each_in((0, 0), (u32::MAX, u32::MAX), |_, _, _| all += 1);
+        assert_eq!(all, expected.len());
+
+        let points: Vec<_> = expected.keys().cloned().collect();
+        for &(x, y) in &points[..points.len() / 2] {
+            assert_eq!(index.remove(x, y), expected.remove(&(x, y)));
+            assert_eq!(index.get(x, y), None);
+        }
+        let mut rest = Vec::new();
+        index.for_each(|x, y, value| rest.push(((x, y), value)));
+        rest.sort();
+        assert!(rest.into_iter().eq(expected.into_iter()));
+
+        index.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/spatial.bin");
+    }
+}
@@ -0,0 +1,133 @@
+//! Per-structure I/O counters, to quantify the write amplification of a data
+//! structure (pages written per logical operation) and compare it to alternatives.
+//!
+//! Counters live in the handle, so they start at zero whenever a structure is
+//! opened and are never stored in the file.
+//!
+//! With the feature `fault-stats`, every page access also looks up whether the page
+//! is mapped in `/proc/self/pagemap`, counting the accesses that are going to fault.
+//! This costs a system call per access, so it's only meant for measurements.
+
+use std::cell::Cell;
+
+use {MappedHeap, PageId};
+
+/// I/O counters of a data structure, see e.g. `RadixTree::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StructureStats {
+    /// The number of pages allocated.
+    pub pages_allocated: u64,
+    /// The number of pages freed.
+    pub pages_freed: u64,
+    /// The number of pages dirtied, counting every page once per operation that
+    /// modifies it (however many of its bytes change).
+    pub pages_written: u64,
+    /// The number of page accesses that page faulted (Linux only, and always zero
+    /// without the feature `fault-stats`).
+    pub faults: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Stats(Cell<StructureStats>);
+
+impl Stats {
+    fn update<F: FnOnce(&mut StructureStats)>(&self, f: F) {
+        let mut stats = self.0.get();
+        f(&mut stats);
+        self.0.set(stats);
+    }
+
+    pub(crate) fn get(&self) -> StructureStats {
+        self.0.get()
+    }
+
+    pub(crate) fn alloc(&self, heap: &MappedHeap) -> PageId {
+        self.update(|x| x.pages_allocated += 1);
+        heap.alloc()
+    }
+
+    pub(crate) fn free(&self, heap: &MappedHeap, id: PageId) {
+        self.update(|x| x.pages_freed += 1);
+        heap.free(id);
+    }
+
+    pub(crate) fn written(&self, pages: u64) {
+        self.update(|x| x.pages_written += pages);
+    }
+
+    // Called before accessing the page at `addr`.
+    #[cfg(all(feature = "fault-stats", target_os = "linux"))]
+    pub(crate) fn access(&self, addr: usize) {
+        if !mapped(addr) {
+            self.update(|x| x.faults += 1);
+        }
+    }
+
+    #[cfg(not(all(feature = "fault-stats", target_os = "linux")))]
+    pub(crate) fn access(&self, _: usize) {}
+}
+
+// Whether the page at `addr` is in our page tables (errors count as yes).
+#[cfg(all(feature = "fault-stats", target_os = "linux"))]
+fn mapped(addr: usize) -> bool {
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+    use PAGESZ;
+
+    thread_local!(static PAGEMAP: Option<File> = File::open("/proc/self/pagemap").ok());
+    PAGEMAP.with(|pagemap| {
+        let mut entry = [0; 8];
+        match *pagemap {
+            // bit 63 is "present"
+            Some(ref file) if file.read_exact_at(&mut entry, (addr / PAGESZ * 8) as u64).is_ok() => {
+                u64::from_ne_bytes(entry) >> 63 == 1
+            }
+            _ => true,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use {PostingList, RadixTree};
+
+    #[test]
+    fn structure_stats() {
+        let _ = fs::remove_file("/tmp/stats.bin");
+        let mapping = MappedHeap::open("/tmp/stats.bin").unwrap();
+
+        let mut tree = RadixTree::create(&mapping);
+        tree.insert(1, 1); // new leaf and meta page
+        tree.insert(2, 1); // leaf and meta page
+        tree.insert(1, 5); // just the leaf
+        // (whether new nodes fault depends on alloc zeroing them, faults are checked below)
+        assert_eq!(tree.stats(), StructureStats { pages_allocated: 1, pages_freed: 0, pages_written: 5, ..tree.stats() });
+        // grows a level: new root, new leaf and meta page
+        tree.insert(1 << 9, 1);
+        assert_eq!((tree.stats().pages_allocated, tree.stats().pages_written), (3, 8));
+        // meta page, both levels, then both go away again
+        tree.remove(1 << 9);
+        assert_eq!((tree.stats().pages_freed, tree.stats().pages_written), (2, 11));
+        tree.destroy();
+
+        let mut list = PostingList::create(&mapping);
+        for doc in 1..4 {
+            list.append(doc);
+        }
+        assert_eq!((list.stats().pages_allocated, list.stats().pages_written), (1, 6));
+        #[cfg(all(feature = "fault-stats", target_os = "linux"))]
+        {
+            mapping.flush().unwrap();
+            for id in 1..mapping.size() {
+                mapping.evict_page(id);
+            }
+            list.iter().count();
+            assert!(list.stats().faults > 0);
+        }
+        list.destroy();
+
+        let _ = fs::remove_file("/tmp/stats.bin");
+    }
+}
@@ -0,0 +1,238 @@
+//! A persistent string interning table.
+//!
+//! Strings are appended to a chain of blob pages and identified by their position in
+//! the file, so an id is a plain `u64` that never changes. A radix tree maps (part of)
+//! the hash of every string to the first of the strings sharing it, each of which links
+//! to the next, so interning a known string finds its id without scanning the blobs.
+
+use std::{cmp, mem, ptr, slice, str};
+
+use {MappedHeap, PageId, RadixTree, NULL_PAGE, PAGESZ};
+
+// Hashes are cut down to this many bits to keep the index two levels high.
+const HASH_BITS: u32 = 18;
+
+/// The longest string a `StringTable` can hold, in bytes.
+pub const MAX_STRING_LEN: usize = PAGESZ - mem::size_of::<Blob>() - ENTRY_SIZE;
+
+#[repr(C)]
+struct Meta {
+    index: PageId,
+    blobs: PageId, // the page strings are currently appended to, linking to the older ones
+    len: u64,
+}
+
+#[repr(C)]
+struct Blob {
+    next: PageId,
+    used: u64, // bytes, including this header
+}
+
+// Precedes every string in its blob page.
+#[repr(C)]
+struct Entry {
+    next: u64, // the next string with the same hash
+    len: u16,
+}
+
+const ENTRY_SIZE: usize = mem::size_of::<Entry>();
+
+// FNV-1a, which unlike std's hashers is guaranteed to stay the same
+fn hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+/// Maps strings to `u64` ids and back, storing every distinct string once.
+///
+/// Ids are never zero, so zero can be used to mean "no string".
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, StringTable};
+///
+/// let mapping = MappedHeap::open("/tmp/test-strings.bin").unwrap();
+/// let mut table = StringTable::create(&mapping);
+/// let id = table.intern("hello");
+/// assert_eq!(table.intern("hello"), id);
+/// assert_eq!(table.resolve(id), "hello");
+/// table.destroy();
+/// ```
+pub struct StringTable<'a> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+    index: RadixTree<'a>,
+}
+
+impl<'a> StringTable<'a> {
+    /// Creates a new, empty table.
+    pub fn create(heap: &'a MappedHeap) -> StringTable<'a> {
+        let meta_page = heap.alloc();
+        let index = RadixTree::create(heap).meta_page();
+        unsafe {
+            ptr::write(heap.page(meta_page).unwrap() as *mut Meta, Meta { index, blobs: NULL_PAGE, len: 0 });
+            StringTable::open(heap, meta_page)
+        }
+    }
+
+    /// Opens an existing table by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, and nobody else may modify
+    /// the table while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> StringTable<'a> {
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        let index = RadixTree::open(heap, (*meta).index);
+        StringTable { heap, meta, meta_page, index }
+    }
+
+    /// The id of the meta page, which identifies this table.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of distinct strings.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn entry(&self, id: u64) -> (*mut Entry, &'a str) {
+        let (page, offset) = (id / PAGESZ as u64, id as usize % PAGESZ);
+        assert!(page != NULL_PAGE && offset + ENTRY_SIZE <= PAGESZ, "invalid string id");
+        let page = self.heap.page(page).expect("invalid string id") as *mut u8;
+        unsafe {
+            let entry = page.add(offset) as *mut Entry;
+            let len = cmp::min((*entry).len as usize, PAGESZ - offset - ENTRY_SIZE);
+            let bytes = slice::from_raw_parts(page.add(offset + ENTRY_SIZE), len);
+            (entry, str::from_utf8(bytes).expect("corrupt string table"))
+        }
+    }
+
+    /// Looks up the id of a string without adding it.
+    pub fn get(&self, s: &str) -> Option<u64> {
+        let mut id = self.index.get(hash(s) >> (64 - HASH_BITS)).unwrap_or(0);
+        // bounded, so a corrupt chain can't make us loop forever
+        for _ in 0..self.len() {
+            if id == 0 {
+                break;
+            }
+            let (entry, x) = self.entry(id);
+            if x == s {
+                return Some(id);
+            }
+            id = unsafe { (*entry).next };
+        }
+        None
+    }
+
+    /// Returns the id of a string, adding it to the table if necessary.
+    ///
+    /// # Panics
+    ///
+    /// * If `s` is longer than `MAX_STRING_LEN`.
+    pub fn intern(&mut self, s: &str) -> u64 {
+        assert!(s.len() <= MAX_STRING_LEN, "string too long to intern");
+        if let Some(id) = self.get(s) {
+            return id;
+        }
+
+        let meta = unsafe { &mut *self.meta };
+        let size = (ENTRY_SIZE + s.len() + 7) & !7;
+        let fits = meta.blobs != NULL_PAGE && unsafe { (*self.blob(meta.blobs)).used } as usize + size <= PAGESZ;
+        if !fits {
+            let page = self.heap.alloc();
+            unsafe { ptr::write(self.blob(page), Blob { next: meta.blobs, used: mem::size_of::<Blob>() as u64 }) };
+            meta.blobs = page;
+        }
+
+        let blob = unsafe { &mut *self.blob(meta.blobs) };
+        let id = meta.blobs * PAGESZ as u64 + blob.used;
+        blob.used += size as u64;
+
+        let bucket = hash(s) >> (64 - HASH_BITS);
+        let next = self.index.get(bucket).unwrap_or(0);
+        unsafe {
+            let entry = (blob as *mut Blob as *mut u8).add((id % PAGESZ as u64) as usize);
+            ptr::write(entry as *mut Entry, Entry { next, len: s.len() as u16 });
+            ptr::copy_nonoverlapping(s.as_ptr(), entry.add(ENTRY_SIZE), s.len());
+        }
+        self.index.insert(bucket, id);
+        meta.len += 1;
+        id
+    }
+
+    /// The string with the given id.
+    ///
+    /// # Panics
+    ///
+    /// * May panic if `id` wasn't returned by `intern` on this table.
+    pub fn resolve(&self, id: u64) -> &str {
+        self.entry(id).1
+    }
+
+    fn blob(&self, id: PageId) -> *mut Blob {
+        self.heap.page(id).expect("corrupt string table") as *mut Blob
+    }
+
+    /// Frees all pages of the table, including the meta page.
+    pub fn destroy(self) {
+        let mut id = unsafe { (*self.meta).blobs };
+        // bounded, there can't be more blob pages than strings
+        for _ in 0..self.len() {
+            if id == NULL_PAGE {
+                break;
+            }
+            let next = unsafe { (*self.blob(id)).next };
+            self.heap.free(id);
+            id = next;
+        }
+        self.index.destroy();
+        self.heap.free(self.meta_page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn string_table() {
+        let _ = fs::remove_file("/tmp/strings.bin");
+        let mapping = MappedHeap::open("/tmp/strings.bin").unwrap();
+
+        let mut table = StringTable::create(&mapping);
+        let words: Vec<String> = (0..20000).map(|i| format!("word-{}", i)).collect();
+        let ids: Vec<u64> = words.iter().map(|x| table.intern(x)).collect();
+        let long = "x".repeat(MAX_STRING_LEN);
+        let long_id = table.intern(&long);
+        assert_eq!(table.intern(""), table.intern(""));
+        assert_eq!(table.len(), 20002);
+
+        let meta = table.meta_page();
+        let mut table = unsafe { StringTable::open(&mapping, meta) };
+        for (word, &id) in words.iter().zip(&ids) {
+            assert_eq!(table.intern(word), id);
+            assert_eq!(table.resolve(id), word);
+        }
+        assert_eq!(table.resolve(long_id), long);
+        assert_eq!(table.get("word-20000"), None);
+        assert_eq!(table.len(), 20002);
+
+        table.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/strings.bin");
+    }
+}
@@ -0,0 +1,236 @@
+//! Tiered storage: moving cold pages of a heap into a secondary heap file.
+//!
+//! A `TieredHeap` pairs the heap holding the data (the hot tier) with a second heap,
+//! typically on slower and cheaper storage (the cold tier). Demoting a page copies it
+//! into the cold heap, records the move in a forwarding table and punches a hole where
+//! it used to be, so the page keeps its id (and stays allocated in the hot heap) but
+//! no longer takes up space there. `TieredHeap::page` resolves forwarded ids, so users
+//! of the pair don't need to know where a page currently lives.
+//!
+//! The forwarding table is a `RadixTree` in the cold heap (in its root slot 0), which
+//! keeps the hot heap free of pages that could themselves be mistaken for cold data.
+//! A second tree (in root slot 1) records the pages allocated through the pair, the
+//! only ones that may be moved: everything else in the hot heap, such as the nodes of
+//! data structures built on it directly, stays where its users expect it. The kernel's
+//! page cache serves as the access tracker: `demote_cold` moves tracked pages that
+//! aren't resident, i.e. that haven't been touched in a while.
+
+use libc::{c_void, mincore};
+use std::ptr;
+
+use {MappedHeap, PageId, RadixTree, PAGESZ, ROOT_SLOTS};
+
+const TABLE_SLOT: usize = 0;
+const OWNED_SLOT: usize = 1;
+
+/// A heap whose rarely used pages are kept in a secondary heap.
+///
+/// Moving a page invalidates the pointers `page` returned for it, and nobody else
+/// (no other handle, in no other process) may use the pair while pages are demoted,
+/// promoted or freed.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, TieredHeap};
+///
+/// let hot = MappedHeap::open("/tmp/test-tier-hot.bin").unwrap();
+/// let cold = MappedHeap::open("/tmp/test-tier-cold.bin").unwrap();
+/// let tiered = TieredHeap::new(hot, cold);
+/// let id = tiered.alloc();
+/// unsafe { (*tiered.page(id).unwrap())[0] = 42 };
+/// tiered.demote(id);
+/// assert_eq!(unsafe { (*tiered.page(id).unwrap())[0] }, 42);
+/// tiered.free(id);
+/// ```
+pub struct TieredHeap {
+    hot: MappedHeap,
+    cold: MappedHeap,
+}
+
+impl TieredHeap {
+    /// Combines a heap with the heap its cold pages go to.
+    ///
+    /// The cold heap must only ever be used as the cold tier of this heap.
+    pub fn new(hot: MappedHeap, cold: MappedHeap) -> TieredHeap {
+        cold.root_or_create(TABLE_SLOT, |heap| RadixTree::create(heap).meta_page());
+        cold.root_or_create(OWNED_SLOT, |heap| RadixTree::create(heap).meta_page());
+        TieredHeap { hot, cold }
+    }
+
+    /// The hot tier.
+    pub fn hot(&self) -> &MappedHeap {
+        &self.hot
+    }
+
+    /// The cold tier.
+    pub fn cold(&self) -> &MappedHeap {
+        &self.cold
+    }
+
+    // Maps page ids in the hot heap to their copies in the cold heap.
+    fn table(&self) -> RadixTree<'_> {
+        unsafe { RadixTree::open(&self.cold, self.cold.root(TABLE_SLOT).unwrap()) }
+    }
+
+    // The pages allocated through the pair (each with the value 1).
+    fn owned(&self) -> RadixTree<'_> {
+        unsafe { RadixTree::open(&self.cold, self.cold.root(OWNED_SLOT).unwrap()) }
+    }
+
+    /// Allocates a page (in the hot tier). Only pages allocated this way can be
+    /// moved to the cold tier.
+    pub fn alloc(&self) -> PageId {
+        let id = self.hot.alloc();
+        self.owned().insert(id, 1);
+        id
+    }
+
+    /// Gets a pointer to a page, wherever it currently lives.
+    pub fn page(&self, id: PageId) -> Option<*mut [u8; PAGESZ]> {
+        let table = self.table();
+        match if table.is_empty() { None } else { table.get(id) } {
+            Some(cold) => self.cold.page(cold),
+            None => self.hot.page(id),
+        }
+    }
+
+    /// Whether a page has been demoted to the cold tier.
+    pub fn is_cold(&self, id: PageId) -> bool {
+        self.table().get(id).is_some()
+    }
+
+    /// Frees a page, wherever it currently lives.
+    pub fn free(&self, id: PageId) {
+        if let Some(cold) = self.table().remove(id) {
+            self.cold.free(cold);
+        }
+        self.owned().remove(id);
+        self.hot.free(id);
+    }
+
+    /// Moves a page to the cold tier. Returns false if it already was there.
+    ///
+    /// # Panics
+    ///
+    /// * If the page wasn't allocated with `alloc`.
+    pub fn demote(&self, id: PageId) -> bool {
+        assert!(self.owned().get(id).is_some(), "page {} wasn't allocated by the tiered heap", id);
+        let mut table = self.table();
+        if table.get(id).is_some() {
+            return false;
+        }
+        let src = self.hot.page(id).expect("invalid page");
+        let cold = self.cold.alloc();
+        unsafe { ptr::copy_nonoverlapping(src, self.cold.page(cold).unwrap(), 1) };
+        table.insert(id, cold);
+        self.hot.punch_page(id);
+        true
+    }
+
+    /// Moves a page back to the hot tier. Returns false if it already was there.
+    pub fn promote(&self, id: PageId) -> bool {
+        let cold = match self.table().remove(id) {
+            Some(cold) => cold,
+            None => return false,
+        };
+        unsafe { ptr::copy_nonoverlapping(self.cold.page(cold).unwrap(), self.hot.page(id).unwrap(), 1) };
+        self.cold.free(cold);
+        true
+    }
+
+    /// Demotes up to `max` pages allocated with `alloc` that are not in the page cache,
+    /// i.e. that haven't been accessed recently, and returns how many were demoted.
+    ///
+    /// Pages in one of the hot heap's root slots are never demoted, even if they were
+    /// allocated with `alloc`. Finding the pages asks the kernel about every page of
+    /// the hot heap.
+    pub fn demote_cold(&self, max: usize) -> usize {
+        let mut owned = Vec::new();
+        self.owned().for_each(|id, _| owned.push(id));
+        let roots: Vec<_> = (0..ROOT_SLOTS).filter_map(|x| self.hot.root(x)).collect();
+
+        let mut cold = Vec::new();
+        for mapping in self.hot.mappings() {
+            let first = mapping.file_offset / PAGESZ as u64;
+            let mut resident = vec![0u8; mapping.len / PAGESZ];
+            if unsafe { mincore(mapping.addr as *mut c_void, mapping.len, resident.as_mut_ptr() as *mut _) } != 0 {
+                continue;
+            }
+            for (i, &x) in resident.iter().enumerate() {
+                let id = first + i as u64;
+                if x & 1 == 0 && owned.binary_search(&id).is_ok() && !roots.contains(&id) {
+                    cold.push(id);
+                }
+            }
+        }
+
+        let mut n = 0;
+        for id in cold {
+            if n == max {
+                break;
+            }
+            if self.demote(id) {
+                n += 1;
+            }
+        }
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn tiered_heap() {
+        let _ = fs::remove_file("/tmp/tier-hot.bin");
+        let _ = fs::remove_file("/tmp/tier-cold.bin");
+        let tiered = TieredHeap::new(MappedHeap::open("/tmp/tier-hot.bin").unwrap(),
+                                     MappedHeap::open("/tmp/tier-cold.bin").unwrap());
+        let ids: Vec<_> = (0..20).map(|_| tiered.alloc()).collect();
+        // neither pages allocated from the hot heap directly nor roots are moved
+        let other = tiered.hot().alloc();
+        tiered.hot().root_or_create(0, |_| ids[19]);
+        for &id in &ids {
+            unsafe { (&mut *tiered.page(id).unwrap())[..8].copy_from_slice(&id.to_ne_bytes()) };
+        }
+
+        assert!(tiered.demote(ids[0]));
+        assert!(!tiered.demote(ids[0]));
+        assert!(tiered.is_cold(ids[0]));
+        assert_eq!(unsafe { (*tiered.hot().page(ids[0]).unwrap())[0] }, 0);
+        assert!(tiered.promote(ids[0]));
+        assert!(!tiered.promote(ids[0]));
+
+        // the policy picks the pages that fell out of the page cache
+        tiered.hot().flush().unwrap();
+        let evict = || for &id in &ids[10..] {
+            tiered.hot().evict_page(id);
+        };
+        evict();
+        tiered.hot().evict_page(other);
+        assert_eq!(tiered.demote_cold(3), 3);
+        // (reading the demoted pages may have read ahead)
+        evict();
+        assert_eq!(tiered.demote_cold(100), 6);
+        assert_eq!(tiered.demote_cold(100), 0);
+        assert!(!tiered.is_cold(other));
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(tiered.is_cold(id), (10..19).contains(&i));
+            assert_eq!(unsafe { &(&*tiered.page(id).unwrap())[..8] }, id.to_ne_bytes());
+        }
+
+        for &id in &ids {
+            tiered.free(id);
+        }
+        tiered.hot().free(other);
+        // all that's left in the cold heap are the empty trees
+        assert_eq!(tiered.cold().free_pages(), tiered.cold().size() - 3);
+        assert_eq!(tiered.hot().free_pages(), tiered.hot().size() - 1);
+
+        let _ = fs::remove_file("/tmp/tier-hot.bin");
+        let _ = fs::remove_file("/tmp/tier-cold.bin");
+    }
+}
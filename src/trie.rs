@@ -0,0 +1,323 @@
+//! A persistent trie over byte-string keys, for prefix lookups.
+//!
+//! Keys are split into nibbles, so every node has 16 children and every byte of a key
+//! takes two levels. Nodes are fixed size and packed into a chain of node pages; nodes
+//! freed by `remove` are kept on a free list and reused before the chain grows. The
+//! chain itself only shrinks on `destroy`.
+
+use std::{mem, ptr};
+
+use {MappedHeap, PageId, NULL_PAGE, PAGESZ};
+
+#[repr(C)]
+struct Meta {
+    root: u64,
+    nodes: PageId, // the page nodes are currently allocated from, linking to the older ones
+    free: u64,     // freed nodes, linked through their first child
+    len: u64,
+}
+
+#[repr(C)]
+struct NodePage {
+    next: PageId,
+    used: u64, // bytes, including this header
+}
+
+// Nodes are identified by their position in the file, so 0 means "no node".
+#[repr(C)]
+struct Node {
+    children: [u64; 16],
+    value: u64,
+    has_value: u64,
+}
+
+const NODE_SIZE: usize = mem::size_of::<Node>();
+
+fn nibbles(key: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    key.iter().flat_map(|&b| [(b >> 4) as usize, (b & 15) as usize])
+}
+
+/// A map from byte strings to `u64` values, stored in a `MappedHeap`, that can list
+/// all keys with a given prefix in order.
+///
+/// Every byte of a key costs up to two nodes of 144 bytes, shared with all keys with
+/// the same prefix, so this suits sets of keys with long common prefixes (paths,
+/// routes, URLs) better than random ones.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, MappedTrie};
+///
+/// let mapping = MappedHeap::open("/tmp/test-trie.bin").unwrap();
+/// let mut trie = MappedTrie::create(&mapping);
+/// trie.insert(b"/api/users", 1);
+/// trie.insert(b"/api/posts", 2);
+/// trie.insert(b"/static", 3);
+/// assert_eq!(trie.get(b"/api/posts"), Some(2));
+/// let api: Vec<_> = trie.iter_prefix(b"/api/").collect();
+/// assert_eq!(api, vec![(b"/api/posts".to_vec(), 2), (b"/api/users".to_vec(), 1)]);
+/// trie.destroy();
+/// ```
+pub struct MappedTrie<'a> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+}
+
+impl<'a> MappedTrie<'a> {
+    /// Creates a new, empty trie.
+    pub fn create(heap: &'a MappedHeap) -> MappedTrie<'a> {
+        let meta_page = heap.alloc();
+        unsafe {
+            ptr::write(heap.page(meta_page).unwrap() as *mut Meta, Meta { root: 0, nodes: NULL_PAGE, free: 0, len: 0 });
+            let mut trie = MappedTrie::open(heap, meta_page);
+            (*trie.meta).root = trie.new_node();
+            trie
+        }
+    }
+
+    /// Opens an existing trie by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, and nobody else may modify
+    /// the trie while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> MappedTrie<'a> {
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        MappedTrie { heap, meta, meta_page }
+    }
+
+    /// The id of the meta page, which identifies this trie.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of keys.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether the trie is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn node(&self, id: u64) -> &'a mut Node {
+        let (page, offset) = (id / PAGESZ as u64, id as usize % PAGESZ);
+        assert!(page != NULL_PAGE && offset + NODE_SIZE <= PAGESZ, "corrupt trie");
+        let page = self.heap.page(page).expect("corrupt trie") as *mut u8;
+        unsafe { &mut *(page.add(offset) as *mut Node) }
+    }
+
+    fn node_page(&self, id: PageId) -> &'a mut NodePage {
+        unsafe { &mut *(self.heap.page(id).expect("corrupt trie") as *mut NodePage) }
+    }
+
+    fn new_node(&mut self) -> u64 {
+        let meta = unsafe { &mut *self.meta };
+        let id = if meta.free != 0 {
+            let id = meta.free;
+            meta.free = self.node(id).children[0];
+            id
+        } else {
+            let fits = meta.nodes != NULL_PAGE && self.node_page(meta.nodes).used as usize + NODE_SIZE <= PAGESZ;
+            if !fits {
+                let page = self.heap.alloc();
+                unsafe { ptr::write(self.node_page(page), NodePage { next: meta.nodes, used: mem::size_of::<NodePage>() as u64 }) };
+                meta.nodes = page;
+            }
+            let page = self.node_page(meta.nodes);
+            let id = meta.nodes * PAGESZ as u64 + page.used;
+            page.used += NODE_SIZE as u64;
+            id
+        };
+        unsafe { ptr::write(self.node(id), Node { children: [0; 16], value: 0, has_value: 0 }) };
+        id
+    }
+
+    fn find(&self, key: &[u8]) -> Option<u64> {
+        let mut id = unsafe { (*self.meta).root };
+        for nibble in nibbles(key) {
+            id = self.node(id).children[nibble];
+            if id == 0 {
+                return None;
+            }
+        }
+        Some(id)
+    }
+
+    /// Looks up the value of a key.
+    pub fn get(&self, key: &[u8]) -> Option<u64> {
+        let node = self.node(self.find(key)?);
+        if node.has_value != 0 { Some(node.value) } else { None }
+    }
+
+    /// Inserts a key, returning its previous value if it already existed.
+    pub fn insert(&mut self, key: &[u8], value: u64) -> Option<u64> {
+        let mut node = self.node(unsafe { (*self.meta).root });
+        for nibble in nibbles(key) {
+            if node.children[nibble] == 0 {
+                node.children[nibble] = self.new_node();
+            }
+            node = self.node(node.children[nibble]);
+        }
+        let old = if node.has_value != 0 { Some(node.value) } else { None };
+        node.value = value;
+        node.has_value = 1;
+        if old.is_none() {
+            unsafe { (*self.meta).len += 1 };
+        }
+        old
+    }
+
+    /// Removes a key, returning its value if it existed.
+    ///
+    /// Nodes left without keys below them are freed for reuse.
+    pub fn remove(&mut self, key: &[u8]) -> Option<u64> {
+        let nibbles: Vec<_> = nibbles(key).collect();
+        let mut path = vec![unsafe { (*self.meta).root }];
+        for &nibble in &nibbles {
+            let id = self.node(*path.last().unwrap()).children[nibble];
+            if id == 0 {
+                return None;
+            }
+            path.push(id);
+        }
+        let node = self.node(*path.last().unwrap());
+        if node.has_value == 0 {
+            return None;
+        }
+        let value = node.value;
+        node.has_value = 0;
+        unsafe { (*self.meta).len -= 1 };
+
+        // prune from the bottom up (but keep the root)
+        for i in (1..path.len()).rev() {
+            let node = self.node(path[i]);
+            if node.has_value != 0 || node.children.iter().any(|&x| x != 0) {
+                break;
+            }
+            let meta = unsafe { &mut *self.meta };
+            node.children[0] = meta.free;
+            meta.free = path[i];
+            self.node(path[i - 1]).children[nibbles[i - 1]] = 0;
+        }
+        Some(value)
+    }
+
+    /// Iterates over all keys starting with `prefix` (including `prefix` itself) and
+    /// their values, in lexicographic order of the keys.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> TrieIter<'_, 'a> {
+        let stack = self.find(prefix).map(|id| (id, 0)).into_iter().collect();
+        TrieIter { trie: self, key: nibbles(prefix).map(|x| x as u8).collect(), stack }
+    }
+
+    /// Iterates over all keys and their values, in lexicographic order of the keys.
+    pub fn iter(&self) -> TrieIter<'_, 'a> {
+        self.iter_prefix(&[])
+    }
+
+    /// Frees all pages of the trie, including the meta page.
+    pub fn destroy(self) {
+        let mut id = unsafe { (*self.meta).nodes };
+        while id != NULL_PAGE {
+            let next = self.node_page(id).next;
+            self.heap.free(id);
+            id = next;
+        }
+        self.heap.free(self.meta_page);
+    }
+}
+
+/// An iterator over the keys and values of a `MappedTrie`, see `MappedTrie::iter_prefix`.
+pub struct TrieIter<'b, 'a: 'b> {
+    trie: &'b MappedTrie<'a>,
+    key: Vec<u8>,           // in nibbles
+    stack: Vec<(u64, u64)>, // (node, 0 for its own value or 1 + the next child to visit)
+}
+
+impl<'b, 'a> Iterator for TrieIter<'b, 'a> {
+    type Item = (Vec<u8>, u64);
+
+    fn next(&mut self) -> Option<(Vec<u8>, u64)> {
+        loop {
+            let &mut (id, ref mut next) = self.stack.last_mut()?;
+            let node = self.trie.node(id);
+            if *next == 0 {
+                *next = 1;
+                if node.has_value != 0 {
+                    let key = self.key.chunks(2).map(|x| x[0] << 4 | x[1]).collect();
+                    return Some((key, node.value));
+                }
+            } else if *next > 16 {
+                self.stack.pop();
+                if !self.stack.is_empty() {
+                    self.key.pop();
+                }
+            } else {
+                let nibble = *next - 1;
+                *next += 1;
+                let child = node.children[nibble as usize];
+                if child != 0 {
+                    self.key.push(nibble as u8);
+                    self.stack.push((child, 0));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use rand::{self, Rng};
+
+    #[test]
+    fn trie() {
+        let _ = fs::remove_file("/tmp/trie.bin");
+        let mapping = MappedHeap::open("/tmp/trie.bin").unwrap();
+
+        let mut trie = MappedTrie::create(&mapping);
+        let mut expected = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for i in 0..5000u64 {
+            let key = format!("/{}/{}", rng.gen_range(0, 20), rng.gen_range(0, 500)).into_bytes();
+            assert_eq!(trie.insert(&key, i), expected.insert(key, i));
+        }
+        assert_eq!(trie.insert(b"", 42), None);
+        expected.insert(Vec::new(), 42);
+        assert_eq!(trie.len(), expected.len() as u64);
+
+        let meta = trie.meta_page();
+        let mut trie = unsafe { MappedTrie::open(&mapping, meta) };
+        assert!(trie.iter().eq(expected.iter().map(|(k, &v)| (k.clone(), v))));
+        assert!(trie.iter_prefix(b"/1").eq(expected.iter().filter(|x| x.0.starts_with(b"/1")).map(|(k, &v)| (k.clone(), v))));
+        assert_eq!(trie.iter_prefix(b"/x").next(), None);
+        assert_eq!(trie.get(b"/1"), None);
+
+        let size = mapping.free_pages();
+        let keys: Vec<_> = expected.keys().cloned().collect();
+        for key in &keys[..keys.len() / 2] {
+            assert_eq!(trie.remove(key), expected.remove(key));
+            assert_eq!(trie.remove(key), None);
+        }
+        assert!(trie.iter().eq(expected.iter().map(|(k, &v)| (k.clone(), v))));
+        // freed nodes are reused
+        for key in &keys[..keys.len() / 2] {
+            trie.insert(key, 0);
+        }
+        assert_eq!(mapping.free_pages(), size);
+
+        trie.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/trie.bin");
+    }
+}
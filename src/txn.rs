@@ -0,0 +1,99 @@
+//! Read transactions: a fixed view of the heap's size and mapping for multi-page reads.
+
+use {CachedFragment, MappedHeap, PageId, NULL_PAGE, PAGESZ};
+
+/// A pinned view of the heap for a sequence of page lookups, see `MappedHeap::read_txn`.
+///
+/// Pages don't become any less mutable by looking them up through a transaction:
+/// this only pins which pages exist and where they are mapped.
+pub struct ReadTxn<'a> {
+    heap: &'a MappedHeap,
+    size: PageId,
+    fragments: Vec<CachedFragment>,
+}
+
+impl MappedHeap {
+    /// Pins the current size of the heap and its mapping for a sequence of reads.
+    ///
+    /// `ReadTxn::page` never takes a lock or makes a system call, and pages beyond
+    /// the pinned size don't exist as far as the transaction is concerned, so the
+    /// file growing meanwhile can't change what a multi-page operation sees halfway
+    /// through. (Fragments never move, so the pinned mapping stays valid.)
+    ///
+    /// # Panics
+    ///
+    /// * If the mapping needs to be extended but the syscall fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mappedheap::MappedHeap;
+    ///
+    /// let mapping = MappedHeap::open("/tmp/test-txn.bin").unwrap();
+    /// let page_id = mapping.alloc();
+    /// let txn = mapping.read_txn();
+    /// assert!(txn.page(page_id).is_some());
+    /// ```
+    pub fn read_txn(&self) -> ReadTxn<'_> {
+        let size = self.size();
+        // (maps everything up to the size, and refreshes our copy of the fragment list)
+        self.page(size - 1).expect("invalid size");
+        ReadTxn { heap: self, size, fragments: self.cache.borrow().clone() }
+    }
+}
+
+impl<'a> ReadTxn<'a> {
+    /// The heap this transaction reads.
+    pub fn heap(&self) -> &'a MappedHeap {
+        self.heap
+    }
+
+    /// The number of pages in the file when the transaction started.
+    pub fn size(&self) -> PageId {
+        self.size
+    }
+
+    /// Like `MappedHeap::page`, but only for pages that existed when the transaction
+    /// started.
+    pub fn page(&self, id: PageId) -> Option<*const [u8; PAGESZ]> {
+        if id == NULL_PAGE || id >= self.size {
+            return None;
+        }
+        let fragment = match self.fragments.binary_search_by_key(&id, |x| x.offset) {
+            Ok(i) => self.fragments[i],
+            Err(i) => self.fragments[i - 1],
+        };
+        debug_assert!(id - fragment.offset < fragment.size);
+        Some((fragment.addr + (id - fragment.offset) as usize * PAGESZ) as *const [u8; PAGESZ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use HeapOptions;
+
+    #[test]
+    fn read_txn() {
+        let _ = fs::remove_file("/tmp/txn.bin");
+        let mapping = HeapOptions::new().guard_pages(true).open("/tmp/txn.bin").unwrap();
+        let id = mapping.alloc();
+        unsafe { (*mapping.page(id).unwrap())[0] = 42 };
+
+        let txn = mapping.read_txn();
+        let size = txn.size();
+        assert_eq!(txn.page(id).map(|x| unsafe { (*x)[0] }), Some(42));
+        assert_eq!(txn.page(id), mapping.page(id).map(|x| x as *const _));
+
+        // growth (into a new fragment, with guard pages) stays invisible
+        let other = mapping.clone();
+        while other.size() == size {
+            other.alloc();
+        }
+        assert_eq!(txn.page(size), None);
+        assert!(txn.page(size - 1).is_some());
+        assert!(mapping.read_txn().page(size).is_some());
+
+        let _ = fs::remove_file("/tmp/txn.bin");
+    }
+}
@@ -0,0 +1,69 @@
+use std::mem;
+
+use bytemuck::{AnyBitPattern, NoUninit};
+
+use {assert_aligned, MappedHeap, PageId, PAGESZ};
+
+impl MappedHeap {
+    /// Retrieves a typed reference to a given page by Id, if it exists within the file.
+    ///
+    /// Unlike `page_ref`, `T` may be smaller than a page (it refers to the start of the
+    /// page) and bytemuck's `AnyBitPattern` guarantees that whatever the page contains
+    /// is a valid `T`, so garbage in the file can't produce invalid values.
+    ///
+    /// # Safety
+    ///
+    /// The page must not be modified while the returned reference is alive.
+    /// The same security notes as for `page_ref` apply.
+    ///
+    /// # Panics
+    ///
+    /// * If T is larger than a page or needs more alignment than a page provides.
+    /// * Same as `page`.
+    pub unsafe fn page_as<T: AnyBitPattern>(&self, id: PageId) -> Option<&T> {
+        assert!(mem::size_of::<T>() <= PAGESZ);
+        assert_aligned::<T>();
+        self.page(id).map(|x| &*(x as *const T))
+    }
+
+    /// Retrieves a typed mutable reference to a given page by Id, if it exists within the file.
+    ///
+    /// `NoUninit` guarantees that writing a `T` never leaves uninitialized (padding)
+    /// bytes in the file, in addition to the guarantees of `page_as`.
+    ///
+    /// # Safety
+    ///
+    /// Nobody else may access the page while the returned reference is alive.
+    /// The same security notes as for `page_ref` apply.
+    ///
+    /// # Panics
+    ///
+    /// Same as `page_as`.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn page_as_mut<T: NoUninit + AnyBitPattern>(&self, id: PageId) -> Option<&mut T> {
+        assert!(mem::size_of::<T>() <= PAGESZ);
+        assert_aligned::<T>();
+        self.page(id).map(|x| &mut *(x as *mut T))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn typed_pages() {
+        let _ = fs::remove_file("/tmp/typed.bin");
+        let mapping = MappedHeap::open("/tmp/typed.bin").unwrap();
+
+        let id = mapping.alloc();
+        unsafe {
+            *mapping.page_as_mut::<[u64; 4]>(id).unwrap() = [1, 2, 3, 4];
+            assert_eq!(mapping.page_as::<[u32; 2]>(id).unwrap(), &[1, 0]);
+            assert!(mapping.page_as::<u64>(mapping.alloc() + 1000).is_none());
+        }
+
+        let _ = fs::remove_file("/tmp/typed.bin");
+    }
+}
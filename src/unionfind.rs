@@ -0,0 +1,166 @@
+//! A persistent union-find (disjoint set) structure over `u64` elements.
+//!
+//! The parent of every element is stored in a `RadixTree` (keyed by element), with
+//! elements that are their own root simply absent, so a fresh structure takes no space
+//! at all. Unions link by size, which keeps trees shallow on its own. Paths are
+//! compressed by halving during `find`, but only where that actually changes a
+//! parent, so lookups on already flat trees don't dirty any pages.
+
+use {MappedHeap, PageId, RadixTree};
+
+/// A disjoint set forest stored in a `MappedHeap`.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, MappedUnionFind};
+///
+/// let mapping = MappedHeap::open("/tmp/test-unionfind.bin").unwrap();
+/// let mut sets = MappedUnionFind::create(&mapping);
+/// sets.union(1, 2);
+/// sets.union(2, 3);
+/// assert_eq!(sets.find(3), sets.find(1));
+/// assert!(sets.find(4) != sets.find(1));
+/// sets.destroy();
+/// ```
+pub struct MappedUnionFind<'a> {
+    parents: RadixTree<'a>, // value is parent + 1
+    sizes: RadixTree<'a>, // of roots, minus one (absent for singletons)
+    meta_page: PageId,
+    heap: &'a MappedHeap,
+}
+
+impl<'a> MappedUnionFind<'a> {
+    /// Creates a new structure in which every element is in a set of its own.
+    pub fn create(heap: &'a MappedHeap) -> MappedUnionFind<'a> {
+        let meta_page = heap.alloc();
+        let parents = RadixTree::create(heap).meta_page();
+        let sizes = RadixTree::create(heap).meta_page();
+        unsafe {
+            *(heap.page(meta_page).unwrap() as *mut [PageId; 2]) = [parents, sizes];
+            MappedUnionFind::open(heap, meta_page)
+        }
+    }
+
+    /// Opens an existing structure by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, and nobody else may access
+    /// the structure while the returned handle is in use (`find` modifies it, too).
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> MappedUnionFind<'a> {
+        let [parents, sizes] = *(heap.page(meta_page).expect("invalid meta page") as *const [PageId; 2]);
+        MappedUnionFind {
+            parents: RadixTree::open(heap, parents),
+            sizes: RadixTree::open(heap, sizes),
+            meta_page,
+            heap,
+        }
+    }
+
+    /// The id of the meta page, which identifies this structure.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    fn parent(&self, x: u64) -> Option<u64> {
+        self.parents.get(x).map(|p| p - 1)
+    }
+
+    /// The representative of the set containing `x`.
+    ///
+    /// # Panics
+    ///
+    /// * If `x` is `u64::MAX`.
+    pub fn find(&mut self, mut x: u64) -> u64 {
+        assert!(x != u64::MAX);
+        while let Some(parent) = self.parent(x) {
+            match self.parent(parent) {
+                Some(grandparent) => {
+                    // path halving: skip a level on the way up
+                    self.parents.insert(x, grandparent + 1);
+                    x = grandparent;
+                }
+                None => return parent,
+            }
+        }
+        x
+    }
+
+    /// The number of elements in the set containing `x`.
+    pub fn set_size(&mut self, x: u64) -> u64 {
+        let root = self.find(x);
+        self.sizes.get(root).unwrap_or(0) + 1
+    }
+
+    /// Merges the sets containing `a` and `b`. Returns false if they already were the same set.
+    ///
+    /// # Panics
+    ///
+    /// * If `a` or `b` is `u64::MAX`.
+    pub fn union(&mut self, a: u64, b: u64) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        let size = |x| self.sizes.get(x).unwrap_or(0) + 1;
+        let (size_a, size_b) = (size(a), size(b));
+        // the smaller tree goes below the larger one
+        let (root, child) = if size_a < size_b { (b, a) } else { (a, b) };
+        self.parents.insert(child, root + 1);
+        self.sizes.remove(child);
+        self.sizes.insert(root, size_a + size_b - 1);
+        true
+    }
+
+    /// Whether `a` and `b` are in the same set.
+    pub fn same_set(&mut self, a: u64, b: u64) -> bool {
+        self.find(a) == self.find(b)
+    }
+
+    /// Frees all pages of the structure, including the meta page.
+    pub fn destroy(self) {
+        self.parents.destroy();
+        self.sizes.destroy();
+        self.heap.free(self.meta_page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn union_find() {
+        let _ = fs::remove_file("/tmp/unionfind.bin");
+        let mapping = MappedHeap::open("/tmp/unionfind.bin").unwrap();
+
+        let mut sets = MappedUnionFind::create(&mapping);
+        // residues mod 7
+        for i in 7..10000 {
+            assert!(sets.union(i, i - 7));
+        }
+        assert!(!sets.union(0, 700));
+        assert_eq!(sets.set_size(3), 1429);
+
+        let meta = sets.meta_page();
+        let mut sets = unsafe { MappedUnionFind::open(&mapping, meta) };
+        for i in 0..10000 {
+            assert!(sets.same_set(i, i % 7));
+            assert!(!sets.same_set(i, (i + 1) % 7));
+        }
+        assert_eq!(sets.find(1 << 40), 1 << 40);
+        sets.union(1 << 40, 5);
+        assert_eq!(sets.set_size(5), 1429);
+
+        sets.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/unionfind.bin");
+    }
+}
@@ -0,0 +1,204 @@
+//! A persistent growable array (feature `bytemuck`).
+//!
+//! Elements are packed into data pages (never straddling two), and a radix tree maps
+//! the index of every data page to its id. Indexing thus costs a lookup in a tree
+//! that is at most a handful of levels high and never needs any comparisons.
+
+use std::marker::PhantomData;
+use std::{mem, ptr};
+
+use bytemuck::Pod;
+
+use {assert_aligned, MappedHeap, PageId, RadixTree, PAGESZ};
+
+#[repr(C)]
+struct Meta {
+    len: u64,
+    elem_size: u64,
+    index: PageId,
+}
+
+/// A growable array of `Pod` values stored in a `MappedHeap`.
+///
+/// # Example
+///
+/// ```
+/// use mappedheap::{MappedHeap, MappedVec};
+///
+/// let mapping = MappedHeap::open("/tmp/test-vec.bin").unwrap();
+/// let mut vec = MappedVec::<u64>::create(&mapping);
+/// vec.push(42);
+/// assert_eq!(vec.get(0), Some(42));
+/// vec.destroy();
+/// ```
+pub struct MappedVec<'a, T: Pod> {
+    heap: &'a MappedHeap,
+    meta: *mut Meta,
+    meta_page: PageId,
+    index: RadixTree<'a>,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T: Pod> MappedVec<'a, T> {
+    const PER_PAGE: u64 = (PAGESZ / mem::size_of::<T>()) as u64;
+
+    fn check_type() {
+        assert!(mem::size_of::<T>() > 0 && mem::size_of::<T>() <= PAGESZ, "unsupported element type");
+        // (sizes are multiples of the alignment, so all further slots are aligned too)
+        assert_aligned::<T>();
+    }
+
+    /// Creates a new, empty vector.
+    ///
+    /// # Panics
+    ///
+    /// * If `T` is zero-sized, larger than a page or needs more than page alignment.
+    pub fn create(heap: &'a MappedHeap) -> MappedVec<'a, T> {
+        MappedVec::<T>::check_type();
+        let meta_page = heap.alloc();
+        let index = RadixTree::create(heap).meta_page();
+        let meta = Meta { len: 0, elem_size: mem::size_of::<T>() as u64, index };
+        unsafe {
+            ptr::write(heap.page(meta_page).unwrap() as *mut Meta, meta);
+            MappedVec::open(heap, meta_page)
+        }
+    }
+
+    /// Opens an existing vector by its meta page.
+    ///
+    /// # Safety
+    ///
+    /// The meta page must have been created by `create`, and nobody else may modify
+    /// the vector while the returned handle is in use.
+    ///
+    /// # Panics
+    ///
+    /// * If `meta_page` doesn't exist within the file.
+    /// * If the vector was created with an element type of a different size.
+    pub unsafe fn open(heap: &'a MappedHeap, meta_page: PageId) -> MappedVec<'a, T> {
+        MappedVec::<T>::check_type();
+        let meta = heap.page(meta_page).expect("invalid meta page") as *mut Meta;
+        assert_eq!((*meta).elem_size, mem::size_of::<T>() as u64, "element size mismatch");
+        let index = RadixTree::open(heap, (*meta).index);
+        MappedVec { heap, meta, meta_page, index, _marker: PhantomData }
+    }
+
+    /// The id of the meta page, which identifies this vector.
+    pub fn meta_page(&self) -> PageId {
+        self.meta_page
+    }
+
+    /// The number of elements.
+    pub fn len(&self) -> u64 {
+        unsafe { (*self.meta).len }
+    }
+
+    /// Whether the vector is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn slot(&self, i: u64) -> *mut T {
+        let page = self.index.get(i / Self::PER_PAGE).expect("corrupt vector");
+        let page = self.heap.page(page).expect("corrupt vector") as *mut T;
+        unsafe { page.add((i % Self::PER_PAGE) as usize) }
+    }
+
+    /// The element at index `i`.
+    pub fn get(&self, i: u64) -> Option<T> {
+        if i < self.len() {
+            Some(unsafe { ptr::read(self.slot(i)) })
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the element at index `i`.
+    ///
+    /// # Panics
+    ///
+    /// * If `i` is out of bounds.
+    pub fn set(&mut self, i: u64, value: T) {
+        assert!(i < self.len(), "index out of bounds");
+        unsafe { ptr::write(self.slot(i), value) };
+    }
+
+    /// Appends an element.
+    pub fn push(&mut self, value: T) {
+        let len = self.len();
+        if len.is_multiple_of(Self::PER_PAGE) {
+            let page = self.heap.alloc();
+            self.index.insert(len / Self::PER_PAGE, page);
+        }
+        unsafe {
+            ptr::write(self.slot(len), value);
+            (*self.meta).len += 1;
+        }
+    }
+
+    /// Removes the last element and returns it.
+    pub fn pop(&mut self) -> Option<T> {
+        let ret = self.get(self.len().checked_sub(1)?);
+        self.truncate(self.len() - 1);
+        ret
+    }
+
+    /// Shortens the vector to `len` elements, freeing pages no longer needed.
+    ///
+    /// Does nothing if the vector is no longer than that.
+    pub fn truncate(&mut self, len: u64) {
+        if len >= self.len() {
+            return;
+        }
+        for chunk in (len.div_ceil(Self::PER_PAGE)..self.len().div_ceil(Self::PER_PAGE)).rev() {
+            self.heap.free(self.index.remove(chunk).expect("corrupt vector"));
+        }
+        unsafe { (*self.meta).len = len };
+    }
+
+    /// Frees all pages of the vector, including the meta page.
+    pub fn destroy(mut self) {
+        self.truncate(0);
+        let heap = self.heap;
+        let meta_page = self.meta_page;
+        self.index.destroy();
+        heap.free(meta_page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn mapped_vec() {
+        let _ = fs::remove_file("/tmp/vec.bin");
+        let mapping = MappedHeap::open("/tmp/vec.bin").unwrap();
+
+        let mut vec = MappedVec::<[u32; 3]>::create(&mapping);
+        for i in 0..100000 {
+            vec.push([i, i * 2, i * 3]);
+        }
+        assert_eq!(vec.len(), 100000);
+        vec.set(77777, [1, 2, 3]);
+
+        let meta = vec.meta_page();
+        let mut vec = unsafe { MappedVec::<[u32; 3]>::open(&mapping, meta) };
+        assert_eq!(vec.get(341), Some([341, 682, 1023]));
+        assert_eq!(vec.get(77777), Some([1, 2, 3]));
+        assert_eq!(vec.get(100000), None);
+        assert_eq!(vec.pop(), Some([99999, 199998, 299997]));
+
+        let used = mapping.size() - mapping.free_pages();
+        vec.truncate(10);
+        assert!(mapping.size() - mapping.free_pages() < used - 290);
+        assert_eq!(vec.get(9), Some([9, 18, 27]));
+        assert_eq!(vec.get(10), None);
+
+        vec.destroy();
+        assert_eq!(mapping.free_pages(), mapping.size() - 1);
+
+        let _ = fs::remove_file("/tmp/vec.bin");
+    }
+}
@@ -0,0 +1,43 @@
+//! The crash test harness, in a test binary of its own: forking is only safe while
+//! no other test thread holds a lock the children need (see `mappedheap::crashtest`).
+//! Run this with `--test-threads=1`.
+
+extern crate mappedheap;
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use mappedheap::crashtest::{check_freelists, random_ops, Failure, Torture};
+
+#[test]
+fn harness() {
+    let _ = fs::remove_file("/tmp/harness.bin");
+    let path = Path::new("/tmp/harness.bin");
+    let mut torture = Torture::new();
+    torture.rounds(3).workers(2).check_timeout(Duration::from_secs(1));
+
+    // workers that never take any locks can't break anything
+    torture.run(path, |heap, rng| { heap.root(rng.below(4) as usize); }, check_freelists).unwrap();
+    match torture.run(path, |_, _| (), |_| Err("broken".to_string())) {
+        Err(Failure::Check { round: 0 }) => (),
+        x => panic!("{:?}", x),
+    }
+    match torture.run(path, |_, _| (), |_| loop { thread::sleep(Duration::from_secs(1)) }) {
+        Err(Failure::Hang { round: 0 }) => (),
+        x => panic!("{:?}", x),
+    }
+
+    let _ = fs::remove_file("/tmp/harness.bin");
+}
+
+#[test]
+fn torture() {
+    let _ = fs::remove_file("/tmp/torture.bin");
+    let result = Torture::new().run(Path::new("/tmp/torture.bin"), random_ops, check_freelists);
+    let _ = fs::remove_file("/tmp/torture.bin");
+    if let Err(e) = result {
+        panic!("{}", e);
+    }
+}